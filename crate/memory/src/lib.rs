@@ -17,6 +17,8 @@ pub use crate::addr::*;
 
 pub enum VMError {
     InvalidPtr,
+    /// The limits of the memory set would be exceeded
+    NoMem,
 }
 
 pub type VMResult<T> = Result<T, VMError>;
@@ -12,6 +12,9 @@ pub struct File<F, T> {
 
 pub trait Read: Clone + Send + Sync + 'static {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
+
+    /// Identity of the file, the same for all handles of it
+    fn id(&self) -> usize;
 }
 
 impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
@@ -88,6 +91,10 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
         pt.flush_cache_copy_user(addr, addr + read_size, execute);
         true
     }
+
+    fn backing(&self, addr: VirtAddr) -> Option<(usize, usize)> {
+        Some((self.file.id(), addr + self.file_start - self.mem_start))
+    }
 }
 
 impl<F: Read, T: FrameAllocator> File<F, T> {
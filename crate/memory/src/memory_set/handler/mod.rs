@@ -76,6 +76,12 @@ pub trait MemoryHandler: Debug + Send + Sync + 'static {
     ) -> bool {
         self.handle_page_fault(pt, addr)
     }
+
+    /// Identity of the object backing `addr`, and the offset of `addr` in it.
+    /// None if the frame is the only identity of the memory, as for anonymous memory.
+    fn backing(&self, _addr: VirtAddr) -> Option<(usize, usize)> {
+        None
+    }
 }
 
 impl Clone for Box<dyn MemoryHandler> {
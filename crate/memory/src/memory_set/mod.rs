@@ -3,6 +3,7 @@
 use alloc::{boxed::Box, vec::Vec};
 use core::fmt::{Debug, Error, Formatter};
 use core::mem::size_of;
+use core::ops::Range;
 
 use crate::paging::*;
 
@@ -23,6 +24,30 @@ pub struct MemoryArea {
 }
 
 impl MemoryArea {
+    /// Get the start address of the area
+    pub fn start_addr(&self) -> VirtAddr {
+        self.start_addr
+    }
+    /// Get the end address of the area
+    pub fn end_addr(&self) -> VirtAddr {
+        self.end_addr
+    }
+    /// Get the attributes of the area
+    pub fn attr(&self) -> &MemoryAttr {
+        &self.attr
+    }
+    /// Get the name of the area
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    /// Get the object backing `addr` and the offset in it, see `MemoryHandler::backing`
+    pub fn backing(&self, addr: VirtAddr) -> Option<(usize, usize)> {
+        self.handler.backing(addr)
+    }
+    /// Writable private memory other than the user stack, which counts toward the data limit
+    pub fn is_data(&self) -> bool {
+        is_data(&self.attr, self.name)
+    }
     /// Test whether a virtual address is in the memory area
     pub fn contains(&self, addr: VirtAddr) -> bool {
         addr >= self.start_addr && addr < self.end_addr
@@ -78,6 +103,7 @@ pub struct MemoryAttr {
     readonly: bool,
     execute: bool,
     mmio: u8,
+    shared: bool,
 }
 
 impl MemoryAttr {
@@ -101,6 +127,23 @@ impl MemoryAttr {
         self.mmio = value;
         self
     }
+    /// The memory is shared with other address spaces mapping the same object
+    pub fn shared(mut self) -> Self {
+        self.shared = true;
+        self
+    }
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+    pub fn is_execute(&self) -> bool {
+        self.execute
+    }
+    pub fn is_mmio(&self) -> bool {
+        self.mmio != 0
+    }
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
     /// Apply the attributes to page table entry, then update it.
     /// NOTE: You may need to set present manually.
     pub fn apply(&self, entry: &mut dyn Entry) {
@@ -112,6 +155,10 @@ impl MemoryAttr {
     }
 }
 
+fn is_data(attr: &MemoryAttr, name: &str) -> bool {
+    !attr.readonly && !attr.shared && !name.starts_with("user_stack")
+}
+
 /// A set of memory space with multiple memory areas with associated page table
 /// NOTE: Don't remove align(64), or you will fail to run MIPS.
 /// Temporary solution for rv64
@@ -119,6 +166,10 @@ impl MemoryAttr {
 pub struct MemorySet<T: PageTableExt> {
     areas: Vec<MemoryArea>,
     page_table: T,
+    /// Max bytes of all areas, see RLIMIT_AS
+    total_limit: usize,
+    /// Max bytes of data areas, see RLIMIT_DATA and `MemoryArea::is_data`
+    data_limit: usize,
 }
 
 impl<T: PageTableExt> MemorySet<T> {
@@ -127,6 +178,8 @@ impl<T: PageTableExt> MemorySet<T> {
         MemorySet {
             areas: Vec::new(),
             page_table: T::new(),
+            total_limit: usize::max_value(),
+            data_limit: usize::max_value(),
         }
     }
     /// Create a new `MemorySet` for kernel remap
@@ -134,6 +187,8 @@ impl<T: PageTableExt> MemorySet<T> {
         MemorySet {
             areas: Vec::new(),
             page_table: T::new_bare(),
+            total_limit: usize::max_value(),
+            data_limit: usize::max_value(),
         }
     }
     /// Check the pointer is within the readable memory
@@ -179,9 +234,12 @@ impl<T: PageTableExt> MemorySet<T> {
     /// Used for mmap.
     pub fn find_free_area(&self, addr_hint: usize, len: usize) -> VirtAddr {
         // brute force:
-        // try each area's end address as the start
+        // try each area's end address as the start,
+        // prefer the ones above the hint so that a randomized mmap base is respected
+        let above = self.areas.iter().filter(|area| area.end_addr >= addr_hint);
+        let below = self.areas.iter().filter(|area| area.end_addr < addr_hint);
         core::iter::once(addr_hint)
-            .chain(self.areas.iter().map(|area| area.end_addr))
+            .chain(above.chain(below).map(|area| area.end_addr))
             .map(|addr| (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)) // round up a page
             .find(|&addr| self.test_free_area(addr, addr + len))
             .expect("failed to find free area ???")
@@ -193,7 +251,38 @@ impl<T: PageTableExt> MemorySet<T> {
             .find(|area| area.is_overlap_with(start_addr, end_addr))
             .is_none()
     }
-    /// Add an area to this set
+    /// Set the max bytes of all areas and of data areas, which `push` keeps to
+    pub fn set_limits(&mut self, total_limit: usize, data_limit: usize) {
+        self.total_limit = total_limit;
+        self.data_limit = data_limit;
+    }
+    /// Check adding areas of `len` bytes, `data_len` of which are data, against the limits.
+    /// The parts of the areas in `replaced` are not counted, as the new ones take their place.
+    pub fn check_limits(
+        &self,
+        len: usize,
+        data_len: usize,
+        replaced: Range<VirtAddr>,
+    ) -> VMResult<()> {
+        let mut total = len;
+        let mut data = data_len;
+        for area in self.areas.iter() {
+            let overlap = area
+                .end_addr
+                .min(replaced.end)
+                .saturating_sub(area.start_addr.max(replaced.start));
+            let size = area.end_addr - area.start_addr - overlap;
+            total = total.saturating_add(size);
+            if area.is_data() {
+                data = data.saturating_add(size);
+            }
+        }
+        if total > self.total_limit || (data_len > 0 && data > self.data_limit) {
+            return Err(VMError::NoMem);
+        }
+        Ok(())
+    }
+    /// Add an area to this set, failing if it exceeds the limits
     pub fn push(
         &mut self,
         mut start_addr: VirtAddr,
@@ -201,7 +290,7 @@ impl<T: PageTableExt> MemorySet<T> {
         attr: MemoryAttr,
         handler: impl MemoryHandler,
         name: &'static str,
-    ) {
+    ) -> VMResult<()> {
         start_addr = start_addr & !(PAGE_SIZE - 1);
         end_addr = (end_addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
         assert!(start_addr < end_addr, "invalid memory area");
@@ -209,6 +298,9 @@ impl<T: PageTableExt> MemorySet<T> {
             self.test_free_area(start_addr, end_addr),
             "memory area overlap"
         );
+        let len = end_addr - start_addr;
+        let data_len = if is_data(&attr, name) { len } else { 0 };
+        self.check_limits(len, data_len, 0..0)?;
         let area = MemoryArea {
             start_addr,
             end_addr,
@@ -226,6 +318,7 @@ impl<T: PageTableExt> MemorySet<T> {
             .map(|(i, _)| i)
             .unwrap_or(self.areas.len());
         self.areas.insert(idx, area);
+        Ok(())
     }
 
     /// Remove the area `[start_addr, end_addr)` from `MemorySet`
@@ -393,6 +486,62 @@ impl<T: PageTableExt> MemorySet<T> {
         }
     }
 
+    /// Read user memory at `addr` into `buf`, even if the memory set is not active.
+    /// Pages not present are faulted in, and permissions are not checked.
+    /// Return false if part of the range is not mapped.
+    pub fn read_forced(&mut self, addr: VirtAddr, buf: &mut [u8]) -> bool {
+        self.access_forced(addr, buf.len(), |data, offset| {
+            buf[offset..offset + data.len()].copy_from_slice(data);
+            false
+        })
+    }
+
+    /// Write `buf` to user memory at `addr`, even if the memory set is not active.
+    /// Pages not present are faulted in, and permissions are not checked,
+    /// so that debuggers can write breakpoints into read-only code.
+    /// Return false if part of the range is not mapped.
+    pub fn write_forced(&mut self, addr: VirtAddr, buf: &[u8]) -> bool {
+        self.access_forced(addr, buf.len(), |data, offset| {
+            data.copy_from_slice(&buf[offset..offset + data.len()]);
+            true
+        })
+    }
+
+    /// Call `f` with the content of each page in `[addr, addr + len)`
+    /// and the offset from `addr`. `f` returns whether the content is modified.
+    fn access_forced(
+        &mut self,
+        addr: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(&mut [u8], usize) -> bool,
+    ) -> bool {
+        let mut offset = 0;
+        while offset < len {
+            let vaddr = addr + offset;
+            let area = match self.areas.iter().find(|area| area.contains(vaddr)) {
+                Some(area) => area,
+                None => return false,
+            };
+            let page = Page::of_addr(vaddr).start_address();
+            let present = self
+                .page_table
+                .get_entry(page)
+                .map_or(false, |entry| entry.present());
+            if !present && !area.handler.handle_page_fault(&mut self.page_table, page) {
+                return false;
+            }
+            let begin = vaddr - page;
+            let end = PAGE_SIZE.min(begin + len - offset);
+            let data = &mut self.page_table.get_page_slice_mut(page)[begin..end];
+            if f(data, offset) {
+                self.page_table
+                    .flush_cache_copy_user(vaddr, vaddr + data.len(), area.attr.execute);
+            }
+            offset += end - begin;
+        }
+        true
+    }
+
     pub fn clone(&mut self) -> Self {
         let mut new_page_table = T::new();
         let Self {
@@ -413,6 +562,8 @@ impl<T: PageTableExt> MemorySet<T> {
         MemorySet {
             areas: areas.clone(),
             page_table: new_page_table,
+            total_limit: self.total_limit,
+            data_limit: self.data_limit,
         }
     }
 }
@@ -428,3 +579,106 @@ impl<T: PageTableExt> Debug for MemorySet<T> {
         f.debug_list().entries(self.areas.iter()).finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::MockPageTable;
+
+    /// Maps pages to the frames of the same address on page faults
+    #[derive(Debug, Clone)]
+    struct LazyIdentity;
+
+    impl MemoryHandler for LazyIdentity {
+        fn box_clone(&self) -> Box<dyn MemoryHandler> {
+            Box::new(self.clone())
+        }
+        fn map(&self, _pt: &mut dyn PageTable, _addr: VirtAddr, _attr: &MemoryAttr) {}
+        fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+            if pt.get_entry(addr).unwrap().present() {
+                pt.unmap(addr);
+            }
+        }
+        fn clone_map(
+            &self,
+            _pt: &mut dyn PageTable,
+            _src_pt: &mut dyn PageTable,
+            _addr: VirtAddr,
+            _attr: &MemoryAttr,
+        ) {
+        }
+        fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
+            pt.map(addr, addr);
+            true
+        }
+    }
+
+    fn push(ms: &mut MemorySet<MockPageTable>, start: usize, end: usize) {
+        let attr = MemoryAttr::default().readonly();
+        assert!(ms.push(start, end, attr, LazyIdentity, "").is_ok());
+    }
+
+    #[test]
+    fn find_free_area() {
+        let mut ms = MemorySet::<MockPageTable>::new();
+        push(&mut ms, 0x1000, 0x3000);
+        push(&mut ms, 0x4000, 0x5000);
+        push(&mut ms, 0x8000, 0x9000);
+        // at the hint if it is free, rounded up a page
+        assert_eq!(ms.find_free_area(0x6000, 0x1000), 0x6000);
+        assert_eq!(ms.find_free_area(0x5800, 0x1000), 0x6000);
+        // after the first area above the hint with room
+        assert_eq!(ms.find_free_area(0x1000, 0x1000), 0x3000);
+        assert_eq!(ms.find_free_area(0x1000, 0x2000), 0x5000);
+        // areas below the hint are tried last
+        assert_eq!(ms.find_free_area(0x8000, 0x1000), 0x9000);
+        assert_eq!(ms.find_free_area(0xa000, 0x1000), 0xa000);
+    }
+
+    #[test]
+    fn read_write_forced() {
+        let mut ms = MemorySet::<MockPageTable>::new();
+        push(&mut ms, 0x1000, 0x3000);
+        // pages are faulted in, even read-only ones
+        assert!(ms.write_forced(0x1ffe, &[1, 2, 3, 4]));
+        let mut buf = [0u8; 6];
+        assert!(ms.read_forced(0x1ffd, &mut buf));
+        assert_eq!(buf, [0, 1, 2, 3, 4, 0]);
+        // the whole range must be mapped
+        assert!(!ms.read_forced(0x2ffe, &mut buf));
+        assert!(!ms.write_forced(0x3000, &[1]));
+        assert!(ms.read_forced(0x2ffe, &mut buf[..2]));
+    }
+
+    #[test]
+    fn limits() {
+        let mut ms = MemorySet::<MockPageTable>::new();
+        ms.set_limits(0x4000, 0x2000);
+        let data = MemoryAttr::default().writable();
+        assert!(ms.push(0x1000, 0x3000, data, LazyIdentity, "").is_ok());
+        // over the data limit
+        let res = ms.push(0x3000, 0x4000, data, LazyIdentity, "");
+        assert!(matches!(res, Err(VMError::NoMem)));
+        // the stack and read-only memory are not data
+        assert!(ms
+            .push(0x3000, 0x4000, data, LazyIdentity, "user_stack")
+            .is_ok());
+        push(&mut ms, 0x4000, 0x5000);
+        // over the total limit
+        let res = ms.push(0x5000, 0x6000, data.readonly(), LazyIdentity, "");
+        assert!(matches!(res, Err(VMError::NoMem)));
+        // areas replaced are not counted
+        assert!(matches!(
+            ms.check_limits(0x1000, 0, 0..0),
+            Err(VMError::NoMem)
+        ));
+        assert!(ms.check_limits(0x1000, 0x1000, 0x2000..0x3000).is_ok());
+        assert!(ms.check_limits(0x2000, 0, 0x2000..0x4000).is_ok());
+        assert!(ms
+            .check_limits(0x3000, 0x3000, 0..usize::max_value())
+            .is_err());
+        assert!(ms
+            .check_limits(0x4000, 0x2000, 0..usize::max_value())
+            .is_ok());
+    }
+}
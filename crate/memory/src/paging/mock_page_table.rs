@@ -128,6 +128,7 @@ impl PageTable for MockPageTable {
         let data = unsafe { &mut *(&mut self.data as *mut [u8; PAGE_SIZE * PAGE_COUNT]) };
         &mut data[pa..pa + PAGE_SIZE]
     }
+    fn flush_cache_copy_user(&mut self, _start: VirtAddr, _end: VirtAddr, _execute: bool) {}
     fn read(&mut self, addr: usize) -> u8 {
         self._read(addr);
         self.data[self.translate(addr)]
@@ -138,6 +139,21 @@ impl PageTable for MockPageTable {
     }
 }
 
+impl PageTableExt for MockPageTable {
+    fn new_bare() -> Self {
+        MockPageTable::new()
+    }
+    fn map_kernel(&mut self) {}
+    fn token(&self) -> usize {
+        0
+    }
+    unsafe fn set_token(_token: usize) {}
+    fn active_token() -> usize {
+        0
+    }
+    fn flush_tlb() {}
+}
+
 impl MockPageTable {
     /*
      **  @brief  create a new MockPageTable
@@ -44,3 +44,15 @@ pub fn is_timer_intr(trap: usize) -> bool {
 pub fn is_reserved_inst(trap: usize) -> bool {
     false
 }
+
+pub fn is_breakpoint(trap: usize) -> bool {
+    // 2: from lower el, sync error
+    if trap != 0x2 {
+        return false;
+    }
+    let esr = ESR_EL1.get() as u32;
+    match Syndrome::from(esr) {
+        Syndrome::Breakpoint | Syndrome::Step | Syndrome::Brk(_) => true,
+        _ => false,
+    }
+}
@@ -56,42 +56,48 @@ fn map_kernel() {
         MemoryAttr::default().execute().readonly(),
         Linear::new(offset),
         "text",
-    );
+    )
+    .unwrap();
     ms.push(
         sdata as usize,
         edata as usize,
         MemoryAttr::default(),
         Linear::new(offset),
         "data",
-    );
+    )
+    .unwrap();
     ms.push(
         srodata as usize,
         erodata as usize,
         MemoryAttr::default().readonly(),
         Linear::new(offset),
         "rodata",
-    );
+    )
+    .unwrap();
     ms.push(
         sbss as usize,
         ebss as usize,
         MemoryAttr::default(),
         Linear::new(offset),
         "bss",
-    );
+    )
+    .unwrap();
     ms.push(
         bootstack as usize,
         bootstacktop as usize,
         MemoryAttr::default(),
         Linear::new(offset),
         "kstack",
-    );
+    )
+    .unwrap();
     ms.push(
         super::board::PERIPHERALS_START,
         super::board::PERIPHERALS_END,
         MemoryAttr::default().mmio(MMIOType::Device as u8),
         Linear::new(0),
         "peripherals",
-    );
+    )
+    .unwrap();
 
     let page_table = ms.get_page_table_mut();
     page_table.map_physical_memory(0, super::board::PERIPHERALS_START);
@@ -112,7 +118,8 @@ pub fn ioremap(paddr: usize, len: usize, name: &'static str) -> usize {
             MemoryAttr::default().mmio(MMIOType::NormalNonCacheable as u8),
             Linear::new(offset),
             name,
-        );
+        )
+        .unwrap();
         return vaddr;
     }
     0
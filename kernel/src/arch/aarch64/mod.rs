@@ -39,6 +39,10 @@ pub extern "C" fn main_start() -> ! {
     board::early_final();
     crate::lkm::manager::ModuleManager::init();
     board::init();
+    crate::drivers::net::loopback::init();
+    // write the frames of network interfaces to a pcap file
+    #[cfg(feature = "pcap")]
+    crate::net::pcap::start();
 
     crate::process::init();
 
@@ -1,5 +1,6 @@
 use crate::signal::Siginfo;
 use crate::signal::SignalUserContext;
+use crate::syscall::SysError;
 use trapframe::UserContext;
 
 // mcontext
@@ -125,6 +126,117 @@ impl MachineContext {
 // TODO
 pub const RET_CODE: [u8; 7] = [0; 7];
 
+/// Number of registers in `elf_gregset_t`: x0..x30, sp, pc and pstate
+pub const ELF_NGREG: usize = 34;
+
+/// Convert user context to `elf_gregset_t`, used by core dumps and ptrace
+pub fn elf_gregs_from_tf(tf: &UserContext) -> [usize; ELF_NGREG] {
+    let g = &tf.general;
+    [
+        g.x0, g.x1, g.x2, g.x3, g.x4, g.x5, g.x6, g.x7, g.x8, g.x9, g.x10, g.x11, g.x12, g.x13,
+        g.x14, g.x15, g.x16, g.x17, g.x18, g.x19, g.x20, g.x21, g.x22, g.x23, g.x24, g.x25, g.x26,
+        g.x27, g.x28, g.x29, g.x30, tf.sp, tf.elr, tf.spsr,
+    ]
+}
+
+/// Update user context from `elf_gregset_t`, used by ptrace
+pub fn set_elf_gregs(tf: &mut UserContext, regs: &[usize; ELF_NGREG]) {
+    // only the condition flags of PSTATE can be changed
+    const SPSR_NZCV: usize = 0xf000_0000;
+    let g = &mut tf.general;
+    g.x0 = regs[0];
+    g.x1 = regs[1];
+    g.x2 = regs[2];
+    g.x3 = regs[3];
+    g.x4 = regs[4];
+    g.x5 = regs[5];
+    g.x6 = regs[6];
+    g.x7 = regs[7];
+    g.x8 = regs[8];
+    g.x9 = regs[9];
+    g.x10 = regs[10];
+    g.x11 = regs[11];
+    g.x12 = regs[12];
+    g.x13 = regs[13];
+    g.x14 = regs[14];
+    g.x15 = regs[15];
+    g.x16 = regs[16];
+    g.x17 = regs[17];
+    g.x18 = regs[18];
+    g.x19 = regs[19];
+    g.x20 = regs[20];
+    g.x21 = regs[21];
+    g.x22 = regs[22];
+    g.x23 = regs[23];
+    g.x24 = regs[24];
+    g.x25 = regs[25];
+    g.x26 = regs[26];
+    g.x27 = regs[27];
+    g.x28 = regs[28];
+    g.x29 = regs[29];
+    g.x30 = regs[30];
+    tf.sp = regs[31];
+    tf.elr = regs[32];
+    tf.spsr = (tf.spsr & !SPSR_NZCV) | (regs[33] & SPSR_NZCV);
+}
+
+/// Index in `elf_gregset_t` of the `PTRACE_PEEKUSER` offset `addr` in `struct user`
+pub fn elf_greg_index(addr: usize) -> Option<usize> {
+    let size = core::mem::size_of::<usize>();
+    if addr % size == 0 && addr / size < ELF_NGREG {
+        Some(addr / size)
+    } else {
+        None
+    }
+}
+
+/// Whether `addr` is an offset into `struct user` not backed by a register.
+/// Linux has no PTRACE_PEEKUSER here, only the registers are offered.
+pub fn user_area_padding(_addr: usize) -> bool {
+    false
+}
+
+/// Program counter of the user context
+pub fn get_pc(tf: &UserContext) -> usize {
+    tf.elr
+}
+
+/// Software step bit in SPSR, cleared by the cpu once the instruction is stepped
+const SPSR_SS: usize = 1 << 21;
+/// Software step enable bit in MDSCR_EL1
+const MDSCR_SS: usize = 1 << 0;
+
+/// Enable or disable single stepping of the user context.
+/// The step is taken as a breakpoint after one instruction,
+/// once `arm_single_step` enabled software step on the way to user.
+pub fn set_single_step(tf: &mut UserContext, enable: bool) -> Result<(), SysError> {
+    if enable {
+        tf.spsr |= SPSR_SS;
+    } else {
+        tf.spsr &= !SPSR_SS;
+    }
+    Ok(())
+}
+
+/// Enable software step of this cpu if `tf` is stepped, disable it otherwise,
+/// as with it enabled a context without SPSR.SS traps before its first instruction
+pub fn arm_single_step(tf: &UserContext) {
+    unsafe {
+        let mut mdscr: usize;
+        llvm_asm!("mrs $0, mdscr_el1" : "=r"(mdscr));
+        let new = if tf.spsr & SPSR_SS != 0 {
+            // debug exceptions are not generated while the OS lock is set
+            llvm_asm!("msr oslar_el1, xzr");
+            mdscr | MDSCR_SS
+        } else {
+            mdscr & !MDSCR_SS
+        };
+        if new != mdscr {
+            llvm_asm!("msr mdscr_el1, $0; isb" :: "r"(new) :: "volatile");
+        }
+    }
+}
+
 pub fn set_signal_handler(
     tf: &mut UserContext,
     sp: usize,
@@ -1,6 +1,9 @@
 //! AArch64 syscall ids
 //! Reference: https://git.musl-libc.org/cgit/musl/tree/arch/aarch64/bits/syscall.h.in
 
+/// Architecture of seccomp_data, AUDIT_ARCH_* of Linux
+pub const AUDIT_ARCH: u32 = 0xc000_00b7;
+
 pub const SYS_IO_SETUP: usize = 0;
 pub const SYS_IO_DESTROY: usize = 1;
 pub const SYS_IO_SUBMIT: usize = 2;
@@ -42,6 +42,10 @@ pub extern "C" fn rust_main() -> ! {
     memory::init();
     timer::init();
     board::init(dtb_start);
+    crate::drivers::net::loopback::init();
+    // write the frames of network interfaces to a pcap file
+    #[cfg(feature = "pcap")]
+    crate::net::pcap::start();
 
     info!("Hello MIPS 32 from CPU {}, dtb @ {:#x}", cpu_id, dtb_start);
 
@@ -1,5 +1,6 @@
 use crate::signal::Siginfo;
 use crate::signal::SignalUserContext;
+use crate::syscall::SysError;
 use trapframe::UserContext;
 
 // mcontext
@@ -18,6 +19,96 @@ impl MachineContext {
 // TODO
 pub const RET_CODE: [u8; 7] = [0; 7];
 
+/// Number of registers in `elf_gregset_t` of o32:
+/// 6 paddings, r0..r31, lo, hi, epc, badvaddr, status and cause
+pub const ELF_NGREG: usize = 45;
+
+/// Convert user context to `elf_gregset_t`, used by core dumps and ptrace
+pub fn elf_gregs_from_tf(tf: &UserContext) -> [usize; ELF_NGREG] {
+    let g = &tf.general;
+    // lo, hi and badvaddr are not saved
+    [
+        0, 0, 0, 0, 0, 0, 0, g.at, g.v0, g.v1, g.a0, g.a1, g.a2, g.a3, g.t0, g.t1, g.t2, g.t3,
+        g.t4, g.t5, g.t6, g.t7, g.s0, g.s1, g.s2, g.s3, g.s4, g.s5, g.s6, g.s7, g.t8, g.t9, g.k0,
+        g.k1, g.gp, g.sp, g.fp, g.ra, 0, 0, tf.epc, 0, tf.status, tf.cause, 0,
+    ]
+}
+
+/// Update user context from `elf_gregset_t`, used by ptrace
+pub fn set_elf_gregs(tf: &mut UserContext, regs: &[usize; ELF_NGREG]) {
+    let g = &mut tf.general;
+    g.at = regs[7];
+    g.v0 = regs[8];
+    g.v1 = regs[9];
+    g.a0 = regs[10];
+    g.a1 = regs[11];
+    g.a2 = regs[12];
+    g.a3 = regs[13];
+    g.t0 = regs[14];
+    g.t1 = regs[15];
+    g.t2 = regs[16];
+    g.t3 = regs[17];
+    g.t4 = regs[18];
+    g.t5 = regs[19];
+    g.t6 = regs[20];
+    g.t7 = regs[21];
+    g.s0 = regs[22];
+    g.s1 = regs[23];
+    g.s2 = regs[24];
+    g.s3 = regs[25];
+    g.s4 = regs[26];
+    g.s5 = regs[27];
+    g.s6 = regs[28];
+    g.s7 = regs[29];
+    g.t8 = regs[30];
+    g.t9 = regs[31];
+    g.k0 = regs[32];
+    g.k1 = regs[33];
+    g.gp = regs[34];
+    g.sp = regs[35];
+    g.fp = regs[36];
+    g.ra = regs[37];
+    tf.epc = regs[40];
+}
+
+/// Index in `elf_gregset_t` of the `PTRACE_PEEKUSER` register number `addr`
+pub fn elf_greg_index(addr: usize) -> Option<usize> {
+    // see arch/mips/include/uapi/asm/ptrace.h
+    match addr {
+        0..=31 => Some(6 + addr),
+        64 => Some(40), // PC
+        65 => Some(43), // CAUSE
+        66 => Some(41), // BADVADDR
+        67 => Some(39), // HI
+        68 => Some(38), // LO
+        _ => None,
+    }
+}
+
+/// Whether the `PTRACE_PEEKUSER` register number `addr` is not backed by a register,
+/// the floating point ones read as 0
+pub fn user_area_padding(addr: usize) -> bool {
+    match addr {
+        32..=63 | 69 | 70 => true,
+        _ => false,
+    }
+}
+
+/// Program counter of the user context
+pub fn get_pc(tf: &UserContext) -> usize {
+    tf.epc
+}
+
+/// Enable or disable single stepping of the user context.
+/// There is no hardware single step, and Linux fails with EIO too,
+/// as debuggers step with breakpoints by themselves.
+pub fn set_single_step(_tf: &mut UserContext, enable: bool) -> Result<(), SysError> {
+    if enable {
+        return Err(SysError::EIO);
+    }
+    Ok(())
+}
+
 pub fn set_signal_handler(
     tf: &mut UserContext,
     sp: usize,
@@ -5,6 +5,9 @@ extern crate paste;
 
 pub const MIPS_SYSCALL_OFFSET: usize = 4000;
 
+/// Architecture of seccomp_data, AUDIT_ARCH_* of Linux
+pub const AUDIT_ARCH: u32 = 0x4000_0008;
+
 macro_rules! define_syscall {
     ($name: ident, $id: expr) => {
         paste::item! {
@@ -1,3 +1,4 @@
+pub const Breakpoint: usize = 3;
 pub const Syscall: usize = 8;
 pub const InstructionPageFault: usize = 12;
 pub const LoadPageFault: usize = 13;
@@ -38,3 +39,7 @@ pub fn is_timer_intr(trap: usize) -> bool {
 pub fn is_reserved_inst(trap: usize) -> bool {
     false
 }
+
+pub fn is_breakpoint(trap: usize) -> bool {
+    trap == Breakpoint
+}
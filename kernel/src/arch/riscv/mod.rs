@@ -69,6 +69,10 @@ pub extern "C" fn rust_main(hartid: usize, device_tree_paddr: usize) -> ! {
     // TODO: init driver on u540
     #[cfg(not(any(feature = "board_u540")))]
     board::init(device_tree_vaddr);
+    crate::drivers::net::loopback::init();
+    // write the frames of network interfaces to a pcap file
+    #[cfg(feature = "pcap")]
+    crate::net::pcap::start();
     unsafe {
         board::init_external_interrupt();
     }
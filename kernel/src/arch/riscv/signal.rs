@@ -1,5 +1,6 @@
 use crate::signal::Siginfo;
 use crate::signal::SignalUserContext;
+use crate::syscall::SysError;
 use trapframe::UserContext;
 
 // mcontext_t
@@ -85,6 +86,87 @@ impl MachineContext {
 // TODO
 pub const RET_CODE: [u8; 7] = [0; 7];
 
+/// Number of registers in `elf_gregset_t`: pc and x1..x31
+pub const ELF_NGREG: usize = 32;
+
+/// Convert user context to `elf_gregset_t`, used by core dumps and ptrace
+pub fn elf_gregs_from_tf(tf: &UserContext) -> [usize; ELF_NGREG] {
+    let g = &tf.general;
+    [
+        tf.sepc, g.ra, g.sp, g.gp, g.tp, g.t0, g.t1, g.t2, g.s0, g.s1, g.a0, g.a1, g.a2, g.a3,
+        g.a4, g.a5, g.a6, g.a7, g.s2, g.s3, g.s4, g.s5, g.s6, g.s7, g.s8, g.s9, g.s10, g.s11, g.t3,
+        g.t4, g.t5, g.t6,
+    ]
+}
+
+/// Update user context from `elf_gregset_t`, used by ptrace
+pub fn set_elf_gregs(tf: &mut UserContext, regs: &[usize; ELF_NGREG]) {
+    let g = &mut tf.general;
+    g.ra = regs[1];
+    g.sp = regs[2];
+    g.gp = regs[3];
+    g.tp = regs[4];
+    g.t0 = regs[5];
+    g.t1 = regs[6];
+    g.t2 = regs[7];
+    g.s0 = regs[8];
+    g.s1 = regs[9];
+    g.a0 = regs[10];
+    g.a1 = regs[11];
+    g.a2 = regs[12];
+    g.a3 = regs[13];
+    g.a4 = regs[14];
+    g.a5 = regs[15];
+    g.a6 = regs[16];
+    g.a7 = regs[17];
+    g.s2 = regs[18];
+    g.s3 = regs[19];
+    g.s4 = regs[20];
+    g.s5 = regs[21];
+    g.s6 = regs[22];
+    g.s7 = regs[23];
+    g.s8 = regs[24];
+    g.s9 = regs[25];
+    g.s10 = regs[26];
+    g.s11 = regs[27];
+    g.t3 = regs[28];
+    g.t4 = regs[29];
+    g.t5 = regs[30];
+    g.t6 = regs[31];
+    tf.sepc = regs[0];
+}
+
+/// Index in `elf_gregset_t` of the `PTRACE_PEEKUSER` offset `addr` in `struct user`
+pub fn elf_greg_index(addr: usize) -> Option<usize> {
+    let size = core::mem::size_of::<usize>();
+    if addr % size == 0 && addr / size < ELF_NGREG {
+        Some(addr / size)
+    } else {
+        None
+    }
+}
+
+/// Whether `addr` is an offset into `struct user` not backed by a register.
+/// Linux has no PTRACE_PEEKUSER here, only the registers are offered.
+pub fn user_area_padding(_addr: usize) -> bool {
+    false
+}
+
+/// Program counter of the user context
+pub fn get_pc(tf: &UserContext) -> usize {
+    tf.sepc
+}
+
+/// Enable or disable single stepping of the user context.
+/// There is no hardware single step, and Linux fails with EIO too,
+/// as debuggers step with breakpoints by themselves.
+pub fn set_single_step(_tf: &mut UserContext, enable: bool) -> Result<(), SysError> {
+    if enable {
+        return Err(SysError::EIO);
+    }
+    Ok(())
+}
+
 pub fn set_signal_handler(
     tf: &mut UserContext,
     sp: usize,
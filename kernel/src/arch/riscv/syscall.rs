@@ -1,6 +1,12 @@
 //! RISCV32 syscall ids
 //! Reference: https://github.com/riscv/riscv-musl/blob/staging/arch/riscv32/bits/syscall.h.in
 
+/// Architecture of seccomp_data, AUDIT_ARCH_* of Linux
+#[cfg(target_arch = "riscv64")]
+pub const AUDIT_ARCH: u32 = 0xc000_00f3;
+#[cfg(target_arch = "riscv32")]
+pub const AUDIT_ARCH: u32 = 0x4000_00f3;
+
 pub const SYS_IO_SETUP: usize = 0;
 pub const SYS_IO_DESTROY: usize = 1;
 pub const SYS_IO_SUBMIT: usize = 2;
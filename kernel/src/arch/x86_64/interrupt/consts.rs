@@ -65,3 +65,7 @@ pub fn is_timer_intr(trap: usize) -> bool {
 pub fn is_reserved_inst(trap: usize) -> bool {
     false
 }
+
+pub fn is_breakpoint(trap: usize) -> bool {
+    trap == Breakpoint || trap == Debug
+}
@@ -64,6 +64,11 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     crate::lkm::manager::ModuleManager::init();
     // init board
     board::init(boot_info);
+    // init loopback network interface, after the network cards
+    crate::drivers::net::loopback::init();
+    // write the frames of network interfaces to a pcap file
+    #[cfg(feature = "pcap")]
+    crate::net::pcap::start();
     // init cpu scheduler and process manager, and add user shell app in process manager
     crate::process::init();
     // load acpi
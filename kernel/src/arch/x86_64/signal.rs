@@ -1,4 +1,5 @@
 use crate::signal::{Siginfo, SignalUserContext};
+use crate::syscall::SysError;
 use trapframe::{GeneralRegs, UserContext};
 
 /// struct mcontext
@@ -104,6 +105,87 @@ pub const RET_CODE: [u8; 7] = [
     0x0f, 0x05,
 ];
 
+/// Number of registers in `elf_gregset_t`, i.e. `struct user_regs_struct`
+pub const ELF_NGREG: usize = 27;
+
+/// Convert user context to `elf_gregset_t`, used by core dumps and ptrace
+pub fn elf_gregs_from_tf(tf: &UserContext) -> [usize; ELF_NGREG] {
+    let g = &tf.general;
+    // orig_rax = rax, cs = 0x23, ss = 0x1b, ds = es = fs = gs = 0
+    [
+        g.r15, g.r14, g.r13, g.r12, g.rbp, g.rbx, g.r11, g.r10, g.r9, g.r8, g.rax, g.rcx, g.rdx,
+        g.rsi, g.rdi, g.rax, g.rip, 0x23, g.rflags, g.rsp, 0x1b, g.fsbase, g.gsbase, 0, 0, 0, 0,
+    ]
+}
+
+/// Update user context from `elf_gregset_t`, used by ptrace
+pub fn set_elf_gregs(tf: &mut UserContext, regs: &[usize; ELF_NGREG]) {
+    // only arithmetic flags, TF and DF can be changed
+    const USER_RFLAGS: usize = 0xdd5;
+    let g = &mut tf.general;
+    g.r15 = regs[0];
+    g.r14 = regs[1];
+    g.r13 = regs[2];
+    g.r12 = regs[3];
+    g.rbp = regs[4];
+    g.rbx = regs[5];
+    g.r11 = regs[6];
+    g.r10 = regs[7];
+    g.r9 = regs[8];
+    g.r8 = regs[9];
+    // orig_rax is read as rax, a changed orig_rax is the new syscall number
+    g.rax = if regs[15] != g.rax {
+        regs[15]
+    } else {
+        regs[10]
+    };
+    g.rcx = regs[11];
+    g.rdx = regs[12];
+    g.rsi = regs[13];
+    g.rdi = regs[14];
+    g.rip = regs[16];
+    g.rflags = (g.rflags & !USER_RFLAGS) | (regs[18] & USER_RFLAGS);
+    g.rsp = regs[19];
+    g.fsbase = regs[21];
+    g.gsbase = regs[22];
+}
+
+/// Index in `elf_gregset_t` of the `PTRACE_PEEKUSER` offset `addr` in `struct user`
+pub fn elf_greg_index(addr: usize) -> Option<usize> {
+    let size = core::mem::size_of::<usize>();
+    if addr % size == 0 && addr / size < ELF_NGREG {
+        Some(addr / size)
+    } else {
+        None
+    }
+}
+
+/// Size of `struct user`, offsets beyond the registers read as 0
+const USER_AREA_SIZE: usize = 0x390;
+
+/// Whether `addr` is an aligned offset into `struct user` not backed by a register
+pub fn user_area_padding(addr: usize) -> bool {
+    addr < USER_AREA_SIZE && addr % core::mem::size_of::<usize>() == 0
+}
+
+/// Trap flag in RFLAGS
+const RFLAGS_TF: usize = 1 << 8;
+
+/// Program counter of the user context
+pub fn get_pc(tf: &UserContext) -> usize {
+    tf.general.rip
+}
+
+/// Enable or disable single stepping of the user context
+pub fn set_single_step(tf: &mut UserContext, enable: bool) -> Result<(), SysError> {
+    if enable {
+        tf.general.rflags |= RFLAGS_TF;
+    } else {
+        tf.general.rflags &= !RFLAGS_TF;
+    }
+    Ok(())
+}
+
 pub fn set_signal_handler(
     tf: &mut UserContext,
     sp: usize,
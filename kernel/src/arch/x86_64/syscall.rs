@@ -1,6 +1,9 @@
 //! x86_64 syscall ids
 //! Reference: https://git.musl-libc.org/cgit/musl/tree/arch/x86_64/bits/syscall.h.in
 
+/// Architecture of seccomp_data, AUDIT_ARCH_* of Linux
+pub const AUDIT_ARCH: u32 = 0xc000_003e;
+
 pub const SYS_READ: usize = 0;
 pub const SYS_WRITE: usize = 1;
 pub const SYS_OPEN: usize = 2;
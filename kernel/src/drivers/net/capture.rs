@@ -0,0 +1,92 @@
+//! A smoltcp device which taps the frames of another one into packet sockets
+
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::Result;
+
+use crate::net::tap;
+
+pub struct Capture<D> {
+    inner: D,
+    /// Index of the interface in `NET_DRIVERS`
+    index: usize,
+}
+
+impl<D> Capture<D> {
+    pub fn new(inner: D, index: usize) -> Self {
+        Capture { inner, index }
+    }
+
+    /// Follow the interface to `index`, after one before it is removed
+    pub fn set_index(&mut self, index: usize) {
+        self.index = index;
+    }
+
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+}
+
+impl<'a, D: Device<'a>> Device<'a> for Capture<D> {
+    type RxToken = RxToken<D::RxToken>;
+    type TxToken = TxToken<D::TxToken>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let index = self.index;
+        self.inner
+            .receive()
+            .map(|(rx, tx)| (RxToken { inner: rx, index }, TxToken { inner: tx, index }))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        let index = self.index;
+        self.inner.transmit().map(|tx| TxToken { inner: tx, index })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+pub struct RxToken<T> {
+    inner: T,
+    index: usize,
+}
+
+impl<T: phy::RxToken> phy::RxToken for RxToken<T> {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let index = self.index;
+        self.inner.consume(timestamp, |buffer| {
+            tap(index, buffer, false);
+            f(buffer)
+        })
+    }
+}
+
+pub struct TxToken<T> {
+    inner: T,
+    index: usize,
+}
+
+impl<T: phy::TxToken> phy::TxToken for TxToken<T> {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let index = self.index;
+        self.inner.consume(timestamp, len, |buffer| {
+            let result = f(buffer);
+            if result.is_ok() {
+                tap(index, buffer, true);
+            }
+            result
+        })
+    }
+}
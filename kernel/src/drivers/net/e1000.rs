@@ -5,7 +5,10 @@ use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use managed::ManagedSlice;
+use smoltcp::dhcp::{Dhcpv4Client, Dhcpv4Config};
 use smoltcp::iface::*;
 use smoltcp::phy::{self, DeviceCapabilities};
 use smoltcp::time::Instant;
@@ -17,22 +20,32 @@ use isomorphic_drivers::net::ethernet::structs::EthernetAddress as DriverEtherne
 use rcore_memory::PAGE_SIZE;
 
 use crate::drivers::{provider::Provider, BlockDriver};
-use crate::net::SOCKETS;
+use crate::net::{wake_sockets, SOCKETS};
 use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::SysError;
 
 use super::{
     super::{DeviceType, Driver, DRIVERS, IRQ_MANAGER, NET_DRIVERS, SOCKET_ACTIVITY},
-    NetDriver,
+    capture::Capture,
+    ipv6, NetDriver, ETHERNET_HEADER_LEN, MIN_MTU,
 };
 
 #[derive(Clone)]
-pub struct E1000Driver(Arc<Mutex<E1000<Provider>>>);
+pub struct E1000Driver {
+    inner: Arc<Mutex<E1000<Provider>>>,
+    // max size of ethernet frames
+    mtu: usize,
+}
+
+/// Jumbo frames are not enabled, so the mtu can only be lowered
+const MAX_MTU: usize = 1500;
 
 pub struct E1000Interface {
-    iface: Mutex<EthernetInterface<'static, 'static, 'static, E1000Driver>>,
+    iface: Mutex<EthernetInterface<'static, 'static, 'static, Capture<E1000Driver>>>,
     driver: E1000Driver,
     name: String,
     irq: Option<usize>,
+    up: AtomicBool,
 }
 
 impl Driver for E1000Interface {
@@ -42,19 +55,22 @@ impl Driver for E1000Interface {
             return false;
         }
 
-        let data = self.driver.0.lock().handle_interrupt();
+        let data = self.driver.inner.lock().handle_interrupt();
 
-        if data {
+        if data && self.is_up() {
             let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
             let mut sockets = SOCKETS.lock();
-            match self.iface.lock().poll(&mut sockets, timestamp) {
+            let mut iface = self.iface.lock();
+            match iface.poll(&mut sockets, timestamp) {
                 Ok(_) => {
+                    wake_sockets(&mut sockets);
                     SOCKET_ACTIVITY.notify_all();
                 }
                 Err(err) => {
                     debug!("poll got err {}", err);
                 }
             }
+            ipv6::autoconf(&mut iface, &mut sockets);
         }
 
         return data;
@@ -95,22 +111,90 @@ impl NetDriver for E1000Interface {
         self.iface.lock().ipv4_address()
     }
 
+    fn set_ip_addresses(&self, addrs: Vec<IpCidr>) -> Result<(), SysError> {
+        self.iface
+            .lock()
+            .update_ip_addrs(|ip_addrs| *ip_addrs = ManagedSlice::Owned(addrs));
+        Ok(())
+    }
+
+    fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+
+    fn get_mtu(&self) -> usize {
+        self.iface.lock().device().get_ref().mtu - ETHERNET_HEADER_LEN
+    }
+
+    fn set_mtu(&self, mtu: usize) -> bool {
+        if mtu < MIN_MTU || mtu > MAX_MTU {
+            return false;
+        }
+        self.iface.lock().device_mut().get_mut().mtu = mtu + ETHERNET_HEADER_LEN;
+        true
+    }
+
+    fn add_route(&self, cidr: IpCidr, gateway: IpAddress) -> Result<(), SysError> {
+        let route = Route {
+            via_router: gateway,
+            preferred_until: None,
+            expires_at: None,
+        };
+        self.iface.lock().routes_mut().update(|routes| {
+            let _ = routes.insert(cidr, route);
+        });
+        Ok(())
+    }
+
+    fn del_route(&self, cidr: IpCidr) -> Result<(), SysError> {
+        self.iface.lock().routes_mut().update(|routes| {
+            routes.remove(&cidr);
+        });
+        Ok(())
+    }
+
     fn poll(&self) {
+        if !self.is_up() {
+            return;
+        }
         let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
         let mut sockets = SOCKETS.lock();
-        match self.iface.lock().poll(&mut sockets, timestamp) {
+        let mut iface = self.iface.lock();
+        match iface.poll(&mut sockets, timestamp) {
             Ok(_) => {
+                wake_sockets(&mut sockets);
                 SOCKET_ACTIVITY.notify_all();
             }
             Err(err) => {
                 debug!("poll got err {}", err);
             }
         }
+        ipv6::autoconf(&mut iface, &mut sockets);
+    }
+
+    fn poll_dhcp(&self, client: &mut Dhcpv4Client) -> Option<Dhcpv4Config> {
+        let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
+        let mut sockets = SOCKETS.lock();
+        let mut iface = self.iface.lock();
+        match client.poll(&mut iface, &mut sockets, timestamp) {
+            Ok(config) => config,
+            Err(err) => {
+                debug!("dhcp poll got err {}", err);
+                None
+            }
+        }
     }
 
     fn send(&self, data: &[u8]) -> Option<usize> {
-        use smoltcp::phy::TxToken;
-        let token = E1000TxToken(self.driver.clone());
+        use smoltcp::phy::{Device, TxToken};
+        if !self.is_up() {
+            return None;
+        }
+        let token = self.iface.lock().device_mut().transmit()?;
         if token
             .consume(Instant::from_millis(0), data.len(), |buffer| {
                 buffer.copy_from_slice(&data);
@@ -139,14 +223,14 @@ impl phy::Device<'_> for E1000Driver {
     type TxToken = E1000TxToken;
 
     fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        self.0
+        self.inner
             .lock()
             .receive()
             .map(|vec| (E1000RxToken(vec), E1000TxToken(self.clone())))
     }
 
     fn transmit(&mut self) -> Option<Self::TxToken> {
-        if self.0.lock().can_send() {
+        if self.inner.lock().can_send() {
             Some(E1000TxToken(self.clone()))
         } else {
             None
@@ -155,7 +239,7 @@ impl phy::Device<'_> for E1000Driver {
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut caps = DeviceCapabilities::default();
-        caps.max_transmission_unit = 1536;
+        caps.max_transmission_unit = self.mtu;
         caps.max_burst_size = Some(64);
         caps
     }
@@ -178,7 +262,7 @@ impl phy::TxToken for E1000TxToken {
         let mut buffer = [0u8; PAGE_SIZE];
         let result = f(&mut buffer[..len]);
 
-        let mut driver = (self.0).0.lock();
+        let mut driver = self.0.inner.lock();
         driver.send(&buffer);
 
         result
@@ -194,27 +278,46 @@ pub fn init(name: String, irq: Option<usize>, header: usize, size: usize, index:
 
     let e1000 = E1000::new(header, size, DriverEthernetAddress::from_bytes(&mac));
 
-    let net_driver = E1000Driver(Arc::new(Mutex::new(e1000)));
+    let net_driver = E1000Driver {
+        inner: Arc::new(Mutex::new(e1000)),
+        mtu: MAX_MTU + ETHERNET_HEADER_LEN,
+    };
 
     let ethernet_addr = EthernetAddress::from_bytes(&mac);
-    let ip_addrs = [IpCidr::new(IpAddress::v4(10, 0, index as u8, 2), 24)];
+    // no ipv4 address until the dhcp client gets one
+    #[cfg(feature = "dhcp")]
+    let ip_addrs = vec![ipv6::link_local_cidr(ethernet_addr)];
+    #[cfg(not(feature = "dhcp"))]
+    let ip_addrs = vec![
+        IpCidr::new(IpAddress::v4(10, 0, index as u8, 2), 24),
+        ipv6::link_local_cidr(ethernet_addr),
+    ];
     let neighbor_cache = NeighborCache::new(BTreeMap::new());
-    let iface = EthernetInterfaceBuilder::new(net_driver.clone())
+    let routes = Routes::new(BTreeMap::new());
+    let iface = EthernetInterfaceBuilder::new(Capture::new(net_driver.clone(), index))
         .ethernet_addr(ethernet_addr)
         .ip_addrs(ip_addrs)
         .neighbor_cache(neighbor_cache)
+        .routes(routes)
         .finalize();
 
-    info!("e1000 interface {} up with addr 10.0.{}.2/24", name, index);
+    info!("e1000 interface {} up", name);
+    #[cfg(not(feature = "dhcp"))]
+    info!("{} has addr 10.0.{}.2/24", name, index);
+    ipv6::init();
     let e1000_iface = E1000Interface {
         iface: Mutex::new(iface),
         driver: net_driver.clone(),
         name,
         irq,
+        up: AtomicBool::new(true),
     };
 
     let driver = Arc::new(e1000_iface);
     DRIVERS.write().push(driver.clone());
     IRQ_MANAGER.write().register_opt(irq, driver.clone());
-    NET_DRIVERS.write().push(driver);
+    NET_DRIVERS.write().push(driver.clone());
+    driver.send(&ipv6::router_solicitation(ethernet_addr));
+    #[cfg(feature = "dhcp")]
+    crate::net::dhcp::start(NET_DRIVERS.read().len() - 1);
 }
@@ -0,0 +1,191 @@
+//! IPv6 addressing of network cards, see RFC 4862
+//!
+//! A card gets a link-local address from its mac address, then global ones from
+//! the prefixes of router advertisements. Duplicate address detection is not done.
+
+use alloc::vec::Vec;
+
+use managed::ManagedSlice;
+use smoltcp::iface::EthernetInterface;
+use smoltcp::phy::Device;
+use smoltcp::socket::{RawPacketMetadata, RawSocket, RawSocketBuffer, SocketHandle, SocketSet};
+use smoltcp::wire::*;
+
+use crate::net::SOCKETS;
+
+const ND_ROUTER_SOLICIT: u8 = 133;
+const ND_ROUTER_ADVERT: u8 = 134;
+const ND_OPT_SOURCE_LL_ADDR: u8 = 1;
+const ND_OPT_PREFIX_INFORMATION: u8 = 3;
+/// The prefix can be used for autoconfiguration
+const PREFIX_AUTONOMOUS: u8 = 0x40;
+
+lazy_static! {
+    /// Receives router advertisements for all cards. It is drained after each poll
+    /// of a card, so what it holds was received by that card.
+    static ref RA_SOCKET: SocketHandle = {
+        let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 8], vec![0; 4096]);
+        let tx_buffer = RawSocketBuffer::new(vec![], vec![]);
+        let socket = RawSocket::new(IpVersion::Ipv6, IpProtocol::Icmpv6, rx_buffer, tx_buffer);
+        SOCKETS.lock().add(socket)
+    };
+}
+
+/// Start listening to router advertisements, before a card is polled
+pub fn init() {
+    lazy_static::initialize(&RA_SOCKET);
+}
+
+/// `prefix` with the modified EUI-64 interface identifier of `mac`
+fn with_interface_id(prefix: Ipv6Address, mac: EthernetAddress) -> Ipv6Address {
+    let mut addr = prefix.0;
+    let mac = mac.0;
+    addr[8..].copy_from_slice(&[
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]);
+    Ipv6Address(addr)
+}
+
+/// The link-local address of a card with `mac`
+pub fn link_local_address(mac: EthernetAddress) -> Ipv6Address {
+    with_interface_id(Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), mac)
+}
+
+pub fn link_local_cidr(mac: EthernetAddress) -> IpCidr {
+    IpCidr::new(IpAddress::Ipv6(link_local_address(mac)), 64)
+}
+
+/// An ethernet frame soliciting routers to advertise now
+pub fn router_solicitation(mac: EthernetAddress) -> Vec<u8> {
+    let src = link_local_address(mac);
+    let dst = Ipv6Address::LINK_LOCAL_ALL_ROUTERS;
+    // type, code, checksum, reserved, then the source link-layer address option
+    let mut icmp = vec![
+        ND_ROUTER_SOLICIT,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        ND_OPT_SOURCE_LL_ADDR,
+        1,
+    ];
+    icmp.extend_from_slice(mac.as_bytes());
+    Icmpv6Packet::new_unchecked(&mut icmp[..]).fill_checksum(&src.into(), &dst.into());
+
+    let mut frame = vec![0u8; 14 + 40 + icmp.len()];
+    let mut ethernet = EthernetFrame::new_unchecked(&mut frame[..]);
+    ethernet.set_dst_addr(EthernetAddress([0x33, 0x33, 0, 0, 0, 2]));
+    ethernet.set_src_addr(mac);
+    ethernet.set_ethertype(EthernetProtocol::Ipv6);
+    let mut packet = Ipv6Packet::new_unchecked(ethernet.payload_mut());
+    packet.set_version(6);
+    packet.set_traffic_class(0);
+    packet.set_flow_label(0);
+    packet.set_payload_len(icmp.len() as u16);
+    packet.set_next_header(IpProtocol::Icmpv6);
+    packet.set_hop_limit(255);
+    packet.set_src_addr(src);
+    packet.set_dst_addr(dst);
+    packet.payload_mut().copy_from_slice(&icmp);
+    frame
+}
+
+struct RouterAdvert {
+    router: Ipv6Address,
+    /// Seconds it is a default router for, 0 if it is not one
+    lifetime: u16,
+    /// Prefixes for autoconfiguration
+    prefixes: Vec<Ipv6Address>,
+}
+
+fn parse_router_advert(packet: &[u8]) -> Option<RouterAdvert> {
+    let packet = Ipv6Packet::new_checked(packet).ok()?;
+    if packet.next_header() != IpProtocol::Icmpv6 || packet.hop_limit() != 255 {
+        return None;
+    }
+    let (src, dst) = (packet.src_addr(), packet.dst_addr());
+    let icmp = packet.payload();
+    // type, code, checksum, hop limit, flags, router lifetime, reachable time, retrans timer
+    if icmp.len() < 16 || icmp[0] != ND_ROUTER_ADVERT || icmp[1] != 0 || !src.is_link_local() {
+        return None;
+    }
+    if !Icmpv6Packet::new_unchecked(icmp).verify_checksum(&src.into(), &dst.into()) {
+        return None;
+    }
+    let mut advert = RouterAdvert {
+        router: src,
+        lifetime: u16::from_be_bytes([icmp[6], icmp[7]]),
+        prefixes: Vec::new(),
+    };
+    let mut options = &icmp[16..];
+    while options.len() >= 8 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            break;
+        }
+        // type, length, prefix length, flags, valid lifetime, preferred lifetime, reserved, prefix
+        if options[0] == ND_OPT_PREFIX_INFORMATION && len == 32 {
+            let valid = u32::from_be_bytes([options[4], options[5], options[6], options[7]]);
+            if options[2] == 64 && options[3] & PREFIX_AUTONOMOUS != 0 && valid > 0 {
+                advert
+                    .prefixes
+                    .push(Ipv6Address::from_bytes(&options[16..32]));
+            }
+        }
+        options = &options[len..];
+    }
+    Some(advert)
+}
+
+/// Configure `iface` by the router advertisements it received in the last poll.
+/// `sockets` has been locked since then.
+pub fn autoconf<DeviceT>(
+    iface: &mut EthernetInterface<'_, '_, '_, DeviceT>,
+    sockets: &mut SocketSet,
+) where
+    DeviceT: for<'d> Device<'d>,
+{
+    let mut socket = sockets.get::<RawSocket>(*RA_SOCKET);
+    while let Ok(packet) = socket.recv() {
+        let advert = match parse_router_advert(packet) {
+            Some(advert) => advert,
+            None => continue,
+        };
+        let mac = iface.ethernet_addr();
+        for prefix in advert.prefixes {
+            let cidr = IpCidr::new(IpAddress::Ipv6(with_interface_id(prefix, mac)), 64);
+            if iface.ip_addrs().contains(&cidr) {
+                continue;
+            }
+            info!("autoconfigured {} from router {}", cidr, advert.router);
+            iface.update_ip_addrs(|addrs| {
+                if let ManagedSlice::Owned(addrs) = addrs {
+                    // before the link-local address, so it is the source for other networks
+                    let index = addrs
+                        .iter()
+                        .position(|cidr| match cidr.address() {
+                            IpAddress::Ipv6(addr) => addr.is_link_local(),
+                            _ => false,
+                        })
+                        .unwrap_or(addrs.len());
+                    addrs.insert(index, cidr);
+                }
+            });
+        }
+        if advert.lifetime > 0 {
+            if let Err(err) = iface.routes_mut().add_default_ipv6_route(advert.router) {
+                debug!("add default route got err {}", err);
+            }
+        }
+    }
+}
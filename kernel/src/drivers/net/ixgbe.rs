@@ -6,8 +6,11 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicBool, Ordering};
 use isomorphic_drivers::net::ethernet::intel::ixgbe;
 use log::*;
+use managed::ManagedSlice;
+use smoltcp::dhcp::{Dhcpv4Client, Dhcpv4Config};
 use smoltcp::iface::*;
 use smoltcp::phy::{self, Checksum, DeviceCapabilities};
 use smoltcp::time::Instant;
@@ -15,15 +18,17 @@ use smoltcp::wire::EthernetAddress;
 use smoltcp::wire::*;
 use smoltcp::Result;
 
-use crate::net::SOCKETS;
+use crate::net::{wake_sockets, SOCKETS};
 use crate::sync::FlagsGuard;
+use crate::syscall::SysError;
 use crate::{drivers::BlockDriver, sync::SpinNoIrqLock as Mutex};
 
 use super::{
     super::{
         provider::Provider, DeviceType, Driver, DRIVERS, IRQ_MANAGER, NET_DRIVERS, SOCKET_ACTIVITY,
     },
-    NetDriver,
+    capture::Capture,
+    ipv6, NetDriver, ETHERNET_HEADER_LEN, MIN_MTU,
 };
 
 #[derive(Clone)]
@@ -35,11 +40,12 @@ struct IXGBEDriver {
 }
 
 pub struct IXGBEInterface {
-    iface: Mutex<EthernetInterface<'static, 'static, 'static, IXGBEDriver>>,
+    iface: Mutex<EthernetInterface<'static, 'static, 'static, Capture<IXGBEDriver>>>,
     driver: IXGBEDriver,
     ifname: String,
     irq: Option<usize>,
     id: String,
+    up: AtomicBool,
 }
 
 impl Driver for IXGBEInterface {
@@ -54,17 +60,20 @@ impl Driver for IXGBEInterface {
             self.driver.inner.lock().try_handle_interrupt()
         };
 
-        if handled {
+        if handled && self.is_up() {
             let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
             let mut sockets = SOCKETS.lock();
-            match self.iface.lock().poll(&mut sockets, timestamp) {
+            let mut iface = self.iface.lock();
+            match iface.poll(&mut sockets, timestamp) {
                 Ok(_) => {
+                    wake_sockets(&mut sockets);
                     SOCKET_ACTIVITY.notify_all();
                 }
                 Err(err) => {
                     debug!("poll got err {}", err);
                 }
             }
+            ipv6::autoconf(&mut iface, &mut sockets);
         }
 
         return handled;
@@ -105,21 +114,97 @@ impl NetDriver for IXGBEInterface {
         self.iface.lock().ipv4_address()
     }
 
+    fn set_ip_addresses(&self, addrs: Vec<IpCidr>) -> Result<(), SysError> {
+        self.iface
+            .lock()
+            .update_ip_addrs(|ip_addrs| *ip_addrs = ManagedSlice::Owned(addrs));
+        Ok(())
+    }
+
+    fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+
+    fn get_mtu(&self) -> usize {
+        self.iface.lock().device().get_ref().mtu - ETHERNET_HEADER_LEN
+    }
+
+    fn set_mtu(&self, mtu: usize) -> bool {
+        // frames are sent from a buffer of the max mtu
+        if mtu < MIN_MTU || mtu + ETHERNET_HEADER_LEN > ixgbe::IXGBE::<Provider>::get_mtu() {
+            return false;
+        }
+        self.iface.lock().device_mut().get_mut().mtu = mtu + ETHERNET_HEADER_LEN;
+        true
+    }
+
+    fn add_route(&self, cidr: IpCidr, gateway: IpAddress) -> Result<(), SysError> {
+        let route = Route {
+            via_router: gateway,
+            preferred_until: None,
+            expires_at: None,
+        };
+        self.iface.lock().routes_mut().update(|routes| {
+            let _ = routes.insert(cidr, route);
+        });
+        Ok(())
+    }
+
+    fn del_route(&self, cidr: IpCidr) -> Result<(), SysError> {
+        self.iface.lock().routes_mut().update(|routes| {
+            routes.remove(&cidr);
+        });
+        Ok(())
+    }
+
     fn poll(&self) {
+        if !self.is_up() {
+            return;
+        }
         let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
         let mut sockets = SOCKETS.lock();
-        match self.iface.lock().poll(&mut sockets, timestamp) {
+        let mut iface = self.iface.lock();
+        match iface.poll(&mut sockets, timestamp) {
             Ok(_) => {
+                wake_sockets(&mut sockets);
                 SOCKET_ACTIVITY.notify_all();
             }
             Err(err) => {
                 debug!("poll got err {}", err);
             }
         }
+        ipv6::autoconf(&mut iface, &mut sockets);
+    }
+
+    fn poll_dhcp(&self, client: &mut Dhcpv4Client) -> Option<Dhcpv4Config> {
+        let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
+        let mut sockets = SOCKETS.lock();
+        let mut iface = self.iface.lock();
+        match client.poll(&mut iface, &mut sockets, timestamp) {
+            Ok(config) => config,
+            Err(err) => {
+                debug!("dhcp poll got err {}", err);
+                None
+            }
+        }
     }
 
     fn send(&self, data: &[u8]) -> Option<usize> {
-        self.driver.inner.lock().send(&data);
+        use smoltcp::phy::{Device, TxToken};
+        if !self.is_up() {
+            return None;
+        }
+        let token = self.iface.lock().device_mut().transmit()?;
+        token
+            .consume(Instant::from_millis(0), data.len(), |buffer| {
+                buffer.copy_from_slice(&data);
+                Ok(())
+            })
+            .ok()?;
         Some(data.len())
     }
 
@@ -214,17 +299,27 @@ pub fn ixgbe_init(
         mtu: 1500,
     };
 
-    let ip_addrs = [IpCidr::new(IpAddress::v4(10, 0, index as u8, 2), 24)];
+    // no ipv4 address until the dhcp client gets one
+    #[cfg(feature = "dhcp")]
+    let ip_addrs = vec![ipv6::link_local_cidr(ethernet_addr)];
+    #[cfg(not(feature = "dhcp"))]
+    let ip_addrs = vec![
+        IpCidr::new(IpAddress::v4(10, 0, index as u8, 2), 24),
+        ipv6::link_local_cidr(ethernet_addr),
+    ];
     let neighbor_cache = NeighborCache::new(BTreeMap::new());
     let routes = Routes::new(BTreeMap::new());
-    let iface = EthernetInterfaceBuilder::new(net_driver.clone())
+    let iface = EthernetInterfaceBuilder::new(Capture::new(net_driver.clone(), index))
         .ethernet_addr(ethernet_addr)
         .ip_addrs(ip_addrs)
         .neighbor_cache(neighbor_cache)
         .routes(routes)
         .finalize();
 
-    info!("ixgbe interface {} up with addr 10.0.{}.2/24", name, index);
+    info!("ixgbe interface {} up", name);
+    #[cfg(not(feature = "dhcp"))]
+    info!("{} has addr 10.0.{}.2/24", name, index);
+    ipv6::init();
 
     let ixgbe_iface = IXGBEInterface {
         iface: Mutex::new(iface),
@@ -232,11 +327,15 @@ pub fn ixgbe_init(
         ifname: name.clone(),
         id: name,
         irq,
+        up: AtomicBool::new(true),
     };
 
     let driver = Arc::new(ixgbe_iface);
     IRQ_MANAGER.write().register_opt(irq, driver.clone());
     DRIVERS.write().push(driver.clone());
     NET_DRIVERS.write().push(driver.clone());
+    driver.send(&ipv6::router_solicitation(ethernet_addr));
+    #[cfg(feature = "dhcp")]
+    crate::net::dhcp::start(NET_DRIVERS.read().len() - 1);
     driver
 }
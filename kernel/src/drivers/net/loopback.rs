@@ -0,0 +1,195 @@
+//! Software loopback interface `lo` with 127.0.0.1/8
+//!
+//! It is always present, so local traffic works without a network card.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use managed::ManagedSlice;
+use smoltcp::iface::*;
+use smoltcp::phy::{Device, Loopback, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::wire::*;
+
+use crate::drivers::BlockDriver;
+use crate::net::{wake_sockets, SOCKETS};
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::SysError;
+
+use super::{
+    super::{DeviceType, Driver, DRIVERS, NET_DRIVERS, SOCKET_ACTIVITY},
+    capture::Capture,
+    NetDriver, ETHERNET_HEADER_LEN,
+};
+
+/// Max polls in a row, as packets sent in a poll are only received in the next one
+const MAX_POLLS: usize = 64;
+
+pub struct LoopbackInterface {
+    iface: Mutex<EthernetInterface<'static, 'static, 'static, Capture<Loopback>>>,
+    up: AtomicBool,
+}
+
+impl Driver for LoopbackInterface {
+    fn try_handle_interrupt(&self, _irq: Option<usize>) -> bool {
+        false
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Net
+    }
+
+    fn get_id(&self) -> String {
+        String::from("loopback")
+    }
+
+    fn as_net(&self) -> Option<&dyn NetDriver> {
+        Some(self)
+    }
+
+    fn as_block(&self) -> Option<&dyn BlockDriver> {
+        None
+    }
+}
+
+impl NetDriver for LoopbackInterface {
+    fn get_mac(&self) -> EthernetAddress {
+        self.iface.lock().ethernet_addr()
+    }
+
+    fn get_ifname(&self) -> String {
+        String::from("lo")
+    }
+
+    fn get_ip_addresses(&self) -> Vec<IpCidr> {
+        Vec::from(self.iface.lock().ip_addrs())
+    }
+
+    fn ipv4_address(&self) -> Option<Ipv4Address> {
+        self.iface.lock().ipv4_address()
+    }
+
+    fn set_ip_addresses(&self, addrs: Vec<IpCidr>) -> Result<(), SysError> {
+        self.iface
+            .lock()
+            .update_ip_addrs(|ip_addrs| *ip_addrs = ManagedSlice::Owned(addrs));
+        Ok(())
+    }
+
+    fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+
+    fn get_mtu(&self) -> usize {
+        self.iface
+            .lock()
+            .device()
+            .capabilities()
+            .max_transmission_unit
+            - ETHERNET_HEADER_LEN
+    }
+
+    fn set_mtu(&self, mtu: usize) -> bool {
+        // fixed by smoltcp
+        mtu == self.get_mtu()
+    }
+
+    fn add_route(&self, cidr: IpCidr, gateway: IpAddress) -> Result<(), SysError> {
+        let route = Route {
+            via_router: gateway,
+            preferred_until: None,
+            expires_at: None,
+        };
+        self.iface.lock().routes_mut().update(|routes| {
+            let _ = routes.insert(cidr, route);
+        });
+        Ok(())
+    }
+
+    fn del_route(&self, cidr: IpCidr) -> Result<(), SysError> {
+        self.iface.lock().routes_mut().update(|routes| {
+            routes.remove(&cidr);
+        });
+        Ok(())
+    }
+
+    fn poll(&self) {
+        if !self.is_up() {
+            return;
+        }
+        let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
+        let mut sockets = SOCKETS.lock();
+        let mut iface = self.iface.lock();
+        // there is no interrupt, so deliver everything looped back now
+        for _ in 0..MAX_POLLS {
+            match iface.poll(&mut sockets, timestamp) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => {
+                    debug!("poll got err {}", err);
+                }
+            }
+        }
+        wake_sockets(&mut sockets);
+        SOCKET_ACTIVITY.notify_all();
+    }
+
+    fn send(&self, data: &[u8]) -> Option<usize> {
+        if !self.is_up() {
+            return None;
+        }
+        let sent = {
+            let mut iface = self.iface.lock();
+            let token = iface.device_mut().transmit()?;
+            token
+                .consume(Instant::from_millis(0), data.len(), |buffer| {
+                    buffer.copy_from_slice(&data);
+                    Ok(())
+                })
+                .is_ok()
+        };
+        if sent {
+            self.poll();
+            Some(data.len())
+        } else {
+            None
+        }
+    }
+
+    fn get_arp(&self, ip: IpAddress) -> Option<EthernetAddress> {
+        let iface = self.iface.lock();
+        let cache = iface.neighbor_cache();
+        cache.lookup_pure(&ip, Instant::from_millis(0))
+    }
+}
+
+pub fn init() {
+    let ip_addrs = vec![
+        IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8),
+        IpCidr::new(IpAddress::Ipv6(Ipv6Address::LOOPBACK), 128),
+    ];
+    let neighbor_cache = NeighborCache::new(BTreeMap::new());
+    let routes = Routes::new(BTreeMap::new());
+    let index = NET_DRIVERS.read().len();
+    let iface = EthernetInterfaceBuilder::new(Capture::new(Loopback::new(), index))
+        .ethernet_addr(EthernetAddress([0; 6]))
+        .ip_addrs(ip_addrs)
+        .neighbor_cache(neighbor_cache)
+        .routes(routes)
+        .finalize();
+
+    info!("loopback interface lo up with addr 127.0.0.1/8 and ::1/128");
+    let driver = Arc::new(LoopbackInterface {
+        iface: Mutex::new(iface),
+        up: AtomicBool::new(true),
+    });
+    DRIVERS.write().push(driver.clone());
+    NET_DRIVERS.write().push(driver);
+}
@@ -1,12 +1,23 @@
 use super::Driver;
+use crate::syscall::SysError;
 use alloc::string::String;
 use alloc::vec::Vec;
-use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
+use smoltcp::dhcp::{Dhcpv4Client, Dhcpv4Config};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv6Address};
 
+pub mod capture;
 pub mod e1000;
+pub mod ipv6;
 pub mod ixgbe;
+pub mod loopback;
+pub mod tun;
 pub mod virtio_net;
 
+/// Size of ethernet headers, counted in the mtu of smoltcp devices but not of links
+pub const ETHERNET_HEADER_LEN: usize = 14;
+/// Min mtu of IPv4 links
+pub const MIN_MTU: usize = 68;
+
 pub trait NetDriver: Driver {
     // get mac address for this device
     fn get_mac(&self) -> EthernetAddress {
@@ -23,11 +34,66 @@ pub trait NetDriver: Driver {
         unimplemented!("not a net driver")
     }
 
+    // replace ip addresses, fails if the driver has no ip stack
+    fn set_ip_addresses(&self, _addrs: Vec<IpCidr>) -> Result<(), SysError> {
+        Err(SysError::EOPNOTSUPP)
+    }
+
     // get ipv4 address
     fn ipv4_address(&self) -> Option<Ipv4Address> {
         unimplemented!("not a net driver")
     }
 
+    // get ipv6 addresses, global ones before link-local ones
+    fn ipv6_addresses(&self) -> Vec<Ipv6Address> {
+        self.get_ip_addresses()
+            .iter()
+            .filter_map(|cidr| match cidr.address() {
+                IpAddress::Ipv6(addr) => Some(addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // whether the link is up, a down link neither sends nor receives
+    fn is_up(&self) -> bool {
+        true
+    }
+
+    // bring the link up or down, ignored by drivers whose link is always up
+    fn set_up(&self, _up: bool) {}
+
+    // get mtu, the max size of ip packets
+    fn get_mtu(&self) -> usize {
+        1500
+    }
+
+    // set mtu, false if it is out of range or the driver has a fixed mtu
+    fn set_mtu(&self, _mtu: usize) -> bool {
+        false
+    }
+
+    // route packets to `cidr` via `gateway`, replacing the old route to it,
+    // fails if the driver has no ip stack
+    fn add_route(&self, _cidr: IpCidr, _gateway: IpAddress) -> Result<(), SysError> {
+        Err(SysError::EOPNOTSUPP)
+    }
+
+    fn del_route(&self, _cidr: IpCidr) -> Result<(), SysError> {
+        Err(SysError::EOPNOTSUPP)
+    }
+
+    // whether the link leads to a single peer, which takes packets to any address
+    fn is_point_to_point(&self) -> bool {
+        false
+    }
+
+    // poll the dhcp client of this interface, get the new configuration if any,
+    // always none if the driver has no ip stack
+    fn poll_dhcp(&self, _client: &mut Dhcpv4Client) -> Option<Dhcpv4Config> {
+        None
+    }
+
     // manually trigger a poll, use it after sending packets
     fn poll(&self) {
         unimplemented!("not a net driver")
@@ -0,0 +1,542 @@
+//! Virtual interfaces `tunN` and `tapN` of `/dev/net/tun`
+//!
+//! A TAP interface sends its ethernet frames to the file attached to it,
+//! and receives the frames written to the file.
+//! A TUN interface does the same with IP packets. smoltcp only speaks ethernet,
+//! so the file adds and strips ethernet headers, and the device answers
+//! the ARP requests and neighbor solicitations of the interface itself,
+//! as the only neighbor is the peer at the other end of the file.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Waker;
+
+use managed::ManagedSlice;
+use smoltcp::iface::*;
+use smoltcp::phy::{self, Device, DeviceCapabilities, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::wire::*;
+use smoltcp::Result;
+use spin::RwLock;
+
+use crate::arch::rand;
+use crate::drivers::BlockDriver;
+use crate::net::{remove_iface_routes, unbind_iface, wake_sockets, SOCKETS};
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::SysError;
+
+use super::{
+    super::{DeviceType, Driver, DRIVERS, NET_DRIVERS, SOCKET_ACTIVITY},
+    capture::Capture,
+    NetDriver, ETHERNET_HEADER_LEN, MIN_MTU,
+};
+
+// flags of TUNSETIFF
+pub const IFF_TUN: u16 = 0x0001;
+pub const IFF_TAP: u16 = 0x0002;
+/// Packets are not prefixed with `struct tun_pi`
+pub const IFF_NO_PI: u16 = 0x1000;
+
+/// Mac of TUN interfaces, which only they see
+pub const TUN_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 1]);
+/// Mac of the peer of TUN interfaces
+pub const PEER_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 2]);
+/// Made-up addresses of the peer, which routes of TUN interfaces go via
+const PEER_IPV4: Ipv4Address = Ipv4Address([169, 254, 0, 1]);
+const PEER_IPV6: Ipv6Address = Ipv6Address([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+/// Max frames queued to the file, later ones are dropped like the txqueuelen of Linux
+const MAX_QUEUED: usize = 500;
+const DEFAULT_MTU: usize = 1500;
+const MAX_MTU: usize = 65535;
+/// Max polls in a row, as the neighbor replies of the device are only received in the next one
+const MAX_POLLS: usize = 64;
+
+const ND_NEIGHBOR_SOLICIT: u8 = 135;
+const ND_NEIGHBOR_ADVERT: u8 = 136;
+const ND_OPT_TARGET_LL_ADDR: u8 = 2;
+/// The solicited and override flags of neighbor advertisements
+const NA_FLAGS: u8 = 0x60;
+
+lazy_static! {
+    /// All TUN and TAP interfaces. They are removed when their files are closed,
+    /// unless they are persistent, and TUNSETIFF attaches them again.
+    static ref TUN_INTERFACES: RwLock<Vec<Arc<TunInterface>>> = RwLock::new(Vec::new());
+}
+
+#[derive(Default)]
+struct TunQueue {
+    /// Frames sent by the interface, for the file
+    to_file: VecDeque<Vec<u8>>,
+    /// Frames written to the file, or made up by the device, for the interface
+    to_iface: VecDeque<Vec<u8>>,
+    /// Whether a file is attached, frames are dropped otherwise
+    attached: bool,
+    /// Tasks waiting for `to_file`
+    waiters: Vec<Waker>,
+}
+
+pub struct TunDevice {
+    queue: Arc<Mutex<TunQueue>>,
+    tun: bool,
+    // max size of ethernet frames
+    mtu: usize,
+}
+
+pub struct TunRxToken(Vec<u8>);
+
+pub struct TunTxToken {
+    queue: Arc<Mutex<TunQueue>>,
+    tun: bool,
+}
+
+impl<'a> Device<'a> for TunDevice {
+    type RxToken = TunRxToken;
+    type TxToken = TunTxToken;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.queue.lock().to_iface.pop_front()?;
+        let tx = TunTxToken {
+            queue: self.queue.clone(),
+            tun: self.tun,
+        };
+        Some((TunRxToken(frame), tx))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(TunTxToken {
+            queue: self.queue.clone(),
+            tun: self.tun,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+impl phy::RxToken for TunRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl phy::TxToken for TunTxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut frame = vec![0u8; len];
+        let result = f(&mut frame)?;
+        let ethertype = match EthernetFrame::new_checked(&frame[..]) {
+            Ok(header) => header.ethertype(),
+            Err(_) => return Ok(result),
+        };
+        let waiters = {
+            let mut queue = self.queue.lock();
+            if self.tun {
+                if let Some(reply) = neighbor_reply(&frame) {
+                    queue.to_iface.push_back(reply);
+                    return Ok(result);
+                }
+                // only IP packets go through a TUN interface
+                if ethertype != EthernetProtocol::Ipv4 && ethertype != EthernetProtocol::Ipv6 {
+                    return Ok(result);
+                }
+            }
+            if !queue.attached || queue.to_file.len() == MAX_QUEUED {
+                return Ok(result);
+            }
+            queue.to_file.push_back(frame);
+            core::mem::replace(&mut queue.waiters, Vec::new())
+        };
+        for waker in waiters {
+            waker.wake();
+        }
+        SOCKET_ACTIVITY.notify_all();
+        Ok(result)
+    }
+}
+
+/// The reply to an ARP request or a neighbor solicitation sent by a TUN interface,
+/// as if the peer had every address
+fn neighbor_reply(frame: &[u8]) -> Option<Vec<u8>> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    let payload = match frame.ethertype() {
+        EthernetProtocol::Arp => arp_reply(frame.payload())?,
+        EthernetProtocol::Ipv6 => neighbor_advert(frame.payload())?,
+        _ => return None,
+    };
+    let mut reply = vec![0u8; ETHERNET_HEADER_LEN + payload.len()];
+    let mut ethernet = EthernetFrame::new_unchecked(&mut reply[..]);
+    ethernet.set_dst_addr(frame.src_addr());
+    ethernet.set_src_addr(PEER_MAC);
+    ethernet.set_ethertype(frame.ethertype());
+    ethernet.payload_mut().copy_from_slice(&payload);
+    Some(reply)
+}
+
+fn arp_reply(packet: &[u8]) -> Option<Vec<u8>> {
+    let packet = ArpPacket::new_checked(packet).ok()?;
+    match ArpRepr::parse(&packet).ok()? {
+        ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_protocol_addr,
+            ..
+        } => {
+            let repr = ArpRepr::EthernetIpv4 {
+                operation: ArpOperation::Reply,
+                source_hardware_addr: PEER_MAC,
+                source_protocol_addr: target_protocol_addr,
+                target_hardware_addr: source_hardware_addr,
+                target_protocol_addr: source_protocol_addr,
+            };
+            let mut reply = vec![0u8; repr.buffer_len()];
+            repr.emit(&mut ArpPacket::new_unchecked(&mut reply[..]));
+            Some(reply)
+        }
+        _ => None,
+    }
+}
+
+fn neighbor_advert(packet: &[u8]) -> Option<Vec<u8>> {
+    let packet = Ipv6Packet::new_checked(packet).ok()?;
+    let icmp = packet.payload();
+    // type, code, checksum, reserved, target address, then options
+    if packet.next_header() != IpProtocol::Icmpv6
+        || icmp.len() < 24
+        || icmp[0] != ND_NEIGHBOR_SOLICIT
+        || packet.src_addr().is_unspecified()
+    {
+        return None;
+    }
+    let src = Ipv6Address::from_bytes(&icmp[8..24]);
+    let dst = packet.src_addr();
+    // type, code, checksum, flags, reserved, target address,
+    // then the target link-layer address option
+    let mut advert = vec![ND_NEIGHBOR_ADVERT, 0, 0, 0, NA_FLAGS, 0, 0, 0];
+    advert.extend_from_slice(src.as_bytes());
+    advert.extend_from_slice(&[ND_OPT_TARGET_LL_ADDR, 1]);
+    advert.extend_from_slice(PEER_MAC.as_bytes());
+    Icmpv6Packet::new_unchecked(&mut advert[..]).fill_checksum(&src.into(), &dst.into());
+
+    let mut reply = vec![0u8; 40 + advert.len()];
+    let mut packet = Ipv6Packet::new_unchecked(&mut reply[..]);
+    packet.set_version(6);
+    packet.set_traffic_class(0);
+    packet.set_flow_label(0);
+    packet.set_payload_len(advert.len() as u16);
+    packet.set_next_header(IpProtocol::Icmpv6);
+    packet.set_hop_limit(255);
+    packet.set_src_addr(src);
+    packet.set_dst_addr(dst);
+    packet.payload_mut().copy_from_slice(&advert);
+    Some(reply)
+}
+
+pub struct TunInterface {
+    iface: Mutex<EthernetInterface<'static, 'static, 'static, Capture<TunDevice>>>,
+    queue: Arc<Mutex<TunQueue>>,
+    name: String,
+    /// IFF_TUN or IFF_TAP
+    kind: u16,
+    up: AtomicBool,
+    /// Kept when its file is closed, see TUNSETPERSIST
+    persistent: AtomicBool,
+}
+
+impl TunInterface {
+    pub fn is_tun(&self) -> bool {
+        self.kind == IFF_TUN
+    }
+
+    /// Attach a file, false if another one is attached
+    pub fn attach(&self) -> bool {
+        let mut queue = self.queue.lock();
+        if queue.attached {
+            return false;
+        }
+        queue.attached = true;
+        true
+    }
+
+    /// Detach the file, which takes the link down.
+    /// The interface is removed unless it is persistent.
+    pub fn detach(&self) {
+        {
+            let mut queue = self.queue.lock();
+            queue.attached = false;
+            queue.to_file.clear();
+        }
+        self.set_up(false);
+        remove_unused(&self.name);
+    }
+
+    pub fn set_persistent(&self, on: bool) {
+        self.persistent.store(on, Ordering::Relaxed);
+    }
+
+    /// The next frame sent by the interface
+    pub fn pop_frame(&self) -> Option<Vec<u8>> {
+        self.queue.lock().to_file.pop_front()
+    }
+
+    pub fn has_frames(&self) -> bool {
+        !self.queue.lock().to_file.is_empty()
+    }
+
+    /// Wake `waker` when the interface sends a frame
+    pub fn register_waker(&self, waker: Waker) {
+        self.queue.lock().waiters.push(waker);
+    }
+
+    /// Receive a frame written to the file, false if the link is down
+    pub fn push_frame(&self, frame: Vec<u8>) -> bool {
+        if !self.is_up() {
+            return false;
+        }
+        self.queue.lock().to_iface.push_back(frame);
+        self.poll();
+        true
+    }
+}
+
+impl Driver for TunInterface {
+    fn try_handle_interrupt(&self, _irq: Option<usize>) -> bool {
+        false
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Net
+    }
+
+    fn get_id(&self) -> String {
+        String::from("tun")
+    }
+
+    fn as_net(&self) -> Option<&dyn NetDriver> {
+        Some(self)
+    }
+
+    fn as_block(&self) -> Option<&dyn BlockDriver> {
+        None
+    }
+}
+
+impl NetDriver for TunInterface {
+    fn get_mac(&self) -> EthernetAddress {
+        self.iface.lock().ethernet_addr()
+    }
+
+    fn get_ifname(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_ip_addresses(&self) -> Vec<IpCidr> {
+        Vec::from(self.iface.lock().ip_addrs())
+    }
+
+    fn ipv4_address(&self) -> Option<Ipv4Address> {
+        self.iface.lock().ipv4_address()
+    }
+
+    fn set_ip_addresses(&self, addrs: Vec<IpCidr>) -> Result<(), SysError> {
+        self.iface
+            .lock()
+            .update_ip_addrs(|ip_addrs| *ip_addrs = ManagedSlice::Owned(addrs));
+        Ok(())
+    }
+
+    fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+
+    fn get_mtu(&self) -> usize {
+        self.iface.lock().device().get_ref().mtu - ETHERNET_HEADER_LEN
+    }
+
+    fn set_mtu(&self, mtu: usize) -> bool {
+        if mtu < MIN_MTU || mtu > MAX_MTU {
+            return false;
+        }
+        self.iface.lock().device_mut().get_mut().mtu = mtu + ETHERNET_HEADER_LEN;
+        true
+    }
+
+    fn add_route(&self, cidr: IpCidr, gateway: IpAddress) -> Result<(), SysError> {
+        // the peer of a TUN interface takes every packet
+        let via_router = match cidr {
+            IpCidr::Ipv4(_) if self.is_tun() => IpAddress::Ipv4(PEER_IPV4),
+            IpCidr::Ipv6(_) if self.is_tun() => IpAddress::Ipv6(PEER_IPV6),
+            _ => gateway,
+        };
+        let route = Route {
+            via_router,
+            preferred_until: None,
+            expires_at: None,
+        };
+        self.iface.lock().routes_mut().update(|routes| {
+            let _ = routes.insert(cidr, route);
+        });
+        Ok(())
+    }
+
+    fn del_route(&self, cidr: IpCidr) -> Result<(), SysError> {
+        self.iface.lock().routes_mut().update(|routes| {
+            routes.remove(&cidr);
+        });
+        Ok(())
+    }
+
+    fn is_point_to_point(&self) -> bool {
+        self.is_tun()
+    }
+
+    fn poll(&self) {
+        if !self.is_up() {
+            return;
+        }
+        let timestamp = Instant::from_millis(crate::trap::uptime_msec() as i64);
+        let mut sockets = SOCKETS.lock();
+        let mut iface = self.iface.lock();
+        for _ in 0..MAX_POLLS {
+            match iface.poll(&mut sockets, timestamp) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => {
+                    debug!("poll got err {}", err);
+                }
+            }
+        }
+        wake_sockets(&mut sockets);
+        SOCKET_ACTIVITY.notify_all();
+    }
+
+    fn send(&self, data: &[u8]) -> Option<usize> {
+        if !self.is_up() {
+            return None;
+        }
+        let token = self.iface.lock().device_mut().transmit()?;
+        token
+            .consume(Instant::from_millis(0), data.len(), |buffer| {
+                buffer.copy_from_slice(&data);
+                Ok(())
+            })
+            .ok()?;
+        Some(data.len())
+    }
+
+    fn get_arp(&self, ip: IpAddress) -> Option<EthernetAddress> {
+        let iface = self.iface.lock();
+        let cache = iface.neighbor_cache();
+        cache.lookup_pure(&ip, Instant::from_millis(0))
+    }
+}
+
+/// The interface of TUNSETIFF named `name` of `kind`, which is created down if there is none.
+/// `%d` in `name` is replaced by the first number not in the name of an interface.
+/// `None` if the name is taken by an interface of another kind.
+pub fn open(name: &str, kind: u16) -> Option<Arc<TunInterface>> {
+    let mut tuns = TUN_INTERFACES.write();
+    let mut ifaces = NET_DRIVERS.write();
+    let taken = |name: &str| ifaces.iter().any(|iface| iface.get_ifname() == name);
+    let name = if name.contains("%d") {
+        (0..)
+            .map(|i: usize| name.replace("%d", &i.to_string()))
+            .find(|name| !taken(name))
+            .unwrap()
+    } else {
+        String::from(name)
+    };
+    if let Some(tun) = tuns.iter().find(|tun| tun.name == name) {
+        return Some(tun.clone()).filter(|tun| tun.kind == kind);
+    }
+    if taken(&name) {
+        return None;
+    }
+
+    let ethernet_addr = match kind {
+        IFF_TUN => TUN_MAC,
+        // random, locally administered
+        _ => {
+            let mut mac = [0u8; 6];
+            mac.copy_from_slice(&rand::rand().to_le_bytes()[..6]);
+            mac[0] = (mac[0] & 0xfe) | 0x02;
+            EthernetAddress(mac)
+        }
+    };
+    let queue = Arc::new(Mutex::new(TunQueue::default()));
+    let device = TunDevice {
+        queue: queue.clone(),
+        tun: kind == IFF_TUN,
+        mtu: DEFAULT_MTU + ETHERNET_HEADER_LEN,
+    };
+    let index = ifaces.len();
+    let iface = EthernetInterfaceBuilder::new(Capture::new(device, index))
+        .ethernet_addr(ethernet_addr)
+        .ip_addrs(Vec::<IpCidr>::new())
+        .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+        .routes(Routes::new(BTreeMap::new()))
+        .finalize();
+
+    info!("tun interface {} created", name);
+    let driver = Arc::new(TunInterface {
+        iface: Mutex::new(iface),
+        queue,
+        name,
+        kind,
+        up: AtomicBool::new(false),
+        persistent: AtomicBool::new(false),
+    });
+    DRIVERS.write().push(driver.clone());
+    ifaces.push(driver.clone());
+    tuns.push(driver.clone());
+    Some(driver)
+}
+
+/// Remove the interface named `name` if it is neither attached nor persistent.
+/// The interfaces after it move down by one in `NET_DRIVERS`, so their indexes follow.
+fn remove_unused(name: &str) {
+    let mut tuns = TUN_INTERFACES.write();
+    let unused = tuns.iter().any(|tun| {
+        tun.name == name && !tun.persistent.load(Ordering::Relaxed) && !tun.queue.lock().attached
+    });
+    if !unused {
+        return;
+    }
+    let mut ifaces = NET_DRIVERS.write();
+    let index = match ifaces.iter().position(|iface| iface.get_ifname() == name) {
+        Some(index) => index,
+        None => return,
+    };
+    let driver = ifaces.remove(index);
+    DRIVERS
+        .write()
+        .retain(|d| Arc::as_ptr(d) as *const u8 != Arc::as_ptr(&driver) as *const u8);
+    tuns.retain(|tun| tun.name != name);
+    for tun in tuns.iter() {
+        if let Some(index) = ifaces
+            .iter()
+            .position(|iface| iface.get_ifname() == tun.name)
+        {
+            tun.iface.lock().device_mut().set_index(index);
+        }
+    }
+    remove_iface_routes(index);
+    unbind_iface(index);
+    info!("tun interface {} removed", name);
+}
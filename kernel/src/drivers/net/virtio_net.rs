@@ -1,10 +1,11 @@
 use alloc::format;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use smoltcp::phy::{self, DeviceCapabilities};
 use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, Ipv4Address};
+use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Address};
 use smoltcp::Result;
 use virtio_drivers::{VirtIOHeader, VirtIONet};
 
@@ -26,8 +27,21 @@ impl NetDriver for VirtIONetDriver {
         format!("virtio{:?}", self.0.lock().mac())
     }
 
+    // no ip stack, so no addresses, and address and route changes fail
+    fn get_ip_addresses(&self) -> Vec<IpCidr> {
+        Vec::new()
+    }
+
     fn ipv4_address(&self) -> Option<Ipv4Address> {
-        unimplemented!()
+        None
+    }
+
+    fn is_up(&self) -> bool {
+        true
+    }
+
+    fn get_mtu(&self) -> usize {
+        1500
     }
 
     fn poll(&self) {
@@ -5,9 +5,11 @@ mod random;
 mod serial;
 mod shm;
 mod tty;
+mod tun;
 
 pub use fbdev::*;
 pub use random::*;
 pub use serial::*;
 pub use shm::*;
 pub use tty::*;
+pub use tun::*;
@@ -6,8 +6,22 @@ use rcore_fs::vfs::FsError::NotSupported;
 // try to create directory under /dev
 // do not have enough time to come up with a better way.
 
-#[derive(Default)]
-pub struct ShmINode;
+pub struct ShmINode {
+    inode: usize,
+}
+
+impl ShmINode {
+    /// An empty directory with inode number `inode`, to mount a filesystem on
+    pub fn new(inode: usize) -> Self {
+        ShmINode { inode }
+    }
+}
+
+impl Default for ShmINode {
+    fn default() -> Self {
+        Self::new(2)
+    }
+}
 
 impl INode for ShmINode {
     /// Read bytes at `offset` into `buf`, return the number of bytes read.
@@ -33,7 +47,7 @@ impl INode for ShmINode {
     fn metadata(&self) -> Result<Metadata> {
         Ok(Metadata {
             dev: 1,
-            inode: 2,
+            inode: self.inode,
             size: 0,
             blk_size: 0,
             blocks: 0,
@@ -0,0 +1,269 @@
+//! `/dev/net/tun`, the files of TUN and TAP interfaces
+//!
+//! Each open of `/dev/net/tun` gets a `TunINode` of its own,
+//! which TUNSETIFF attaches to an interface. Reads take the frames sent by it,
+//! and writes give it frames, as IP packets for TUN and ethernet frames for TAP.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::min;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use rcore_fs::vfs::*;
+use smoltcp::wire::{EthernetFrame, EthernetProtocol};
+
+use crate::drivers::net::tun::{
+    self, TunInterface, IFF_NO_PI, IFF_TAP, IFF_TUN, PEER_MAC, TUN_MAC,
+};
+use crate::drivers::net::ETHERNET_HEADER_LEN;
+use crate::drivers::NetDriver;
+use crate::fs::ioctl::{TUNSETIFF, TUNSETPERSIST};
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// Size of interface names with the nul
+const IFNAMSIZ: usize = 16;
+/// Size of `struct tun_pi`, the flags and the ethernet type of a packet
+const TUN_PI_LEN: usize = 4;
+
+/// The head of `struct ifreq` used by TUNSETIFF
+#[repr(C)]
+struct IfReq {
+    name: [u8; IFNAMSIZ],
+    flags: u16,
+}
+
+fn metadata(inode: usize) -> Metadata {
+    Metadata {
+        dev: 1,
+        inode,
+        size: 0,
+        blk_size: 0,
+        blocks: 0,
+        atime: Timespec { sec: 0, nsec: 0 },
+        mtime: Timespec { sec: 0, nsec: 0 },
+        ctime: Timespec { sec: 0, nsec: 0 },
+        type_: FileType::CharDevice,
+        mode: 0o666,
+        nlinks: 1,
+        uid: 0,
+        gid: 0,
+        rdev: make_rdev(10, 200),
+    }
+}
+
+/// `/dev/net/tun` itself, which open replaces by a new `TunINode`
+#[derive(Default)]
+pub struct TunCloneINode;
+
+impl INode for TunCloneINode {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: false,
+            write: false,
+            error: true,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(metadata(1))
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// An open file of `/dev/net/tun`
+#[derive(Default)]
+pub struct TunINode {
+    /// The attached interface and the flags of TUNSETIFF
+    iface: Mutex<Option<(Arc<TunInterface>, u16)>>,
+}
+
+impl TunINode {
+    fn attached(&self) -> Result<(Arc<TunInterface>, u16)> {
+        self.iface.lock().clone().ok_or(FsError::InvalidParam)
+    }
+
+    fn set_iff(&self, ifreq: &mut IfReq) -> Result<usize> {
+        let kind = ifreq.flags & (IFF_TUN | IFF_TAP);
+        if kind != IFF_TUN && kind != IFF_TAP {
+            return Err(FsError::InvalidParam);
+        }
+        let mut attached = self.iface.lock();
+        if attached.is_some() {
+            return Err(FsError::InvalidParam);
+        }
+        let len = ifreq
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .ok_or(FsError::InvalidParam)?;
+        let name = core::str::from_utf8(&ifreq.name[..len]).map_err(|_| FsError::InvalidParam)?;
+        let name = match name {
+            "" if kind == IFF_TUN => "tun%d",
+            "" => "tap%d",
+            name => name,
+        };
+        let iface = tun::open(name, kind).ok_or(FsError::InvalidParam)?;
+        if !iface.attach() {
+            return Err(FsError::Busy);
+        }
+        // the name is given back with the number filled in
+        let name = iface.get_ifname();
+        ifreq.name = [0; IFNAMSIZ];
+        ifreq.name[..name.len()].copy_from_slice(name.as_bytes());
+        *attached = Some((iface, ifreq.flags));
+        Ok(0)
+    }
+}
+
+impl INode for TunINode {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let (iface, flags) = self.attached()?;
+        let frame = iface.pop_frame().ok_or(FsError::Again)?;
+        let mut packet = Vec::with_capacity(TUN_PI_LEN + frame.len());
+        if flags & IFF_NO_PI == 0 {
+            packet.extend_from_slice(&[0, 0]);
+            packet.extend_from_slice(&frame[12..ETHERNET_HEADER_LEN]);
+        }
+        if iface.is_tun() {
+            packet.extend_from_slice(&frame[ETHERNET_HEADER_LEN..]);
+        } else {
+            packet.extend_from_slice(&frame);
+        }
+        // the rest of a packet which does not fit is dropped
+        let len = min(buf.len(), packet.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        let (iface, flags) = self.attached()?;
+        let (protocol, data) = if flags & IFF_NO_PI == 0 {
+            if buf.len() < TUN_PI_LEN {
+                return Err(FsError::InvalidParam);
+            }
+            (
+                Some(u16::from_be_bytes([buf[2], buf[3]])),
+                &buf[TUN_PI_LEN..],
+            )
+        } else {
+            (None, buf)
+        };
+        let frame = if iface.is_tun() {
+            // without `struct tun_pi`, the version of the packet tells its type
+            let protocol = match (protocol, data.first().map(|byte| byte >> 4)) {
+                (Some(protocol), _) => EthernetProtocol::from(protocol),
+                (None, Some(4)) => EthernetProtocol::Ipv4,
+                (None, Some(6)) => EthernetProtocol::Ipv6,
+                _ => return Err(FsError::InvalidParam),
+            };
+            let mut frame = vec![0u8; ETHERNET_HEADER_LEN + data.len()];
+            let mut header = EthernetFrame::new_unchecked(&mut frame[..]);
+            header.set_dst_addr(TUN_MAC);
+            header.set_src_addr(PEER_MAC);
+            header.set_ethertype(protocol);
+            header.payload_mut().copy_from_slice(data);
+            frame
+        } else if data.len() >= ETHERNET_HEADER_LEN {
+            data.to_vec()
+        } else {
+            return Err(FsError::InvalidParam);
+        };
+        if !iface.push_frame(frame) {
+            // the link is down
+            return Err(FsError::DeviceError);
+        }
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(match self.iface.lock().as_ref() {
+            Some((iface, _)) => PollStatus {
+                read: iface.has_frames(),
+                write: true,
+                error: false,
+            },
+            None => PollStatus {
+                read: false,
+                write: false,
+                error: true,
+            },
+        })
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct TunFuture<'a> {
+            tun: &'a TunINode,
+        };
+
+        impl<'a> Future for TunFuture<'a> {
+            type Output = Result<PollStatus>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                if let Some((iface, _)) = self.tun.iface.lock().as_ref() {
+                    iface.register_waker(cx.waker().clone());
+                }
+                let status = self.tun.poll();
+                // writes never block, so only reads are waited for
+                match status {
+                    Ok(PollStatus {
+                        read: false,
+                        error: false,
+                        ..
+                    }) => Poll::Pending,
+                    status => Poll::Ready(status),
+                }
+            }
+        }
+
+        Box::pin(TunFuture { tun: self })
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        match cmd as usize {
+            TUNSETIFF => {
+                let ifreq = unsafe { &mut *(data as *mut IfReq) };
+                self.set_iff(ifreq)
+            }
+            TUNSETPERSIST => {
+                let (iface, _) = self.attached()?;
+                iface.set_persistent(data != 0);
+                Ok(0)
+            }
+            _ => Err(FsError::NotSupported),
+        }
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(metadata(2))
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for TunINode {
+    fn drop(&mut self) {
+        if let Some((iface, _)) = self.iface.lock().take() {
+            iface.detach();
+        }
+    }
+}
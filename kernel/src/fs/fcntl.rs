@@ -15,6 +15,14 @@ const F_LINUX_SPECIFIC_BASE: usize = 1024;
 pub const FD_CLOEXEC: usize = 1;
 pub const F_DUPFD_CLOEXEC: usize = F_LINUX_SPECIFIC_BASE + 6;
 
+pub const F_OFD_GETLK: usize = 36; /* Get open file description locking info.  */
+pub const F_OFD_SETLK: usize = 37; /* Set open file description locking info (non-blocking).  */
+pub const F_OFD_SETLKW: usize = 38; /* Set open file description locking info (blocking).  */
+
+pub const F_RDLCK: i16 = 0; /* Read lock.  */
+pub const F_WRLCK: i16 = 1; /* Write lock.  */
+pub const F_UNLCK: i16 = 2; /* Remove lock.  */
+
 pub const O_NONBLOCK: usize = 0o4000;
 pub const O_APPEND: usize = 0o2000;
 pub const O_CLOEXEC: usize = 0o2000000; /* set close_on_exec */
@@ -1,8 +1,9 @@
 //! File handle for process
 
 use crate::memory::GlobalFrameAlloc;
+use crate::process::rlimit::RLIM_INFINITY;
 use crate::process::{current_thread, INodeForMap};
-use crate::syscall::{MmapProt, SysResult, TimeSpec};
+use crate::syscall::{MmapFlags, MmapProt, SysError, SysResult, TimeSpec};
 use alloc::{string::String, sync::Arc};
 use core::fmt;
 
@@ -11,30 +12,21 @@ use rcore_fs::vfs::{FileType, FsError, INode, MMapArea, Metadata, PollStatus, Re
 use rcore_memory::memory_set::handler::File;
 
 use crate::fs::fcntl::{O_APPEND, O_NONBLOCK};
+use crate::fs::inotify::{self, InotifyMask};
+use crate::fs::lock;
 use crate::sync::SpinLock as Mutex;
 use crate::syscall::SysError::{EAGAIN, ESPIPE};
 use bitflags::_core::cell::Cell;
 use spin::RwLock;
 
-enum Flock {
-    None = 0,
-    Shared = 1,
-    Exclusive = 2,
-}
-
 struct OpenFileDescription {
     offset: u64,
     options: OpenOptions,
-    flock: Flock,
 }
 
 impl OpenFileDescription {
     fn create(options: OpenOptions) -> Arc<RwLock<Self>> {
-        Arc::new(RwLock::new(OpenFileDescription {
-            offset: 0,
-            options,
-            flock: Flock::None,
-        }))
+        Arc::new(RwLock::new(OpenFileDescription { offset: 0, options }))
     }
 }
 
@@ -45,6 +37,8 @@ pub struct FileHandle {
     pub path: String,
     pub pipe: bool, // specify if this is pipe, socket, or FIFO
     pub fd_cloexec: bool,
+    /// Directory and name it was opened at, for inotify
+    pub parent: Option<(Arc<dyn INode>, String)>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -77,6 +71,7 @@ impl FileHandle {
             path,
             pipe,
             fd_cloexec,
+            parent: None,
         };
     }
 
@@ -88,9 +83,14 @@ impl FileHandle {
             path: self.path.clone(),
             pipe: self.pipe,
             fd_cloexec, // this field do not share
+            parent: self.parent.clone(),
         }
     }
 
+    pub fn options(&self) -> OpenOptions {
+        self.description.read().options
+    }
+
     pub fn set_options(&self, arg: usize) {
         let options = &mut self.description.write().options;
         options.nonblock = (arg & O_NONBLOCK) != 0;
@@ -137,23 +137,57 @@ impl FileHandle {
     }
 
     pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let description = self.description.read();
-        let offset = match description.options.append {
-            true => self.inode.metadata()?.size as u64,
-            false => description.offset,
-        } as usize;
-        drop(description);
+        let offset = self.write_offset()? as usize;
         let len = self.write_at(offset, buf)?;
         self.description.write().offset += len as u64;
         Ok(len)
     }
 
+    /// Offset where the next `write` starts
+    fn write_offset(&self) -> Result<u64> {
+        let description = self.description.read();
+        Ok(match description.options.append {
+            true => self.inode.metadata()?.size as u64,
+            false => description.offset,
+        })
+    }
+
+    /// Like `write`, but a regular file does not grow beyond `limit` bytes,
+    /// see RLIMIT_FSIZE. Fail with EFBIG if nothing can be written.
+    pub fn write_limited(&mut self, buf: &[u8], limit: u64) -> SysResult {
+        let len = self.allowed_len(self.write_offset()?, buf.len(), limit)?;
+        Ok(self.write(&buf[..len])?)
+    }
+
+    /// `write_at` with the file size `limit` of `write_limited`
+    pub fn write_at_limited(&self, offset: usize, buf: &[u8], limit: u64) -> SysResult {
+        let len = self.allowed_len(offset as u64, buf.len(), limit)?;
+        Ok(self.write_at(offset, &buf[..len])?)
+    }
+
+    /// Bytes of a `len` bytes write at `offset` within the file size `limit`
+    fn allowed_len(
+        &self,
+        offset: u64,
+        len: usize,
+        limit: u64,
+    ) -> core::result::Result<usize, SysError> {
+        if len == 0 || limit == RLIM_INFINITY || self.inode.metadata()?.type_ != FileType::File {
+            return Ok(len);
+        }
+        if offset >= limit {
+            return Err(SysError::EFBIG);
+        }
+        Ok((limit - offset).min(len as u64) as usize)
+    }
+
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
         if !self.description.read().options.write {
             return Err(FsError::InvalidParam); // TODO: => EBADF
         }
         let len = self.inode.write_at(offset, buf)?;
         TimeSpec::update(&self.inode);
+        self.notify(InotifyMask::MODIFY);
         Ok(len)
     }
 
@@ -172,6 +206,7 @@ impl FileHandle {
             return Err(FsError::InvalidParam); // TODO: => EBADF
         }
         self.inode.resize(len as usize)?;
+        self.notify(InotifyMask::MODIFY);
         Ok(())
     }
 
@@ -230,21 +265,28 @@ impl FileHandle {
         match self.inode.metadata()?.type_ {
             FileType::File => {
                 let prot = MmapProt::from_bits_truncate(area.prot);
+                let mut attr = prot.to_attr();
+                if MmapFlags::from_bits_truncate(area.flags).contains(MmapFlags::SHARED) {
+                    attr = attr.shared();
+                }
                 let thread = current_thread().unwrap();
-                thread.vm.lock().push(
-                    area.start_vaddr,
-                    area.end_vaddr,
-                    prot.to_attr(),
-                    File {
-                        file: INodeForMap(self.inode.clone()),
-                        mem_start: area.start_vaddr,
-                        file_start: area.offset,
-                        file_end: area.offset + area.end_vaddr - area.start_vaddr,
-                        allocator: GlobalFrameAlloc,
-                    },
-                    "mmap_file",
-                );
-                Ok(())
+                thread
+                    .vm
+                    .lock()
+                    .push(
+                        area.start_vaddr,
+                        area.end_vaddr,
+                        attr,
+                        File {
+                            file: INodeForMap(self.inode.clone()),
+                            mem_start: area.start_vaddr,
+                            file_start: area.offset,
+                            file_end: area.offset + area.end_vaddr - area.start_vaddr,
+                            allocator: GlobalFrameAlloc,
+                        },
+                        "mmap_file",
+                    )
+                    .map_err(|_| FsError::NoDeviceSpace)
             }
             FileType::CharDevice => self.inode.mmap(area),
             _ => Err(FsError::NotSupported),
@@ -254,6 +296,38 @@ impl FileHandle {
     pub fn inode(&self) -> Arc<dyn INode> {
         self.inode.clone()
     }
+
+    /// Identity of the open file description, shared by duplicated handles
+    pub fn description_id(&self) -> usize {
+        Arc::as_ptr(&self.description) as *const u8 as usize
+    }
+
+    /// Report `mask` to the watches on the file and on the directory it was opened at
+    pub fn notify(&self, mask: InotifyMask) {
+        if !inotify::watching() {
+            return;
+        }
+        let mask = mask | inotify::type_mask(&self.inode);
+        inotify::notify_inode(&self.inode, mask, 0);
+        if let Some((dir, name)) = &self.parent {
+            inotify::notify_entry(dir, name, mask, 0);
+        }
+    }
+}
+
+/// The file is closed with its last handle
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        if self.pipe || Arc::strong_count(&self.description) > 1 {
+            return;
+        }
+        lock::release_file(&self.inode, self.description_id());
+        let mask = match self.description.read().options.write {
+            true => InotifyMask::CLOSE_WRITE,
+            false => InotifyMask::CLOSE_NOWRITE,
+        };
+        self.notify(mask);
+    }
 }
 
 impl fmt::Debug for FileHandle {
@@ -12,7 +12,11 @@ use rcore_fs::vfs::{MMapArea, PollStatus};
 #[derive(Clone)]
 pub enum FileLike {
     File(FileHandle),
-    Socket(Box<dyn Socket>),
+    Socket {
+        socket: Box<dyn Socket>,
+        /// FD_CLOEXEC of the fd, while the rest of the socket is shared by its dups
+        fd_cloexec: bool,
+    },
     EpollInstance(EpollInstance),
 }
 
@@ -21,15 +25,27 @@ impl FileLike {
         use FileLike::*;
         match self {
             File(file) => File(file.dup(fd_cloexec)),
-            Socket(s) => Socket(s.clone()),
+            Socket { socket, .. } => Socket {
+                socket: socket.clone(),
+                fd_cloexec,
+            },
             EpollInstance(e) => EpollInstance(e.clone()),
         }
     }
 
+    /// Whether the fd is closed on exec
+    pub fn fd_cloexec(&self) -> bool {
+        match self {
+            FileLike::File(file) => file.fd_cloexec,
+            FileLike::Socket { fd_cloexec, .. } => *fd_cloexec,
+            FileLike::EpollInstance(_) => false,
+        }
+    }
+
     pub async fn read(&mut self, buf: &mut [u8]) -> SysResult {
         let len = match self {
             FileLike::File(file) => file.read(buf).await?,
-            FileLike::Socket(socket) => socket.read(buf).0?,
+            FileLike::Socket { socket, .. } => socket.async_read(buf, false).await.0?,
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
@@ -39,17 +55,24 @@ impl FileLike {
     pub fn write(&mut self, buf: &[u8]) -> SysResult {
         let len = match self {
             FileLike::File(file) => file.write(buf)?,
-            FileLike::Socket(socket) => socket.write(buf, None)?,
+            FileLike::Socket { socket, .. } => socket.write(buf, None)?,
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
         };
         Ok(len)
     }
+    /// Like `write`, but regular files do not grow beyond `limit` bytes, see RLIMIT_FSIZE
+    pub fn write_limited(&mut self, buf: &[u8], limit: u64) -> SysResult {
+        match self {
+            FileLike::File(file) => file.write_limited(buf, limit),
+            _ => self.write(buf),
+        }
+    }
     pub fn ioctl(&mut self, request: usize, arg1: usize, arg2: usize, arg3: usize) -> SysResult {
         match self {
             FileLike::File(file) => file.io_control(request as u32, arg1).map_err(Into::into),
-            FileLike::Socket(socket) => socket.ioctl(request, arg1, arg2, arg3),
+            FileLike::Socket { socket, .. } => socket.ioctl(request, arg1, arg2, arg3),
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
@@ -65,7 +88,7 @@ impl FileLike {
     pub fn poll(&self) -> Result<PollStatus, SysError> {
         let status = match self {
             FileLike::File(file) => file.poll()?,
-            FileLike::Socket(socket) => {
+            FileLike::Socket { socket, .. } => {
                 let (read, write, error) = socket.poll();
                 PollStatus { read, write, error }
             }
@@ -78,7 +101,7 @@ impl FileLike {
     pub async fn async_poll(&self) -> Result<PollStatus, SysError> {
         let status = match self {
             FileLike::File(file) => file.async_poll().await?,
-            FileLike::Socket(socket) => {
+            FileLike::Socket { socket, .. } => {
                 let (read, write, error) = socket.poll();
                 PollStatus { read, write, error }
             }
@@ -94,7 +117,7 @@ impl fmt::Debug for FileLike {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             FileLike::File(file) => write!(f, "File({:?})", file),
-            FileLike::Socket(socket) => write!(f, "Socket({:?})", socket),
+            FileLike::Socket { socket, .. } => write!(f, "Socket({:?})", socket),
             FileLike::EpollInstance(_) => write!(f, "EpollInstance()"),
         }
     }
@@ -0,0 +1,65 @@
+//! Read-only text files generated by the kernel, like the listings of `/proc/sysvipc`
+
+use alloc::string::String;
+use core::any::Any;
+
+use rcore_fs::vfs::*;
+
+/// A read-only text file whose content is generated on each read
+pub struct GeneratedINode {
+    generate: fn() -> String,
+}
+
+impl GeneratedINode {
+    pub fn new(generate: fn() -> String) -> Self {
+        GeneratedINode { generate }
+    }
+}
+
+impl INode for GeneratedINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let content = (self.generate)();
+        let content = content.as_bytes();
+        if offset >= content.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: false,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 1,
+            inode: 1,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::File,
+            mode: 0o444,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
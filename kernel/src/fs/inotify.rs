@@ -0,0 +1,490 @@
+//! File change notifications, see `inotify(7)`
+//!
+//! Watches are kept in a global table by the identity of the watched inode.
+//! The syscall layer reports changes with `notify_inode` and `notify_entry`,
+//! which queue events on the instances watching the inode or its directory.
+//! An instance is an inode read like a pipe, so it can be polled.
+
+use crate::sync::{Event, EventBus, SpinNoIrqLock as Mutex};
+use crate::syscall::{SysError, UserOutPtr};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use bitflags::*;
+use core::any::Any;
+use core::future::Future;
+use core::mem::size_of;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
+use rcore_fs::vfs::*;
+use spin::RwLock;
+
+use super::ioctl::FIONREAD;
+
+bitflags! {
+    pub struct InotifyMask: u32 {
+        /// File was accessed
+        const ACCESS = 0x1;
+        /// File was modified
+        const MODIFY = 0x2;
+        /// Metadata changed
+        const ATTRIB = 0x4;
+        /// Writable file was closed
+        const CLOSE_WRITE = 0x8;
+        /// Unwritable file was closed
+        const CLOSE_NOWRITE = 0x10;
+        /// File was opened
+        const OPEN = 0x20;
+        /// File was moved from the watched directory
+        const MOVED_FROM = 0x40;
+        /// File was moved to the watched directory
+        const MOVED_TO = 0x80;
+        /// File was created in the watched directory
+        const CREATE = 0x100;
+        /// File was deleted from the watched directory
+        const DELETE = 0x200;
+        /// The watched inode was deleted
+        const DELETE_SELF = 0x400;
+        /// The watched inode was moved
+        const MOVE_SELF = 0x800;
+        const ALL_EVENTS = 0xfff;
+
+        /// Event queue overflowed
+        const Q_OVERFLOW = 0x4000;
+        /// The watch was removed
+        const IGNORED = 0x8000;
+
+        /// Only watch the path if it is a directory
+        const ONLYDIR = 0x0100_0000;
+        /// Do not follow a symbolic link
+        const DONT_FOLLOW = 0x0200_0000;
+        /// Exclude events on unlinked objects
+        const EXCL_UNLINK = 0x0400_0000;
+        /// Fail if the inode is already watched
+        const MASK_CREATE = 0x1000_0000;
+        /// Add to the mask of an existing watch
+        const MASK_ADD = 0x2000_0000;
+        /// The subject of the event is a directory
+        const ISDIR = 0x4000_0000;
+        /// Only report one event, then remove the watch
+        const ONESHOT = 0x8000_0000;
+    }
+}
+
+/// Max events queued on an instance, see `/proc/sys/fs/inotify/max_queued_events`
+const MAX_QUEUED_EVENTS: usize = 16384;
+
+/// Identity of an inode, device and inode number
+type InodeKey = (usize, usize);
+
+// struct inotify_event
+#[repr(C)]
+struct InotifyEventHeader {
+    wd: i32,
+    mask: u32,
+    cookie: u32,
+    len: u32,
+}
+
+#[derive(PartialEq)]
+struct InotifyEvent {
+    wd: i32,
+    mask: InotifyMask,
+    cookie: u32,
+    name: String,
+}
+
+impl InotifyEvent {
+    /// Length of the name with a terminating null, padded to a whole header
+    fn name_len(&self) -> usize {
+        if self.name.is_empty() {
+            return 0;
+        }
+        let align = size_of::<InotifyEventHeader>();
+        (self.name.len() + 1 + align - 1) / align * align
+    }
+
+    fn len(&self) -> usize {
+        size_of::<InotifyEventHeader>() + self.name_len()
+    }
+
+    fn write_to(&self, buf: &mut [u8]) {
+        let header = InotifyEventHeader {
+            wd: self.wd,
+            mask: self.mask.bits(),
+            cookie: self.cookie,
+            len: self.name_len() as u32,
+        };
+        let header_len = size_of::<InotifyEventHeader>();
+        let header =
+            unsafe { core::slice::from_raw_parts(&header as *const _ as *const u8, header_len) };
+        buf[..header_len].copy_from_slice(header);
+        let name = &mut buf[header_len..self.len()];
+        for b in name.iter_mut() {
+            *b = 0;
+        }
+        name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+    }
+}
+
+struct InotifyInner {
+    events: VecDeque<InotifyEvent>,
+    /// Watched inodes by watch descriptor
+    watches: BTreeMap<i32, InodeKey>,
+    next_wd: i32,
+    eventbus: EventBus,
+}
+
+impl InotifyInner {
+    /// Queue an event, merging it into the last one if they are the same
+    fn queue(&mut self, wd: i32, mask: InotifyMask, cookie: u32, name: &str) {
+        let event = InotifyEvent {
+            wd,
+            mask,
+            cookie,
+            name: name.to_string(),
+        };
+        if self.events.back() == Some(&event) {
+            return;
+        }
+        if self.events.len() >= MAX_QUEUED_EVENTS {
+            let overflowed = self
+                .events
+                .back()
+                .map_or(false, |last| last.mask == InotifyMask::Q_OVERFLOW);
+            if !overflowed {
+                self.events.push_back(InotifyEvent {
+                    wd: -1,
+                    mask: InotifyMask::Q_OVERFLOW,
+                    cookie: 0,
+                    name: String::new(),
+                });
+            }
+        } else {
+            self.events.push_back(event);
+        }
+        self.eventbus.set(Event::READABLE);
+    }
+
+    fn bytes(&self) -> usize {
+        self.events.iter().map(|event| event.len()).sum()
+    }
+}
+
+/// A watch of an instance on an inode
+struct Watch {
+    instance: Weak<Mutex<InotifyInner>>,
+    wd: i32,
+    mask: InotifyMask,
+    /// The watched inode is kept alive with the watch
+    _inode: Arc<dyn INode>,
+}
+
+lazy_static! {
+    /// Watches by the inode they are on
+    static ref WATCHES: RwLock<BTreeMap<InodeKey, Vec<Watch>>> = RwLock::new(BTreeMap::new());
+}
+
+static NEXT_COOKIE: AtomicU32 = AtomicU32::new(1);
+
+/// The key of `inode` if anything is being watched
+fn watched_key(inode: &Arc<dyn INode>) -> Option<InodeKey> {
+    if !watching() {
+        return None;
+    }
+    let metadata = inode.metadata().ok()?;
+    Some((metadata.dev, metadata.inode))
+}
+
+/// Whether anything is being watched
+pub fn watching() -> bool {
+    !WATCHES.read().is_empty()
+}
+
+/// ISDIR if `inode` is a directory and anything is being watched
+pub fn type_mask(inode: &Arc<dyn INode>) -> InotifyMask {
+    if !watching() {
+        return InotifyMask::empty();
+    }
+    match inode.metadata() {
+        Ok(metadata) if metadata.type_ == FileType::Dir => InotifyMask::ISDIR,
+        _ => InotifyMask::empty(),
+    }
+}
+
+/// A new cookie relating the MOVED_FROM and MOVED_TO events of a rename
+pub fn new_cookie() -> u32 {
+    NEXT_COOKIE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Report `mask` on `inode` itself
+pub fn notify_inode(inode: &Arc<dyn INode>, mask: InotifyMask, cookie: u32) {
+    if let Some(key) = watched_key(inode) {
+        deliver(key, mask, cookie, "");
+    }
+}
+
+/// Report `mask` on the entry `name` of the directory `dir`
+pub fn notify_entry(dir: &Arc<dyn INode>, name: &str, mask: InotifyMask, cookie: u32) {
+    if let Some(key) = watched_key(dir) {
+        deliver(key, mask, cookie, name);
+    }
+}
+
+/// Report that `inode` was unlinked. If it has no more links,
+/// its watches get DELETE_SELF and are removed.
+pub fn notify_unlinked(inode: &Arc<dyn INode>) {
+    let key = match watched_key(inode) {
+        Some(key) => key,
+        None => return,
+    };
+    match inode.metadata() {
+        Ok(metadata) if metadata.nlinks == 0 => {}
+        _ => {
+            deliver(key, InotifyMask::ATTRIB, 0, "");
+            return;
+        }
+    }
+    deliver(key, InotifyMask::DELETE_SELF, 0, "");
+    if let Some(watches) = WATCHES.write().remove(&key) {
+        for watch in watches {
+            if let Some(instance) = watch.instance.upgrade() {
+                let mut inner = instance.lock();
+                inner.watches.remove(&watch.wd);
+                inner.queue(watch.wd, InotifyMask::IGNORED, 0, "");
+            }
+        }
+    }
+}
+
+/// Queue an event on the watches of `key` that want it
+fn deliver(key: InodeKey, mask: InotifyMask, cookie: u32, name: &str) {
+    let mut table = WATCHES.write();
+    let watches = match table.get_mut(&key) {
+        Some(watches) => watches,
+        None => return,
+    };
+    // ISDIR comes with any event
+    let isdir = mask & InotifyMask::ISDIR;
+    watches.retain(|watch| {
+        let instance = match watch.instance.upgrade() {
+            Some(instance) => instance,
+            None => return false,
+        };
+        let events = mask & watch.mask & InotifyMask::ALL_EVENTS;
+        if events.is_empty() {
+            return true;
+        }
+        let mut inner = instance.lock();
+        inner.queue(watch.wd, events | isdir, cookie, name);
+        if watch.mask.contains(InotifyMask::ONESHOT) {
+            inner.watches.remove(&watch.wd);
+            inner.queue(watch.wd, InotifyMask::IGNORED, 0, "");
+            return false;
+        }
+        true
+    });
+    if watches.is_empty() {
+        table.remove(&key);
+    }
+}
+
+/// An inotify instance
+pub struct InotifyINode {
+    inner: Arc<Mutex<InotifyInner>>,
+}
+
+impl InotifyINode {
+    pub fn new() -> Self {
+        InotifyINode {
+            inner: Arc::new(Mutex::new(InotifyInner {
+                events: VecDeque::new(),
+                watches: BTreeMap::new(),
+                next_wd: 1,
+                eventbus: EventBus::default(),
+            })),
+        }
+    }
+
+    /// Watch `inode` for the events in `mask`, return the watch descriptor.
+    /// An inode is watched once by an instance, watching it again changes the mask.
+    pub fn add_watch(&self, inode: Arc<dyn INode>, mask: InotifyMask) -> Result<i32, SysError> {
+        let metadata = inode.metadata()?;
+        let key = (metadata.dev, metadata.inode);
+        let instance = Arc::downgrade(&self.inner);
+        let mut table = WATCHES.write();
+        let watches = table.entry(key).or_insert_with(Vec::new);
+        if let Some(watch) = watches
+            .iter_mut()
+            .find(|watch| watch.instance.ptr_eq(&instance))
+        {
+            if mask.contains(InotifyMask::MASK_CREATE) {
+                return Err(SysError::EEXIST);
+            }
+            if mask.contains(InotifyMask::MASK_ADD) {
+                watch.mask |= mask;
+            } else {
+                watch.mask = mask;
+            }
+            return Ok(watch.wd);
+        }
+        let mut inner = self.inner.lock();
+        let wd = inner.next_wd;
+        inner.next_wd += 1;
+        inner.watches.insert(wd, key);
+        watches.push(Watch {
+            instance,
+            wd,
+            mask,
+            _inode: inode,
+        });
+        Ok(wd)
+    }
+
+    /// Remove the watch `wd`, queueing IGNORED for it
+    pub fn rm_watch(&self, wd: i32) -> Result<(), SysError> {
+        let key = self
+            .inner
+            .lock()
+            .watches
+            .remove(&wd)
+            .ok_or(SysError::EINVAL)?;
+        let instance = Arc::downgrade(&self.inner);
+        let mut table = WATCHES.write();
+        if let Some(watches) = table.get_mut(&key) {
+            watches.retain(|watch| !watch.instance.ptr_eq(&instance));
+            if watches.is_empty() {
+                table.remove(&key);
+            }
+        }
+        self.inner.lock().queue(wd, InotifyMask::IGNORED, 0, "");
+        Ok(())
+    }
+}
+
+impl Drop for InotifyINode {
+    fn drop(&mut self) {
+        let keys: Vec<InodeKey> = self.inner.lock().watches.values().cloned().collect();
+        let instance = Arc::downgrade(&self.inner);
+        let mut table = WATCHES.write();
+        for key in keys {
+            if let Some(watches) = table.get_mut(&key) {
+                watches.retain(|watch| !watch.instance.ptr_eq(&instance));
+                if watches.is_empty() {
+                    table.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+impl INode for InotifyINode {
+    /// Read whole events, EINVAL if the first one does not fit
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut inner = self.inner.lock();
+        if inner.events.is_empty() {
+            return Err(FsError::Again);
+        }
+        let mut len = 0;
+        while let Some(event) = inner.events.front() {
+            let event_len = event.len();
+            if len + event_len > buf.len() {
+                break;
+            }
+            event.write_to(&mut buf[len..]);
+            len += event_len;
+            inner.events.pop_front();
+        }
+        if len == 0 {
+            return Err(FsError::InvalidParam);
+        }
+        if inner.events.is_empty() {
+            inner.eventbus.clear(Event::READABLE);
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: !self.inner.lock().events.is_empty(),
+            write: false,
+            error: false,
+        })
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct InotifyFuture<'a> {
+            inotify: &'a InotifyINode,
+        };
+
+        impl<'a> Future for InotifyFuture<'a> {
+            type Output = Result<PollStatus>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                let mut inner = self.inotify.inner.lock();
+                if !inner.events.is_empty() {
+                    drop(inner);
+                    return Poll::Ready(self.inotify.poll());
+                }
+                let waker = cx.waker().clone();
+                inner.eventbus.subscribe(Box::new({
+                    move |_| {
+                        waker.wake_by_ref();
+                        true
+                    }
+                }));
+                Poll::Pending
+            }
+        }
+
+        Box::pin(InotifyFuture { inotify: self })
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        match cmd as usize {
+            FIONREAD => {
+                let bytes = self.inner.lock().bytes() as i32;
+                UserOutPtr::<i32>::from(data)
+                    .write(bytes)
+                    .map_err(|_| FsError::InvalidParam)?;
+                Ok(0)
+            }
+            _ => Err(FsError::NotSupported),
+        }
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: 0,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::File,
+            mode: 0o600,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -43,6 +43,11 @@ pub const FIOCLEX: usize = 0x5451;
 #[cfg(target_arch = "mips")]
 pub const FIOCLEX: usize = 0x6601;
 
+#[cfg(not(target_arch = "mips"))]
+pub const FIONREAD: usize = 0x541B;
+#[cfg(target_arch = "mips")]
+pub const FIONREAD: usize = 0x467F;
+
 // rustc using pipe and ioctl pipe file with this request id
 // for non-blocking/blocking IO control setting
 #[cfg(not(target_arch = "mips"))]
@@ -50,6 +55,18 @@ pub const FIONBIO: usize = 0x5421;
 #[cfg(target_arch = "mips")]
 pub const FIONBIO: usize = 0x667E;
 
+// _IOW('T', 202, int), sets the interface of a /dev/net/tun file
+#[cfg(not(target_arch = "mips"))]
+pub const TUNSETIFF: usize = 0x4004_54CA;
+#[cfg(target_arch = "mips")]
+pub const TUNSETIFF: usize = 0x8004_54CA;
+
+// _IOW('T', 203, int), keeps the interface of a /dev/net/tun file when it is closed
+#[cfg(not(target_arch = "mips"))]
+pub const TUNSETPERSIST: usize = 0x4004_54CB;
+#[cfg(target_arch = "mips")]
+pub const TUNSETPERSIST: usize = 0x8004_54CB;
+
 // ref: https://www.man7.org/linux/man-pages/man3/termios.3.html
 // c_lflag constants
 bitflags! {
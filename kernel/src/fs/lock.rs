@@ -0,0 +1,378 @@
+//! Advisory file locks, see `fcntl(2)` and `flock(2)`
+//!
+//! Locks are kept in a global table by the identity of the locked inode.
+//! POSIX record locks cover byte ranges and are owned by a process, or by an
+//! open file description for OFD locks. BSD flock locks cover the whole file
+//! and are owned by an open file description. The two kinds do not conflict.
+
+use crate::process::Thread;
+use crate::sync::{EventBus, SpinNoIrqLock as Mutex};
+use crate::syscall::SysError;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use rcore_fs::vfs::INode;
+
+/// Identity of an inode, device and inode number
+type InodeKey = (usize, usize);
+
+/// Max length of a chain of processes waiting for each other
+const MAX_DEADLK_ITERATIONS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockOwner {
+    /// A process by pid, for traditional POSIX locks
+    Process(usize),
+    /// An open file description, for OFD and flock locks
+    File(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockType {
+    Read,
+    Write,
+}
+
+/// A POSIX lock on bytes `start..end`
+#[derive(Debug, Clone, Copy)]
+pub struct RecordLock {
+    pub owner: LockOwner,
+    pub type_: LockType,
+    pub start: u64,
+    /// Exclusive, `u64::max_value()` for locks to the end of file and beyond
+    pub end: u64,
+}
+
+impl RecordLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+
+    fn conflicts(&self, other: &RecordLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.type_ == LockType::Write || other.type_ == LockType::Write)
+    }
+}
+
+/// A BSD lock on a whole file
+#[derive(Debug, Clone, Copy)]
+struct FlockLock {
+    owner: LockOwner,
+    type_: LockType,
+}
+
+/// Locks of an inode
+#[derive(Default)]
+struct InodeLocks {
+    posix: Vec<RecordLock>,
+    flock: Vec<FlockLock>,
+    /// Waiters blocked on a lock of the inode
+    waiters: Vec<Waker>,
+}
+
+impl InodeLocks {
+    /// The first POSIX lock conflicting with `lock`
+    fn posix_conflict(&self, lock: &RecordLock) -> Option<RecordLock> {
+        self.posix.iter().find(|l| l.conflicts(lock)).cloned()
+    }
+
+    /// The owner of a flock lock conflicting with `lock`
+    fn flock_conflict(&self, lock: &FlockLock) -> Option<LockOwner> {
+        self.flock
+            .iter()
+            .find(|l| {
+                l.owner != lock.owner
+                    && (l.type_ == LockType::Write || lock.type_ == LockType::Write)
+            })
+            .map(|l| l.owner)
+    }
+
+    /// Set the locks of `owner` on `start..end` to `type_`, or remove them if it is `None`.
+    /// Locks of the owner are split and merged as needed.
+    fn set_range(&mut self, owner: LockOwner, type_: Option<LockType>, start: u64, end: u64) {
+        let mut locks = Vec::new();
+        for l in self.posix.drain(..) {
+            if l.owner != owner || !l.overlaps(start, end) {
+                locks.push(l);
+                continue;
+            }
+            // keep the parts outside the range
+            if l.start < start {
+                locks.push(RecordLock { end: start, ..l });
+            }
+            if l.end > end {
+                locks.push(RecordLock { start: end, ..l });
+            }
+        }
+        if let Some(type_) = type_ {
+            let mut new = RecordLock {
+                owner,
+                type_,
+                start,
+                end,
+            };
+            // merge adjacent locks of the same type
+            locks.retain(|l| {
+                let adjacent = l.owner == owner
+                    && l.type_ == type_
+                    && (l.end == new.start || l.start == new.end);
+                if adjacent {
+                    new.start = new.start.min(l.start);
+                    new.end = new.end.max(l.end);
+                }
+                !adjacent
+            });
+            locks.push(new);
+        }
+        self.posix = locks;
+        self.wake_all();
+    }
+
+    fn remove_flock(&mut self, owner: LockOwner) {
+        self.flock.retain(|l| l.owner != owner);
+        self.wake_all();
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.posix.is_empty() && self.flock.is_empty() && self.waiters.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct LockTable {
+    inodes: BTreeMap<InodeKey, InodeLocks>,
+    /// Processes blocked on POSIX locks, with the process holding the lock
+    blocked: BTreeMap<usize, usize>,
+}
+
+impl LockTable {
+    /// Whether process `pid` waiting for `holder` would wait for itself
+    fn would_deadlock(&self, pid: usize, holder: usize) -> bool {
+        let mut holder = holder;
+        for _ in 0..MAX_DEADLK_ITERATIONS {
+            if holder == pid {
+                return true;
+            }
+            match self.blocked.get(&holder) {
+                Some(&next) => holder = next,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    fn remove_if_empty(&mut self, key: InodeKey) {
+        if self
+            .inodes
+            .get(&key)
+            .map_or(false, |locks| locks.is_empty())
+        {
+            self.inodes.remove(&key);
+        }
+    }
+}
+
+lazy_static! {
+    static ref LOCKS: Mutex<LockTable> = Mutex::new(LockTable::default());
+}
+
+fn inode_key(inode: &Arc<dyn INode>) -> Result<InodeKey, SysError> {
+    let metadata = inode.metadata()?;
+    Ok((metadata.dev, metadata.inode))
+}
+
+/// The key of `inode` if any file is locked
+fn locked_key(inode: &Arc<dyn INode>) -> Option<InodeKey> {
+    if LOCKS.lock().inodes.is_empty() {
+        return None;
+    }
+    inode_key(inode).ok()
+}
+
+/// The first POSIX lock on `inode` that would block `lock`, for F_GETLK
+pub fn get_posix(
+    inode: &Arc<dyn INode>,
+    lock: &RecordLock,
+) -> Result<Option<RecordLock>, SysError> {
+    let key = inode_key(inode)?;
+    let table = LOCKS.lock();
+    Ok(table
+        .inodes
+        .get(&key)
+        .and_then(|locks| locks.posix_conflict(lock)))
+}
+
+/// Remove the POSIX locks of `owner` on bytes `start..end` of `inode`
+pub fn unlock_posix(inode: &Arc<dyn INode>, owner: LockOwner, start: u64, end: u64) {
+    if let Some(key) = locked_key(inode) {
+        let mut table = LOCKS.lock();
+        if let Some(locks) = table.inodes.get_mut(&key) {
+            locks.set_range(owner, None, start, end);
+        }
+        table.remove_if_empty(key);
+    }
+}
+
+/// Set the POSIX lock `lock` on `inode`. If it conflicts with another one,
+/// fail with EAGAIN unless `wait`, or EDEADLK if waiting would deadlock.
+pub async fn lock_posix(
+    thread: &Arc<Thread>,
+    inode: &Arc<dyn INode>,
+    lock: RecordLock,
+    wait: bool,
+) -> Result<(), SysError> {
+    let key = inode_key(inode)?;
+    LockFuture {
+        thread,
+        eventbus: thread.proc.lock().eventbus.clone(),
+        key,
+        request: Request::Posix(lock),
+        wait,
+        blocked: None,
+    }
+    .await
+}
+
+/// Remove the flock lock of `owner` on `inode`
+pub fn unlock_flock(inode: &Arc<dyn INode>, owner: LockOwner) {
+    if let Some(key) = locked_key(inode) {
+        let mut table = LOCKS.lock();
+        if let Some(locks) = table.inodes.get_mut(&key) {
+            locks.remove_flock(owner);
+        }
+        table.remove_if_empty(key);
+    }
+}
+
+/// Set a flock lock of `owner` on `inode`, converting the one it holds.
+/// If it conflicts with another one, fail with EAGAIN unless `wait`.
+pub async fn lock_flock(
+    thread: &Arc<Thread>,
+    inode: &Arc<dyn INode>,
+    owner: LockOwner,
+    type_: LockType,
+    wait: bool,
+) -> Result<(), SysError> {
+    // conversion is not atomic, the old lock is released first
+    unlock_flock(inode, owner);
+    let key = inode_key(inode)?;
+    LockFuture {
+        thread,
+        eventbus: thread.proc.lock().eventbus.clone(),
+        key,
+        request: Request::Flock(FlockLock { owner, type_ }),
+        wait,
+        blocked: None,
+    }
+    .await
+}
+
+/// Release the POSIX locks of process `pid` on `inode`, as it closes a descriptor of it
+pub fn release_process(inode: &Arc<dyn INode>, pid: usize) {
+    unlock_posix(inode, LockOwner::Process(pid), 0, u64::max_value());
+}
+
+/// Release the OFD and flock locks of the open file description `file` on `inode`,
+/// as it is closed
+pub fn release_file(inode: &Arc<dyn INode>, file: usize) {
+    if let Some(key) = locked_key(inode) {
+        let owner = LockOwner::File(file);
+        let mut table = LOCKS.lock();
+        if let Some(locks) = table.inodes.get_mut(&key) {
+            locks.set_range(owner, None, 0, u64::max_value());
+            locks.remove_flock(owner);
+        }
+        table.remove_if_empty(key);
+    }
+}
+
+enum Request {
+    Posix(RecordLock),
+    Flock(FlockLock),
+}
+
+#[must_use = "future does nothing unless polled/`await`-ed"]
+struct LockFuture<'a> {
+    thread: &'a Arc<Thread>,
+    eventbus: Arc<Mutex<EventBus>>,
+    key: InodeKey,
+    request: Request,
+    wait: bool,
+    /// Pid of the process recorded blocked
+    blocked: Option<usize>,
+}
+
+impl Future for LockFuture<'_> {
+    type Output = Result<(), SysError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        {
+            let mut table = LOCKS.lock();
+            if let Some(pid) = this.blocked.take() {
+                table.blocked.remove(&pid);
+            }
+            let locks = table.inodes.entry(this.key).or_default();
+            let holder = match &this.request {
+                Request::Posix(lock) => match locks.posix_conflict(lock) {
+                    Some(conflict) => conflict.owner,
+                    None => {
+                        locks.set_range(lock.owner, Some(lock.type_), lock.start, lock.end);
+                        return Poll::Ready(Ok(()));
+                    }
+                },
+                Request::Flock(lock) => match locks.flock_conflict(lock) {
+                    Some(owner) => owner,
+                    None => {
+                        locks.flock.push(*lock);
+                        return Poll::Ready(Ok(()));
+                    }
+                },
+            };
+            if !this.wait {
+                table.remove_if_empty(this.key);
+                return Poll::Ready(Err(SysError::EAGAIN));
+            }
+            locks.waiters.push(cx.waker().clone());
+            if let (Request::Posix(lock), LockOwner::Process(holder)) = (&this.request, holder) {
+                if let LockOwner::Process(pid) = lock.owner {
+                    if table.would_deadlock(pid, holder) {
+                        return Poll::Ready(Err(SysError::EDEADLK));
+                    }
+                    table.blocked.insert(pid, holder);
+                    this.blocked = Some(pid);
+                }
+            }
+        }
+        if this.thread.has_signal_to_handle() {
+            if let Some(pid) = this.blocked.take() {
+                LOCKS.lock().blocked.remove(&pid);
+            }
+            return Poll::Ready(Err(SysError::EINTR));
+        }
+        let waker = cx.waker().clone();
+        this.eventbus.lock().subscribe(Box::new({
+            move |_| {
+                waker.wake_by_ref();
+                true
+            }
+        }));
+        Poll::Pending
+    }
+}
+
+impl Drop for LockFuture<'_> {
+    fn drop(&mut self) {
+        if let Some(pid) = self.blocked.take() {
+            LOCKS.lock().blocked.remove(&pid);
+        }
+    }
+}
@@ -5,16 +5,19 @@ use rcore_fs_devfs::{
     special::{NullINode, ZeroINode},
     DevFS,
 };
-use rcore_fs_mountfs::MountFS;
 use rcore_fs_ramfs::RamFS;
 use rcore_fs_sfs::{INodeImpl, SimpleFileSystem};
 
 use self::devfs::{Fbdev, RandomINode};
+use self::generated::GeneratedINode;
+use self::sysctl::SysctlINode;
 
-pub use self::devfs::{Serial, ShmINode, TTY};
+pub use self::devfs::{Serial, ShmINode, TunCloneINode, TunINode, TTY};
 pub use self::file::*;
 pub use self::file_like::*;
-pub use self::pipe::Pipe;
+pub use self::mount::{MountNamespace, MountTable};
+pub use self::mqueue::{MqAttr, MqNotify, MqueueINode, MQUEUE_FS};
+pub use self::pipe::{Pipe, PipeBuffer};
 pub use self::pseudo::*;
 use crate::drivers::{BlockDriver, BlockDriverWrapper};
 
@@ -24,9 +27,15 @@ pub mod epoll;
 pub mod fcntl;
 mod file;
 mod file_like;
+mod generated;
+pub mod inotify;
 pub mod ioctl;
+pub mod lock;
+mod mount;
+mod mqueue;
 mod pipe;
 mod pseudo;
+mod sysctl;
 
 // Hard link user programs
 #[cfg(feature = "link_user")]
@@ -44,8 +53,8 @@ _user_img_end:
 ));
 
 lazy_static! {
-    /// The root of file system
-    pub static ref ROOT_INODE: Arc<dyn INode> = {
+    /// The initial mount namespace, with filesystems mounted at boot
+    pub static ref ROOT_MNT_NS: Arc<MountNamespace> = {
         #[cfg(not(feature = "link_user"))]
         let device = {
             let driver = BlockDriverWrapper(
@@ -70,8 +79,8 @@ lazy_static! {
 
         // use SFS as rootfs
         let sfs = SimpleFileSystem::open(device).expect("failed to open SFS");
-        let rootfs = MountFS::new(sfs);
-        let root = rootfs.root_inode();
+        let mut table = MountTable::new(sfs);
+        let root = table.root();
 
         // create DevFS
         let devfs = DevFS::new();
@@ -82,6 +91,8 @@ lazy_static! {
         devfs.add("tty", TTY.clone()).expect("failed to mknod /dev/tty");
         devfs.add("fb0", Arc::new(Fbdev::default())).expect("failed to mknod /dev/fb0");
         devfs.add("shm", Arc::new(ShmINode::default())).expect("failed to mkdir shm");
+        devfs.add("mqueue", Arc::new(ShmINode::new(3))).expect("failed to mkdir mqueue");
+        devfs.add("net", Arc::new(ShmINode::new(4))).expect("failed to mkdir net");
         for (i, serial) in Serial::wrap_all_serial_devices().into_iter().enumerate(){
             devfs.add(&format!("ttyS{}", i), Arc::new(serial)).expect("failed to add a serial");
         }
@@ -91,25 +102,73 @@ lazy_static! {
         devfs.add("rvm", Arc::new(crate::rvm::RvmINode::new())).expect("failed to mknod /dev/rvm");
 
         // mount DevFS at /dev
-        let dev = root.find(true, "dev").unwrap_or_else(|_| {
-            root.create("dev", FileType::Dir, 0o666).expect("failed to mkdir /dev")
-        });
-        let devfs = dev.mount(devfs).expect("failed to mount DevFS");
+        root.find("dev").or_else(|_| root.create("dev", FileType::Dir, 0o666))
+            .expect("failed to mkdir /dev");
+        table.mount("/dev", devfs).expect("failed to mount DevFS");
 
         // mount RamFS at /dev/shm
-        let shm = devfs.root_inode().find(true, "shm").expect("cannot find shm");
-        let shmfs = RamFS::new();
-        shm.mount(shmfs).expect("failed to mount /dev/shm");
+        table.mount("/dev/shm", RamFS::new()).expect("failed to mount /dev/shm");
 
-        // mount RamFS at /tmp
-        let ramfs = RamFS::new();
-        let tmp = root.find(true, "tmp").unwrap_or_else(|_| {
-            root.create("tmp", FileType::Dir, 0o666).expect("failed to mkdir /tmp")
-        });
-        tmp.mount(ramfs).expect("failed to mount RamFS");
+        // mount the mqueue filesystem at /dev/mqueue
+        table.mount("/dev/mqueue", MQUEUE_FS.clone()).expect("failed to mount /dev/mqueue");
+
+        // mount DevFS at /dev/net, with the clone device of TUN and TAP interfaces
+        let netdev = DevFS::new();
+        netdev.add("tun", Arc::new(TunCloneINode)).expect("failed to mknod /dev/net/tun");
+        table.mount("/dev/net", netdev).expect("failed to mount /dev/net");
 
-        root
+        // mount RamFS at /tmp
+        root.find("tmp").or_else(|_| root.create("tmp", FileType::Dir, 0o666))
+            .expect("failed to mkdir /tmp");
+        table.mount("/tmp", RamFS::new()).expect("failed to mount RamFS");
+
+        // mount RamFS at /proc, with kernel parameters at /proc/sys/kernel and /proc/sys/net/ipv4
+        root.find("proc").or_else(|_| root.create("proc", FileType::Dir, 0o555))
+            .expect("failed to mkdir /proc");
+        table.mount("/proc", RamFS::new()).expect("failed to mount /proc");
+        table
+            .root()
+            .lookup("proc")
+            .and_then(|proc| proc.create("sys", FileType::Dir, 0o555))
+            .and_then(|sys| sys.create("kernel", FileType::Dir, 0o555))
+            .expect("failed to mkdir /proc/sys/kernel");
+        let sysctl = DevFS::new();
+        let core_pattern = SysctlINode::new(&crate::process::coredump::CORE_PATTERN);
+        sysctl.add("core_pattern", Arc::new(core_pattern)).expect("failed to add core_pattern");
+        table.mount("/proc/sys/kernel", sysctl).expect("failed to mount /proc/sys/kernel");
+        table
+            .root()
+            .lookup("proc/sys")
+            .and_then(|sys| sys.create("net", FileType::Dir, 0o555))
+            .and_then(|net| net.create("ipv4", FileType::Dir, 0o555))
+            .expect("failed to mkdir /proc/sys/net/ipv4");
+        let sysctl = DevFS::new();
+        let port_range = SysctlINode::new(&crate::net::PORT_RANGE);
+        sysctl
+            .add("ip_local_port_range", Arc::new(port_range))
+            .expect("failed to add ip_local_port_range");
+        table.mount("/proc/sys/net/ipv4", sysctl).expect("failed to mount /proc/sys/net/ipv4");
+
+        // System V IPC objects at /proc/sysvipc
+        table
+            .root()
+            .lookup("proc")
+            .and_then(|proc| proc.create("sysvipc", FileType::Dir, 0o555))
+            .expect("failed to mkdir /proc/sysvipc");
+        let sysvipc = DevFS::new();
+        let msg = GeneratedINode::new(crate::ipc::MsgQueue::list);
+        sysvipc.add("msg", Arc::new(msg)).expect("failed to add msg");
+        let sem = GeneratedINode::new(crate::ipc::SemArray::list);
+        sysvipc.add("sem", Arc::new(sem)).expect("failed to add sem");
+        let shm = GeneratedINode::new(crate::ipc::ShmSegment::list);
+        sysvipc.add("shm", Arc::new(shm)).expect("failed to add shm");
+        table.mount("/proc/sysvipc", sysvipc).expect("failed to mount /proc/sysvipc");
+
+        Arc::new(MountNamespace::new(table))
     };
+
+    /// The root of file system in the initial mount namespace
+    pub static ref ROOT_INODE: Arc<dyn INode> = ROOT_MNT_NS.root();
 }
 
 pub const FOLLOW_MAX_DEPTH: usize = 3;
@@ -0,0 +1,309 @@
+//! Mount tables of mount namespaces
+//!
+//! A table records which filesystem is mounted at which absolute path,
+//! and builds a `MountFS` tree from them. Namespaces are copied by
+//! building a new tree over the same filesystems, so later mounts in
+//! one namespace are not seen by the other.
+
+use crate::process::namespace::new_ns_id;
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use rcore_fs::vfs::*;
+use rcore_fs_mountfs::{MNode, MountFS};
+use spin::RwLock;
+
+pub struct MountTable {
+    /// Filesystem at "/"
+    root_fs: Arc<dyn FileSystem>,
+    /// Other filesystems with their mount points, parents come first
+    mounts: Vec<(String, Arc<dyn FileSystem>)>,
+    /// Root of the tree
+    root: Arc<MNode>,
+}
+
+impl MountTable {
+    pub fn new(root_fs: Arc<dyn FileSystem>) -> Self {
+        let root = MountFS::new(root_fs.clone()).root_inode();
+        MountTable {
+            root_fs,
+            mounts: Vec::new(),
+            root,
+        }
+    }
+
+    /// Build a new tree with `mounts` on top of `root_fs`
+    fn build(
+        root_fs: Arc<dyn FileSystem>,
+        mounts: Vec<(String, Arc<dyn FileSystem>)>,
+    ) -> Result<Self> {
+        let mut table = Self::new(root_fs);
+        for (path, fs) in mounts {
+            table.mount(&path, fs)?;
+        }
+        Ok(table)
+    }
+
+    /// A table with the same mounts and a tree of its own
+    pub fn copy(&self) -> Result<Self> {
+        Self::build(self.root_fs.clone(), self.mounts.clone())
+    }
+
+    pub fn root(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+
+    /// Find the node at absolute `path`, crossing mount points
+    fn find(&self, path: &str) -> Result<Arc<MNode>> {
+        let mut node = self.root.clone();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            node = node.find(true, name)?;
+        }
+        Ok(node)
+    }
+
+    /// Mount `fs` at the directory `path`
+    pub fn mount(&mut self, path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+        let path = normalize(path)?;
+        if path == "/" {
+            return Err(FsError::Busy);
+        }
+        let node = self.find(&path)?;
+        if node.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        node.mount(fs.clone())?;
+        self.mounts.push((path, fs));
+        Ok(())
+    }
+
+    /// Unmount the filesystem mounted last at `path`
+    pub fn umount(&mut self, path: &str) -> Result<()> {
+        let path = normalize(path)?;
+        let idx = self
+            .mounts
+            .iter()
+            .rposition(|(p, _)| *p == path)
+            .ok_or(FsError::InvalidParam)?;
+        if self.mounts[idx + 1..]
+            .iter()
+            .any(|(p, _)| is_under(p, &path))
+        {
+            return Err(FsError::Busy);
+        }
+        let mut mounts = self.mounts.clone();
+        mounts.remove(idx);
+        *self = Self::build(self.root_fs.clone(), mounts)?;
+        Ok(())
+    }
+
+    /// Make the filesystem at `new_root` the root,
+    /// and move the old root to `put_old` under it
+    pub fn pivot_root(&mut self, new_root: &str, put_old: &str) -> Result<()> {
+        let new_root = normalize(new_root)?;
+        let put_old = normalize(put_old)?;
+        if new_root == "/" || put_old == new_root || !is_under(&put_old, &new_root) {
+            return Err(FsError::InvalidParam);
+        }
+        // new root must be a mount point
+        let idx = self
+            .mounts
+            .iter()
+            .rposition(|(p, _)| *p == new_root)
+            .ok_or(FsError::InvalidParam)?;
+        let new_root_fs = self.mounts[idx].1.clone();
+        let old = relative(&put_old, &new_root);
+
+        let mut mounts = Vec::new();
+        // mounts under the new root move up
+        for (i, (path, fs)) in self.mounts.iter().enumerate() {
+            if i != idx && is_under(path, &new_root) {
+                mounts.push((relative(path, &new_root), fs.clone()));
+            }
+        }
+        // the others go to `put_old` with the old root
+        mounts.push((old.clone(), self.root_fs.clone()));
+        for (i, (path, fs)) in self.mounts.iter().enumerate() {
+            if i != idx && !is_under(path, &new_root) {
+                mounts.push((old.clone() + path, fs.clone()));
+            }
+        }
+        *self = Self::build(new_root_fs, mounts)?;
+        Ok(())
+    }
+}
+
+/// Mount table of a mount namespace
+pub struct MountNamespace {
+    id: usize,
+    table: RwLock<MountTable>,
+}
+
+impl MountNamespace {
+    pub fn new(table: MountTable) -> Self {
+        MountNamespace {
+            id: new_ns_id(),
+            table: RwLock::new(table),
+        }
+    }
+
+    /// Id shown in /proc/[pid]/ns/mnt
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// A new namespace with a copy of the mount table, for CLONE_NEWNS
+    pub fn copy(&self) -> Result<Self> {
+        Ok(Self::new(self.table.read().copy()?))
+    }
+
+    /// Root directory of the namespace
+    pub fn root(&self) -> Arc<dyn INode> {
+        self.table.read().root()
+    }
+
+    pub fn mount(&self, path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+        self.table.write().mount(path, fs)
+    }
+
+    pub fn umount(&self, path: &str) -> Result<()> {
+        self.table.write().umount(path)
+    }
+
+    pub fn pivot_root(&self, new_root: &str, put_old: &str) -> Result<()> {
+        self.table.write().pivot_root(new_root, put_old)
+    }
+}
+
+/// Normalize an absolute `path`, which must not contain ".."
+fn normalize(path: &str) -> Result<String> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidParam);
+    }
+    let mut res = String::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => return Err(FsError::InvalidParam),
+            _ => {
+                res.push('/');
+                res.push_str(name);
+            }
+        }
+    }
+    if res.is_empty() {
+        res.push('/');
+    }
+    Ok(res)
+}
+
+/// Whether normalized `path` is `base` or inside it
+fn is_under(path: &str, base: &str) -> bool {
+    base == "/"
+        || path == base
+        || (path.starts_with(base) && path.as_bytes().get(base.len()) == Some(&b'/'))
+}
+
+/// Path of `path` relative to its ancestor `base`, as an absolute path
+fn relative(path: &str, base: &str) -> String {
+    if path == base {
+        "/".to_string()
+    } else if base == "/" {
+        path.to_string()
+    } else {
+        path[base.len()..].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcore_fs_ramfs::RamFS;
+
+    fn mkdir(table: &MountTable, path: &str, name: &str) {
+        let dir = table.find(path).unwrap();
+        dir.create(name, FileType::Dir, 0o755).unwrap();
+    }
+
+    fn exists(table: &MountTable, path: &str) -> bool {
+        table.find(path).is_ok()
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize("/").unwrap(), "/");
+        assert_eq!(normalize("//.").unwrap(), "/");
+        assert_eq!(normalize("/a//b/./c/").unwrap(), "/a/b/c");
+        assert_eq!(normalize("a/b"), Err(FsError::InvalidParam));
+        assert_eq!(normalize("/a/../b"), Err(FsError::InvalidParam));
+    }
+
+    #[test]
+    fn paths_under() {
+        assert!(is_under("/a/b", "/"));
+        assert!(is_under("/a", "/a"));
+        assert!(is_under("/a/b", "/a"));
+        assert!(!is_under("/ab", "/a"));
+        assert!(!is_under("/a", "/a/b"));
+        assert_eq!(relative("/a", "/a"), "/");
+        assert_eq!(relative("/a/b", "/a"), "/b");
+        assert_eq!(relative("/a/b", "/"), "/a/b");
+    }
+
+    #[test]
+    fn mount_and_umount() {
+        let mut table = MountTable::new(RamFS::new());
+        mkdir(&table, "/", "mnt");
+        table.mount("/mnt", RamFS::new()).unwrap();
+        mkdir(&table, "/mnt", "sub");
+        assert!(exists(&table, "/mnt/sub"));
+        table.mount("/mnt/sub/", RamFS::new()).unwrap();
+        assert_eq!(table.mount("/", RamFS::new()), Err(FsError::Busy));
+        assert_eq!(table.umount("/mnt"), Err(FsError::Busy));
+        assert_eq!(table.umount("/other"), Err(FsError::InvalidParam));
+
+        // a copy does not see later mounts
+        let copy = table.copy().unwrap();
+        table.umount("/mnt/sub").unwrap();
+        table.umount("/mnt").unwrap();
+        assert!(!exists(&table, "/mnt/sub"));
+        assert!(exists(&copy, "/mnt/sub"));
+    }
+
+    #[test]
+    fn pivot_root_moves_mounts() {
+        let mut table = MountTable::new(RamFS::new());
+        mkdir(&table, "/", "new");
+        mkdir(&table, "/", "mnt");
+        table.mount("/mnt", RamFS::new()).unwrap();
+        mkdir(&table, "/mnt", "in_mnt");
+        table.mount("/new", RamFS::new()).unwrap();
+        mkdir(&table, "/new", "old");
+        mkdir(&table, "/new", "proc");
+        table.mount("/new/proc", RamFS::new()).unwrap();
+        mkdir(&table, "/new/proc", "in_proc");
+
+        assert_eq!(table.pivot_root("/", "/old"), Err(FsError::InvalidParam));
+        assert_eq!(table.pivot_root("/new", "/new"), Err(FsError::InvalidParam));
+        assert_eq!(table.pivot_root("/new", "/mnt"), Err(FsError::InvalidParam));
+        assert_eq!(
+            table.pivot_root("/mnt/in_mnt", "/mnt/in_mnt/old"),
+            Err(FsError::InvalidParam)
+        );
+
+        table.pivot_root("/new/", "/new/old").unwrap();
+        // mounts under the new root move up
+        assert!(exists(&table, "/proc/in_proc"));
+        // the old root and its other mounts are under `put_old`
+        assert!(exists(&table, "/old/new"));
+        assert!(exists(&table, "/old/mnt/in_mnt"));
+        assert!(!exists(&table, "/old/new/old"));
+        // and can be unmounted from there
+        table.umount("/old/mnt").unwrap();
+        table.umount("/old").unwrap();
+        assert!(!exists(&table, "/old/new"));
+        assert!(exists(&table, "/proc/in_proc"));
+    }
+}
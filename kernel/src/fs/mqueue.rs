@@ -0,0 +1,662 @@
+//! POSIX message queues, see `mq_overview(7)`
+//!
+//! Queues are files of a flat mqueue filesystem, mounted at /dev/mqueue.
+//! mq_open and mq_unlink work on the filesystem by name, and queue
+//! descriptors are file handles of it, so they can be polled like pipes.
+//! Reading a queue file gives its status line as on Linux.
+
+use crate::arch::timer::timer_now;
+use crate::process::{process, Thread};
+use crate::signal::{send_signal, RtFields, Siginfo, SiginfoFields, SI_MESGQ};
+use crate::sync::{EventBus, SpinNoIrqLock as Mutex};
+use crate::syscall::SysError;
+use crate::trap::NAIVE_TIMER;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::any::Any;
+use core::future::Future;
+use core::mem::size_of;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use rcore_fs::vfs::*;
+use spin::RwLock;
+
+/// Priorities are below this
+pub const MQ_PRIO_MAX: u32 = 32768;
+
+/// Default `mq_maxmsg`
+const DFLT_MAXMSG: usize = 10;
+/// Default `mq_msgsize`
+const DFLT_MSGSIZE: usize = 8192;
+const HARD_MAXMSG: usize = 65536;
+const HARD_MSGSIZE: usize = 16 * 1024 * 1024;
+
+/// Longest queue name
+const NAME_MAX: usize = 255;
+
+lazy_static! {
+    /// The mqueue filesystem, all mounts of type "mqueue" share it
+    pub static ref MQUEUE_FS: Arc<MqueueFS> = Arc::new(MqueueFS::new());
+}
+
+// struct mq_attr
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MqAttr {
+    pub flags: isize,   /* Flags: 0 or O_NONBLOCK */
+    pub maxmsg: isize,  /* Max. # of messages on queue */
+    pub msgsize: isize, /* Max. message size (bytes) */
+    pub curmsgs: isize, /* # of messages currently in queue */
+    __reserved: [isize; 4],
+}
+
+/// A process to notify of a message on an empty queue, see `mq_notify(3)`
+#[derive(Debug, Clone, Copy)]
+pub struct MqNotify {
+    /// Global pid of the process
+    pub pid: usize,
+    /// Signal to send, None for SIGEV_NONE
+    pub signo: Option<i32>,
+    /// `sigev_value` passed in the siginfo
+    pub value: usize,
+}
+
+struct MqMessage {
+    prio: u32,
+    data: Vec<u8>,
+}
+
+struct MqueueInner {
+    /// Messages with the highest priority first, FIFO in a priority
+    msgs: VecDeque<MqMessage>,
+    /// Bytes of all messages
+    bytes: usize,
+    /// Number of receivers blocked on an empty queue
+    receivers: usize,
+    notify: Option<MqNotify>,
+    /// Senders, receivers and pollers blocked on the queue
+    waiters: Vec<Waker>,
+}
+
+/// A message queue
+pub struct MqueueINode {
+    inode: usize,
+    mode: u32,
+    maxmsg: usize,
+    msgsize: usize,
+    inner: Mutex<MqueueInner>,
+}
+
+/// The mqueue filesystem
+pub struct MqueueFS {
+    table: Arc<MqueueTable>,
+}
+
+struct MqueueTable {
+    queues: RwLock<BTreeMap<String, Arc<MqueueINode>>>,
+    /// Bytes reserved by the queues, charged to RLIMIT_MSGQUEUE.
+    /// There is only one user, so they are all charged to it.
+    bytes: AtomicUsize,
+    next_inode: AtomicUsize,
+}
+
+/// Root directory of the mqueue filesystem
+struct MqueueRoot {
+    table: Arc<MqueueTable>,
+}
+
+impl MqueueFS {
+    fn new() -> Self {
+        MqueueFS {
+            table: Arc::new(MqueueTable {
+                queues: RwLock::new(BTreeMap::new()),
+                bytes: AtomicUsize::new(0),
+                // the root is 1
+                next_inode: AtomicUsize::new(2),
+            }),
+        }
+    }
+
+    /// Get the queue `name`
+    pub fn find(&self, name: &str) -> Result<Arc<MqueueINode>> {
+        self.table
+            .queues
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    /// Create the queue `name` with `attr`, or the default attributes if None.
+    /// Fail with EMFILE if the queues would reserve more than `limit` bytes.
+    pub fn create(
+        &self,
+        name: &str,
+        mode: u32,
+        attr: Option<MqAttr>,
+        limit: u64,
+    ) -> core::result::Result<Arc<MqueueINode>, SysError> {
+        let (maxmsg, msgsize) = match attr {
+            Some(attr) => {
+                if attr.maxmsg <= 0
+                    || attr.msgsize <= 0
+                    || attr.maxmsg as usize > HARD_MAXMSG
+                    || attr.msgsize as usize > HARD_MSGSIZE
+                {
+                    return Err(SysError::EINVAL);
+                }
+                (attr.maxmsg as usize, attr.msgsize as usize)
+            }
+            None => (DFLT_MAXMSG, DFLT_MSGSIZE),
+        };
+        self.table.create(name, mode, maxmsg, msgsize, limit)
+    }
+
+    /// Remove the queue `name`, it is destroyed after closed by all
+    pub fn unlink(&self, name: &str) -> Result<()> {
+        self.table.unlink(name)
+    }
+}
+
+impl FileSystem for MqueueFS {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        Arc::new(MqueueRoot {
+            table: self.table.clone(),
+        })
+    }
+
+    fn info(&self) -> FsInfo {
+        FsInfo {
+            bsize: 0,
+            frsize: 0,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: NAME_MAX,
+        }
+    }
+}
+
+impl MqueueTable {
+    fn create(
+        &self,
+        name: &str,
+        mode: u32,
+        maxmsg: usize,
+        msgsize: usize,
+        limit: u64,
+    ) -> core::result::Result<Arc<MqueueINode>, SysError> {
+        if name.is_empty() || name.len() > NAME_MAX || name.contains('/') {
+            return Err(SysError::EINVAL);
+        }
+        let mut queues = self.queues.write();
+        if queues.contains_key(name) {
+            return Err(SysError::EEXIST);
+        }
+        let bytes = maxmsg * (msgsize + size_of::<usize>());
+        let total = self.bytes.load(Ordering::SeqCst) + bytes;
+        if total as u64 > limit {
+            return Err(SysError::EMFILE);
+        }
+        self.bytes.store(total, Ordering::SeqCst);
+        let queue = Arc::new(MqueueINode {
+            inode: self.next_inode.fetch_add(1, Ordering::SeqCst),
+            mode: mode & 0o777,
+            maxmsg,
+            msgsize,
+            inner: Mutex::new(MqueueInner {
+                msgs: VecDeque::new(),
+                bytes: 0,
+                receivers: 0,
+                notify: None,
+                waiters: Vec::new(),
+            }),
+        });
+        queues.insert(name.to_string(), queue.clone());
+        Ok(queue)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let queue = self
+            .queues
+            .write()
+            .remove(name)
+            .ok_or(FsError::EntryNotFound)?;
+        let bytes = queue.maxmsg * (queue.msgsize + size_of::<usize>());
+        self.bytes.fetch_sub(bytes, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl INode for MqueueRoot {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: false,
+            write: false,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: 1,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::Dir,
+            mode: 0o1777,
+            nlinks: 2,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    /// Create a queue with the default attributes, for open(2) with O_CREAT
+    fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        if type_ != FileType::File {
+            return Err(FsError::NotSupported);
+        }
+        let queue = self
+            .table
+            .create(name, mode, DFLT_MAXMSG, DFLT_MSGSIZE, u64::max_value())
+            .map_err(|err| match err {
+                SysError::EEXIST => FsError::EntryExist,
+                _ => FsError::InvalidParam,
+            })?;
+        Ok(queue)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.table.unlink(name)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match name {
+            "." | ".." => Ok(Arc::new(MqueueRoot {
+                table: self.table.clone(),
+            })),
+            _ => Ok(self
+                .table
+                .queues
+                .read()
+                .get(name)
+                .cloned()
+                .ok_or(FsError::EntryNotFound)?),
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            _ => self
+                .table
+                .queues
+                .read()
+                .keys()
+                .nth(id - 2)
+                .cloned()
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl MqueueINode {
+    /// Attributes of the queue, without flags
+    pub fn attr(&self) -> MqAttr {
+        MqAttr {
+            maxmsg: self.maxmsg as isize,
+            msgsize: self.msgsize as isize,
+            curmsgs: self.inner.lock().msgs.len() as isize,
+            ..MqAttr::default()
+        }
+    }
+
+    /// Register `notify` for process `pid`, or remove its registration if None
+    pub fn set_notify(
+        &self,
+        pid: usize,
+        notify: Option<MqNotify>,
+    ) -> core::result::Result<(), SysError> {
+        let mut inner = self.inner.lock();
+        match (notify, &inner.notify) {
+            (Some(_), Some(_)) => Err(SysError::EBUSY),
+            (Some(notify), None) => {
+                inner.notify = Some(notify);
+                Ok(())
+            }
+            (None, Some(old)) if old.pid == pid => {
+                inner.notify = None;
+                Ok(())
+            }
+            (None, _) => Ok(()),
+        }
+    }
+
+    /// Send a message with priority `prio`, blocking while the queue is full
+    /// unless `nonblock`, and fail with ETIMEDOUT after `deadline`
+    pub async fn send(
+        &self,
+        thread: &Arc<Thread>,
+        data: Vec<u8>,
+        prio: u32,
+        nonblock: bool,
+        deadline: Option<Duration>,
+    ) -> core::result::Result<(), SysError> {
+        if data.len() > self.msgsize {
+            return Err(SysError::EMSGSIZE);
+        }
+        if prio >= MQ_PRIO_MAX {
+            return Err(SysError::EINVAL);
+        }
+        let mut msg = Some(MqMessage { prio, data });
+        let mut notify = None;
+        self.wait_for(thread, deadline, false, |inner| {
+            if inner.msgs.len() >= self.maxmsg {
+                return if nonblock {
+                    Some(Err(SysError::EAGAIN))
+                } else {
+                    None
+                };
+            }
+            let msg = msg.take().unwrap();
+            // notify only of messages to an empty queue nobody waits on
+            if inner.msgs.is_empty() && inner.receivers == 0 {
+                notify = inner.notify.take();
+            }
+            let idx = inner
+                .msgs
+                .iter()
+                .position(|m| m.prio < msg.prio)
+                .unwrap_or(inner.msgs.len());
+            inner.bytes += msg.data.len();
+            inner.msgs.insert(idx, msg);
+            inner.wake_all();
+            Some(Ok(()))
+        })
+        .await?;
+        if let Some(notify) = notify {
+            notify.deliver();
+        }
+        Ok(())
+    }
+
+    /// Receive the oldest message of the highest priority into a buffer of `len` bytes,
+    /// blocking until there is one unless `nonblock`, and fail with ETIMEDOUT after `deadline`
+    pub async fn receive(
+        &self,
+        thread: &Arc<Thread>,
+        len: usize,
+        nonblock: bool,
+        deadline: Option<Duration>,
+    ) -> core::result::Result<(Vec<u8>, u32), SysError> {
+        if len < self.msgsize {
+            return Err(SysError::EMSGSIZE);
+        }
+        self.wait_for(thread, deadline, true, |inner| {
+            match inner.msgs.pop_front() {
+                Some(msg) => {
+                    inner.bytes -= msg.data.len();
+                    inner.wake_all();
+                    Some(Ok((msg.data, msg.prio)))
+                }
+                None if nonblock => Some(Err(SysError::EAGAIN)),
+                None => None,
+            }
+        })
+        .await
+    }
+
+    /// Wait until `op` returns a result, interrupted by signals to `thread`.
+    /// `op` returns None to keep waiting for changes of the queue.
+    /// `receiver` waits are counted in `MqueueInner::receivers`.
+    fn wait_for<'a, T, F>(
+        &'a self,
+        thread: &'a Arc<Thread>,
+        deadline: Option<Duration>,
+        receiver: bool,
+        op: F,
+    ) -> impl Future<Output = core::result::Result<T, SysError>> + 'a
+    where
+        T: 'a,
+        F: FnMut(&mut MqueueInner) -> Option<core::result::Result<T, SysError>> + Unpin + 'a,
+    {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct MqueueFuture<'a, F> {
+            queue: &'a MqueueINode,
+            thread: &'a Arc<Thread>,
+            eventbus: Arc<Mutex<EventBus>>,
+            deadline: Option<Duration>,
+            receiver: bool,
+            /// Whether counted in `receivers`
+            waiting: bool,
+            op: F,
+        }
+
+        impl<'a, T, F> Future for MqueueFuture<'a, F>
+        where
+            F: FnMut(&mut MqueueInner) -> Option<core::result::Result<T, SysError>> + Unpin,
+        {
+            type Output = core::result::Result<T, SysError>;
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                let this = &mut *self;
+                {
+                    let mut inner = this.queue.inner.lock();
+                    if let Some(res) = (this.op)(&mut inner) {
+                        if this.waiting {
+                            inner.receivers -= 1;
+                            this.waiting = false;
+                        }
+                        return Poll::Ready(res);
+                    }
+                    if this.receiver && !this.waiting {
+                        inner.receivers += 1;
+                        this.waiting = true;
+                    }
+                    inner.waiters.push(cx.waker().clone());
+                }
+                if this.thread.has_signal_to_handle() {
+                    return Poll::Ready(Err(SysError::EINTR));
+                }
+                if let Some(deadline) = this.deadline {
+                    if timer_now() >= deadline {
+                        return Poll::Ready(Err(SysError::ETIMEDOUT));
+                    }
+                    let waker = cx.waker().clone();
+                    NAIVE_TIMER
+                        .lock()
+                        .add(deadline, Box::new(move |_| waker.wake()));
+                }
+                let waker = cx.waker().clone();
+                this.eventbus.lock().subscribe(Box::new({
+                    move |_| {
+                        waker.wake_by_ref();
+                        true
+                    }
+                }));
+                Poll::Pending
+            }
+        }
+
+        impl<'a, F> Drop for MqueueFuture<'a, F> {
+            fn drop(&mut self) {
+                if self.waiting {
+                    self.queue.inner.lock().receivers -= 1;
+                }
+            }
+        }
+
+        MqueueFuture {
+            queue: self,
+            thread,
+            eventbus: thread.proc.lock().eventbus.clone(),
+            deadline,
+            receiver,
+            waiting: false,
+            op,
+        }
+    }
+
+    fn can_read(&self) -> bool {
+        !self.inner.lock().msgs.is_empty()
+    }
+
+    fn can_write(&self) -> bool {
+        self.inner.lock().msgs.len() < self.maxmsg
+    }
+}
+
+impl MqueueInner {
+    fn wake_all(&mut self) {
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl MqNotify {
+    /// Send the notification signal to the process
+    fn deliver(&self) {
+        let signo = match self.signo {
+            Some(signo) => signo,
+            None => return,
+        };
+        if let Some(proc) = process(self.pid) {
+            let mut field = SiginfoFields::default();
+            field.rt = RtFields {
+                pid: self.pid as i32,
+                uid: 0,
+                value: self.value,
+            };
+            send_signal(
+                proc,
+                -1,
+                Siginfo {
+                    signo,
+                    errno: 0,
+                    code: SI_MESGQ,
+                    field,
+                },
+            );
+        }
+    }
+}
+
+impl INode for MqueueINode {
+    /// Read the status line of the queue
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let inner = self.inner.lock();
+        // SIGEV_SIGNAL is 0, SIGEV_NONE is 1
+        let (pid, notify, signo) = match &inner.notify {
+            Some(MqNotify {
+                pid,
+                signo: Some(signo),
+                ..
+            }) => (*pid, 0, *signo),
+            Some(MqNotify { pid, .. }) => (*pid, 1, 0),
+            None => (0, 0, 0),
+        };
+        let status = format!(
+            "QSIZE:{:<10} NOTIFY:{:<5} SIGNO:{:<5} NOTIFY_PID:{:<6}\n",
+            inner.bytes, notify, signo, pid
+        );
+        drop(inner);
+        let status = status.as_bytes();
+        if offset >= status.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(status.len() - offset);
+        buf[..len].copy_from_slice(&status[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: self.can_read(),
+            write: self.can_write(),
+            error: false,
+        })
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct MqueuePollFuture<'a> {
+            queue: &'a MqueueINode,
+        };
+
+        impl<'a> Future for MqueuePollFuture<'a> {
+            type Output = Result<PollStatus>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                if self.queue.can_read() || self.queue.can_write() {
+                    return Poll::Ready(self.queue.poll());
+                }
+                self.queue.inner.lock().waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+
+        Box::pin(MqueuePollFuture { queue: self })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: self.inode,
+            size: self.inner.lock().bytes,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::File,
+            mode: self.mode,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -4,7 +4,7 @@ use crate::sync::{Event, EventBus, SpinNoIrqLock as Mutex};
 use crate::syscall::SysError::EAGAIN;
 use alloc::boxed::Box;
 use alloc::collections::BTreeSet;
-use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+use alloc::{collections::vec_deque::VecDeque, sync::Arc, vec::Vec};
 use core::any::Any;
 use core::cmp::min;
 use core::{
@@ -15,6 +15,7 @@ use core::{
 };
 use rcore_fs::vfs::FsError::Again;
 use rcore_fs::vfs::*;
+use rcore_memory::PAGE_SIZE;
 
 #[derive(Clone, PartialEq)]
 pub enum PipeEnd {
@@ -22,13 +23,136 @@ pub enum PipeEnd {
     Write,
 }
 
+/// Bytes of a page in a pipe. Pages are shared, not copied, by `tee`.
+#[derive(Clone)]
+pub struct PipeBuffer {
+    page: Arc<Vec<u8>>,
+    /// Start of the bytes in the page
+    offset: usize,
+    len: usize,
+}
+
+impl PipeBuffer {
+    /// A buffer of `data`, which is at most a page
+    pub fn new(data: Vec<u8>) -> Self {
+        let len = data.len();
+        PipeBuffer {
+            page: Arc::new(data),
+            offset: 0,
+            len,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.page[self.offset..self.offset + self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Drop the first `len` bytes
+    pub fn advance(&mut self, len: usize) {
+        self.offset += len;
+        self.len -= len;
+    }
+
+    /// The first `len` bytes
+    fn prefix(&self, len: usize) -> PipeBuffer {
+        PipeBuffer {
+            len,
+            ..self.clone()
+        }
+    }
+
+    /// Append to the page if it is not shared and has room, returning the bytes appended
+    fn extend(&mut self, data: &[u8]) -> usize {
+        let end = self.offset + self.len;
+        match Arc::get_mut(&mut self.page) {
+            Some(page) if page.len() == end && end < PAGE_SIZE => {
+                let len = min(PAGE_SIZE - end, data.len());
+                page.extend_from_slice(&data[..len]);
+                self.len += len;
+                len
+            }
+            _ => 0,
+        }
+    }
+}
+
 pub struct PipeData {
-    buf: VecDeque<u8>,
+    bufs: VecDeque<PipeBuffer>,
+    /// number of bytes in `bufs`
+    len: usize,
     eventbus: EventBus,
     /// number of pipe ends
     end_cnt: i32,
 }
 
+impl PipeData {
+    fn push_bytes(&mut self, mut data: &[u8]) {
+        self.len += data.len();
+        if let Some(last) = self.bufs.back_mut() {
+            let len = last.extend(data);
+            data = &data[len..];
+        }
+        for chunk in data.chunks(PAGE_SIZE) {
+            let mut page = Vec::with_capacity(PAGE_SIZE);
+            page.extend_from_slice(chunk);
+            self.bufs.push_back(PipeBuffer::new(page));
+        }
+        self.eventbus.set(Event::READABLE);
+    }
+
+    fn push(&mut self, bufs: Vec<PipeBuffer>) -> usize {
+        let len: usize = bufs.iter().map(|buf| buf.len).sum();
+        self.len += len;
+        self.bufs.extend(bufs.into_iter().filter(|buf| buf.len > 0));
+        if self.len > 0 {
+            self.eventbus.set(Event::READABLE);
+        }
+        len
+    }
+
+    /// Buffers of the first `len` bytes
+    fn peek(&self, len: usize) -> Vec<PipeBuffer> {
+        let mut bufs = Vec::new();
+        let mut left = len;
+        for buf in self.bufs.iter() {
+            if left == 0 {
+                break;
+            }
+            let len = min(left, buf.len);
+            bufs.push(buf.prefix(len));
+            left -= len;
+        }
+        bufs
+    }
+
+    /// Drop the first `len` bytes
+    fn advance(&mut self, len: usize) {
+        let mut left = len;
+        while left > 0 {
+            let buf = self.bufs.front_mut().unwrap();
+            if buf.len > left {
+                buf.advance(left);
+                break;
+            }
+            left -= buf.len;
+            self.bufs.pop_front();
+        }
+        self.len -= len;
+        if self.len == 0 {
+            self.eventbus.clear(Event::READABLE);
+        }
+    }
+
+    /// Whether nothing can be read for now, though the write end is open
+    fn would_block(&self) -> bool {
+        self.len == 0 && self.end_cnt == 2
+    }
+}
+
 #[derive(Clone)]
 pub struct Pipe {
     data: Arc<Mutex<PipeData>>,
@@ -48,7 +172,8 @@ impl Pipe {
     /// Create a pair of INode: (read, write)
     pub fn create_pair() -> (Pipe, Pipe) {
         let inner = PipeData {
-            buf: VecDeque::new(),
+            bufs: VecDeque::new(),
+            len: 0,
             eventbus: EventBus::default(),
             end_cnt: 2, // one read, one write
         };
@@ -69,7 +194,7 @@ impl Pipe {
         if let PipeEnd::Read = self.direction {
             // true
             let data = self.data.lock();
-            data.buf.len() > 0 || data.end_cnt < 2 // other end closed
+            data.len > 0 || data.end_cnt < 2 // other end closed
         } else {
             false
         }
@@ -82,6 +207,64 @@ impl Pipe {
             false
         }
     }
+
+    pub fn is_read_end(&self) -> bool {
+        self.direction == PipeEnd::Read
+    }
+
+    pub fn is_write_end(&self) -> bool {
+        self.direction == PipeEnd::Write
+    }
+
+    /// Whether the read end of the pipe is closed, so that writes fail with EPIPE
+    pub fn read_end_closed(&self) -> bool {
+        self.is_write_end() && self.data.lock().end_cnt < 2
+    }
+
+    /// Whether `self` and `other` are ends of the same pipe
+    pub fn same_pipe(&self, other: &Pipe) -> bool {
+        Arc::ptr_eq(&self.data, &other.data)
+    }
+
+    /// Take the buffers of at most `len` bytes from the read end, see `splice(2)`.
+    /// Fail with Again if it is empty and the write end is open.
+    pub fn take(&self, len: usize) -> Result<Vec<PipeBuffer>> {
+        let mut data = self.data.lock();
+        if data.would_block() {
+            return Err(Again);
+        }
+        let bufs = data.peek(len);
+        let len = bufs.iter().map(|buf| buf.len).sum();
+        data.advance(len);
+        Ok(bufs)
+    }
+
+    /// Return the buffers of `take` which are not consumed, in front of the others
+    pub fn put_back(&self, bufs: Vec<PipeBuffer>) {
+        let mut data = self.data.lock();
+        for buf in bufs.into_iter().rev().filter(|buf| buf.len > 0) {
+            data.len += buf.len;
+            data.bufs.push_front(buf);
+        }
+        if data.len > 0 {
+            data.eventbus.set(Event::READABLE);
+        }
+    }
+
+    /// Share the buffers of at most `len` bytes at the read end without consuming them,
+    /// see `tee(2)`. Fail with Again if it is empty and the write end is open.
+    pub fn peek(&self, len: usize) -> Result<Vec<PipeBuffer>> {
+        let data = self.data.lock();
+        if data.would_block() {
+            return Err(Again);
+        }
+        Ok(data.peek(len))
+    }
+
+    /// Append `bufs` to the write end, returning the number of bytes
+    pub fn give(&self, bufs: Vec<PipeBuffer>) -> usize {
+        self.data.lock().push(bufs)
+    }
 }
 
 impl INode for Pipe {
@@ -91,16 +274,15 @@ impl INode for Pipe {
         }
         if let PipeEnd::Read = self.direction {
             let mut data = self.data.lock();
-            if data.buf.len() == 0 && data.end_cnt == 2 {
+            if data.would_block() {
                 Err(Again)
             } else {
-                let len = min(buf.len(), data.buf.len());
-                for i in 0..len {
-                    buf[i] = data.buf.pop_front().unwrap();
-                }
-                if data.buf.len() == 0 {
-                    data.eventbus.clear(Event::READABLE);
+                let mut len = 0;
+                for page in data.peek(buf.len()) {
+                    buf[len..len + page.len].copy_from_slice(page.as_slice());
+                    len += page.len;
                 }
+                data.advance(len);
                 Ok(len)
             }
         } else {
@@ -110,11 +292,7 @@ impl INode for Pipe {
 
     fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
         if let PipeEnd::Write = self.direction {
-            let mut data = self.data.lock();
-            for c in buf {
-                data.buf.push_back(*c);
-            }
-            data.eventbus.set(Event::READABLE);
+            self.data.lock().push_bytes(buf);
             Ok(buf.len())
         } else {
             Ok(0)
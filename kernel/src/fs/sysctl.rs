@@ -0,0 +1,84 @@
+//! Kernel parameters exported as files, like `/proc/sys` of Linux
+
+use alloc::string::String;
+use core::any::Any;
+use core::str;
+
+use rcore_fs::vfs::*;
+use spin::RwLock;
+
+/// A text file backed by a kernel string parameter.
+///
+/// Reading gives the value followed by a newline,
+/// writing replaces the value with the written line.
+pub struct SysctlINode {
+    value: &'static RwLock<String>,
+}
+
+impl SysctlINode {
+    pub fn new(value: &'static RwLock<String>) -> Self {
+        SysctlINode { value }
+    }
+}
+
+impl INode for SysctlINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut content = self.value.read().clone();
+        content.push('\n');
+        let content = content.as_bytes();
+        if offset >= content.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let input = str::from_utf8(buf).map_err(|_| FsError::InvalidParam)?;
+        let mut value = self.value.write();
+        if offset == 0 {
+            value.clear();
+        }
+        value.push_str(input);
+        let len = value.trim_end_matches('\n').len();
+        value.truncate(len);
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 1,
+            inode: 1,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::File,
+            mode: 0o644,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn resize(&self, _len: usize) -> Result<()> {
+        // opened with O_TRUNC, the following write replaces the value
+        Ok(())
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
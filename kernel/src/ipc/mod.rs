@@ -1,147 +1,163 @@
+mod msgqueue;
 mod semary;
 mod shared_mem;
 
+pub use self::msgqueue::*;
 pub use self::semary::*;
 pub use self::shared_mem::*;
-use crate::memory::GlobalFrameAlloc;
-use crate::sync::SpinLock as Mutex;
+use crate::syscall::SysError;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
-use rcore_memory::memory_set::handler::{Shared, SharedGuard};
-use rcore_memory::{PhysAddr, VirtAddr, PAGE_SIZE};
-
-/// Semaphore table in a process
-#[derive(Default)]
-pub struct SemProc {
-    /// Semaphore arrays
-    arrays: BTreeMap<SemId, Arc<SemArray>>,
-    /// Undo operations when process terminates
-    undos: BTreeMap<(SemId, SemNum), SemOp>,
-}
+use bitflags::*;
 
-// TODO: Remove hack
-#[derive(Default)]
-pub struct ShmProc {
-    shm_identifiers: BTreeMap<ShmId, ShmIdentifier>,
+bitflags! {
+    struct IpcGetFlag: usize {
+        const CREAT = 1 << 9;
+        const EXCLUSIVE = 1 << 10;
+    }
 }
 
-/// Semaphore set identifier (in a process)
-type SemId = usize;
-type ShmId = usize;
-
-/// Semaphore number (in an array)
-type SemNum = u16;
-
-/// Semaphore operation value
-type SemOp = i16;
-
-impl SemProc {
-    /// Insert the `array` and return its ID
-    pub fn add(&mut self, array: Arc<SemArray>) -> SemId {
-        let id = self.get_free_id();
-        self.arrays.insert(id, array);
-        id
-    }
+/// Max number of objects of each kind, and the multiplier of sequence numbers in ids
+pub const IPCMNI: usize = 32768;
+
+// structure specifies the access permissions on the IPC object
+// struct ipc_perm
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct IpcPerm {
+    // key_t is int
+    pub key: u32,  /* Key supplied to *get(2) */
+    pub uid: u32,  /* Effective UID of owner */
+    pub gid: u32,  /* Effective GID of owner */
+    pub cuid: u32, /* Effective UID of creator */
+    pub cgid: u32, /* Effective GID of creator */
+    // mode_t is unsigned int
+    pub mode: u32,  /* Permissions */
+    pub __seq: u32, /* Sequence number */
+    pub __pad1: usize,
+    pub __pad2: usize,
+}
 
-    /// Remove an `array` by ID
-    pub fn remove(&mut self, id: SemId) {
-        self.arrays.remove(&id);
+impl IpcPerm {
+    /// Permissions of a new object with `key`, taking the mode from `flags`
+    pub fn new(key: u32, flags: usize, seq: u32) -> Self {
+        IpcPerm {
+            key,
+            uid: 0,
+            gid: 0,
+            cuid: 0,
+            cgid: 0,
+            // least significant 9 bits
+            mode: (flags as u32) & 0x1ff,
+            __seq: seq,
+            __pad1: 0,
+            __pad2: 0,
+        }
     }
 
-    /// Get a free ID
-    fn get_free_id(&self) -> SemId {
-        (0..).find(|i| self.arrays.get(i).is_none()).unwrap()
+    /// for IPC_SET
+    pub fn set(&mut self, new: &IpcPerm) {
+        self.uid = new.uid;
+        self.gid = new.gid;
+        self.mode = (self.mode & !0x1ff) | (new.mode & 0x1ff);
     }
+}
 
-    /// Get an semaphore set by `id`
-    pub fn get(&self, id: SemId) -> Option<Arc<SemArray>> {
-        self.arrays.get(&id).map(|a| a.clone())
-    }
+/// A System V IPC object
+pub trait IpcObject {
+    /// The key, IPC_PRIVATE if it can not be found by key
+    fn key(&self) -> u32;
+}
 
-    /// Add an undo operation
-    pub fn add_undo(&mut self, id: SemId, num: SemNum, op: SemOp) {
-        let old_val = *self.undos.get(&(id, num)).unwrap_or(&0);
-        let new_val = old_val - op;
-        self.undos.insert((id, num), new_val);
-    }
+/// The objects of one kind of System V IPC, shared by all processes.
+///
+/// An id is the index of the object plus its sequence number times IPCMNI,
+/// so a stale id does not refer to a new object at the same index.
+pub struct IpcIds<T> {
+    /// Objects with their ids by index
+    objects: BTreeMap<usize, (usize, Arc<T>)>,
+    /// Sequence number of the next object
+    seq: usize,
 }
 
-/// Fork the semaphore table. Clear undo info.
-impl Clone for SemProc {
-    fn clone(&self) -> Self {
-        SemProc {
-            arrays: self.arrays.clone(),
-            undos: BTreeMap::default(),
+impl<T> Default for IpcIds<T> {
+    fn default() -> Self {
+        IpcIds {
+            objects: BTreeMap::new(),
+            seq: 0,
         }
     }
 }
 
-/// Auto perform semaphores undo on drop
-impl Drop for SemProc {
-    fn drop(&mut self) {
-        for (&(id, num), &op) in self.undos.iter() {
-            debug!("semundo: id: {}, num: {}, op: {}", id, num, op);
-            let sem_array = self.arrays[&id].clone();
-            let sem = &sem_array[num as usize];
-            match op {
-                1 => sem.release(),
-                0 => {}
-                _ => unimplemented!("Semaphore: semundo.(Not 1)"),
+impl<T: IpcObject> IpcIds<T> {
+    /// Get the id of the object with `key`, creating one if needed and IPC_CREAT is in `flags`.
+    /// `check` is called on an existing one, and `create` makes a new one with its id and sequence number.
+    pub fn get_or_create(
+        &mut self,
+        key: u32,
+        flags: usize,
+        check: impl FnOnce(&T) -> Result<(), SysError>,
+        create: impl FnOnce(usize, u32) -> Result<Arc<T>, SysError>,
+    ) -> Result<usize, SysError> {
+        let flag = IpcGetFlag::from_bits_truncate(flags);
+        // IPC_PRIVATE always creates a new one
+        if key != 0 {
+            let found = self.objects.values().find(|(_, obj)| obj.key() == key);
+            if let Some((id, obj)) = found {
+                if flag.contains(IpcGetFlag::CREAT) && flag.contains(IpcGetFlag::EXCLUSIVE) {
+                    return Err(SysError::EEXIST);
+                }
+                check(obj)?;
+                return Ok(*id);
+            }
+            if !flag.contains(IpcGetFlag::CREAT) {
+                return Err(SysError::ENOENT);
             }
         }
-    }
-}
+        if self.objects.len() >= IPCMNI {
+            return Err(SysError::ENOSPC);
+        }
 
-impl ShmProc {
-    /// Insert the `SharedGuard` and return its ID
-    pub fn add(&mut self, shared_guard: Arc<spin::Mutex<SharedGuard<GlobalFrameAlloc>>>) -> ShmId {
-        let id = self.get_free_id();
-        let shm_identifier = ShmIdentifier {
-            addr: 0,
-            shared_guard: shared_guard,
-        };
-        self.shm_identifiers.insert(id, shm_identifier);
-        id
+        let idx = (0..).find(|i| !self.objects.contains_key(i)).unwrap();
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % (i32::max_value() as usize / IPCMNI);
+        let id = seq * IPCMNI + idx;
+        let obj = create(id, seq as u32)?;
+        self.objects.insert(idx, (id, obj));
+        Ok(id)
     }
 
-    /// Get a free ID
-    fn get_free_id(&self) -> ShmId {
-        (0..)
-            .find(|i| self.shm_identifiers.get(i).is_none())
-            .unwrap()
+    /// Get the object by `id`
+    pub fn get(&self, id: usize) -> Option<Arc<T>> {
+        match self.objects.get(&(id % IPCMNI)) {
+            Some((obj_id, obj)) if *obj_id == id => Some(obj.clone()),
+            _ => None,
+        }
     }
 
-    /// Get an semaphore set by `id`
-    pub fn get(&self, id: ShmId) -> Option<ShmIdentifier> {
-        self.shm_identifiers.get(&id).map(|a| a.clone())
+    /// Get the object and its id by index, for *_STAT
+    pub fn get_by_index(&self, idx: usize) -> Option<(usize, Arc<T>)> {
+        self.objects.get(&idx).cloned()
     }
 
-    /// Used to set Virtual Addr
-    pub fn set(&mut self, id: ShmId, shm_id: ShmIdentifier) {
-        self.shm_identifiers.insert(id, shm_id);
+    /// Remove the object by `id`
+    pub fn remove(&mut self, id: usize) -> Option<Arc<T>> {
+        self.get(id)?;
+        self.objects.remove(&(id % IPCMNI)).map(|(_, obj)| obj)
     }
 
-    /// get id from virtaddr
-    pub fn get_id(&self, addr: VirtAddr) -> Option<ShmId> {
-        for (key, value) in &self.shm_identifiers {
-            if value.addr == addr {
-                return Some(*key);
-            }
-        }
-        None
+    /// Objects with their ids
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Arc<T>)> {
+        self.objects.values().map(|(id, obj)| (*id, obj))
     }
 
-    /// Pop Shared Area
-    pub fn pop(&mut self, id: ShmId) {
-        self.shm_identifiers.remove(&id);
+    /// Number of objects
+    pub fn len(&self) -> usize {
+        self.objects.len()
     }
-}
 
-/// Fork the semaphore table. Clear undo info.
-impl Clone for ShmProc {
-    fn clone(&self) -> Self {
-        ShmProc {
-            shm_identifiers: self.shm_identifiers.clone(),
-        }
+    /// Highest index in use, for *_INFO
+    pub fn max_index(&self) -> usize {
+        self.objects.keys().next_back().cloned().unwrap_or(0)
     }
 }
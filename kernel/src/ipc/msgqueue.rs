@@ -0,0 +1,369 @@
+//! System V message queues, see `msgop(2)`
+
+use super::{IpcIds, IpcObject, IpcPerm, IPCMNI};
+use crate::process::Thread;
+use crate::sync::{EventBus, SpinNoIrqLock as Mutex};
+use crate::syscall::{SysError, TimeSpec};
+use alloc::boxed::Box;
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::RwLock;
+
+/// Max size of a message
+pub const MSGMAX: usize = 8192;
+/// Default max size of a queue in bytes
+pub const MSGMNB: usize = 16384;
+/// Max number of queues
+pub const MSGMNI: usize = IPCMNI;
+/// Max number of messages in all queues
+const MSGTQL: usize = MSGMNB;
+
+// struct msqid_ds
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MsqidDs {
+    pub perm: IpcPerm, /* Ownership and permissions */
+    pub stime: usize,  /* Time of last msgsnd */
+    pub rtime: usize,  /* Time of last msgrcv */
+    pub ctime: usize,  /* Time of last change */
+    pub cbytes: usize, /* Current number of bytes in queue */
+    pub qnum: usize,   /* Current number of messages in queue */
+    pub qbytes: usize, /* Maximum number of bytes allowed in queue */
+    pub lspid: u32,    /* PID of last msgsnd */
+    pub lrpid: u32,    /* PID of last msgrcv */
+    __unused4: usize,
+    __unused5: usize,
+}
+
+// struct msginfo
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct MsgInfo {
+    pub msgpool: i32,
+    pub msgmap: i32,
+    pub msgmax: i32,
+    pub msgmnb: i32,
+    pub msgmni: i32,
+    pub msgssz: i32,
+    pub msgtql: i32,
+    pub msgseg: u16,
+}
+
+/// Which message msgrcv takes
+#[derive(Debug, Clone, Copy)]
+pub enum MsgSelector {
+    /// The first message, `msgtyp` is 0
+    First,
+    /// The first message of a type, `msgtyp` is positive
+    Type(isize),
+    /// The first message not of a type, positive `msgtyp` with MSG_EXCEPT
+    Except(isize),
+    /// The first message of the lowest type not above, `msgtyp` is negative
+    AtMost(isize),
+}
+
+struct Message {
+    mtype: isize,
+    data: Vec<u8>,
+}
+
+struct MsgQueueInner {
+    msqid_ds: MsqidDs,
+    msgs: VecDeque<Message>,
+    removed: bool,
+    /// Senders and receivers blocked on the queue
+    waiters: Vec<Waker>,
+}
+
+/// A System V message queue
+pub struct MsgQueue {
+    inner: Mutex<MsgQueueInner>,
+}
+
+lazy_static! {
+    /// Message queues by id
+    static ref MSG_IDS: RwLock<IpcIds<MsgQueue>> = RwLock::new(IpcIds::default());
+}
+
+impl IpcObject for MsgQueue {
+    fn key(&self) -> u32 {
+        self.inner.lock().msqid_ds.perm.key
+    }
+}
+
+impl MsgQueue {
+    /// Get the queue with `key`, creating one if needed and IPC_CREAT is in `flags`.
+    /// Return its id.
+    pub fn get_or_create(key: u32, flags: usize) -> Result<usize, SysError> {
+        MSG_IDS.write().get_or_create(
+            key,
+            flags,
+            |_| Ok(()),
+            |_, seq| {
+                Ok(Arc::new(MsgQueue {
+                    inner: Mutex::new(MsgQueueInner {
+                        msqid_ds: MsqidDs {
+                            perm: IpcPerm::new(key, flags, seq),
+                            stime: 0,
+                            rtime: 0,
+                            ctime: TimeSpec::get_epoch().sec,
+                            cbytes: 0,
+                            qnum: 0,
+                            qbytes: MSGMNB,
+                            lspid: 0,
+                            lrpid: 0,
+                            __unused4: 0,
+                            __unused5: 0,
+                        },
+                        msgs: VecDeque::new(),
+                        removed: false,
+                        waiters: Vec::new(),
+                    }),
+                }))
+            },
+        )
+    }
+
+    /// Get the queue by `id`
+    pub fn get(id: usize) -> Option<Arc<Self>> {
+        MSG_IDS.read().get(id)
+    }
+
+    /// Get the queue and its id by index, for MSG_STAT
+    pub fn get_by_index(idx: usize) -> Option<(usize, Arc<Self>)> {
+        MSG_IDS.read().get_by_index(idx)
+    }
+
+    /// Limits for IPC_INFO, or usage for MSG_INFO, with the highest index in use
+    pub fn info(usage: bool) -> (MsgInfo, usize) {
+        let ids = MSG_IDS.read();
+        let mut info = MsgInfo {
+            msgpool: (MSGMNI * MSGMNB / 1024) as i32,
+            msgmap: MSGMNB as i32,
+            msgmax: MSGMAX as i32,
+            msgmnb: MSGMNB as i32,
+            msgmni: MSGMNI as i32,
+            msgssz: 16,
+            msgtql: MSGTQL as i32,
+            msgseg: 0xffff,
+        };
+        if usage {
+            let stats: Vec<MsqidDs> = ids.iter().map(|(_, queue)| queue.stat()).collect();
+            info.msgpool = ids.len() as i32;
+            info.msgmap = stats.iter().map(|ds| ds.qnum).sum::<usize>() as i32;
+            info.msgtql = stats.iter().map(|ds| ds.cbytes).sum::<usize>() as i32;
+        }
+        (info, ids.max_index())
+    }
+
+    /// Lines of `/proc/sysvipc/msg`
+    pub fn list() -> String {
+        let mut list = String::from("       key      msqid perms      cbytes       qnum lspid lrpid   uid   gid  cuid  cgid      stime      rtime      ctime\n");
+        for (id, queue) in MSG_IDS.read().iter() {
+            let ds = queue.stat();
+            let perm = &ds.perm;
+            list += &format!(
+                "{:10} {:10}  {:4o}  {:10} {:10} {:5} {:5} {:5} {:5} {:5} {:5} {:10} {:10} {:10}\n",
+                perm.key as i32,
+                id,
+                perm.mode,
+                ds.cbytes,
+                ds.qnum,
+                ds.lspid,
+                ds.lrpid,
+                perm.uid,
+                perm.gid,
+                perm.cuid,
+                perm.cgid,
+                ds.stime,
+                ds.rtime,
+                ds.ctime
+            );
+        }
+        list
+    }
+
+    /// Remove the queue, failing blocked senders and receivers with EIDRM
+    pub fn remove(id: usize) {
+        if let Some(queue) = MSG_IDS.write().remove(id) {
+            let mut inner = queue.inner.lock();
+            inner.removed = true;
+            inner.msgs.clear();
+            inner.wake_all();
+        }
+    }
+
+    /// for IPC_STAT
+    pub fn stat(&self) -> MsqidDs {
+        self.inner.lock().msqid_ds
+    }
+
+    /// for IPC_SET
+    /// see man msgctl(2)
+    pub fn set(&self, new: &MsqidDs) -> Result<(), SysError> {
+        if new.qbytes == 0 {
+            return Err(SysError::EINVAL);
+        }
+        let mut inner = self.inner.lock();
+        let ds = &mut inner.msqid_ds;
+        ds.perm.set(&new.perm);
+        ds.qbytes = new.qbytes;
+        ds.ctime = TimeSpec::get_epoch().sec;
+        // more room for blocked senders
+        inner.wake_all();
+        Ok(())
+    }
+
+    /// Send a message of `mtype` by process `pid`,
+    /// blocking while the queue is full unless `nowait`
+    pub async fn send(
+        &self,
+        thread: &Arc<Thread>,
+        mtype: isize,
+        data: Vec<u8>,
+        nowait: bool,
+        pid: usize,
+    ) -> Result<(), SysError> {
+        let mut msg = Some(Message { mtype, data });
+        self.wait_for(thread, move |inner| {
+            let len = msg.as_ref().unwrap().data.len();
+            let ds = &inner.msqid_ds;
+            if ds.cbytes + len > ds.qbytes || ds.qnum + 1 > ds.qbytes {
+                return if nowait {
+                    Some(Err(SysError::EAGAIN))
+                } else {
+                    None
+                };
+            }
+            inner.msgs.push_back(msg.take().unwrap());
+            let ds = &mut inner.msqid_ds;
+            ds.cbytes += len;
+            ds.qnum += 1;
+            ds.lspid = pid as u32;
+            ds.stime = TimeSpec::get_epoch().sec;
+            inner.wake_all();
+            Some(Ok(()))
+        })
+        .await
+    }
+
+    /// Receive a message selected by `selector` by process `pid`,
+    /// blocking until there is one unless `nowait`.
+    /// A message longer than `max_size` is truncated with `noerror`,
+    /// otherwise it stays in the queue and E2BIG is returned.
+    pub async fn receive(
+        &self,
+        thread: &Arc<Thread>,
+        selector: MsgSelector,
+        max_size: usize,
+        nowait: bool,
+        noerror: bool,
+        pid: usize,
+    ) -> Result<(isize, Vec<u8>), SysError> {
+        self.wait_for(thread, move |inner| {
+            let idx = match inner.find(selector) {
+                Some(idx) => idx,
+                None if nowait => return Some(Err(SysError::ENOMSG)),
+                None => return None,
+            };
+            if inner.msgs[idx].data.len() > max_size && !noerror {
+                return Some(Err(SysError::E2BIG));
+            }
+            let mut msg = inner.msgs.remove(idx).unwrap();
+            let ds = &mut inner.msqid_ds;
+            ds.cbytes -= msg.data.len();
+            ds.qnum -= 1;
+            ds.lrpid = pid as u32;
+            ds.rtime = TimeSpec::get_epoch().sec;
+            inner.wake_all();
+            msg.data.truncate(max_size);
+            Some(Ok((msg.mtype, msg.data)))
+        })
+        .await
+    }
+
+    /// Wait until `op` returns a result, interrupted by signals to `thread`.
+    /// `op` returns None to keep waiting for changes of the queue.
+    fn wait_for<'a, T, F>(
+        &'a self,
+        thread: &'a Arc<Thread>,
+        op: F,
+    ) -> impl Future<Output = Result<T, SysError>> + 'a
+    where
+        T: 'a,
+        F: FnMut(&mut MsgQueueInner) -> Option<Result<T, SysError>> + Unpin + 'a,
+    {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct MsgQueueFuture<'a, F> {
+            queue: &'a MsgQueue,
+            thread: &'a Arc<Thread>,
+            eventbus: Arc<Mutex<EventBus>>,
+            op: F,
+        }
+
+        impl<'a, T, F> Future for MsgQueueFuture<'a, F>
+        where
+            F: FnMut(&mut MsgQueueInner) -> Option<Result<T, SysError>> + Unpin,
+        {
+            type Output = Result<T, SysError>;
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                let this = &mut *self;
+                {
+                    let mut inner = this.queue.inner.lock();
+                    if inner.removed {
+                        return Poll::Ready(Err(SysError::EIDRM));
+                    }
+                    if let Some(res) = (this.op)(&mut inner) {
+                        return Poll::Ready(res);
+                    }
+                    inner.waiters.push(cx.waker().clone());
+                }
+                if this.thread.has_signal_to_handle() {
+                    return Poll::Ready(Err(SysError::EINTR));
+                }
+                let waker = cx.waker().clone();
+                this.eventbus.lock().subscribe(Box::new({
+                    move |_| {
+                        waker.wake_by_ref();
+                        true
+                    }
+                }));
+                Poll::Pending
+            }
+        }
+
+        MsgQueueFuture {
+            queue: self,
+            thread,
+            eventbus: thread.proc.lock().eventbus.clone(),
+            op,
+        }
+    }
+}
+
+impl MsgQueueInner {
+    /// Index of the message `selector` takes
+    fn find(&self, selector: MsgSelector) -> Option<usize> {
+        let mut msgs = self.msgs.iter();
+        match selector {
+            MsgSelector::First => msgs.next().map(|_| 0),
+            MsgSelector::Type(mtype) => msgs.position(|msg| msg.mtype == mtype),
+            MsgSelector::Except(mtype) => msgs.position(|msg| msg.mtype != mtype),
+            MsgSelector::AtMost(max) => msgs
+                .enumerate()
+                .filter(|(_, msg)| msg.mtype <= max)
+                // the first one of the lowest type
+                .min_by_key(|&(idx, msg)| (msg.mtype, idx))
+                .map(|(idx, _)| idx),
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
@@ -1,36 +1,25 @@
-use crate::sync::Semaphore;
-use crate::sync::SpinLock as Mutex;
-use crate::syscall::{SemBuf, SysError, SysResult, TimeSpec};
-use alloc::{collections::BTreeMap, sync::Arc, sync::Weak, vec::Vec};
-use bitflags::*;
-use core::ops::Index;
-use spin::RwLock;
+//! System V semaphores, see `semop(2)`
 
-bitflags! {
-    struct SemGetFlag: usize {
-        const CREAT = 1 << 9;
-        const EXCLUSIVE = 1 << 10;
-        const NO_WAIT = 1 << 11;
-    }
-}
+use super::{IpcIds, IpcObject, IpcPerm, IPCMNI};
+use crate::arch::timer::timer_now;
+use crate::process::Thread;
+use crate::sync::{EventBus, SpinNoIrqLock as Mutex};
+use crate::syscall::{SemBuf, SemFlags, SysError, TimeSpec};
+use crate::trap::NAIVE_TIMER;
+use alloc::boxed::Box;
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use spin::RwLock;
 
-// structure specifies the access permissions on the semaphore set
-// struct ipc_perm
-#[repr(C)]
-#[derive(Clone, Copy)]
-pub struct IpcPerm {
-    // key_t is int
-    pub key: u32,  /* Key supplied to semget(2) */
-    pub uid: u32,  /* Effective UID of owner */
-    pub gid: u32,  /* Effective GID of owner */
-    pub cuid: u32, /* Effective UID of creator */
-    pub cgid: u32, /* Effective GID of creator */
-    // mode_t is unsigned int
-    pub mode: u32,  /* Permissions */
-    pub __seq: u32, /* Sequence number */
-    pub __pad1: usize,
-    pub __pad2: usize,
-}
+/// Max semaphores per set
+pub const SEMMSL: usize = 32000;
+/// Max operations per semop
+pub const SEMOPM: usize = 500;
+/// Max value of a semaphore
+pub const SEMVMX: i32 = 32767;
 
 // semid data structure
 // struct semid_ds
@@ -45,104 +34,453 @@ pub struct SemidDs {
     pub nsems: usize, /* number of semaphores in set */
 }
 
+// struct seminfo
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SemInfo {
+    pub semmap: i32,
+    pub semmni: i32,
+    pub semmns: i32,
+    pub semmnu: i32,
+    pub semmsl: i32,
+    pub semopm: i32,
+    pub semume: i32,
+    pub semusz: i32,
+    pub semvmx: i32,
+    pub semaem: i32,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Sem {
+    val: u16,
+    /// PID of the last operation
+    pid: usize,
+    /// Number of operations waiting for an increase
+    ncnt: usize,
+    /// Number of operations waiting for zero
+    zcnt: usize,
+}
+
+struct SemArrayInner {
+    semid_ds: SemidDs,
+    sems: Vec<Sem>,
+    removed: bool,
+    /// Operations blocked on the set
+    waiters: Vec<Waker>,
+}
+
 /// A System V semaphore set
 pub struct SemArray {
-    pub semid_ds: Mutex<SemidDs>,
-    sems: Vec<Semaphore>,
+    inner: Mutex<SemArrayInner>,
 }
 
-impl Index<usize> for SemArray {
-    type Output = Semaphore;
-    fn index(&self, idx: usize) -> &Semaphore {
-        &self.sems[idx]
-    }
+lazy_static! {
+    /// Semaphore sets by id
+    static ref SEM_IDS: RwLock<IpcIds<SemArray>> = RwLock::new(IpcIds::default());
 }
 
-lazy_static! {
-    static ref KEY2SEM: RwLock<BTreeMap<u32, Weak<SemArray>>> = RwLock::new(BTreeMap::new());
+impl IpcObject for SemArray {
+    fn key(&self) -> u32 {
+        self.inner.lock().semid_ds.perm.key
+    }
 }
 
 impl SemArray {
-    // remove semaphores
-    pub fn remove(&self) {
-        let mut key2sem = KEY2SEM.write();
-        let key = self.semid_ds.lock().perm.key;
-        key2sem.remove(&key);
-        for sem in self.sems.iter() {
-            sem.remove();
+    /// Get the set with `key`, creating one with `nsems` semaphores if needed
+    /// and IPC_CREAT is in `flags`. Return its id.
+    pub fn get_or_create(key: u32, nsems: usize, flags: usize) -> Result<usize, SysError> {
+        if nsems > SEMMSL {
+            return Err(SysError::EINVAL);
+        }
+        SEM_IDS.write().get_or_create(
+            key,
+            flags,
+            |array| {
+                if nsems > array.nsems() {
+                    return Err(SysError::EINVAL);
+                }
+                Ok(())
+            },
+            |_, seq| {
+                if nsems == 0 {
+                    return Err(SysError::EINVAL);
+                }
+                Ok(Arc::new(SemArray {
+                    inner: Mutex::new(SemArrayInner {
+                        semid_ds: SemidDs {
+                            perm: IpcPerm::new(key, flags, seq),
+                            otime: 0,
+                            ctime: TimeSpec::get_epoch().sec,
+                            nsems,
+                            __pad1: 0,
+                            __pad2: 0,
+                        },
+                        sems: vec![Sem::default(); nsems],
+                        removed: false,
+                        waiters: Vec::new(),
+                    }),
+                }))
+            },
+        )
+    }
+
+    /// Get the set by `id`
+    pub fn get(id: usize) -> Option<Arc<Self>> {
+        SEM_IDS.read().get(id)
+    }
+
+    /// Get the set and its id by index, for SEM_STAT
+    pub fn get_by_index(idx: usize) -> Option<(usize, Arc<Self>)> {
+        SEM_IDS.read().get_by_index(idx)
+    }
+
+    /// Remove the set, failing blocked operations with EIDRM
+    pub fn remove(id: usize) {
+        if let Some(array) = SEM_IDS.write().remove(id) {
+            let mut inner = array.inner.lock();
+            inner.removed = true;
+            inner.wake_all();
         }
     }
 
-    pub fn otime(&self) {
-        self.semid_ds.lock().otime = TimeSpec::get_epoch().sec;
+    /// Limits and usage for IPC_INFO and SEM_INFO, with the highest index in use
+    pub fn info(usage: bool) -> (SemInfo, usize) {
+        let ids = SEM_IDS.read();
+        let mut info = SemInfo {
+            semmap: (IPCMNI * SEMMSL) as i32,
+            semmni: IPCMNI as i32,
+            semmns: (IPCMNI * SEMMSL) as i32,
+            semmnu: (IPCMNI * SEMMSL) as i32,
+            semmsl: SEMMSL as i32,
+            semopm: SEMOPM as i32,
+            semume: SEMOPM as i32,
+            semusz: 20,
+            semvmx: SEMVMX,
+            semaem: SEMVMX,
+        };
+        if usage {
+            info.semusz = ids.len() as i32;
+            info.semaem = ids.iter().map(|(_, array)| array.nsems()).sum::<usize>() as i32;
+        }
+        (info, ids.max_index())
+    }
+
+    /// Lines of `/proc/sysvipc/sem`
+    pub fn list() -> String {
+        let mut list = String::from("       key      semid perms      nsems   uid   gid  cuid  cgid      otime      ctime\n");
+        for (id, array) in SEM_IDS.read().iter() {
+            let ds = array.stat();
+            let perm = &ds.perm;
+            list += &format!(
+                "{:10} {:10}  {:4o} {:10} {:5} {:5} {:5} {:5} {:10} {:10}\n",
+                perm.key as i32,
+                id,
+                perm.mode,
+                ds.nsems,
+                perm.uid,
+                perm.gid,
+                perm.cuid,
+                perm.cgid,
+                ds.otime,
+                ds.ctime
+            );
+        }
+        list
     }
 
-    pub fn ctime(&self) {
-        self.semid_ds.lock().ctime = TimeSpec::get_epoch().sec;
+    pub fn nsems(&self) -> usize {
+        self.inner.lock().sems.len()
+    }
+
+    /// for IPC_STAT
+    pub fn stat(&self) -> SemidDs {
+        self.inner.lock().semid_ds
     }
 
     /// for IPC_SET
     /// see man semctl(2)
     pub fn set(&self, new: &SemidDs) {
-        let mut lock = self.semid_ds.lock();
-        lock.perm.uid = new.perm.uid;
-        lock.perm.gid = new.perm.gid;
-        lock.perm.mode = new.perm.mode & 0x1ff;
-    }
-
-    /// Get the semaphore array with `key`.
-    /// If not exist, create a new one with `nsems` elements.
-    pub fn get_or_create(mut key: u32, nsems: usize, flags: usize) -> Result<Arc<Self>, SysError> {
-        let mut key2sem = KEY2SEM.write();
-        let flag = SemGetFlag::from_bits_truncate(flags);
-
-        if key == 0 {
-            // IPC_PRIVATE
-            // find an empty key slot
-            key = (1u32..).find(|i| key2sem.get(i).is_none()).unwrap();
+        let mut inner = self.inner.lock();
+        inner.semid_ds.perm.set(&new.perm);
+        inner.semid_ds.ctime = TimeSpec::get_epoch().sec;
+    }
+
+    /// Value of semaphore `num`, for GETVAL
+    pub fn get_val(&self, num: usize) -> Result<usize, SysError> {
+        self.with_sem(num, |sem| sem.val as usize)
+    }
+
+    /// PID of the last operation on semaphore `num`, for GETPID
+    pub fn get_pid(&self, num: usize) -> Result<usize, SysError> {
+        self.with_sem(num, |sem| sem.pid)
+    }
+
+    /// Number of operations waiting for semaphore `num` to increase, for GETNCNT
+    pub fn get_ncnt(&self, num: usize) -> Result<usize, SysError> {
+        self.with_sem(num, |sem| sem.ncnt)
+    }
+
+    /// Number of operations waiting for semaphore `num` to be zero, for GETZCNT
+    pub fn get_zcnt(&self, num: usize) -> Result<usize, SysError> {
+        self.with_sem(num, |sem| sem.zcnt)
+    }
+
+    fn with_sem<T>(&self, num: usize, f: impl FnOnce(&Sem) -> T) -> Result<T, SysError> {
+        self.inner
+            .lock()
+            .sems
+            .get(num)
+            .map(f)
+            .ok_or(SysError::EINVAL)
+    }
+
+    /// Values of all semaphores, for GETALL
+    pub fn get_all(&self) -> Vec<u16> {
+        self.inner.lock().sems.iter().map(|sem| sem.val).collect()
+    }
+
+    /// Set the value of semaphore `num` by process `pid`, for SETVAL
+    pub fn set_val(&self, num: usize, val: i32, pid: usize) -> Result<(), SysError> {
+        if val < 0 || val > SEMVMX {
+            return Err(SysError::ERANGE);
+        }
+        let mut inner = self.inner.lock();
+        let sem = inner.sems.get_mut(num).ok_or(SysError::EINVAL)?;
+        sem.val = val as u16;
+        sem.pid = pid;
+        inner.semid_ds.ctime = TimeSpec::get_epoch().sec;
+        inner.wake_all();
+        Ok(())
+    }
+
+    /// Set the values of all semaphores by process `pid`, for SETALL
+    pub fn set_all(&self, vals: &[u16], pid: usize) -> Result<(), SysError> {
+        if vals.iter().any(|&val| val as i32 > SEMVMX) {
+            return Err(SysError::ERANGE);
+        }
+        let mut inner = self.inner.lock();
+        for (sem, &val) in inner.sems.iter_mut().zip(vals) {
+            sem.val = val;
+            sem.pid = pid;
+        }
+        inner.semid_ds.ctime = TimeSpec::get_epoch().sec;
+        inner.wake_all();
+        Ok(())
+    }
+
+    /// Perform `ops` atomically by process `pid`,
+    /// blocking until all of them can be done unless IPC_NOWAIT is in the blocked one.
+    /// Fail with EAGAIN after `deadline` of `timer_now`, or EINTR on signals to `thread`.
+    pub async fn op(
+        &self,
+        thread: &Arc<Thread>,
+        ops: &[SemBuf],
+        deadline: Option<Duration>,
+        pid: usize,
+    ) -> Result<(), SysError> {
+        let nsems = self.nsems();
+        if ops.iter().any(|op| op.num as usize >= nsems) {
+            return Err(SysError::EFBIG);
+        }
+        SemFuture {
+            array: self,
+            thread,
+            eventbus: thread.proc.lock().eventbus.clone(),
+            ops,
+            pid,
+            deadline,
+            timer_added: false,
+            blocked: None,
+        }
+        .await
+    }
+
+    /// Apply the SEM_UNDO adjustment `adj` of semaphore `num` when a process exits
+    fn undo(&self, num: usize, adj: i32) {
+        let mut inner = self.inner.lock();
+        if let Some(sem) = inner.sems.get_mut(num) {
+            sem.val = (sem.val as i32 + adj).max(0).min(SEMVMX) as u16;
+            inner.wake_all();
+        }
+    }
+}
+
+impl SemArrayInner {
+    /// Try to perform all `ops`, changing nothing if any can not be done.
+    /// Return the index of the operation to block on.
+    fn try_ops(&mut self, ops: &[SemBuf], pid: usize) -> Result<Option<usize>, SysError> {
+        let old: Vec<u16> = self.sems.iter().map(|sem| sem.val).collect();
+        for (i, op) in ops.iter().enumerate() {
+            let sem = &mut self.sems[op.num as usize];
+            let val = sem.val as i32 + op.op as i32;
+            let blocked = match op.op {
+                0 => sem.val != 0,
+                _ => val < 0,
+            };
+            if blocked || val > SEMVMX {
+                for (sem, &val) in self.sems.iter_mut().zip(old.iter()) {
+                    sem.val = val;
+                }
+                return if blocked {
+                    Ok(Some(i))
+                } else {
+                    Err(SysError::ERANGE)
+                };
+            }
+            sem.val = val as u16;
+        }
+        for op in ops.iter() {
+            self.sems[op.num as usize].pid = pid;
+        }
+        self.semid_ds.otime = TimeSpec::get_epoch().sec;
+        Ok(None)
+    }
+
+    /// Count an operation waiting on semaphore `num`, for zero or an increase
+    fn count_waiting(&mut self, num: usize, zero: bool, waiting: bool) {
+        let sem = &mut self.sems[num];
+        let count = if zero { &mut sem.zcnt } else { &mut sem.ncnt };
+        if waiting {
+            *count += 1;
         } else {
-            // check existence
-            if let Some(weak_array) = key2sem.get(&key) {
-                if let Some(array) = weak_array.upgrade() {
-                    if flag.contains(SemGetFlag::CREAT) && flag.contains(SemGetFlag::EXCLUSIVE) {
-                        // exclusive
-                        return Err(SysError::EEXIST);
-                    }
-                    return Ok(array);
+            *count -= 1;
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[must_use = "future does nothing unless polled/`await`-ed"]
+struct SemFuture<'a> {
+    array: &'a SemArray,
+    thread: &'a Arc<Thread>,
+    eventbus: Arc<Mutex<EventBus>>,
+    ops: &'a [SemBuf],
+    pid: usize,
+    deadline: Option<Duration>,
+    timer_added: bool,
+    /// Semaphore it is counted waiting on, and whether for zero
+    blocked: Option<(usize, bool)>,
+}
+
+impl SemFuture<'_> {
+    /// Stop being counted waiting
+    fn retract(&mut self, inner: &mut SemArrayInner) {
+        if let Some((num, zero)) = self.blocked.take() {
+            if !inner.removed {
+                inner.count_waiting(num, zero, false);
+            }
+        }
+    }
+}
+
+impl Future for SemFuture<'_> {
+    type Output = Result<(), SysError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let array = this.array;
+        {
+            let mut inner = array.inner.lock();
+            if inner.removed {
+                this.blocked = None;
+                return Poll::Ready(Err(SysError::EIDRM));
+            }
+            this.retract(&mut inner);
+            let idx = match inner.try_ops(this.ops, this.pid) {
+                Ok(None) => {
+                    inner.wake_all();
+                    return Poll::Ready(Ok(()));
                 }
+                Ok(Some(idx)) => idx,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+            let op = &this.ops[idx];
+            if SemFlags::from_bits_truncate(op.flags).contains(SemFlags::IPC_NOWAIT) {
+                return Poll::Ready(Err(SysError::EAGAIN));
             }
+            // timed out
+            if let Some(deadline) = this.deadline {
+                if timer_now() >= deadline {
+                    return Poll::Ready(Err(SysError::EAGAIN));
+                }
+            }
+            let blocked = (op.num as usize, op.op == 0);
+            inner.count_waiting(blocked.0, blocked.1, true);
+            this.blocked = Some(blocked);
+            inner.waiters.push(cx.waker().clone());
+        }
+        if this.thread.has_signal_to_handle() {
+            this.retract(&mut array.inner.lock());
+            return Poll::Ready(Err(SysError::EINTR));
+        }
+        if let Some(deadline) = this.deadline {
+            if !this.timer_added {
+                this.timer_added = true;
+                let waker = cx.waker().clone();
+                NAIVE_TIMER
+                    .lock()
+                    .add(deadline, Box::new(move |_| waker.wake()));
+            }
+        }
+        let waker = cx.waker().clone();
+        this.eventbus.lock().subscribe(Box::new({
+            move |_| {
+                waker.wake_by_ref();
+                true
+            }
+        }));
+        Poll::Pending
+    }
+}
+
+impl Drop for SemFuture<'_> {
+    fn drop(&mut self) {
+        if self.blocked.is_some() {
+            let array = self.array;
+            self.retract(&mut array.inner.lock());
         }
+    }
+}
+
+/// Semaphore undo adjustments of a process
+#[derive(Default)]
+pub struct SemProc {
+    /// Adjustments by set id and semaphore number, applied when process terminates
+    undos: BTreeMap<(usize, u16), i32>,
+}
 
-        // not found, create one
-        let mut semaphores = Vec::new();
-        for _ in 0..nsems {
-            semaphores.push(Semaphore::new(0));
-        }
-
-        // insert to global map
-        let array = Arc::new(SemArray {
-            semid_ds: Mutex::new(SemidDs {
-                perm: IpcPerm {
-                    key,
-                    uid: 0,
-                    gid: 0,
-                    cuid: 0,
-                    cgid: 0,
-                    // least significant 9 bits
-                    mode: (flags as u32) & 0x1ff,
-                    __seq: 0,
-                    __pad1: 0,
-                    __pad2: 0,
-                },
-                otime: 0,
-                ctime: TimeSpec::get_epoch().sec,
-                nsems,
-                __pad1: 0,
-                __pad2: 0,
-            }),
-            sems: semaphores,
-        });
-        key2sem.insert(key, Arc::downgrade(&array));
-        Ok(array)
+impl SemProc {
+    /// Record the undo of operation `op` on semaphore `num` of set `id`
+    pub fn add_undo(&mut self, id: usize, num: u16, op: i16) {
+        let adj = self.undos.entry((id, num)).or_insert(0);
+        *adj -= op as i32;
+        if *adj == 0 {
+            self.undos.remove(&(id, num));
+        }
+    }
+}
+
+/// Fork the semaphore table. Clear undo info.
+impl Clone for SemProc {
+    fn clone(&self) -> Self {
+        SemProc::default()
+    }
+}
+
+/// Auto perform semaphores undo on drop
+impl Drop for SemProc {
+    fn drop(&mut self) {
+        for (&(id, num), &adj) in self.undos.iter() {
+            debug!("semundo: id: {}, num: {}, adj: {}", id, num, adj);
+            // the set may have been removed
+            if let Some(array) = SemArray::get(id) {
+                array.undo(num as usize, adj);
+            }
+        }
     }
 }
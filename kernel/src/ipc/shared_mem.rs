@@ -1,47 +1,297 @@
-use crate::memory::{FrameAllocator, GlobalFrameAlloc};
-use crate::sync::Semaphore;
-use crate::sync::SpinLock as Mutex;
-use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, sync::Weak, vec::Vec};
-use core::cell::UnsafeCell;
-use lazy_static::lazy_static;
-use rcore_memory::memory_set::handler::{Shared, SharedGuard};
-use rcore_memory::{PhysAddr, VirtAddr};
+//! System V shared memory, see `shmop(2)`
+
+use super::{IpcIds, IpcObject, IpcPerm, IPCMNI};
+use crate::memory::GlobalFrameAlloc;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::{SysError, TimeSpec};
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use rcore_memory::memory_set::handler::SharedGuard;
+use rcore_memory::{VirtAddr, PAGE_SIZE};
 use spin::RwLock;
 
+/// Min size of a segment
+pub const SHMMIN: usize = 1;
+/// Max size of a segment
+pub const SHMMAX: usize = 1 << 30;
+/// Max total pages of all segments
+pub const SHMALL: usize = 1 << 20;
+/// The segment is destroyed after the last detach
+const SHM_DEST: u32 = 0o1000;
+
+/// Physical pages of a segment
+pub type ShmGuard = Arc<spin::Mutex<SharedGuard<GlobalFrameAlloc>>>;
+
+// struct shmid_ds
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ShmidDs {
+    pub perm: IpcPerm, /* Ownership and permissions */
+    pub segsz: usize,  /* Size of segment (bytes) */
+    pub atime: usize,  /* Last attach time */
+    pub dtime: usize,  /* Last detach time */
+    pub ctime: usize,  /* Last change time */
+    pub cpid: u32,     /* PID of creator */
+    pub lpid: u32,     /* PID of last shmat(2)/shmdt(2) */
+    pub nattch: usize, /* No. of current attaches */
+    __unused4: usize,
+    __unused5: usize,
+}
+
+// struct shminfo
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ShmInfo {
+    pub shmmax: usize,
+    pub shmmin: usize,
+    pub shmmni: usize,
+    pub shmseg: usize,
+    pub shmall: usize,
+    __unused: [usize; 4],
+}
+
+// struct shm_info
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ShmUsage {
+    pub used_ids: i32,
+    pub shm_tot: usize,
+    pub shm_rss: usize,
+    pub shm_swp: usize,
+    pub swap_attempts: usize,
+    pub swap_successes: usize,
+}
+
+/// A System V shared memory segment
+pub struct ShmSegment {
+    id: usize,
+    guard: ShmGuard,
+    shmid_ds: Mutex<ShmidDs>,
+}
+
 lazy_static! {
-    static ref KEY2SHM: RwLock<BTreeMap<usize, Weak<spin::Mutex<SharedGuard<GlobalFrameAlloc>>>>> =
-        RwLock::new(BTreeMap::new());
+    /// Shared memory segments by id
+    static ref SHM_IDS: RwLock<IpcIds<ShmSegment>> = RwLock::new(IpcIds::default());
 }
 
-#[derive(Clone)]
-pub struct ShmIdentifier {
-    pub addr: VirtAddr,
-    pub shared_guard: Arc<spin::Mutex<SharedGuard<GlobalFrameAlloc>>>,
+impl IpcObject for ShmSegment {
+    fn key(&self) -> u32 {
+        self.shmid_ds.lock().perm.key
+    }
 }
 
-impl ShmIdentifier {
-    pub fn set_addr(&mut self, addr: VirtAddr) {
-        self.addr = addr;
+impl ShmSegment {
+    /// Get the segment with `key`, creating one of `size` bytes by process `pid`
+    /// if needed and IPC_CREAT is in `flags`. Return its id.
+    pub fn get_or_create(
+        key: u32,
+        size: usize,
+        flags: usize,
+        pid: usize,
+    ) -> Result<usize, SysError> {
+        SHM_IDS.write().get_or_create(
+            key,
+            flags,
+            |segment| {
+                if size > segment.size() {
+                    return Err(SysError::EINVAL);
+                }
+                Ok(())
+            },
+            |id, seq| {
+                if size < SHMMIN || size > SHMMAX {
+                    return Err(SysError::EINVAL);
+                }
+                let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+                Ok(Arc::new(ShmSegment {
+                    id,
+                    guard: Arc::new(spin::Mutex::new(SharedGuard::new_with_size(
+                        GlobalFrameAlloc,
+                        pages * PAGE_SIZE,
+                    ))),
+                    shmid_ds: Mutex::new(ShmidDs {
+                        perm: IpcPerm::new(key, flags, seq),
+                        segsz: size,
+                        atime: 0,
+                        dtime: 0,
+                        ctime: TimeSpec::get_epoch().sec,
+                        cpid: pid as u32,
+                        lpid: 0,
+                        nattch: 0,
+                        __unused4: 0,
+                        __unused5: 0,
+                    }),
+                }))
+            },
+        )
+    }
+
+    /// Get the segment by `id`
+    pub fn get(id: usize) -> Option<Arc<Self>> {
+        SHM_IDS.read().get(id)
+    }
+
+    /// Get the segment and its id by index, for SHM_STAT
+    pub fn get_by_index(idx: usize) -> Option<(usize, Arc<Self>)> {
+        SHM_IDS.read().get_by_index(idx)
+    }
+
+    /// Mark the segment to be destroyed. It is removed after the last detach,
+    /// and can not be found by key from now on.
+    pub fn remove(&self) {
+        let unused = {
+            let mut ds = self.shmid_ds.lock();
+            ds.perm.mode |= SHM_DEST;
+            ds.perm.key = 0;
+            ds.nattch == 0
+        };
+        if unused {
+            SHM_IDS.write().remove(self.id);
+        }
+    }
+
+    /// Limits for IPC_INFO, with the highest index in use
+    pub fn info() -> (ShmInfo, usize) {
+        let info = ShmInfo {
+            shmmax: SHMMAX,
+            shmmin: SHMMIN,
+            shmmni: IPCMNI,
+            shmseg: IPCMNI,
+            shmall: SHMALL,
+            ..ShmInfo::default()
+        };
+        (info, SHM_IDS.read().max_index())
+    }
+
+    /// Usage for SHM_INFO, with the highest index in use
+    pub fn usage() -> (ShmUsage, usize) {
+        let ids = SHM_IDS.read();
+        let usage = ShmUsage {
+            used_ids: ids.len() as i32,
+            shm_tot: ids
+                .iter()
+                .map(|(_, segment)| segment.size() / PAGE_SIZE)
+                .sum(),
+            ..ShmUsage::default()
+        };
+        (usage, ids.max_index())
+    }
+
+    /// Lines of `/proc/sysvipc/shm`
+    pub fn list() -> String {
+        let mut list = String::from("       key      shmid perms                  size  cpid  lpid nattch   uid   gid  cuid  cgid      atime      dtime      ctime\n");
+        for (id, segment) in SHM_IDS.read().iter() {
+            let ds = segment.stat();
+            let perm = &ds.perm;
+            list += &format!(
+                "{:10} {:10}  {:4o} {:21} {:5} {:5} {:6} {:5} {:5} {:5} {:5} {:10} {:10} {:10}\n",
+                perm.key as i32,
+                id,
+                perm.mode,
+                ds.segsz,
+                ds.cpid,
+                ds.lpid,
+                ds.nattch,
+                perm.uid,
+                perm.gid,
+                perm.cuid,
+                perm.cgid,
+                ds.atime,
+                ds.dtime,
+                ds.ctime
+            );
+        }
+        list
     }
 
-    pub fn new_shared_guard(
-        key: usize,
-        memsize: usize,
-    ) -> Arc<spin::Mutex<SharedGuard<GlobalFrameAlloc>>> {
-        let mut key2shm = KEY2SHM.write();
+    /// Size of the pages of the segment
+    pub fn size(&self) -> usize {
+        self.guard.lock().size
+    }
+
+    pub fn guard(&self) -> ShmGuard {
+        self.guard.clone()
+    }
+
+    /// for IPC_STAT
+    pub fn stat(&self) -> ShmidDs {
+        *self.shmid_ds.lock()
+    }
+
+    /// for IPC_SET
+    pub fn set(&self, new: &ShmidDs) {
+        let mut ds = self.shmid_ds.lock();
+        ds.perm.set(&new.perm);
+        ds.ctime = TimeSpec::get_epoch().sec;
+    }
+
+    /// Count an attach by process `pid`
+    pub fn attach(&self, pid: usize) {
+        let mut ds = self.shmid_ds.lock();
+        ds.nattch += 1;
+        ds.atime = TimeSpec::get_epoch().sec;
+        ds.lpid = pid as u32;
+    }
+
+    /// Count a detach by process `pid`, removing the segment if it is the last one after IPC_RMID
+    pub fn detach(&self, pid: usize) {
+        // not holding the lock of the segment, as SHM_IDS is locked before it
+        let destroy = {
+            let mut ds = self.shmid_ds.lock();
+            ds.nattch -= 1;
+            ds.dtime = TimeSpec::get_epoch().sec;
+            ds.lpid = pid as u32;
+            ds.nattch == 0 && ds.perm.mode & SHM_DEST != 0
+        };
+        if destroy {
+            SHM_IDS.write().remove(self.id);
+        }
+    }
+}
+
+/// Shared memory attaches of a process
+#[derive(Default)]
+pub struct ShmProc {
+    /// Attached segments by address
+    attaches: BTreeMap<VirtAddr, Arc<ShmSegment>>,
+    /// Pid of the process, for the last attach or detach
+    pid: usize,
+}
+
+impl ShmProc {
+    /// Record `segment` attached at `addr` by process `pid`
+    pub fn attach(&mut self, addr: VirtAddr, segment: Arc<ShmSegment>, pid: usize) {
+        segment.attach(pid);
+        self.pid = pid;
+        self.attaches.insert(addr, segment);
+    }
+
+    /// Forget the segment attached at `addr` by process `pid`, returning its size
+    pub fn detach(&mut self, addr: VirtAddr, pid: usize) -> Option<usize> {
+        let segment = self.attaches.remove(&addr)?;
+        self.pid = pid;
+        segment.detach(pid);
+        Some(segment.size())
+    }
+}
+
+/// Fork the attaches, which are inherited by the child
+impl Clone for ShmProc {
+    fn clone(&self) -> Self {
+        for segment in self.attaches.values() {
+            segment.attach(self.pid);
+        }
+        ShmProc {
+            attaches: self.attaches.clone(),
+            pid: self.pid,
+        }
+    }
+}
 
-        // found in the map
-        if let Some(weak_guard) = key2shm.get(&key) {
-            if let Some(guard) = weak_guard.upgrade() {
-                return guard;
-            }
+/// Detach all segments on exit and exec
+impl Drop for ShmProc {
+    fn drop(&mut self) {
+        for segment in self.attaches.values() {
+            segment.detach(self.pid);
         }
-        let mut shared_guard = Arc::new(spin::Mutex::new(SharedGuard::new_with_size(
-            GlobalFrameAlloc,
-            memsize,
-        )));
-        // insert to global map
-        key2shm.insert(key, Arc::downgrade(&shared_guard));
-        shared_guard
     }
 }
@@ -0,0 +1,235 @@
+//! DHCPv4 client of network cards, enabled by the `dhcp` feature
+//!
+//! Each card gets a kernel task, which polls the smoltcp client when it asks to,
+//! then applies the lease: the address to the card, the default route to the routing table,
+//! and the nameservers to `/etc/resolv.conf`, where resolvers look for them.
+//! They are removed when the lease is lost.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use rcore_fs::vfs::{FileType, FsError, INode};
+use smoltcp::dhcp::{Dhcpv4Client, Dhcpv4Config};
+use smoltcp::socket::{RawPacketMetadata, RawSocketBuffer};
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+
+use crate::arch::timer::timer_now;
+use crate::drivers::{NetDriver, NET_DRIVERS};
+use crate::fs::ROOT_INODE;
+use crate::net::{add_route, del_route, RouteEntry, SOCKETS};
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::trap::NAIVE_TIMER;
+
+/// Max time between polls, as replies are only handled when the client is polled
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// Nameservers of the leases, by the index of cards in `NET_DRIVERS`
+    static ref NAMESERVERS: Mutex<BTreeMap<usize, Vec<Ipv4Address>>> = Mutex::new(BTreeMap::new());
+}
+
+/// What a lease gave to a card
+struct Lease {
+    cidr: Ipv4Cidr,
+    router: Option<Ipv4Address>,
+    /// When the lease ends unless renewed, of `timer_now`
+    expires: Duration,
+}
+
+/// Start the client of the card at `index` of `NET_DRIVERS`
+pub fn start(index: usize) {
+    executor::spawn(run(index));
+}
+
+async fn run(index: usize) {
+    let iface = NET_DRIVERS.read()[index].clone();
+    let mut client = {
+        let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 1], vec![0; 900]);
+        let tx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 1], vec![0; 600]);
+        Dhcpv4Client::new(&mut SOCKETS.lock(), rx_buffer, tx_buffer, now())
+    };
+    info!("dhcp: requesting an address for {}", iface.get_ifname());
+    let mut lease = None;
+    loop {
+        iface.poll();
+        if let Some(config) = iface.poll_dhcp(&mut client) {
+            // the client renews at half of the lease
+            let renew = Duration::from_millis(client.next_poll(now()).total_millis());
+            lease = apply(index, &iface, config, timer_now() + renew * 2, lease.take());
+        } else if let Some(old) = lease.take() {
+            // the client starts over without telling when renewing fails
+            if timer_now() >= old.expires {
+                info!("dhcp: lease of {} expired", iface.get_ifname());
+                forget(index, &iface, old);
+            } else {
+                lease = Some(old);
+            }
+        }
+        let delay = client.next_poll(now()).total_millis();
+        Delay::new(Duration::from_millis(delay).min(MAX_DELAY)).await;
+    }
+}
+
+/// Apply the lease of `config` in place of the `old` one, which is lost if it has no address
+fn apply(
+    index: usize,
+    iface: &Arc<dyn NetDriver>,
+    config: Dhcpv4Config,
+    expires: Duration,
+    old: Option<Lease>,
+) -> Option<Lease> {
+    let cidr = match config.address {
+        Some(cidr) => cidr,
+        None => {
+            if let Some(old) = old {
+                info!("dhcp: {} lost its lease", iface.get_ifname());
+                forget(index, iface, old);
+            }
+            return None;
+        }
+    };
+    // replace the ipv4 address, keeping the ipv6 ones
+    let mut addrs = iface.get_ip_addresses();
+    if !addrs.contains(&IpCidr::Ipv4(cidr)) {
+        info!("dhcp: {} got address {}", iface.get_ifname(), cidr);
+        match addrs.iter().position(|addr| match addr.address() {
+            IpAddress::Ipv4(_) => true,
+            _ => false,
+        }) {
+            Some(i) => addrs[i] = IpCidr::Ipv4(cidr),
+            None => addrs.insert(0, IpCidr::Ipv4(cidr)),
+        }
+        if let Err(err) = iface.set_ip_addresses(addrs) {
+            warn!("dhcp: failed to set the address: {:?}", err);
+        }
+    }
+    match config.router {
+        Some(router) => {
+            let route = RouteEntry {
+                dst: default_dst(),
+                gateway: Some(IpAddress::Ipv4(router)),
+                iface: index,
+                metric: 0,
+            };
+            if let Err(err) = add_route(route, true) {
+                warn!("dhcp: failed to add the default route: {:?}", err);
+            }
+        }
+        None => {
+            if let Some(router) = old.and_then(|old| old.router) {
+                del_default_route(index, router);
+            }
+        }
+    }
+    let servers: Vec<Ipv4Address> = config.dns_servers.iter().filter_map(|s| *s).collect();
+    let changed = if servers.is_empty() {
+        NAMESERVERS.lock().remove(&index).is_some()
+    } else {
+        NAMESERVERS.lock().insert(index, servers.clone()) != Some(servers)
+    };
+    if changed {
+        write_resolv_conf();
+    }
+    Some(Lease {
+        cidr,
+        router: config.router,
+        expires,
+    })
+}
+
+/// Remove what the lost `lease` gave to the card at `index`
+fn forget(index: usize, iface: &Arc<dyn NetDriver>, lease: Lease) {
+    let cidr = IpCidr::Ipv4(lease.cidr);
+    let addrs = iface.get_ip_addresses();
+    iface
+        .set_ip_addresses(addrs.into_iter().filter(|addr| *addr != cidr).collect())
+        .ok();
+    if let Some(router) = lease.router {
+        del_default_route(index, router);
+    }
+    if NAMESERVERS.lock().remove(&index).is_some() {
+        write_resolv_conf();
+    }
+}
+
+fn default_dst() -> IpCidr {
+    IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0)
+}
+
+fn del_default_route(index: usize, router: Ipv4Address) {
+    let gateway = Some(IpAddress::Ipv4(router));
+    // it may have been removed by rtnetlink
+    del_route(default_dst(), gateway, Some(index), Some(0)).ok();
+}
+
+/// Write the nameservers of all leases to `/etc/resolv.conf`, replacing its content
+fn write_resolv_conf() {
+    let mut content = String::new();
+    for servers in NAMESERVERS.lock().values() {
+        for server in servers {
+            writeln!(content, "nameserver {}", server).unwrap();
+        }
+    }
+    let result = find_or_create(&ROOT_INODE, "etc", FileType::Dir, 0o755)
+        .and_then(|etc| find_or_create(&etc, "resolv.conf", FileType::File, 0o644))
+        .and_then(|file| {
+            file.resize(0)?;
+            file.write_at(0, content.as_bytes())
+        });
+    if let Err(err) = result {
+        warn!("dhcp: failed to write /etc/resolv.conf: {:?}", err);
+    }
+}
+
+fn find_or_create(
+    dir: &Arc<dyn INode>,
+    name: &str,
+    type_: FileType,
+    mode: u32,
+) -> Result<Arc<dyn INode>, FsError> {
+    match dir.find(name) {
+        Err(FsError::EntryNotFound) => dir.create(name, type_, mode),
+        result => result,
+    }
+}
+
+fn now() -> Instant {
+    Instant::from_millis(crate::trap::uptime_msec() as i64)
+}
+
+/// Sleep of a kernel task, which has no thread to sleep on
+struct Delay {
+    deadline: Duration,
+}
+
+impl Delay {
+    fn new(duration: Duration) -> Self {
+        Delay {
+            deadline: timer_now() + duration,
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if timer_now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let waker = cx.waker().clone();
+        NAIVE_TIMER
+            .lock()
+            .add(self.deadline, Box::new(move |_| waker.wake()));
+        Poll::Pending
+    }
+}
@@ -1,5 +1,20 @@
+pub mod dhcp;
+mod options;
+mod packet;
+#[cfg(feature = "pcap")]
+pub mod pcap;
+mod port;
+mod route;
 mod structs;
 mod test;
+mod unix;
+mod wait;
 
+pub use self::options::*;
+pub use self::packet::*;
+pub use self::port::*;
+pub use self::route::*;
 pub use self::structs::*;
 pub use self::test::server;
+pub use self::unix::*;
+pub use self::wait::*;
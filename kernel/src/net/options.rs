@@ -0,0 +1,158 @@
+//! Typed socket options of setsockopt and getsockopt
+//!
+//! The syscalls convert options from and to user memory,
+//! and sockets only see the typed values.
+
+use core::time::Duration;
+
+use crate::arch::timer::timer_now;
+use crate::syscall::{SocketType, SysError};
+
+/// Name of a socket option, by which it is got
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocketOptionName {
+    ReuseAddr,
+    ReusePort,
+    KeepAlive,
+    Broadcast,
+    RecvTimeout,
+    SendTimeout,
+    Linger,
+    SendBuffer,
+    RecvBuffer,
+    Error,
+    Type,
+    PassCred,
+    Ttl,
+    HeaderIncluded,
+    PacketInfo,
+    V6Only,
+    NoDelay,
+    KeepIdle,
+    NonBlock,
+}
+
+/// A socket option with its value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocketOption {
+    /// SO_REUSEADDR
+    ReuseAddr(bool),
+    /// SO_REUSEPORT
+    ReusePort(bool),
+    /// SO_KEEPALIVE
+    KeepAlive(bool),
+    /// SO_BROADCAST
+    Broadcast(bool),
+    /// SO_RCVTIMEO, `None` to block without a timeout
+    RecvTimeout(Option<Duration>),
+    /// SO_SNDTIMEO, `None` to block without a timeout
+    SendTimeout(Option<Duration>),
+    /// SO_LINGER, the seconds to linger if it is on
+    Linger(Option<u32>),
+    /// SO_SNDBUF
+    SendBuffer(usize),
+    /// SO_RCVBUF
+    RecvBuffer(usize),
+    /// SO_ERROR, the errno of the pending error or 0, read only
+    Error(usize),
+    /// SO_TYPE, read only
+    Type(SocketType),
+    /// SO_PASSCRED
+    PassCred(bool),
+    /// IP_TTL
+    Ttl(u8),
+    /// IP_HDRINCL
+    HeaderIncluded(bool),
+    /// IP_PKTINFO
+    PacketInfo(bool),
+    /// IPV6_V6ONLY
+    V6Only(bool),
+    /// TCP_NODELAY
+    NoDelay(bool),
+    /// TCP_KEEPIDLE, in seconds
+    KeepIdle(u32),
+    /// O_NONBLOCK of the socket file, set by fcntl, FIONBIO and SOCK_NONBLOCK
+    NonBlock(bool),
+}
+
+impl SocketOption {
+    pub fn name(&self) -> SocketOptionName {
+        use SocketOption::*;
+        match self {
+            ReuseAddr(_) => SocketOptionName::ReuseAddr,
+            ReusePort(_) => SocketOptionName::ReusePort,
+            KeepAlive(_) => SocketOptionName::KeepAlive,
+            Broadcast(_) => SocketOptionName::Broadcast,
+            RecvTimeout(_) => SocketOptionName::RecvTimeout,
+            SendTimeout(_) => SocketOptionName::SendTimeout,
+            Linger(_) => SocketOptionName::Linger,
+            SendBuffer(_) => SocketOptionName::SendBuffer,
+            RecvBuffer(_) => SocketOptionName::RecvBuffer,
+            Error(_) => SocketOptionName::Error,
+            Type(_) => SocketOptionName::Type,
+            PassCred(_) => SocketOptionName::PassCred,
+            Ttl(_) => SocketOptionName::Ttl,
+            HeaderIncluded(_) => SocketOptionName::HeaderIncluded,
+            PacketInfo(_) => SocketOptionName::PacketInfo,
+            V6Only(_) => SocketOptionName::V6Only,
+            NoDelay(_) => SocketOptionName::NoDelay,
+            KeepIdle(_) => SocketOptionName::KeepIdle,
+            NonBlock(_) => SocketOptionName::NonBlock,
+        }
+    }
+}
+
+/// Options of the socket level, kept the same way by the smoltcp-backed sockets
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SocketOptions {
+    pub reuse_addr: bool,
+    pub reuse_port: bool,
+    pub broadcast: bool,
+    pub recv_timeout: Option<Duration>,
+    pub send_timeout: Option<Duration>,
+    /// smoltcp closes connections in the background, so this is only reported
+    pub linger: Option<u32>,
+    /// Operations fail with EAGAIN instead of waiting
+    pub nonblock: bool,
+}
+
+impl SocketOptions {
+    pub fn set(&mut self, option: SocketOption) -> Result<usize, SysError> {
+        match option {
+            SocketOption::ReuseAddr(on) => self.reuse_addr = on,
+            SocketOption::ReusePort(on) => self.reuse_port = on,
+            SocketOption::Broadcast(on) => self.broadcast = on,
+            SocketOption::RecvTimeout(timeout) => self.recv_timeout = timeout,
+            SocketOption::SendTimeout(timeout) => self.send_timeout = timeout,
+            SocketOption::Linger(linger) => self.linger = linger,
+            SocketOption::NonBlock(on) => self.nonblock = on,
+            SocketOption::Error(_) | SocketOption::Type(_) => return Err(SysError::EINVAL),
+            _ => return Err(SysError::ENOPROTOOPT),
+        }
+        Ok(0)
+    }
+
+    pub fn get(&self, name: SocketOptionName) -> Result<SocketOption, SysError> {
+        match name {
+            SocketOptionName::ReuseAddr => Ok(SocketOption::ReuseAddr(self.reuse_addr)),
+            SocketOptionName::ReusePort => Ok(SocketOption::ReusePort(self.reuse_port)),
+            SocketOptionName::Broadcast => Ok(SocketOption::Broadcast(self.broadcast)),
+            SocketOptionName::RecvTimeout => Ok(SocketOption::RecvTimeout(self.recv_timeout)),
+            SocketOptionName::SendTimeout => Ok(SocketOption::SendTimeout(self.send_timeout)),
+            SocketOptionName::Linger => Ok(SocketOption::Linger(self.linger)),
+            SocketOptionName::NonBlock => Ok(SocketOption::NonBlock(self.nonblock)),
+            _ => Err(SysError::ENOPROTOOPT),
+        }
+    }
+}
+
+/// When a blocking operation under `timeout` started now gives up.
+/// A timeout too long to be reached is no timeout.
+pub fn deadline(timeout: Option<Duration>) -> Option<Duration> {
+    timeout.and_then(|timeout| timer_now().checked_add(timeout))
+}
+
+/// Whether `deadline` has passed
+pub fn expired(deadline: Option<Duration>) -> bool {
+    deadline.map_or(false, |deadline| timer_now() >= deadline)
+}
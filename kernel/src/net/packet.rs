@@ -0,0 +1,309 @@
+//! Packet sockets of AF_PACKET
+//!
+//! Network interfaces give every frame they receive and transmit to `tap`,
+//! which queues it to the packet sockets that want its interface and ethernet type.
+//! SOCK_RAW sockets see whole frames, and SOCK_DGRAM ones see the payloads.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::time::Duration;
+
+use smoltcp::wire::{EthernetFrame, EthernetProtocol};
+
+use crate::drivers::net::ETHERNET_HEADER_LEN;
+use crate::drivers::{NET_DRIVERS, SOCKET_ACTIVITY};
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::*;
+
+use super::{
+    deadline, expired, wait_queue, Endpoint, LinkLevelEndpoint, QueueWakers, Socket, SocketFuture,
+    SocketOption, SocketOptionName, SocketOptions, WaitQueue,
+};
+
+/// Max bytes of frames queued to a socket, later ones are dropped
+const PACKET_RECVBUF: usize = 256 * 1024;
+
+/// The ethernet type receiving all frames
+pub const ETH_P_ALL: u16 = 0x0003;
+
+// packet types of received frames
+const PACKET_HOST: u8 = 0;
+const PACKET_BROADCAST: u8 = 1;
+const PACKET_MULTICAST: u8 = 2;
+const PACKET_OUTGOING: u8 = 4;
+
+lazy_static! {
+    /// Queues of all packet sockets, which are gone when their sockets are closed
+    static ref PACKET_QUEUES: Mutex<Vec<Weak<Mutex<PacketQueue>>>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug)]
+struct PacketQueue {
+    frames: VecDeque<(Vec<u8>, LinkLevelEndpoint)>,
+    /// Bytes of `frames`
+    len: usize,
+    /// Ethernet type of frames to receive, ETH_P_ALL for all of them, 0 for none
+    protocol: u16,
+    /// Index of the bound interface in `NET_DRIVERS`, `None` for all of them
+    interface_index: Option<usize>,
+    /// Tasks waiting for frames
+    wakers: QueueWakers,
+}
+
+impl WaitQueue for PacketQueue {
+    fn wakers(&mut self) -> &mut QueueWakers {
+        &mut self.wakers
+    }
+}
+
+impl PacketQueue {
+    fn accepts(&self, interface_index: usize, protocol: u16) -> bool {
+        self.interface_index
+            .map_or(true, |index| index == interface_index)
+            && (self.protocol == ETH_P_ALL || (self.protocol != 0 && self.protocol == protocol))
+    }
+}
+
+/// Renumber the interfaces packet sockets are bound to, after the one at `index`
+/// is removed from `NET_DRIVERS`. The sockets bound to it get no more frames,
+/// and fail to send with ENXIO.
+pub fn unbind_iface(index: usize) {
+    for queue in PACKET_QUEUES
+        .lock()
+        .iter()
+        .filter_map(|queue| queue.upgrade())
+    {
+        let mut queue = queue.lock();
+        queue.interface_index = match queue.interface_index {
+            Some(i) if i == index => Some(usize::MAX),
+            Some(i) if i > index => Some(i - 1),
+            other => other,
+        };
+    }
+}
+
+/// Give a frame received or transmitted by the interface at `interface_index` of `NET_DRIVERS`
+/// to the packet sockets, and to the pcap capture if it is enabled
+pub fn tap(interface_index: usize, frame: &[u8], outgoing: bool) {
+    #[cfg(feature = "pcap")]
+    super::pcap::capture(frame);
+
+    let header = match EthernetFrame::new_checked(frame) {
+        Ok(header) => header,
+        Err(_) => return,
+    };
+    let protocol = u16::from(header.ethertype());
+    let packet_type = if outgoing {
+        PACKET_OUTGOING
+    } else if header.dst_addr().is_broadcast() {
+        PACKET_BROADCAST
+    } else if header.dst_addr().is_multicast() {
+        PACKET_MULTICAST
+    } else {
+        PACKET_HOST
+    };
+    let endpoint = LinkLevelEndpoint {
+        interface_index: Some(interface_index),
+        protocol,
+        packet_type,
+        address: header.src_addr(),
+    };
+
+    let mut queued = false;
+    PACKET_QUEUES.lock().retain(|queue| match queue.upgrade() {
+        Some(queue) => {
+            let mut queue = queue.lock();
+            if queue.accepts(interface_index, protocol) && queue.len + frame.len() <= PACKET_RECVBUF
+            {
+                queue.frames.push_back((frame.to_vec(), endpoint.clone()));
+                queue.len += frame.len();
+                queue.wakers.wake();
+                queued = true;
+            }
+            true
+        }
+        None => false,
+    });
+    if queued {
+        SOCKET_ACTIVITY.notify_all();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PacketSocketState {
+    /// SOCK_RAW or SOCK_DGRAM
+    socket_type: SocketType,
+    queue: Arc<Mutex<PacketQueue>>,
+    /// Shared by the clones of the socket
+    options: Arc<Mutex<SocketOptions>>,
+}
+
+impl PacketSocketState {
+    /// A socket receiving frames of ethernet type `protocol` from all interfaces
+    pub fn new(socket_type: SocketType, protocol: u16) -> Self {
+        let queue = Arc::new(Mutex::new(PacketQueue {
+            frames: VecDeque::new(),
+            len: 0,
+            protocol,
+            interface_index: None,
+            wakers: QueueWakers::default(),
+        }));
+        PACKET_QUEUES.lock().push(Arc::downgrade(&queue));
+        PacketSocketState {
+            socket_type,
+            queue,
+            options: Arc::new(Mutex::new(SocketOptions::default())),
+        }
+    }
+
+    fn bound_endpoint(&self) -> LinkLevelEndpoint {
+        let queue = self.queue.lock();
+        LinkLevelEndpoint::new(queue.interface_index, queue.protocol)
+    }
+
+    /// Take a frame from `queue`, or None to wait for one
+    fn try_read(
+        &self,
+        queue: &mut PacketQueue,
+        data: &mut [u8],
+        deadline: Option<Duration>,
+        nonblock: bool,
+    ) -> Option<(SysResult, Endpoint)> {
+        if let Some((frame, endpoint)) = queue.frames.pop_front() {
+            queue.len -= frame.len();
+            let frame = match self.socket_type {
+                SocketType::Datagram => &frame[ETHERNET_HEADER_LEN..],
+                _ => &frame[..],
+            };
+            // the rest of a frame which does not fit is dropped
+            let len = min(data.len(), frame.len());
+            data[..len].copy_from_slice(&frame[..len]);
+            return Some((Ok(len), Endpoint::LinkLevel(endpoint)));
+        }
+        if nonblock || expired(deadline) {
+            let bound = LinkLevelEndpoint::new(queue.interface_index, queue.protocol);
+            return Some((Err(SysError::EAGAIN), Endpoint::LinkLevel(bound)));
+        }
+        None
+    }
+}
+
+impl Socket for PacketSocketState {
+    fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        let (deadline, nonblock) = {
+            let options = self.options.lock();
+            (deadline(options.recv_timeout), options.nonblock)
+        };
+        let mut queue = self.queue.lock();
+        loop {
+            if let Some(result) = self.try_read(&mut queue, data, deadline, nonblock) {
+                return result;
+            }
+            queue = SOCKET_ACTIVITY.wait(queue);
+        }
+    }
+
+    fn async_read<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint)> {
+        let (deadline, nonblock) = {
+            let options = self.options.lock();
+            (deadline(options.recv_timeout), nonblock || options.nonblock)
+        };
+        Box::pin(wait_queue(&self.queue, deadline, move |queue| {
+            self.try_read(queue, data, deadline, nonblock)
+        }))
+    }
+
+    fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
+        let bound = self.bound_endpoint();
+        let endpoint = match sendto_endpoint {
+            Some(Endpoint::LinkLevel(endpoint)) => endpoint,
+            Some(_) => return Err(SysError::EINVAL),
+            // a SOCK_DGRAM socket needs the destination of the frame
+            None if self.socket_type == SocketType::Datagram => return Err(SysError::EDESTADDRREQ),
+            None => bound.clone(),
+        };
+        let ifaces = NET_DRIVERS.read();
+        let iface = endpoint
+            .interface_index
+            .or(bound.interface_index)
+            .and_then(|index| ifaces.get(index))
+            .ok_or(SysError::ENXIO)?;
+        let sent = match self.socket_type {
+            SocketType::Datagram => {
+                let protocol = match endpoint.protocol {
+                    0 => bound.protocol,
+                    protocol => protocol,
+                };
+                let mut frame = vec![0u8; ETHERNET_HEADER_LEN + data.len()];
+                let mut header = EthernetFrame::new_unchecked(&mut frame[..]);
+                header.set_dst_addr(endpoint.address);
+                header.set_src_addr(iface.get_mac());
+                header.set_ethertype(EthernetProtocol::from(protocol));
+                header.payload_mut().copy_from_slice(data);
+                iface.send(&frame).map(|_| data.len())
+            }
+            _ => iface.send(data),
+        };
+        sent.ok_or(SysError::ENOBUFS)
+    }
+
+    fn poll(&self) -> (bool, bool, bool) {
+        let queue = self.queue.lock();
+        (!queue.frames.is_empty(), true, false)
+    }
+
+    fn connect(&mut self, _endpoint: Endpoint) -> SysResult {
+        Err(SysError::EOPNOTSUPP)
+    }
+
+    fn bind(&mut self, endpoint: Endpoint) -> SysResult {
+        if let Endpoint::LinkLevel(endpoint) = endpoint {
+            if let Some(index) = endpoint.interface_index {
+                if index >= NET_DRIVERS.read().len() {
+                    return Err(SysError::ENODEV);
+                }
+            }
+            let mut queue = self.queue.lock();
+            queue.interface_index = endpoint.interface_index;
+            // the ethernet type is kept if none is given
+            if endpoint.protocol != 0 {
+                queue.protocol = endpoint.protocol;
+            }
+            Ok(0)
+        } else {
+            Err(SysError::EINVAL)
+        }
+    }
+
+    fn endpoint(&self) -> Option<Endpoint> {
+        Some(Endpoint::LinkLevel(self.bound_endpoint()))
+    }
+
+    fn set_option(&mut self, option: SocketOption) -> SysResult {
+        match option {
+            // frames are queued up to a fixed size
+            SocketOption::RecvBuffer(_) | SocketOption::SendBuffer(_) => Ok(0),
+            option => self.options.lock().set(option),
+        }
+    }
+
+    fn get_option(&self, name: SocketOptionName) -> Result<SocketOption, SysError> {
+        match name {
+            SocketOptionName::RecvBuffer => Ok(SocketOption::RecvBuffer(PACKET_RECVBUF)),
+            SocketOptionName::Error => Ok(SocketOption::Error(0)),
+            SocketOptionName::Type => Ok(SocketOption::Type(self.socket_type)),
+            name => self.options.lock().get(name),
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Socket> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,119 @@
+//! Capture of network frames to a pcap file, enabled by the `pcap` feature
+//!
+//! `capture` queues the frames tapped from the interfaces,
+//! and a kernel task appends them to `/capture.pcap`, which is truncated at boot.
+//! The file is read by tcpdump or wireshark after the disk image is unpacked.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use rcore_fs::vfs::{FileType, FsError, INode};
+
+use crate::arch::timer::timer_now;
+use crate::fs::ROOT_INODE;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// Name of the file in the root directory
+const PCAP_FILE: &str = "capture.pcap";
+/// Max frames queued while the task is behind, the oldest ones are dropped
+const MAX_FRAMES: usize = 1024;
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+#[derive(Default)]
+struct CaptureQueue {
+    /// Frames and the uptime when they were captured
+    frames: VecDeque<(Duration, Vec<u8>)>,
+    waker: Option<Waker>,
+}
+
+lazy_static! {
+    static ref QUEUE: Mutex<CaptureQueue> = Mutex::new(CaptureQueue::default());
+}
+
+/// Queue a frame to be written
+pub fn capture(frame: &[u8]) {
+    let waker = {
+        let mut queue = QUEUE.lock();
+        if queue.frames.len() == MAX_FRAMES {
+            queue.frames.pop_front();
+        }
+        queue.frames.push_back((timer_now(), frame.to_vec()));
+        queue.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Start the task writing the file
+pub fn start() {
+    executor::spawn(run());
+}
+
+async fn run() {
+    let file = match create_file() {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("pcap: failed to create /{}: {:?}", PCAP_FILE, err);
+            return;
+        }
+    };
+    info!("pcap: capturing frames to /{}", PCAP_FILE);
+    // the global header, in the byte order of the magic
+    let mut data = Vec::new();
+    for field in &[PCAP_MAGIC, 2 | 4 << 16, 0, 0, SNAPLEN, LINKTYPE_ETHERNET] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    let mut offset = 0;
+    loop {
+        if let Err(err) = file.write_at(offset, &data).and_then(|_| file.sync_data()) {
+            warn!("pcap: failed to write /{}: {:?}", PCAP_FILE, err);
+            return;
+        }
+        offset += data.len();
+        data.clear();
+        for (time, frame) in Frames.await {
+            let len = frame.len() as u32;
+            let header = [time.as_secs() as u32, time.subsec_micros(), len, len];
+            for field in &header {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+            data.extend_from_slice(&frame);
+        }
+    }
+}
+
+/// Open the file for writing, truncating an existing one
+fn create_file() -> Result<Arc<dyn INode>, FsError> {
+    match ROOT_INODE.find(PCAP_FILE) {
+        Ok(inode) => {
+            inode.resize(0)?;
+            Ok(inode)
+        }
+        Err(FsError::EntryNotFound) => ROOT_INODE.create(PCAP_FILE, FileType::File, 0o644),
+        Err(err) => Err(err),
+    }
+}
+
+/// The frames queued since the last poll, ready when there are some
+struct Frames;
+
+impl Future for Frames {
+    type Output = VecDeque<(Duration, Vec<u8>)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut queue = QUEUE.lock();
+        if queue.frames.is_empty() {
+            queue.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(core::mem::replace(&mut queue.frames, VecDeque::new()))
+    }
+}
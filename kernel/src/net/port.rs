@@ -0,0 +1,376 @@
+//! Local ports of TCP and UDP sockets
+//!
+//! A socket takes its port from the table of its protocol when it binds or connects,
+//! and gives it back when the last clone of the socket is dropped.
+//! Sockets on the same address share a port only if both of them allow it:
+//! by SO_REUSEPORT, or by SO_REUSEADDR unless one of them is listening,
+//! in which case only connections may share the port with it.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use smoltcp::wire::{IpAddress, IpEndpoint};
+use spin::RwLock;
+
+use crate::arch::rand;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::SysError;
+
+use super::SocketOptions;
+
+/// Ephemeral ports when `PORT_RANGE` is not valid, the dynamic ports of IANA
+const DEFAULT_PORT_RANGE: (u16, u16) = (49152, 65535);
+
+lazy_static! {
+    /// The first and the last ephemeral port separated by whitespace,
+    /// as `/proc/sys/net/ipv4/ip_local_port_range`
+    pub static ref PORT_RANGE: RwLock<String> = RwLock::new(String::from("49152\t65535"));
+    pub static ref TCP_PORTS: PortTable = PortTable::new();
+    pub static ref UDP_PORTS: PortTable = PortTable::new();
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The ephemeral ports in `PORT_RANGE`
+fn port_range() -> (u16, u16) {
+    let range = PORT_RANGE.read();
+    let mut ports = range.split_whitespace().map(|port| port.parse::<u16>());
+    match (ports.next(), ports.next()) {
+        (Some(Ok(first)), Some(Ok(last))) if first > 0 && first <= last => (first, last),
+        _ => DEFAULT_PORT_RANGE,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PortEntry {
+    id: usize,
+    /// The local address, unspecified for all of them
+    addr: IpAddress,
+    reuse_addr: bool,
+    reuse_port: bool,
+    listening: bool,
+    /// The peer of a connection
+    remote: Option<IpEndpoint>,
+}
+
+impl PortEntry {
+    fn new(addr: IpAddress, remote: Option<IpEndpoint>, options: &SocketOptions) -> Self {
+        PortEntry {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            addr,
+            reuse_addr: options.reuse_addr,
+            reuse_port: options.reuse_port,
+            listening: false,
+            remote,
+        }
+    }
+
+    /// Whether `self` and `other` can not be on the same port
+    fn conflicts(&self, other: &PortEntry) -> bool {
+        if !self.addr.is_unspecified() && !other.addr.is_unspecified() && self.addr != other.addr {
+            return false;
+        }
+        if let (Some(remote), Some(other_remote)) = (self.remote, other.remote) {
+            // connections are told apart by their peers
+            return remote == other_remote;
+        }
+        if self.reuse_port && other.reuse_port {
+            return false;
+        }
+        // a restarted server may listen on the port of the connections left by the old one
+        let connection = self.remote.is_some() || other.remote.is_some();
+        let listening = self.listening || other.listening;
+        !(self.reuse_addr && other.reuse_addr && (connection || !listening))
+    }
+}
+
+/// Sockets of a protocol by their local ports
+#[derive(Debug)]
+pub struct PortTable {
+    ports: Mutex<BTreeMap<u16, Vec<PortEntry>>>,
+}
+
+impl PortTable {
+    fn new() -> Self {
+        PortTable {
+            ports: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Bind `port` of `addr`, or a free ephemeral port if it is 0
+    pub fn bind(
+        &'static self,
+        addr: IpAddress,
+        port: u16,
+        options: &SocketOptions,
+    ) -> Result<PortBinding, SysError> {
+        let entry = PortEntry::new(addr, None, options);
+        if port == 0 {
+            self.insert_ephemeral(entry).ok_or(SysError::EADDRINUSE)
+        } else {
+            self.insert(port, entry)
+        }
+    }
+
+    /// Take an ephemeral port of `addr` to connect to `remote`.
+    /// The port may be shared with connections to other peers.
+    pub fn connect(
+        &'static self,
+        addr: IpAddress,
+        remote: IpEndpoint,
+        options: &SocketOptions,
+    ) -> Result<PortBinding, SysError> {
+        let entry = PortEntry::new(addr, Some(remote), options);
+        self.insert_ephemeral(entry).ok_or(SysError::EADDRNOTAVAIL)
+    }
+
+    /// Add a connection from `remote` accepted on the port of a listening socket
+    pub fn accept(
+        &'static self,
+        local: IpEndpoint,
+        remote: IpEndpoint,
+        options: &SocketOptions,
+    ) -> PortBinding {
+        let entry = PortEntry::new(local.addr, Some(remote), options);
+        let mut ports = self.ports.lock();
+        ports.entry(local.port).or_insert_with(Vec::new).push(entry);
+        PortBinding {
+            table: self,
+            port: local.port,
+            id: entry.id,
+        }
+    }
+
+    fn insert(&'static self, port: u16, entry: PortEntry) -> Result<PortBinding, SysError> {
+        let mut ports = self.ports.lock();
+        let entries = ports.entry(port).or_insert_with(Vec::new);
+        if entries.iter().any(|other| entry.conflicts(other)) {
+            return Err(SysError::EADDRINUSE);
+        }
+        entries.push(entry);
+        Ok(PortBinding {
+            table: self,
+            port,
+            id: entry.id,
+        })
+    }
+
+    /// Insert `entry` to a port of the range, starting from a random one.
+    /// A bound socket gets a port of its own, and a connection may share it
+    /// with connections to other peers.
+    fn insert_ephemeral(&'static self, entry: PortEntry) -> Option<PortBinding> {
+        let (first, last) = port_range();
+        let count = (last - first) as u64 + 1;
+        let start = rand::rand() % count;
+        let mut ports = self.ports.lock();
+        for i in 0..count {
+            let port = first + ((start + i) % count) as u16;
+            let entries = ports.entry(port).or_insert_with(Vec::new);
+            let free = entries
+                .iter()
+                .all(|other| match (entry.remote, other.remote) {
+                    (Some(remote), Some(other_remote)) => remote != other_remote,
+                    _ => false,
+                });
+            if free {
+                entries.push(entry);
+                return Some(PortBinding {
+                    table: self,
+                    port,
+                    id: entry.id,
+                });
+            }
+        }
+        None
+    }
+
+    /// Update the entry of `id` on `port` if it does not conflict with the others then
+    fn update(&self, port: u16, id: usize, f: impl FnOnce(&mut PortEntry)) -> Result<(), SysError> {
+        let mut ports = self.ports.lock();
+        let entries = ports.get_mut(&port).unwrap();
+        let i = entries.iter().position(|entry| entry.id == id).unwrap();
+        let mut entry = entries[i];
+        f(&mut entry);
+        if entries
+            .iter()
+            .any(|other| other.id != id && entry.conflicts(other))
+        {
+            return Err(SysError::EADDRINUSE);
+        }
+        entries[i] = entry;
+        Ok(())
+    }
+}
+
+/// A port taken from a `PortTable`, which is given back on drop
+#[derive(Debug)]
+pub struct PortBinding {
+    table: &'static PortTable,
+    port: u16,
+    id: usize,
+}
+
+impl PortBinding {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Mark the socket listening, which no other socket on the port may be
+    /// unless all of them have SO_REUSEPORT
+    pub fn listen(&self) -> Result<(), SysError> {
+        self.table
+            .update(self.port, self.id, |entry| entry.listening = true)
+    }
+
+    /// Connect the bound socket to `remote`
+    pub fn connect(&self, remote: IpEndpoint) -> Result<(), SysError> {
+        self.table
+            .update(self.port, self.id, |entry| entry.remote = Some(remote))
+    }
+}
+
+impl Drop for PortBinding {
+    fn drop(&mut self) {
+        let mut ports = self.table.ports.lock();
+        let entries = ports.get_mut(&self.port).unwrap();
+        entries.retain(|entry| entry.id != self.id);
+        if entries.is_empty() {
+            ports.remove(&self.port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use smoltcp::wire::Ipv4Address;
+
+    fn table() -> &'static PortTable {
+        Box::leak(Box::new(PortTable::new()))
+    }
+
+    fn options(reuse_addr: bool, reuse_port: bool) -> SocketOptions {
+        SocketOptions {
+            reuse_addr,
+            reuse_port,
+            ..SocketOptions::default()
+        }
+    }
+
+    fn addr(last: u8) -> IpAddress {
+        IpAddress::Ipv4(Ipv4Address::new(10, 0, 0, last))
+    }
+
+    fn endpoint(last: u8, port: u16) -> IpEndpoint {
+        IpEndpoint::new(addr(last), port)
+    }
+
+    #[test]
+    fn bind_conflicts() {
+        let table = table();
+        let any = IpAddress::Unspecified;
+        let plain = options(false, false);
+        let first = table.bind(addr(1), 80, &plain).unwrap();
+        assert!(matches!(
+            table.bind(addr(1), 80, &plain),
+            Err(SysError::EADDRINUSE)
+        ));
+        assert!(matches!(
+            table.bind(any, 80, &plain),
+            Err(SysError::EADDRINUSE)
+        ));
+        // other addresses do not conflict
+        let _second = table.bind(addr(2), 80, &plain).unwrap();
+        // the port is given back on drop
+        drop(first);
+        let _first = table.bind(addr(1), 80, &plain).unwrap();
+    }
+
+    #[test]
+    fn reuse_addr_and_port() {
+        let table = table();
+        let reuse_addr = options(true, false);
+        let reuse_port = options(false, true);
+
+        let a = table.bind(addr(1), 80, &reuse_addr).unwrap();
+        let b = table.bind(addr(1), 80, &reuse_addr).unwrap();
+        // no one may listen with SO_REUSEADDR alone while others are bound
+        assert!(matches!(a.listen(), Err(SysError::EADDRINUSE)));
+        drop(b);
+        a.listen().unwrap();
+        assert!(matches!(
+            table.bind(addr(1), 80, &reuse_addr),
+            Err(SysError::EADDRINUSE)
+        ));
+
+        let c = table.bind(addr(1), 81, &reuse_port).unwrap();
+        let d = table.bind(addr(1), 81, &reuse_port).unwrap();
+        c.listen().unwrap();
+        d.listen().unwrap();
+        assert!(matches!(
+            table.bind(addr(1), 81, &reuse_addr),
+            Err(SysError::EADDRINUSE)
+        ));
+    }
+
+    #[test]
+    fn connections_share_ports() {
+        let table = table();
+        let plain = options(false, false);
+        let reuse_addr = options(true, false);
+
+        let server = table.bind(addr(1), 80, &reuse_addr).unwrap();
+        server.listen().unwrap();
+        let first = table.accept(endpoint(1, 80), endpoint(2, 1000), &reuse_addr);
+        let _second = table.accept(endpoint(1, 80), endpoint(2, 1001), &reuse_addr);
+        // a restarted server listens on the port of the old connections
+        drop(server);
+        let server = table.bind(addr(1), 80, &reuse_addr).unwrap();
+        server.listen().unwrap();
+        drop(server);
+
+        // a bound socket connects unless the same connection exists
+        let client = table.bind(addr(1), 80, &reuse_addr).unwrap();
+        assert!(matches!(
+            client.connect(endpoint(2, 1000)),
+            Err(SysError::EADDRINUSE)
+        ));
+        drop(first);
+        client.connect(endpoint(2, 1000)).unwrap();
+        let _plain = table.bind(addr(3), 80, &plain).unwrap();
+    }
+
+    #[test]
+    fn ephemeral_ports() {
+        *PORT_RANGE.write() = String::from("40000 40001");
+        assert_eq!(port_range(), (40000, 40001));
+        let table = table();
+        let plain = options(false, false);
+
+        let a = table.bind(addr(1), 0, &plain).unwrap();
+        let b = table.bind(addr(1), 0, &plain).unwrap();
+        assert_ne!(a.port(), b.port());
+        assert!(matches!(
+            table.bind(addr(1), 0, &plain),
+            Err(SysError::EADDRINUSE)
+        ));
+        // connections do not share ports with bound sockets
+        let c = table.connect(addr(1), endpoint(2, 80), &plain);
+        assert!(matches!(c, Err(SysError::EADDRNOTAVAIL)));
+        drop(a);
+        // but share them with connections to other peers
+        let c = table.connect(addr(1), endpoint(2, 80), &plain).unwrap();
+        let d = table.connect(addr(1), endpoint(3, 80), &plain).unwrap();
+        assert_ne!(c.port(), b.port());
+        assert_eq!(c.port(), d.port());
+        let e = table.connect(addr(1), endpoint(2, 80), &plain);
+        assert!(matches!(e, Err(SysError::EADDRNOTAVAIL)));
+
+        *PORT_RANGE.write() = String::from("0 1");
+        assert_eq!(port_range(), DEFAULT_PORT_RANGE);
+        *PORT_RANGE.write() = String::from("49152\t65535");
+    }
+}
@@ -0,0 +1,180 @@
+//! Kernel routing table, changed by rtnetlink
+//!
+//! Networks of the addresses of an interface are reached directly and are not in the table.
+//! Routes via a gateway are installed in the smoltcp interface they go out of,
+//! which sends packets for other networks to the gateway.
+//! A point-to-point link like a TUN interface has a single peer,
+//! so it takes its on-link routes too and sends all of them to the peer.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use smoltcp::wire::{IpAddress, IpCidr};
+use spin::RwLock;
+
+use crate::drivers::{NetDriver, NET_DRIVERS};
+use crate::syscall::SysError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RouteEntry {
+    /// Destination network
+    pub dst: IpCidr,
+    /// Next hop, `None` if the destination is on the link
+    pub gateway: Option<IpAddress>,
+    /// Index of the outgoing interface in `NET_DRIVERS`
+    pub iface: usize,
+    /// Lower is preferred
+    pub metric: u32,
+}
+
+lazy_static! {
+    pub static ref ROUTE_TABLE: RwLock<Vec<RouteEntry>> = RwLock::new(Vec::new());
+}
+
+/// Add `route`. An existing route to the same network with the same metric
+/// is replaced if `replace`, or it is an error.
+pub fn add_route(route: RouteEntry, replace: bool) -> Result<(), SysError> {
+    let ifaces = NET_DRIVERS.read();
+    if route.iface >= ifaces.len() {
+        return Err(SysError::ENODEV);
+    }
+    let mut table = ROUTE_TABLE.write();
+    let old = match table
+        .iter()
+        .position(|r| r.dst == route.dst && r.metric == route.metric)
+    {
+        Some(_) if !replace => return Err(SysError::EEXIST),
+        Some(i) => Some((i, table.remove(i))),
+        None => None,
+    };
+    table.push(route);
+    let result = install(&table, &ifaces[route.iface], route.iface, route.dst);
+    if result.is_err() {
+        // the interface takes no routes, keep the replaced one
+        table.pop();
+    }
+    if let Some((i, old)) = old {
+        if result.is_err() {
+            table.insert(i, old);
+        }
+        // its interface took it before, so it takes the one preferred now
+        install(&table, &ifaces[old.iface], old.iface, old.dst).ok();
+    }
+    result
+}
+
+/// Delete the first route to `dst` which matches the given fields
+pub fn del_route(
+    dst: IpCidr,
+    gateway: Option<IpAddress>,
+    iface: Option<usize>,
+    metric: Option<u32>,
+) -> Result<(), SysError> {
+    let ifaces = NET_DRIVERS.read();
+    let mut table = ROUTE_TABLE.write();
+    let i = table
+        .iter()
+        .position(|r| {
+            r.dst == dst
+                && gateway.map_or(true, |gateway| r.gateway == Some(gateway))
+                && iface.map_or(true, |iface| r.iface == iface)
+                && metric.map_or(true, |metric| r.metric == metric)
+        })
+        .ok_or(SysError::ESRCH)?;
+    let old = table.remove(i);
+    install(&table, &ifaces[old.iface], old.iface, old.dst)
+}
+
+/// Delete the routes of the interface at `index`, which is removed from `NET_DRIVERS`,
+/// and renumber the routes of the interfaces after it, which move down by one
+pub fn remove_iface_routes(index: usize) {
+    let mut table = ROUTE_TABLE.write();
+    table.retain(|r| r.iface != index);
+    for route in table.iter_mut().filter(|r| r.iface > index) {
+        route.iface -= 1;
+    }
+}
+
+/// The route to `addr` of the longest prefix, then of the lowest metric
+pub fn lookup_route(addr: &IpAddress) -> Option<RouteEntry> {
+    ROUTE_TABLE
+        .read()
+        .iter()
+        .filter(|r| r.dst.contains_addr(addr))
+        .min_by_key(|r| (!r.dst.prefix_len(), r.metric))
+        .cloned()
+}
+
+/// Install the preferred gateway route to `dst` of `iface` in its smoltcp interface,
+/// as it keeps one route for each network. It fails if the driver takes no routes.
+fn install(
+    table: &[RouteEntry],
+    driver: &Arc<dyn NetDriver>,
+    iface: usize,
+    dst: IpCidr,
+) -> Result<(), SysError> {
+    let point_to_point = driver.is_point_to_point();
+    let best = table
+        .iter()
+        .filter(|r| r.iface == iface && r.dst == dst && (r.gateway.is_some() || point_to_point))
+        .min_by_key(|r| r.metric);
+    match best {
+        // a point-to-point link does not look at the gateway
+        Some(route) => driver.add_route(dst, route.gateway.unwrap_or(dst.address())),
+        None => driver.del_route(dst),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+
+    fn cidr(a: u8, b: u8, prefix_len: u8) -> IpCidr {
+        IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::new(10, a, b, 0), prefix_len))
+    }
+
+    fn addr(a: u8, b: u8, c: u8) -> IpAddress {
+        IpAddress::Ipv4(Ipv4Address::new(10, a, b, c))
+    }
+
+    fn route(dst: IpCidr, iface: usize, metric: u32) -> RouteEntry {
+        RouteEntry {
+            dst,
+            gateway: Some(addr(0, 0, 1)),
+            iface,
+            metric,
+        }
+    }
+
+    // one test, as the table is global
+    #[test]
+    fn routes() {
+        // no interface takes routes
+        let default = route(cidr(0, 0, 0), 0, 0);
+        assert!(matches!(add_route(default, false), Err(SysError::ENODEV)));
+
+        *ROUTE_TABLE.write() = vec![
+            route(cidr(0, 0, 8), 0, 10),
+            route(cidr(1, 0, 16), 1, 10),
+            route(cidr(1, 0, 16), 2, 5),
+            route(cidr(1, 2, 24), 3, 20),
+        ];
+        // the longest prefix, then the lowest metric
+        assert_eq!(lookup_route(&addr(9, 0, 1)).unwrap().iface, 0);
+        assert_eq!(lookup_route(&addr(1, 1, 1)).unwrap().iface, 2);
+        assert_eq!(lookup_route(&addr(1, 2, 1)).unwrap().iface, 3);
+        assert_eq!(lookup_route(&IpAddress::v4(192, 168, 0, 1)), None);
+
+        // routes of interfaces after a removed one are renumbered
+        remove_iface_routes(2);
+        assert_eq!(lookup_route(&addr(1, 1, 1)).unwrap().iface, 1);
+        assert_eq!(lookup_route(&addr(1, 2, 1)).unwrap().iface, 2);
+        remove_iface_routes(0);
+        assert_eq!(lookup_route(&addr(9, 0, 1)), None);
+        assert_eq!(
+            *ROUTE_TABLE.read(),
+            vec![route(cidr(1, 0, 16), 0, 10), route(cidr(1, 2, 24), 1, 20)]
+        );
+        ROUTE_TABLE.write().clear();
+    }
+}
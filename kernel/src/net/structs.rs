@@ -1,5 +1,9 @@
-use crate::arch::rand;
-use crate::drivers::{NET_DRIVERS, SOCKET_ACTIVITY};
+use crate::drivers::{NetDriver, NET_DRIVERS, SOCKET_ACTIVITY};
+use crate::fs::FileLike;
+use crate::net::{
+    add_route, deadline, del_route, expired, lookup_route, wait_socket, PortBinding, RouteEntry,
+    SocketOption, SocketOptionName, SocketOptions, ROUTE_TABLE, TCP_PORTS, UDP_PORTS,
+};
 use crate::sync::SpinNoIrqLock as Mutex;
 use crate::syscall::*;
 use crate::util;
@@ -9,21 +13,34 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bitflags::*;
 use core::cmp::min;
+use core::future::Future;
 use core::mem::size_of;
+use core::pin::Pin;
 use core::slice;
+use core::time::Duration;
 
 use smoltcp::socket::*;
 use smoltcp::wire::*;
 
 #[derive(Clone, Debug)]
 pub struct LinkLevelEndpoint {
-    pub interface_index: usize,
+    /// Index of the interface in `NET_DRIVERS`, `None` for any
+    pub interface_index: Option<usize>,
+    /// Ethernet type, 0 for none
+    pub protocol: u16,
+    /// PACKET_HOST, PACKET_OUTGOING and so on of a received frame
+    pub packet_type: u8,
+    /// Source of a received frame, or destination of a sent one
+    pub address: EthernetAddress,
 }
 
 impl LinkLevelEndpoint {
-    pub fn new(ifindex: usize) -> Self {
+    pub fn new(interface_index: Option<usize>, protocol: u16) -> Self {
         LinkLevelEndpoint {
-            interface_index: ifindex,
+            interface_index,
+            protocol,
+            packet_type: 0,
+            address: EthernetAddress([0; 6]),
         }
     }
 }
@@ -43,19 +60,148 @@ impl NetlinkEndpoint {
     }
 }
 
+/// Address family of an IP socket.
+/// An IPv6 socket also talks to IPv4 peers by v4-mapped addresses, unless it is v6only.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpFamily {
+    V4,
+    V6 { v6only: bool },
+}
+
+impl IpFamily {
+    /// Whether a socket of this family can talk to `addr`
+    pub fn accepts(&self, addr: &IpAddress) -> bool {
+        match (self, addr) {
+            (_, IpAddress::Unspecified) => true,
+            (IpFamily::V4, IpAddress::Ipv4(_)) => true,
+            (IpFamily::V6 { .. }, IpAddress::Ipv6(_)) => true,
+            (IpFamily::V6 { v6only }, IpAddress::Ipv4(_)) => !v6only,
+            _ => false,
+        }
+    }
+
+    /// Handle IPV6_V6ONLY
+    fn set_v6only(&mut self, on: bool) -> SysResult {
+        match self {
+            IpFamily::V6 { v6only } => {
+                *v6only = on;
+                Ok(0)
+            }
+            IpFamily::V4 => Err(SysError::ENOPROTOOPT),
+        }
+    }
+
+    fn v6only(&self) -> Result<SocketOption, SysError> {
+        match self {
+            IpFamily::V6 { v6only } => Ok(SocketOption::V6Only(*v6only)),
+            IpFamily::V4 => Err(SysError::ENOPROTOOPT),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Endpoint {
     Ip(IpEndpoint),
     LinkLevel(LinkLevelEndpoint),
     Netlink(NetlinkEndpoint),
+    /// An unnamed unix socket, i.e. one end of a socketpair
+    Unix,
+}
+
+/// Credentials of the sender of a message, as `struct ucred`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UCred {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Ancillary data of a message, i.e. control messages of sendmsg and recvmsg
+#[derive(Clone, Debug, Default)]
+pub struct Ancillary {
+    /// Files passed by SCM_RIGHTS
+    pub files: Vec<FileLike>,
+    /// Credentials of the sender, received by SCM_CREDENTIALS
+    pub credentials: Option<UCred>,
+    /// Local address and interface of a received datagram, for IP_PKTINFO
+    pub pktinfo: Option<(Ipv4Address, usize)>,
 }
 
+/// A future of an operation of a socket, which borrows it
+pub type SocketFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + Sync + 'a>>;
+
 /// Common methods that a socket must have
 pub trait Socket: Send + Sync + Debug {
     fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint);
     fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult;
+    /// Like `read`, also receiving ancillary data
+    fn recvmsg(&self, data: &mut [u8]) -> (SysResult, Endpoint, Ancillary) {
+        let (result, endpoint) = self.read(data);
+        (result, endpoint, Ancillary::default())
+    }
+    /// Like `write`, also sending ancillary data.
+    /// Only unix sockets pass files, and credentials are dropped by others.
+    fn sendmsg(
+        &self,
+        data: &[u8],
+        sendto_endpoint: Option<Endpoint>,
+        ancillary: Ancillary,
+    ) -> SysResult {
+        if !ancillary.files.is_empty() {
+            return Err(SysError::EINVAL);
+        }
+        self.write(data, sendto_endpoint)
+    }
+    /// Like `read`, but the task sleeps instead of the CPU while there is no data.
+    /// It fails with EAGAIN instead if `nonblock`, i.e. MSG_DONTWAIT, or O_NONBLOCK is set.
+    /// The default one blocks in `read` once the socket is readable.
+    fn async_read<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint)> {
+        Box::pin(async move {
+            if (nonblock || self.nonblock()) && !self.poll().0 {
+                return (Err(SysError::EAGAIN), Endpoint::Ip(IpEndpoint::UNSPECIFIED));
+            }
+            self.read(data)
+        })
+    }
+    /// `recvmsg` as `async_read`
+    fn async_recvmsg<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint, Ancillary)> {
+        Box::pin(async move {
+            if (nonblock || self.nonblock()) && !self.poll().0 {
+                let endpoint = Endpoint::Ip(IpEndpoint::UNSPECIFIED);
+                return (Err(SysError::EAGAIN), endpoint, Ancillary::default());
+            }
+            self.recvmsg(data)
+        })
+    }
+    /// `sendmsg` as `async_read`, waiting for room instead of data
+    fn async_sendmsg<'a>(
+        &'a self,
+        data: &'a [u8],
+        sendto_endpoint: Option<Endpoint>,
+        ancillary: Ancillary,
+        _nonblock: bool,
+    ) -> SocketFuture<'a, SysResult> {
+        Box::pin(async move { self.sendmsg(data, sendto_endpoint, ancillary) })
+    }
     fn poll(&self) -> (bool, bool, bool); // (in, out, err)
     fn connect(&mut self, endpoint: Endpoint) -> SysResult;
+    /// `connect` as `async_read`, failing with EINPROGRESS instead of waiting
+    fn async_connect<'a>(
+        &'a mut self,
+        endpoint: Endpoint,
+        _nonblock: bool,
+    ) -> SocketFuture<'a, SysResult> {
+        Box::pin(async move { self.connect(endpoint) })
+    }
     fn bind(&mut self, _endpoint: Endpoint) -> SysResult {
         Err(SysError::EINVAL)
     }
@@ -68,20 +214,40 @@ pub trait Socket: Send + Sync + Debug {
     fn accept(&mut self) -> Result<(Box<dyn Socket>, Endpoint), SysError> {
         Err(SysError::EINVAL)
     }
+    /// `accept` as `async_read`
+    fn async_accept<'a>(
+        &'a mut self,
+        _nonblock: bool,
+    ) -> SocketFuture<'a, Result<(Box<dyn Socket>, Endpoint), SysError>> {
+        Box::pin(async move { self.accept() })
+    }
     fn endpoint(&self) -> Option<Endpoint> {
         None
     }
     fn remote_endpoint(&self) -> Option<Endpoint> {
         None
     }
-    fn setsockopt(&mut self, _level: usize, _opt: usize, _data: &[u8]) -> SysResult {
-        warn!("setsockopt is unimplemented");
-        Ok(0)
+    fn set_option(&mut self, _option: SocketOption) -> SysResult {
+        Err(SysError::ENOPROTOOPT)
+    }
+    fn get_option(&self, _name: SocketOptionName) -> Result<SocketOption, SysError> {
+        Err(SysError::ENOPROTOOPT)
     }
     fn ioctl(&mut self, _request: usize, _arg1: usize, _arg2: usize, _arg3: usize) -> SysResult {
         warn!("ioctl is unimplemented for this socket");
         Ok(0)
     }
+    /// Whether O_NONBLOCK is set
+    fn nonblock(&self) -> bool {
+        match self.get_option(SocketOptionName::NonBlock) {
+            Ok(SocketOption::NonBlock(on)) => on,
+            _ => false,
+        }
+    }
+    /// Address family of an IP socket, `None` for other sockets
+    fn ip_family(&self) -> Option<IpFamily> {
+        None
+    }
     fn box_clone(&self) -> Box<dyn Socket>;
 }
 
@@ -100,28 +266,56 @@ lazy_static! {
         Mutex::new(SocketSet::new(vec![]));
 }
 
+/// A TCP socket. Its clones, made by dup and fork, share the state behind `inner`.
 #[derive(Debug, Clone)]
 pub struct TcpSocketState {
+    inner: Arc<Mutex<TcpSocketInner>>,
+}
+
+#[derive(Debug)]
+struct TcpSocketInner {
     handle: GlobalSocketHandle,
+    family: IpFamily,
     local_endpoint: Option<IpEndpoint>, // save local endpoint for bind()
+    port: Option<PortBinding>,
     is_listening: bool,
+    options: SocketOptions,
+    keep_alive: bool,
+    keep_idle: u32,   // seconds idle before keep-alive probes
+    no_delay: bool,   // smoltcp has no Nagle, segments are always sent at once
+    error: usize,     // errno of the last failed connect, for SO_ERROR
+    connecting: bool, // a connect was started, and may fail after it returned EINPROGRESS
 }
 
+/// A UDP socket. Its clones share the state behind `inner`.
 #[derive(Debug, Clone)]
 pub struct UdpSocketState {
     handle: GlobalSocketHandle,
+    inner: Arc<Mutex<UdpSocketInner>>,
+}
+
+#[derive(Debug)]
+struct UdpSocketInner {
+    family: IpFamily,
     remote_endpoint: Option<IpEndpoint>, // remember remote endpoint for connect()
+    port: Option<PortBinding>,
+    pktinfo: bool, // IP_PKTINFO
+    options: SocketOptions,
 }
 
+/// A raw IP socket. Its clones share the state behind `inner`.
 #[derive(Debug, Clone)]
 pub struct RawSocketState {
     handle: GlobalSocketHandle,
-    header_included: bool,
+    inner: Arc<Mutex<RawSocketInner>>,
 }
 
-#[derive(Debug, Clone)]
-pub struct PacketSocketState {
-    // no state, only ethernet egress
+#[derive(Debug)]
+struct RawSocketInner {
+    family: IpFamily,
+    header_included: bool,
+    ttl: u8,
+    options: SocketOptions,
 }
 
 #[derive(Debug, Clone)]
@@ -154,80 +348,292 @@ impl Drop for GlobalSocketHandle {
 }
 
 impl TcpSocketState {
-    pub fn new() -> Self {
+    pub fn new(family: IpFamily) -> Self {
         let rx_buffer = TcpSocketBuffer::new(vec![0; TCP_RECVBUF]);
         let tx_buffer = TcpSocketBuffer::new(vec![0; TCP_SENDBUF]);
         let socket = TcpSocket::new(rx_buffer, tx_buffer);
         let handle = GlobalSocketHandle(SOCKETS.lock().add(socket));
 
         TcpSocketState {
-            handle,
-            local_endpoint: None,
-            is_listening: false,
+            inner: Arc::new(Mutex::new(TcpSocketInner {
+                handle,
+                family,
+                local_endpoint: None,
+                port: None,
+                is_listening: false,
+                options: SocketOptions::default(),
+                keep_alive: false,
+                keep_idle: TCP_KEEPIDLE_DEFAULT,
+                no_delay: false,
+                error: 0,
+                connecting: false,
+            })),
         }
     }
 }
 
-impl Socket for TcpSocketState {
-    fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
-        spin_and_wait(&[&SOCKET_ACTIVITY], move || {
+impl TcpSocketInner {
+    /// Apply the keep-alive options to the smoltcp socket
+    fn set_keep_alive(&self, socket: &mut TcpSocket) {
+        let interval = if self.keep_alive {
+            Some(smoltcp::time::Duration::from_secs(self.keep_idle as u64))
+        } else {
+            None
+        };
+        socket.set_keep_alive(interval);
+    }
+
+    /// One try of `read`, `None` to wait for data
+    fn try_read(
+        &self,
+        data: &mut [u8],
+        deadline: Option<Duration>,
+        nonblock: bool,
+    ) -> Option<(SysResult, Endpoint)> {
+        poll_ifaces();
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+
+        if socket.may_recv() {
+            if let Ok(size) = socket.recv_slice(data) {
+                if size > 0 {
+                    let endpoint = socket.remote_endpoint();
+                    // avoid deadlock
+                    drop(socket);
+                    drop(sockets);
+
+                    poll_ifaces();
+                    return Some((Ok(size), Endpoint::Ip(endpoint)));
+                }
+            }
+        } else {
+            return Some((
+                Err(SysError::ENOTCONN),
+                Endpoint::Ip(IpEndpoint::UNSPECIFIED),
+            ));
+        }
+        if nonblock || expired(deadline) {
+            return Some((Err(SysError::EAGAIN), Endpoint::Ip(IpEndpoint::UNSPECIFIED)));
+        }
+        None
+    }
+
+    /// One try of `write`, `None` to wait for room in the send buffer
+    fn try_write(&self, data: &[u8], deadline: Option<Duration>) -> Option<SysResult> {
+        poll_ifaces();
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+
+        if !socket.is_open() {
+            return Some(Err(SysError::ENOTCONN));
+        }
+        if socket.can_send() {
+            let result = socket.send_slice(&data).map_err(|_| SysError::ENOBUFS);
+            // avoid deadlock
+            drop(socket);
+            drop(sockets);
+
             poll_ifaces();
+            return Some(result);
+        }
+        if self.options.nonblock || expired(deadline) {
+            return Some(Err(SysError::EAGAIN));
+        }
+        None
+    }
+
+    /// Start connecting to `endpoint`
+    fn start_connect(&mut self, endpoint: Endpoint) -> SysResult {
+        let ip = match endpoint {
+            Endpoint::Ip(ip) => ip,
+            _ => return Err(SysError::EINVAL),
+        };
+        {
             let mut sockets = SOCKETS.lock();
-            let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+            let socket = sockets.get::<TcpSocket>(self.handle.0);
+            match socket.state() {
+                TcpState::SynSent => return Err(SysError::EALREADY),
+                TcpState::Established => return Err(SysError::EISCONN),
+                _ => {}
+            }
+        }
+        let local_endpoint = match self.port {
+            Some(ref binding) => {
+                binding.connect(ip)?;
+                let addr = self
+                    .local_endpoint
+                    .map_or(IpAddress::Unspecified, |e| e.addr);
+                IpEndpoint::new(addr, binding.port())
+            }
+            None => {
+                let binding = TCP_PORTS.connect(IpAddress::Unspecified, ip, &self.options)?;
+                let local_endpoint = IpEndpoint::new(IpAddress::Unspecified, binding.port());
+                self.port = Some(binding);
+                local_endpoint
+            }
+        };
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+        socket
+            .connect(ip, local_endpoint)
+            .map_err(|_| SysError::ENOBUFS)?;
+        self.error = 0;
+        self.connecting = true;
+        Ok(0)
+    }
 
-            if socket.may_recv() {
-                if let Ok(size) = socket.recv_slice(data) {
-                    if size > 0 {
-                        let endpoint = socket.remote_endpoint();
-                        // avoid deadlock
-                        drop(socket);
-                        drop(sockets);
+    /// One try of waiting for the result of `start_connect`, `None` to wait more
+    fn try_connect(&mut self, deadline: Option<Duration>, nonblock: bool) -> Option<SysResult> {
+        poll_ifaces();
 
-                        poll_ifaces();
-                        return Some((Ok(size), Endpoint::Ip(endpoint)));
-                    }
-                }
-            } else {
-                return Some((
-                    Err(SysError::ENOTCONN),
-                    Endpoint::Ip(IpEndpoint::UNSPECIFIED),
-                ));
+        let mut sockets = SOCKETS.lock();
+        let socket = sockets.get::<TcpSocket>(self.handle.0);
+        match socket.state() {
+            TcpState::SynSent if nonblock || expired(deadline) => Some(Err(SysError::EINPROGRESS)),
+            // still connecting
+            TcpState::SynSent => None,
+            TcpState::Established => {
+                self.connecting = false;
+                Some(Ok(0))
             }
-            None
-        })
+            _ => {
+                self.connecting = false;
+                self.error = SysError::ECONNREFUSED as usize;
+                Some(Err(SysError::ECONNREFUSED))
+            }
+        }
     }
 
-    fn write(&self, data: &[u8], _sendto_endpoint: Option<Endpoint>) -> SysResult {
+    /// One try of `accept` on `endpoint`, `None` to wait for a connection
+    fn try_accept(
+        &mut self,
+        endpoint: IpEndpoint,
+        deadline: Option<Duration>,
+        nonblock: bool,
+    ) -> Option<Result<(Box<dyn Socket>, Endpoint), SysError>> {
         let mut sockets = SOCKETS.lock();
         let mut socket = sockets.get::<TcpSocket>(self.handle.0);
 
-        if socket.is_open() {
-            if socket.can_send() {
-                match socket.send_slice(&data) {
-                    Ok(size) => {
-                        // avoid deadlock
-                        drop(socket);
-                        drop(sockets);
+        if socket.is_active() {
+            let remote_endpoint = socket.remote_endpoint();
+            if !self.family.accepts(&remote_endpoint.addr) {
+                // reset the connection from the other family and listen again
+                socket.abort();
+                drop(socket);
+                drop(sockets);
+                poll_ifaces();
 
-                        poll_ifaces();
-                        Ok(size)
-                    }
-                    Err(_) => Err(SysError::ENOBUFS),
+                let mut sockets = SOCKETS.lock();
+                let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+                if socket.listen(endpoint).is_err() {
+                    return Some(Err(SysError::EINVAL));
                 }
-            } else {
-                Err(SysError::ENOBUFS)
+                return if nonblock {
+                    Some(Err(SysError::EAGAIN))
+                } else {
+                    None
+                };
             }
-        } else {
-            Err(SysError::ENOTCONN)
+            drop(socket);
+
+            let new_socket = {
+                let rx_buffer = TcpSocketBuffer::new(vec![0; TCP_RECVBUF]);
+                let tx_buffer = TcpSocketBuffer::new(vec![0; TCP_SENDBUF]);
+                let mut socket = TcpSocket::new(rx_buffer, tx_buffer);
+                socket.listen(endpoint).unwrap();
+                // the connection keeps the options of the listening socket
+                self.set_keep_alive(&mut socket);
+                socket.set_hop_limit(sockets.get::<TcpSocket>(self.handle.0).hop_limit());
+                let new_handle = GlobalSocketHandle(sockets.add(socket));
+                let old_handle = ::core::mem::replace(&mut self.handle, new_handle);
+
+                let binding = TCP_PORTS.accept(endpoint, remote_endpoint, &self.options);
+
+                Box::new(TcpSocketState {
+                    inner: Arc::new(Mutex::new(TcpSocketInner {
+                        handle: old_handle,
+                        family: self.family,
+                        local_endpoint: self.local_endpoint,
+                        port: Some(binding),
+                        is_listening: false,
+                        // but not O_NONBLOCK, which is of the file
+                        options: SocketOptions {
+                            nonblock: false,
+                            ..self.options
+                        },
+                        keep_alive: self.keep_alive,
+                        keep_idle: self.keep_idle,
+                        no_delay: self.no_delay,
+                        error: 0,
+                        connecting: false,
+                    })),
+                })
+            };
+
+            drop(sockets);
+            poll_ifaces();
+            return Some(Ok((new_socket, Endpoint::Ip(remote_endpoint))));
+        }
+
+        drop(socket);
+        if nonblock || expired(deadline) {
+            return Some(Err(SysError::EAGAIN));
         }
+        None
+    }
+}
+
+impl Socket for TcpSocketState {
+    fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        let (deadline, nonblock) = {
+            let inner = self.inner.lock();
+            (deadline(inner.options.recv_timeout), inner.options.nonblock)
+        };
+        spin_and_wait(&[&SOCKET_ACTIVITY], move || {
+            self.inner.lock().try_read(data, deadline, nonblock)
+        })
+    }
+
+    fn async_read<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint)> {
+        let (handle, deadline, nonblock) = {
+            let inner = self.inner.lock();
+            let deadline = deadline(inner.options.recv_timeout);
+            (inner.handle.0, deadline, nonblock || inner.options.nonblock)
+        };
+        Box::pin(wait_socket(handle, tcp_readable, move || {
+            self.inner.lock().try_read(data, deadline, nonblock)
+        }))
+    }
+
+    fn async_recvmsg<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint, Ancillary)> {
+        Box::pin(async move {
+            let (result, endpoint) = self.async_read(data, nonblock).await;
+            (result, endpoint, Ancillary::default())
+        })
+    }
+
+    fn write(&self, data: &[u8], _sendto_endpoint: Option<Endpoint>) -> SysResult {
+        let deadline = deadline(self.inner.lock().options.send_timeout);
+        spin_and_wait(&[&SOCKET_ACTIVITY], move || {
+            self.inner.lock().try_write(data, deadline)
+        })
     }
 
     fn poll(&self) -> (bool, bool, bool) {
+        let inner = self.inner.lock();
         let mut sockets = SOCKETS.lock();
-        let socket = sockets.get::<TcpSocket>(self.handle.0);
+        let socket = sockets.get::<TcpSocket>(inner.handle.0);
 
         let (mut input, mut output, mut err) = (false, false, false);
-        if self.is_listening && socket.is_active() {
+        if inner.is_listening && socket.is_active() {
             // a new connection
             input = true;
         } else if !socket.is_open() {
@@ -244,54 +650,47 @@ impl Socket for TcpSocketState {
     }
 
     fn connect(&mut self, endpoint: Endpoint) -> SysResult {
-        let mut sockets = SOCKETS.lock();
-        let mut socket = sockets.get::<TcpSocket>(self.handle.0);
-
-        if let Endpoint::Ip(ip) = endpoint {
-            let temp_port = get_ephemeral_port();
-
-            match socket.connect(ip, temp_port) {
-                Ok(()) => {
-                    // avoid deadlock
-                    drop(socket);
-                    drop(sockets);
-
-                    // wait for connection result
-                    loop {
-                        poll_ifaces();
+        let (deadline, nonblock) = {
+            let mut inner = self.inner.lock();
+            inner.start_connect(endpoint)?;
+            (deadline(inner.options.send_timeout), inner.options.nonblock)
+        };
+        // wait for connection result
+        spin_and_wait(&[&SOCKET_ACTIVITY], || {
+            self.inner.lock().try_connect(deadline, nonblock)
+        })
+    }
 
-                        let mut sockets = SOCKETS.lock();
-                        let socket = sockets.get::<TcpSocket>(self.handle.0);
-                        match socket.state() {
-                            TcpState::SynSent => {
-                                // still connecting
-                                drop(socket);
-                                debug!("poll for connection wait");
-                                SOCKET_ACTIVITY.wait(sockets);
-                            }
-                            TcpState::Established => {
-                                break Ok(0);
-                            }
-                            _ => {
-                                break Err(SysError::ECONNREFUSED);
-                            }
-                        }
-                    }
-                }
-                Err(_) => Err(SysError::ENOBUFS),
-            }
-        } else {
-            Err(SysError::EINVAL)
-        }
+    fn async_connect<'a>(
+        &'a mut self,
+        endpoint: Endpoint,
+        nonblock: bool,
+    ) -> SocketFuture<'a, SysResult> {
+        Box::pin(async move {
+            let (handle, deadline, nonblock) = {
+                let mut inner = self.inner.lock();
+                inner.start_connect(endpoint)?;
+                let deadline = deadline(inner.options.send_timeout);
+                (inner.handle.0, deadline, nonblock || inner.options.nonblock)
+            };
+            wait_socket(handle, tcp_connected, || {
+                self.inner.lock().try_connect(deadline, nonblock)
+            })
+            .await
+        })
     }
 
     fn bind(&mut self, endpoint: Endpoint) -> SysResult {
         if let Endpoint::Ip(mut ip) = endpoint {
-            if ip.port == 0 {
-                ip.port = get_ephemeral_port();
+            let mut inner = self.inner.lock();
+            if inner.port.is_some() {
+                return Err(SysError::EINVAL);
             }
-            self.local_endpoint = Some(ip);
-            self.is_listening = false;
+            let binding = TCP_PORTS.bind(ip.addr, ip.port, &inner.options)?;
+            ip.port = binding.port();
+            inner.port = Some(binding);
+            inner.local_endpoint = Some(ip);
+            inner.is_listening = false;
             Ok(0)
         } else {
             Err(SysError::EINVAL)
@@ -299,13 +698,17 @@ impl Socket for TcpSocketState {
     }
 
     fn listen(&mut self) -> SysResult {
-        if self.is_listening {
+        let mut inner = self.inner.lock();
+        if inner.is_listening {
             // it is ok to listen twice
             return Ok(0);
         }
-        let local_endpoint = self.local_endpoint.ok_or(SysError::EINVAL)?;
+        let local_endpoint = inner.local_endpoint.ok_or(SysError::EINVAL)?;
+        if let Some(ref binding) = inner.port {
+            binding.listen()?;
+        }
         let mut sockets = SOCKETS.lock();
-        let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+        let mut socket = sockets.get::<TcpSocket>(inner.handle.0);
 
         info!("socket listening on {:?}", local_endpoint);
         if socket.is_listening() {
@@ -313,7 +716,7 @@ impl Socket for TcpSocketState {
         }
         match socket.listen(local_endpoint) {
             Ok(()) => {
-                self.is_listening = true;
+                inner.is_listening = true;
                 Ok(0)
             }
             Err(_) => Err(SysError::EINVAL),
@@ -321,54 +724,59 @@ impl Socket for TcpSocketState {
     }
 
     fn shutdown(&self) -> SysResult {
+        let inner = self.inner.lock();
         let mut sockets = SOCKETS.lock();
-        let mut socket = sockets.get::<TcpSocket>(self.handle.0);
+        let mut socket = sockets.get::<TcpSocket>(inner.handle.0);
         socket.close();
         Ok(0)
     }
 
     fn accept(&mut self) -> Result<(Box<dyn Socket>, Endpoint), SysError> {
-        let endpoint = self.local_endpoint.ok_or(SysError::EINVAL)?;
-        loop {
-            let mut sockets = SOCKETS.lock();
-            let socket = sockets.get::<TcpSocket>(self.handle.0);
-
-            if socket.is_active() {
-                let remote_endpoint = socket.remote_endpoint();
-                drop(socket);
-
-                let new_socket = {
-                    let rx_buffer = TcpSocketBuffer::new(vec![0; TCP_RECVBUF]);
-                    let tx_buffer = TcpSocketBuffer::new(vec![0; TCP_SENDBUF]);
-                    let mut socket = TcpSocket::new(rx_buffer, tx_buffer);
-                    socket.listen(endpoint).unwrap();
-                    let new_handle = GlobalSocketHandle(sockets.add(socket));
-                    let old_handle = ::core::mem::replace(&mut self.handle, new_handle);
-
-                    Box::new(TcpSocketState {
-                        handle: old_handle,
-                        local_endpoint: self.local_endpoint,
-                        is_listening: false,
-                    })
-                };
-
-                drop(sockets);
-                poll_ifaces();
-                return Ok((new_socket, Endpoint::Ip(remote_endpoint)));
+        let (endpoint, deadline, nonblock) = {
+            let inner = self.inner.lock();
+            if !inner.is_listening {
+                return Err(SysError::EINVAL);
             }
+            let endpoint = inner.local_endpoint.ok_or(SysError::EINVAL)?;
+            let deadline = deadline(inner.options.recv_timeout);
+            (endpoint, deadline, inner.options.nonblock)
+        };
+        spin_and_wait(&[&SOCKET_ACTIVITY], || {
+            self.inner.lock().try_accept(endpoint, deadline, nonblock)
+        })
+    }
 
-            drop(socket);
-            SOCKET_ACTIVITY.wait(sockets);
-        }
+    fn async_accept<'a>(
+        &'a mut self,
+        nonblock: bool,
+    ) -> SocketFuture<'a, Result<(Box<dyn Socket>, Endpoint), SysError>> {
+        Box::pin(async move {
+            let (handle, endpoint, deadline, nonblock) = {
+                let inner = self.inner.lock();
+                if !inner.is_listening {
+                    return Err(SysError::EINVAL);
+                }
+                let endpoint = inner.local_endpoint.ok_or(SysError::EINVAL)?;
+                let deadline = deadline(inner.options.recv_timeout);
+                let nonblock = nonblock || inner.options.nonblock;
+                (inner.handle.0, endpoint, deadline, nonblock)
+            };
+            wait_socket(handle, tcp_acceptable, || {
+                self.inner.lock().try_accept(endpoint, deadline, nonblock)
+            })
+            .await
+        })
     }
 
     fn endpoint(&self) -> Option<Endpoint> {
-        self.local_endpoint
+        let inner = self.inner.lock();
+        inner
+            .local_endpoint
             .clone()
             .map(|e| Endpoint::Ip(e))
             .or_else(|| {
                 let mut sockets = SOCKETS.lock();
-                let socket = sockets.get::<TcpSocket>(self.handle.0);
+                let socket = sockets.get::<TcpSocket>(inner.handle.0);
                 let endpoint = socket.local_endpoint();
                 if endpoint.port != 0 {
                     Some(Endpoint::Ip(endpoint))
@@ -379,8 +787,9 @@ impl Socket for TcpSocketState {
     }
 
     fn remote_endpoint(&self) -> Option<Endpoint> {
+        let inner = self.inner.lock();
         let mut sockets = SOCKETS.lock();
-        let socket = sockets.get::<TcpSocket>(self.handle.0);
+        let socket = sockets.get::<TcpSocket>(inner.handle.0);
         if socket.is_open() {
             Some(Endpoint::Ip(socket.remote_endpoint()))
         } else {
@@ -388,13 +797,70 @@ impl Socket for TcpSocketState {
         }
     }
 
+    fn set_option(&mut self, option: SocketOption) -> SysResult {
+        let mut inner = self.inner.lock();
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<TcpSocket>(inner.handle.0);
+        match option {
+            SocketOption::KeepAlive(on) => inner.keep_alive = on,
+            SocketOption::KeepIdle(secs) => {
+                if secs == 0 {
+                    return Err(SysError::EINVAL);
+                }
+                inner.keep_idle = secs;
+            }
+            SocketOption::NoDelay(on) => inner.no_delay = on,
+            SocketOption::Ttl(ttl) => socket.set_hop_limit(Some(ttl)),
+            SocketOption::V6Only(on) => return inner.family.set_v6only(on),
+            // buffers are allocated with the socket
+            SocketOption::SendBuffer(_) | SocketOption::RecvBuffer(_) => {}
+            option => return inner.options.set(option),
+        }
+        inner.set_keep_alive(&mut socket);
+        Ok(0)
+    }
+
+    fn get_option(&self, name: SocketOptionName) -> Result<SocketOption, SysError> {
+        let inner = self.inner.lock();
+        match name {
+            SocketOptionName::KeepAlive => Ok(SocketOption::KeepAlive(inner.keep_alive)),
+            SocketOptionName::KeepIdle => Ok(SocketOption::KeepIdle(inner.keep_idle)),
+            SocketOptionName::NoDelay => Ok(SocketOption::NoDelay(inner.no_delay)),
+            SocketOptionName::Ttl => {
+                let mut sockets = SOCKETS.lock();
+                let socket = sockets.get::<TcpSocket>(inner.handle.0);
+                Ok(SocketOption::Ttl(socket.hop_limit().unwrap_or(DEFAULT_TTL)))
+            }
+            SocketOptionName::V6Only => inner.family.v6only(),
+            SocketOptionName::SendBuffer => Ok(SocketOption::SendBuffer(TCP_SENDBUF)),
+            SocketOptionName::RecvBuffer => Ok(SocketOption::RecvBuffer(TCP_RECVBUF)),
+            SocketOptionName::Error if inner.connecting => {
+                // a connect which returned EINPROGRESS is refused once the socket is closed
+                let mut sockets = SOCKETS.lock();
+                let socket = sockets.get::<TcpSocket>(inner.handle.0);
+                let error = match socket.state() {
+                    TcpState::Closed => SysError::ECONNREFUSED as usize,
+                    _ => inner.error,
+                };
+                Ok(SocketOption::Error(error))
+            }
+            SocketOptionName::Error => Ok(SocketOption::Error(inner.error)),
+            SocketOptionName::Type => Ok(SocketOption::Type(SocketType::Stream)),
+            name => inner.options.get(name),
+        }
+    }
+
+    fn ip_family(&self) -> Option<IpFamily> {
+        Some(self.inner.lock().family)
+    }
+
     fn box_clone(&self) -> Box<dyn Socket> {
         Box::new(self.clone())
     }
 }
 
 impl UdpSocketState {
-    pub fn new() -> Self {
+    pub fn new(family: IpFamily) -> Self {
         let rx_buffer = UdpSocketBuffer::new(
             vec![UdpPacketMetadata::EMPTY; UDP_METADATA_BUF],
             vec![0; UDP_RECVBUF],
@@ -408,85 +874,193 @@ impl UdpSocketState {
 
         UdpSocketState {
             handle,
-            remote_endpoint: None,
+            inner: Arc::new(Mutex::new(UdpSocketInner {
+                family,
+                remote_endpoint: None,
+                port: None,
+                pktinfo: false,
+                options: SocketOptions::default(),
+            })),
         }
     }
-}
 
-#[repr(C)]
-struct ArpReq {
-    arp_pa: SockAddrPlaceholder,
-    arp_ha: SockAddrPlaceholder,
-    arp_flags: u32,
-    arp_netmask: SockAddrPlaceholder,
-    arp_dev: [u8; 16],
-}
+    /// Take `endpoint` from the port table, or an ephemeral port if its port is 0
+    fn bind_port(&self, inner: &mut UdpSocketInner, mut endpoint: IpEndpoint) -> SysResult {
+        if inner.port.is_some() {
+            return Err(SysError::EINVAL);
+        }
+        let binding = UDP_PORTS.bind(endpoint.addr, endpoint.port, &inner.options)?;
+        endpoint.port = binding.port();
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<UdpSocket>(self.handle.0);
+        socket.bind(endpoint).map_err(|_| SysError::EINVAL)?;
+        inner.port = Some(binding);
+        Ok(0)
+    }
 
-impl Socket for UdpSocketState {
-    fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+    /// One try of `read`, `None` to wait for a datagram
+    fn try_read(
+        &self,
+        data: &mut [u8],
+        deadline: Option<Duration>,
+        nonblock: bool,
+    ) -> Option<(SysResult, Endpoint)> {
+        let family = self.inner.lock().family;
         loop {
             let mut sockets = SOCKETS.lock();
             let mut socket = sockets.get::<UdpSocket>(self.handle.0);
 
+            if !socket.is_open() {
+                return Some((
+                    Err(SysError::ENOTCONN),
+                    Endpoint::Ip(IpEndpoint::UNSPECIFIED),
+                ));
+            }
             if socket.can_recv() {
                 if let Ok((size, remote_endpoint)) = socket.recv_slice(data) {
+                    if !family.accepts(&remote_endpoint.addr) {
+                        // drop datagrams from the other family
+                        continue;
+                    }
                     let endpoint = remote_endpoint;
                     // avoid deadlock
                     drop(socket);
                     drop(sockets);
 
                     poll_ifaces();
-                    return (Ok(size), Endpoint::Ip(endpoint));
+                    return Some((Ok(size), Endpoint::Ip(endpoint)));
                 }
-            } else {
-                return (
-                    Err(SysError::ENOTCONN),
-                    Endpoint::Ip(IpEndpoint::UNSPECIFIED),
-                );
+            } else if nonblock || expired(deadline) {
+                return Some((Err(SysError::EAGAIN), Endpoint::Ip(IpEndpoint::UNSPECIFIED)));
             }
+            return None;
+        }
+    }
 
-            drop(socket);
-            SOCKET_ACTIVITY.wait(sockets);
+    /// Ancillary data of a datagram received by `read`
+    fn ancillary(&self, result: &SysResult, endpoint: &Endpoint) -> Ancillary {
+        let mut ancillary = Ancillary::default();
+        let pktinfo = self.inner.lock().pktinfo;
+        if let (true, Ok(_), Endpoint::Ip(remote)) = (pktinfo, result, endpoint) {
+            // smoltcp does not tell the destination of a datagram,
+            // so report the address it is bound to, or the one of the interface to the sender
+            let ifaces = NET_DRIVERS.read();
+            let index = route_index(&ifaces, &remote.addr);
+            let bound = {
+                let mut sockets = SOCKETS.lock();
+                let socket = sockets.get::<UdpSocket>(self.handle.0);
+                socket.endpoint().addr
+            };
+            let local = match bound {
+                IpAddress::Ipv4(addr) => Some(addr),
+                _ => ifaces[index].ipv4_address(),
+            };
+            ancillary.pktinfo = local.map(|addr| (addr, index));
         }
+        ancillary
+    }
+}
+
+#[repr(C)]
+struct ArpReq {
+    arp_pa: SockAddrPlaceholder,
+    arp_ha: SockAddrPlaceholder,
+    arp_flags: u32,
+    arp_netmask: SockAddrPlaceholder,
+    arp_dev: [u8; 16],
+}
+
+impl Socket for UdpSocketState {
+    fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        let (deadline, nonblock) = {
+            let inner = self.inner.lock();
+            (deadline(inner.options.recv_timeout), inner.options.nonblock)
+        };
+        spin_and_wait(&[&SOCKET_ACTIVITY], move || {
+            self.try_read(data, deadline, nonblock)
+        })
+    }
+
+    fn async_read<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint)> {
+        let (deadline, nonblock) = {
+            let inner = self.inner.lock();
+            let deadline = deadline(inner.options.recv_timeout);
+            (deadline, nonblock || inner.options.nonblock)
+        };
+        Box::pin(wait_socket(self.handle.0, udp_readable, move || {
+            self.try_read(data, deadline, nonblock)
+        }))
     }
 
     fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
-        let remote_endpoint = {
-            if let Some(Endpoint::Ip(ref endpoint)) = sendto_endpoint {
-                endpoint
-            } else if let Some(ref endpoint) = self.remote_endpoint {
-                endpoint
-            } else {
-                return Err(SysError::ENOTCONN);
+        let (remote_endpoint, deadline, nonblock) = {
+            let mut inner = self.inner.lock();
+            let remote_endpoint = match sendto_endpoint {
+                Some(Endpoint::Ip(endpoint)) => endpoint,
+                _ => inner.remote_endpoint.ok_or(SysError::ENOTCONN)?,
+            };
+            if let IpAddress::Ipv4(addr) = remote_endpoint.addr {
+                if addr.is_broadcast() && !inner.options.broadcast {
+                    return Err(SysError::EACCES);
+                }
             }
+            if inner.port.is_none() {
+                self.bind_port(&mut inner, IpEndpoint::new(IpAddress::Unspecified, 0))?;
+            }
+            let deadline = deadline(inner.options.send_timeout);
+            (remote_endpoint, deadline, inner.options.nonblock)
         };
-        let mut sockets = SOCKETS.lock();
-        let mut socket = sockets.get::<UdpSocket>(self.handle.0);
-
-        if socket.endpoint().port == 0 {
-            let temp_port = get_ephemeral_port();
-            socket
-                .bind(IpEndpoint::new(IpAddress::Unspecified, temp_port))
-                .unwrap();
-        }
+        loop {
+            let mut sockets = SOCKETS.lock();
+            let mut socket = sockets.get::<UdpSocket>(self.handle.0);
 
-        if socket.can_send() {
-            match socket.send_slice(&data, *remote_endpoint) {
-                Ok(()) => {
-                    // avoid deadlock
-                    drop(socket);
-                    drop(sockets);
+            if socket.can_send() {
+                return match socket.send_slice(&data, remote_endpoint) {
+                    Ok(()) => {
+                        // avoid deadlock
+                        drop(socket);
+                        drop(sockets);
 
-                    poll_ifaces();
-                    Ok(data.len())
-                }
-                Err(_) => Err(SysError::ENOBUFS),
+                        poll_ifaces();
+                        Ok(data.len())
+                    }
+                    Err(_) => Err(SysError::ENOBUFS),
+                };
             }
-        } else {
-            Err(SysError::ENOBUFS)
+            if nonblock || expired(deadline) {
+                return Err(SysError::EAGAIN);
+            }
+
+            // wait for the queued datagrams to go out
+            drop(socket);
+            drop(sockets);
+            poll_ifaces();
+            SOCKET_ACTIVITY.wait(SOCKETS.lock());
         }
     }
 
+    fn recvmsg(&self, data: &mut [u8]) -> (SysResult, Endpoint, Ancillary) {
+        let (result, endpoint) = self.read(data);
+        let ancillary = self.ancillary(&result, &endpoint);
+        (result, endpoint, ancillary)
+    }
+
+    fn async_recvmsg<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint, Ancillary)> {
+        Box::pin(async move {
+            let (result, endpoint) = self.async_read(data, nonblock).await;
+            let ancillary = self.ancillary(&result, &endpoint);
+            (result, endpoint, ancillary)
+        })
+    }
+
     fn poll(&self) -> (bool, bool, bool) {
         let mut sockets = SOCKETS.lock();
         let socket = sockets.get::<UdpSocket>(self.handle.0);
@@ -503,7 +1077,7 @@ impl Socket for UdpSocketState {
 
     fn connect(&mut self, endpoint: Endpoint) -> SysResult {
         if let Endpoint::Ip(ip) = endpoint {
-            self.remote_endpoint = Some(ip);
+            self.inner.lock().remote_endpoint = Some(ip);
             Ok(0)
         } else {
             Err(SysError::EINVAL)
@@ -511,13 +1085,9 @@ impl Socket for UdpSocketState {
     }
 
     fn bind(&mut self, endpoint: Endpoint) -> SysResult {
-        let mut sockets = SOCKETS.lock();
-        let mut socket = sockets.get::<UdpSocket>(self.handle.0);
         if let Endpoint::Ip(ip) = endpoint {
-            match socket.bind(ip) {
-                Ok(()) => Ok(0),
-                Err(_) => Err(SysError::EINVAL),
-            }
+            let mut inner = self.inner.lock();
+            self.bind_port(&mut inner, ip)
         } else {
             Err(SysError::EINVAL)
         }
@@ -572,7 +1142,46 @@ impl Socket for UdpSocketState {
     }
 
     fn remote_endpoint(&self) -> Option<Endpoint> {
-        self.remote_endpoint.clone().map(|e| Endpoint::Ip(e))
+        self.inner.lock().remote_endpoint.map(|e| Endpoint::Ip(e))
+    }
+
+    fn set_option(&mut self, option: SocketOption) -> SysResult {
+        let mut inner = self.inner.lock();
+        match option {
+            SocketOption::PacketInfo(on) => inner.pktinfo = on,
+            SocketOption::Ttl(ttl) => {
+                let mut sockets = SOCKETS.lock();
+                let mut socket = sockets.get::<UdpSocket>(self.handle.0);
+                socket.set_hop_limit(Some(ttl));
+            }
+            SocketOption::V6Only(on) => return inner.family.set_v6only(on),
+            // buffers are allocated with the socket
+            SocketOption::SendBuffer(_) | SocketOption::RecvBuffer(_) => {}
+            option => return inner.options.set(option),
+        }
+        Ok(0)
+    }
+
+    fn get_option(&self, name: SocketOptionName) -> Result<SocketOption, SysError> {
+        let inner = self.inner.lock();
+        match name {
+            SocketOptionName::PacketInfo => Ok(SocketOption::PacketInfo(inner.pktinfo)),
+            SocketOptionName::Ttl => {
+                let mut sockets = SOCKETS.lock();
+                let socket = sockets.get::<UdpSocket>(self.handle.0);
+                Ok(SocketOption::Ttl(socket.hop_limit().unwrap_or(DEFAULT_TTL)))
+            }
+            SocketOptionName::V6Only => inner.family.v6only(),
+            SocketOptionName::SendBuffer => Ok(SocketOption::SendBuffer(UDP_SENDBUF)),
+            SocketOptionName::RecvBuffer => Ok(SocketOption::RecvBuffer(UDP_RECVBUF)),
+            SocketOptionName::Error => Ok(SocketOption::Error(0)),
+            SocketOptionName::Type => Ok(SocketOption::Type(SocketType::Datagram)),
+            name => inner.options.get(name),
+        }
+    }
+
+    fn ip_family(&self) -> Option<IpFamily> {
+        Some(self.inner.lock().family)
     }
 
     fn box_clone(&self) -> Box<dyn Socket> {
@@ -581,7 +1190,7 @@ impl Socket for UdpSocketState {
 }
 
 impl RawSocketState {
-    pub fn new(protocol: u8) -> Self {
+    pub fn new(family: IpFamily, protocol: u8) -> Self {
         let rx_buffer = RawSocketBuffer::new(
             vec![RawPacketMetadata::EMPTY; RAW_METADATA_BUF],
             vec![0; RAW_RECVBUF],
@@ -590,46 +1199,102 @@ impl RawSocketState {
             vec![RawPacketMetadata::EMPTY; RAW_METADATA_BUF],
             vec![0; RAW_SENDBUF],
         );
-        let socket = RawSocket::new(
-            IpVersion::Ipv4,
-            IpProtocol::from(protocol),
-            rx_buffer,
-            tx_buffer,
-        );
+        let version = match family {
+            IpFamily::V4 => IpVersion::Ipv4,
+            IpFamily::V6 { .. } => IpVersion::Ipv6,
+        };
+        let socket = RawSocket::new(version, IpProtocol::from(protocol), rx_buffer, tx_buffer);
         let handle = GlobalSocketHandle(SOCKETS.lock().add(socket));
 
         RawSocketState {
             handle,
-            header_included: false,
+            inner: Arc::new(Mutex::new(RawSocketInner {
+                family,
+                header_included: false,
+                ttl: DEFAULT_TTL,
+                options: SocketOptions::default(),
+            })),
         }
     }
+
+    /// One try of `read`, `None` to wait for a packet
+    fn try_read(
+        &self,
+        data: &mut [u8],
+        deadline: Option<Duration>,
+        nonblock: bool,
+    ) -> Option<(SysResult, Endpoint)> {
+        let family = self.inner.lock().family;
+        let mut sockets = SOCKETS.lock();
+        let mut socket = sockets.get::<RawSocket>(self.handle.0);
+
+        if let Ok(size) = socket.recv_slice(data) {
+            let (size, addr) = match family {
+                IpFamily::V4 => {
+                    let packet = Ipv4Packet::new_unchecked(data);
+                    (size, IpAddress::Ipv4(packet.src_addr()))
+                }
+                IpFamily::V6 { .. } => {
+                    // IPv6 raw sockets get no header
+                    let addr = Ipv6Packet::new_unchecked(&data[..]).src_addr();
+                    let size = size.saturating_sub(IPV6_HEADER_LEN);
+                    data.copy_within(IPV6_HEADER_LEN..IPV6_HEADER_LEN + size, 0);
+                    (size, IpAddress::Ipv6(addr))
+                }
+            };
+
+            return Some((Ok(size), Endpoint::Ip(IpEndpoint { addr, port: 0 })));
+        }
+        if nonblock || expired(deadline) {
+            return Some((Err(SysError::EAGAIN), Endpoint::Ip(IpEndpoint::UNSPECIFIED)));
+        }
+        None
+    }
 }
 
 impl Socket for RawSocketState {
     fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
-        loop {
-            let mut sockets = SOCKETS.lock();
-            let mut socket = sockets.get::<RawSocket>(self.handle.0);
+        let (deadline, nonblock) = {
+            let inner = self.inner.lock();
+            (deadline(inner.options.recv_timeout), inner.options.nonblock)
+        };
+        spin_and_wait(&[&SOCKET_ACTIVITY], move || {
+            self.try_read(data, deadline, nonblock)
+        })
+    }
 
-            if let Ok(size) = socket.recv_slice(data) {
-                let packet = Ipv4Packet::new_unchecked(data);
-
-                return (
-                    Ok(size),
-                    Endpoint::Ip(IpEndpoint {
-                        addr: IpAddress::Ipv4(packet.src_addr()),
-                        port: 0,
-                    }),
-                );
-            }
+    fn async_read<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint)> {
+        let (deadline, nonblock) = {
+            let inner = self.inner.lock();
+            let deadline = deadline(inner.options.recv_timeout);
+            (deadline, nonblock || inner.options.nonblock)
+        };
+        Box::pin(wait_socket(self.handle.0, raw_readable, move || {
+            self.try_read(data, deadline, nonblock)
+        }))
+    }
 
-            drop(socket);
-            SOCKET_ACTIVITY.wait(sockets);
-        }
+    fn async_recvmsg<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint, Ancillary)> {
+        Box::pin(async move {
+            let (result, endpoint) = self.async_read(data, nonblock).await;
+            (result, endpoint, Ancillary::default())
+        })
     }
 
     fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
-        if self.header_included {
+        let (header_included, ttl) = {
+            let inner = self.inner.lock();
+            (inner.header_included, inner.ttl)
+        };
+        if header_included {
             let mut sockets = SOCKETS.lock();
             let mut socket = sockets.get::<RawSocket>(self.handle.0);
 
@@ -639,13 +1304,13 @@ impl Socket for RawSocketState {
             }
         } else {
             if let Some(Endpoint::Ip(endpoint)) = sendto_endpoint {
-                // temporary solution
-                let iface = &*(NET_DRIVERS.read()[0]);
-                let v4_src = iface.ipv4_address().unwrap();
+                let ifaces = NET_DRIVERS.read();
+                let iface = route_iface(&ifaces, &endpoint.addr);
                 let mut sockets = SOCKETS.lock();
                 let mut socket = sockets.get::<RawSocket>(self.handle.0);
 
                 if let IpAddress::Ipv4(v4_dst) = endpoint.addr {
+                    let v4_src = iface.ipv4_address().ok_or(SysError::ENETUNREACH)?;
                     let len = data.len();
                     // using 20-byte IPv4 header
                     let mut buffer = vec![0u8; len + 20];
@@ -654,6 +1319,7 @@ impl Socket for RawSocketState {
                     packet.set_header_len(20);
                     packet.set_total_len((20 + len) as u16);
                     packet.set_protocol(socket.ip_protocol().into());
+                    packet.set_hop_limit(ttl);
                     packet.set_src_addr(v4_src);
                     packet.set_dst_addr(v4_dst);
                     let payload = packet.payload_mut();
@@ -667,9 +1333,49 @@ impl Socket for RawSocketState {
                     drop(sockets);
                     iface.poll();
 
+                    Ok(len)
+                } else if let IpAddress::Ipv6(v6_dst) = endpoint.addr {
+                    // the address on the network of the destination, or the first one
+                    let v6_addrs = iface.ipv6_addresses();
+                    let v6_src = iface
+                        .get_ip_addresses()
+                        .iter()
+                        .find(|cidr| cidr.contains_addr(&endpoint.addr))
+                        .and_then(|cidr| match cidr.address() {
+                            IpAddress::Ipv6(addr) => Some(addr),
+                            _ => None,
+                        })
+                        .or_else(|| v6_addrs.first().cloned())
+                        .ok_or(SysError::ENETUNREACH)?;
+                    let len = data.len();
+                    let mut buffer = vec![0u8; len + IPV6_HEADER_LEN];
+                    let mut packet = Ipv6Packet::new_unchecked(&mut buffer);
+                    packet.set_version(6);
+                    packet.set_traffic_class(0);
+                    packet.set_flow_label(0);
+                    packet.set_payload_len(len as u16);
+                    packet.set_next_header(socket.ip_protocol());
+                    packet.set_hop_limit(ttl);
+                    packet.set_src_addr(v6_src);
+                    packet.set_dst_addr(v6_dst);
+                    let payload = packet.payload_mut();
+                    payload.copy_from_slice(data);
+                    // the kernel computes ICMPv6 checksums, as they cover the pseudo header
+                    if socket.ip_protocol() == IpProtocol::Icmpv6 && len >= 4 {
+                        Icmpv6Packet::new_unchecked(payload)
+                            .fill_checksum(&v6_src.into(), &v6_dst.into());
+                    }
+
+                    socket.send_slice(&buffer).map_err(|_| SysError::ENOBUFS)?;
+
+                    // avoid deadlock
+                    drop(socket);
+                    drop(sockets);
+                    iface.poll();
+
                     Ok(len)
                 } else {
-                    unimplemented!("ip type")
+                    Err(SysError::EINVAL)
                 }
             } else {
                 Err(SysError::ENOTCONN)
@@ -689,53 +1395,40 @@ impl Socket for RawSocketState {
         Box::new(self.clone())
     }
 
-    fn setsockopt(&mut self, level: usize, opt: usize, data: &[u8]) -> SysResult {
-        match (level, opt) {
-            (IPPROTO_IP, IP_HDRINCL) => {
-                if let Some(arg) = data.first() {
-                    self.header_included = *arg > 0;
-                    debug!("hdrincl set to {}", self.header_included);
-                }
+    fn set_option(&mut self, option: SocketOption) -> SysResult {
+        let mut inner = self.inner.lock();
+        match option {
+            SocketOption::HeaderIncluded(on) => {
+                inner.header_included = on;
+                debug!("hdrincl set to {}", inner.header_included);
             }
-            _ => {}
+            SocketOption::Ttl(ttl) => inner.ttl = ttl,
+            SocketOption::V6Only(on) => return inner.family.set_v6only(on),
+            // buffers are allocated with the socket
+            SocketOption::SendBuffer(_) | SocketOption::RecvBuffer(_) => {}
+            option => return inner.options.set(option),
         }
         Ok(0)
     }
-}
-
-impl PacketSocketState {
-    pub fn new() -> Self {
-        PacketSocketState {}
-    }
-}
-
-impl Socket for PacketSocketState {
-    fn read(&self, _data: &mut [u8]) -> (SysResult, Endpoint) {
-        unimplemented!()
-    }
 
-    fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
-        if let Some(Endpoint::LinkLevel(endpoint)) = sendto_endpoint {
-            let ifaces = NET_DRIVERS.read();
-            match ifaces[endpoint.interface_index].send(data) {
-                Some(len) => Ok(len),
-                None => Err(SysError::ENOBUFS),
+    fn get_option(&self, name: SocketOptionName) -> Result<SocketOption, SysError> {
+        let inner = self.inner.lock();
+        match name {
+            SocketOptionName::HeaderIncluded => {
+                Ok(SocketOption::HeaderIncluded(inner.header_included))
             }
-        } else {
-            Err(SysError::ENOTCONN)
+            SocketOptionName::Ttl => Ok(SocketOption::Ttl(inner.ttl)),
+            SocketOptionName::V6Only => inner.family.v6only(),
+            SocketOptionName::SendBuffer => Ok(SocketOption::SendBuffer(RAW_SENDBUF)),
+            SocketOptionName::RecvBuffer => Ok(SocketOption::RecvBuffer(RAW_RECVBUF)),
+            SocketOptionName::Error => Ok(SocketOption::Error(0)),
+            SocketOptionName::Type => Ok(SocketOption::Type(SocketType::Raw)),
+            name => inner.options.get(name),
         }
     }
 
-    fn poll(&self) -> (bool, bool, bool) {
-        unimplemented!()
-    }
-
-    fn connect(&mut self, _endpoint: Endpoint) -> SysResult {
-        unimplemented!()
-    }
-
-    fn box_clone(&self) -> Box<dyn Socket> {
-        Box::new(self.clone())
+    fn ip_family(&self) -> Option<IpFamily> {
+        Some(self.inner.lock().family)
     }
 }
 
@@ -780,6 +1473,20 @@ struct RouteAttr {
     rta_type: u16,
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct RouteMsg {
+    rtm_family: u8,
+    rtm_dst_len: u8,
+    rtm_src_len: u8,
+    rtm_tos: u8,
+    rtm_table: u8,
+    rtm_protocol: u8,
+    rtm_scope: u8,
+    rtm_type: u8,
+    rtm_flags: u32,
+}
+
 bitflags! {
     struct NetlinkMessageFlags : u16 {
         const REQUEST = 0x01;
@@ -831,6 +1538,12 @@ enum_with_unknown! {
         DelAddr = 21,
         /// Get addr
         GetAddr = 22,
+        /// New route
+        NewRoute = 24,
+        /// Delete route
+        DelRoute = 25,
+        /// Get route
+        GetRoute = 26,
     }
 }
 
@@ -864,6 +1577,7 @@ trait VecExt {
     fn align4(&mut self);
     fn push_ext<T: Sized>(&mut self, data: T);
     fn set_ext<T: Sized>(&mut self, offset: usize, data: T);
+    fn push_attr(&mut self, rta_type: u16, data: &[u8]);
 }
 
 impl VecExt for Vec<u8> {
@@ -892,6 +1606,282 @@ impl VecExt for Vec<u8> {
             self[offset + i] = bytes[i];
         }
     }
+
+    fn push_attr(&mut self, rta_type: u16, data: &[u8]) {
+        let attr = RouteAttr {
+            rta_len: (data.len() + size_of::<RouteAttr>()) as u16,
+            rta_type,
+        };
+        self.align4();
+        self.push_ext(attr);
+        self.extend_from_slice(data);
+    }
+}
+
+/// Read a `T` at `offset` of a request
+fn read_ext<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    if data.len() < offset + size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { (data.as_ptr().add(offset) as *const T).read_unaligned() })
+}
+
+/// Attributes after a body of `body_len` bytes, as (type, data)
+fn parse_attrs(payload: &[u8], body_len: usize) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    let mut offset = (body_len + 3) & !3;
+    while let Some(attr) = read_ext::<RouteAttr>(payload, offset) {
+        let len = attr.rta_len as usize;
+        if len < size_of::<RouteAttr>() || offset + len > payload.len() {
+            break;
+        }
+        attrs.push((
+            attr.rta_type,
+            &payload[offset + size_of::<RouteAttr>()..offset + len],
+        ));
+        offset += (len + 3) & !3;
+    }
+    attrs
+}
+
+fn find_attr<'a>(attrs: &[(u16, &'a [u8])], rta_type: u16) -> Option<&'a [u8]> {
+    attrs
+        .iter()
+        .find(|(t, _)| *t == rta_type)
+        .map(|(_, data)| *data)
+}
+
+/// An address of `family` in an attribute
+fn parse_ip(family: u8, data: &[u8]) -> Result<IpAddress, SysError> {
+    match (AddressFamily::from(family as u16), data.len()) {
+        (AddressFamily::Internet, 4) => Ok(IpAddress::Ipv4(Ipv4Address::from_bytes(data))),
+        (AddressFamily::Internet6, 16) => Ok(IpAddress::Ipv6(Ipv6Address::from_bytes(data))),
+        _ => Err(SysError::EINVAL),
+    }
+}
+
+/// `addr` with `prefix_len`, checked as `IpCidr::new` panics on a bad one
+fn new_cidr(addr: IpAddress, prefix_len: u8) -> Result<IpCidr, SysError> {
+    let max_len = match addr {
+        IpAddress::Ipv4(_) => 32,
+        IpAddress::Ipv6(_) => 128,
+        _ => return Err(SysError::EINVAL),
+    };
+    if prefix_len > max_len {
+        return Err(SysError::EINVAL);
+    }
+    Ok(IpCidr::new(addr, prefix_len))
+}
+
+/// The network of `cidr`, with the host part cleared
+fn network_of(cidr: &IpCidr) -> IpCidr {
+    let mut bytes = Vec::from(cidr.address().as_bytes());
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let bits = (cidr.prefix_len() as usize).saturating_sub(i * 8).min(8);
+        *byte &= !0xffu8.checked_shr(bits as u32).unwrap_or(0);
+    }
+    let addr = match cidr.address() {
+        IpAddress::Ipv4(_) => IpAddress::Ipv4(Ipv4Address::from_bytes(&bytes)),
+        IpAddress::Ipv6(_) => IpAddress::Ipv6(Ipv6Address::from_bytes(&bytes)),
+        addr => addr,
+    };
+    IpCidr::new(addr, cidr.prefix_len())
+}
+
+/// Index in `NET_DRIVERS` of `ifindex`, which counts from 1 as 0 means none
+fn iface_index(ifaces: &[Arc<dyn NetDriver>], ifindex: u32) -> Result<usize, SysError> {
+    (ifindex as usize)
+        .checked_sub(1)
+        .filter(|&i| i < ifaces.len())
+        .ok_or(SysError::ENODEV)
+}
+
+fn family_of(addr: &IpAddress) -> u8 {
+    let family: u16 = match addr {
+        IpAddress::Ipv6(_) => AddressFamily::Internet6.into(),
+        _ => AddressFamily::Internet.into(),
+    };
+    family as u8
+}
+
+/// RTM_NEWADDR and RTM_DELADDR
+fn change_addr(message_type: NetlinkMessageType, payload: &[u8]) -> Result<(), SysError> {
+    let msg = read_ext::<IfaceAddrMsg>(payload, 0).ok_or(SysError::EINVAL)?;
+    let attrs = parse_attrs(payload, size_of::<IfaceAddrMsg>());
+    // IFA_ADDRESS is the peer address on point-to-point links, else the same as IFA_LOCAL
+    let data = find_attr(&attrs, IFA_LOCAL)
+        .or_else(|| find_attr(&attrs, IFA_ADDRESS))
+        .ok_or(SysError::EINVAL)?;
+    let addr = parse_ip(msg.ifa_family, data)?;
+    let cidr = new_cidr(addr, msg.ifa_prefixlen)?;
+
+    let ifaces = NET_DRIVERS.read();
+    let iface = &ifaces[iface_index(&ifaces, msg.ifa_index)?];
+    let mut addrs = iface.get_ip_addresses();
+    let old = addrs.iter().position(|cidr| cidr.address() == addr);
+    if let NetlinkMessageType::NewAddr = message_type {
+        if old.is_some() {
+            return Err(SysError::EEXIST);
+        }
+        addrs.push(cidr);
+    } else {
+        addrs.remove(old.ok_or(SysError::EADDRNOTAVAIL)?);
+    }
+    iface.set_ip_addresses(addrs)
+}
+
+/// RTM_NEWLINK and RTM_SETLINK on an existing link
+fn set_link(payload: &[u8]) -> Result<(), SysError> {
+    let msg = read_ext::<IfaceInfoMsg>(payload, 0).ok_or(SysError::EINVAL)?;
+    let attrs = parse_attrs(payload, size_of::<IfaceInfoMsg>());
+    let ifaces = NET_DRIVERS.read();
+    let iface = if msg.ifi_index != 0 {
+        &ifaces[iface_index(&ifaces, msg.ifi_index)?]
+    } else {
+        // by name, which may end with NUL
+        let name = find_attr(&attrs, RouteAttrTypes::Ifname.into()).ok_or(SysError::EINVAL)?;
+        let name = name.split(|&c| c == 0).next().unwrap_or(name);
+        ifaces
+            .iter()
+            .find(|iface| iface.get_ifname().as_bytes() == name)
+            .ok_or(SysError::ENODEV)?
+    };
+
+    if let Some(data) = find_attr(&attrs, RouteAttrTypes::MTU.into()) {
+        let mtu = read_ext::<u32>(data, 0).ok_or(SysError::EINVAL)?;
+        if !iface.set_mtu(mtu as usize) {
+            return Err(SysError::EINVAL);
+        }
+    }
+    if msg.ifi_flags != 0 || msg.ifi_change != 0 {
+        // no change mask means all flags
+        let change = if msg.ifi_change == 0 {
+            !0
+        } else {
+            msg.ifi_change
+        };
+        if change & IFF_UP != 0 {
+            iface.set_up(msg.ifi_flags & IFF_UP != 0);
+        }
+    }
+    Ok(())
+}
+
+/// RTM_NEWROUTE and RTM_DELROUTE
+fn change_route(
+    message_type: NetlinkMessageType,
+    flags: NetlinkMessageFlags,
+    payload: &[u8],
+) -> Result<(), SysError> {
+    let msg = read_ext::<RouteMsg>(payload, 0).ok_or(SysError::EINVAL)?;
+    let attrs = parse_attrs(payload, size_of::<RouteMsg>());
+    let dst = match find_attr(&attrs, RTA_DST) {
+        Some(data) => parse_ip(msg.rtm_family, data)?,
+        // the default route
+        None => match AddressFamily::from(msg.rtm_family as u16) {
+            AddressFamily::Internet => IpAddress::Ipv4(Ipv4Address::UNSPECIFIED),
+            AddressFamily::Internet6 => IpAddress::Ipv6(Ipv6Address::UNSPECIFIED),
+            _ => return Err(SysError::EAFNOSUPPORT),
+        },
+    };
+    let dst = new_cidr(dst, msg.rtm_dst_len)?;
+    if network_of(&dst) != dst {
+        return Err(SysError::EINVAL);
+    }
+    let gateway = match find_attr(&attrs, RTA_GATEWAY) {
+        Some(data) => Some(parse_ip(msg.rtm_family, data)?),
+        None => None,
+    };
+    let iface = match find_attr(&attrs, RTA_OIF) {
+        Some(data) => {
+            let ifindex = read_ext::<u32>(data, 0).ok_or(SysError::EINVAL)?;
+            Some(iface_index(&NET_DRIVERS.read(), ifindex)?)
+        }
+        None => None,
+    };
+    let metric = match find_attr(&attrs, RTA_PRIORITY) {
+        Some(data) => Some(read_ext::<u32>(data, 0).ok_or(SysError::EINVAL)?),
+        None => None,
+    };
+
+    if let NetlinkMessageType::NewRoute = message_type {
+        let iface = match (iface, gateway) {
+            (Some(iface), _) => iface,
+            // the interface on the network of the gateway
+            (None, Some(gateway)) => NET_DRIVERS
+                .read()
+                .iter()
+                .position(|iface| {
+                    let addrs = iface.get_ip_addresses();
+                    addrs.iter().any(|cidr| cidr.contains_addr(&gateway))
+                })
+                .ok_or(SysError::ENETUNREACH)?,
+            (None, None) => return Err(SysError::EINVAL),
+        };
+        let route = RouteEntry {
+            dst,
+            gateway,
+            iface,
+            metric: metric.unwrap_or(0),
+        };
+        add_route(route, flags.contains(NetlinkMessageFlags::REPLACE))
+    } else {
+        del_route(dst, gateway, iface, metric)
+    }
+}
+
+/// A message of a route dump
+fn route_message(
+    header: &NetlinkMessageHeader,
+    route: &RouteEntry,
+    protocol: u8,
+    prefsrc: Option<IpAddress>,
+) -> Vec<u8> {
+    let mut msg = Vec::new();
+    let new_header = NetlinkMessageHeader {
+        nlmsg_len: 0, // to be determined later
+        nlmsg_type: NetlinkMessageType::NewRoute.into(),
+        nlmsg_flags: NetlinkMessageFlags::MULTI,
+        nlmsg_seq: header.nlmsg_seq,
+        nlmsg_pid: header.nlmsg_pid,
+    };
+    msg.push_ext(new_header);
+
+    let route_msg = RouteMsg {
+        rtm_family: family_of(&route.dst.address()),
+        rtm_dst_len: route.dst.prefix_len(),
+        rtm_src_len: 0,
+        rtm_tos: 0,
+        rtm_table: RT_TABLE_MAIN,
+        rtm_protocol: protocol,
+        rtm_scope: if route.gateway.is_some() {
+            RT_SCOPE_UNIVERSE
+        } else {
+            RT_SCOPE_LINK
+        },
+        rtm_type: RTN_UNICAST,
+        rtm_flags: 0,
+    };
+    msg.align4();
+    msg.push_ext(route_msg);
+
+    if route.dst.prefix_len() > 0 {
+        msg.push_attr(RTA_DST, route.dst.address().as_bytes());
+    }
+    if let Some(gateway) = route.gateway {
+        msg.push_attr(RTA_GATEWAY, gateway.as_bytes());
+    }
+    if let Some(addr) = prefsrc {
+        msg.push_attr(RTA_PREFSRC, addr.as_bytes());
+    }
+    msg.push_attr(RTA_OIF, &(route.iface as u32 + 1).to_ne_bytes());
+    if route.metric != 0 {
+        msg.push_attr(RTA_PRIORITY, &route.metric.to_ne_bytes());
+    }
+
+    msg.align4();
+    msg.set_ext(0, msg.len() as u32);
+    msg
 }
 
 impl Socket for NetlinkSocketState {
@@ -924,14 +1914,19 @@ impl Socket for NetlinkSocketState {
             return Err(SysError::EINVAL);
         }
         let header = unsafe { &*(data.as_ptr() as *const NetlinkMessageHeader) };
-        if header.nlmsg_len as usize > data.len() {
+        let len = header.nlmsg_len as usize;
+        if len > data.len() || len < size_of::<NetlinkMessageHeader>() {
             return Err(SysError::EINVAL);
         }
         let message_type = NetlinkMessageType::from(header.nlmsg_type);
         debug!("type: {:?}", message_type);
+        // the body and attributes of the request
+        let payload = &data[size_of::<NetlinkMessageHeader>()..len];
+        // the family to dump, all if unspecified
+        let family = payload.first().cloned().unwrap_or(0);
         let mut buffer = self.data.lock();
         buffer.clear();
-        match message_type {
+        let result = match message_type {
             NetlinkMessageType::GetLink => {
                 let ifaces = NET_DRIVERS.read();
                 for i in 0..ifaces.len() {
@@ -948,8 +1943,12 @@ impl Socket for NetlinkSocketState {
                     let if_info = IfaceInfoMsg {
                         ifi_family: AddressFamily::Unspecified.into(),
                         ifi_type: 0,
-                        ifi_index: i as u32,
-                        ifi_flags: 0,
+                        ifi_index: i as u32 + 1,
+                        ifi_flags: if ifaces[i].is_up() {
+                            IFF_UP | IFF_RUNNING
+                        } else {
+                            0
+                        },
                         ifi_change: 0,
                     };
                     msg.align4();
@@ -979,6 +1978,9 @@ impl Socket for NetlinkSocketState {
                         attrs.push(*byte);
                     }
 
+                    let mtu = ifaces[i].get_mtu() as u32;
+                    attrs.push_attr(RouteAttrTypes::MTU.into(), &mtu.to_ne_bytes());
+
                     msg.align4();
                     msg.append(&mut attrs);
 
@@ -987,12 +1989,16 @@ impl Socket for NetlinkSocketState {
 
                     buffer.push(msg);
                 }
+                Ok(())
             }
             NetlinkMessageType::GetAddr => {
                 let ifaces = NET_DRIVERS.read();
                 for i in 0..ifaces.len() {
                     let ip_addrs = ifaces[i].get_ip_addresses();
                     for j in 0..ip_addrs.len() {
+                        if family != 0 && family != family_of(&ip_addrs[j].address()) {
+                            continue;
+                        }
                         let mut msg = Vec::new();
                         let new_header = NetlinkMessageHeader {
                             nlmsg_len: 0, // to be determined later
@@ -1003,13 +2009,23 @@ impl Socket for NetlinkSocketState {
                         };
                         msg.push_ext(new_header);
 
-                        let family: u16 = AddressFamily::Internet.into();
+                        let (family, scope) = match ip_addrs[j].address() {
+                            IpAddress::Ipv6(addr) if addr.is_loopback() => {
+                                (AddressFamily::Internet6, RT_SCOPE_HOST)
+                            }
+                            IpAddress::Ipv6(addr) if addr.is_link_local() => {
+                                (AddressFamily::Internet6, RT_SCOPE_LINK)
+                            }
+                            IpAddress::Ipv6(_) => (AddressFamily::Internet6, RT_SCOPE_UNIVERSE),
+                            _ => (AddressFamily::Internet, RT_SCOPE_UNIVERSE),
+                        };
+                        let family: u16 = family.into();
                         let if_addr = IfaceAddrMsg {
                             ifa_family: family as u8,
                             ifa_prefixlen: ip_addrs[j].prefix_len(),
                             ifa_flags: 0,
-                            ifa_scope: 0,
-                            ifa_index: i as u32,
+                            ifa_scope: scope,
+                            ifa_index: i as u32 + 1,
                         };
                         msg.align4();
                         msg.push_ext(if_addr);
@@ -1036,21 +2052,84 @@ impl Socket for NetlinkSocketState {
                         buffer.push(msg);
                     }
                 }
+                Ok(())
             }
-            _ => {}
-        }
-        let mut msg = Vec::new();
-        let new_header = NetlinkMessageHeader {
-            nlmsg_len: 0, // to be determined later
-            nlmsg_type: NetlinkMessageType::Done.into(),
-            nlmsg_flags: NetlinkMessageFlags::MULTI,
-            nlmsg_seq: header.nlmsg_seq,
-            nlmsg_pid: header.nlmsg_pid,
+            NetlinkMessageType::GetRoute => {
+                // networks of the addresses first, then the routing table
+                let ifaces = NET_DRIVERS.read();
+                for i in 0..ifaces.len() {
+                    for cidr in ifaces[i].get_ip_addresses() {
+                        if family != 0 && family != family_of(&cidr.address()) {
+                            continue;
+                        }
+                        let route = RouteEntry {
+                            dst: network_of(&cidr),
+                            gateway: None,
+                            iface: i,
+                            metric: 0,
+                        };
+                        let msg =
+                            route_message(header, &route, RTPROT_KERNEL, Some(cidr.address()));
+                        buffer.push(msg);
+                    }
+                }
+                for route in ROUTE_TABLE.read().iter() {
+                    if family != 0 && family != family_of(&route.dst.address()) {
+                        continue;
+                    }
+                    buffer.push(route_message(header, route, RTPROT_BOOT, None));
+                }
+                Ok(())
+            }
+            NetlinkMessageType::NewLink | NetlinkMessageType::SetLink => set_link(payload),
+            NetlinkMessageType::NewAddr | NetlinkMessageType::DelAddr => {
+                change_addr(message_type, payload)
+            }
+            NetlinkMessageType::NewRoute | NetlinkMessageType::DelRoute => {
+                change_route(message_type, header.nlmsg_flags, payload)
+            }
+            _ => Err(SysError::EOPNOTSUPP),
+        };
+
+        let is_dump = match message_type {
+            NetlinkMessageType::GetLink
+            | NetlinkMessageType::GetAddr
+            | NetlinkMessageType::GetRoute => true,
+            _ => false,
         };
-        msg.push_ext(new_header);
-        msg.align4();
-        msg.set_ext(0, msg.len() as u32);
-        buffer.push(msg);
+        if is_dump {
+            let mut msg = Vec::new();
+            let new_header = NetlinkMessageHeader {
+                nlmsg_len: 0, // to be determined later
+                nlmsg_type: NetlinkMessageType::Done.into(),
+                nlmsg_flags: NetlinkMessageFlags::MULTI,
+                nlmsg_seq: header.nlmsg_seq,
+                nlmsg_pid: header.nlmsg_pid,
+            };
+            msg.push_ext(new_header);
+            msg.align4();
+            msg.set_ext(0, msg.len() as u32);
+            buffer.push(msg);
+        } else if result.is_err() || header.nlmsg_flags.contains(NetlinkMessageFlags::ACK) {
+            // an error message with error 0 is an ack, followed by the request header
+            let error = match result {
+                Ok(()) => 0,
+                Err(err) => -(err as i32),
+            };
+            let mut msg = Vec::new();
+            let new_header = NetlinkMessageHeader {
+                nlmsg_len: 0, // to be determined later
+                nlmsg_type: NetlinkMessageType::Error.into(),
+                nlmsg_flags: NetlinkMessageFlags::CAPPED,
+                nlmsg_seq: header.nlmsg_seq,
+                nlmsg_pid: header.nlmsg_pid,
+            };
+            msg.push_ext(new_header);
+            msg.push_ext(error);
+            msg.push_ext(*header);
+            msg.set_ext(0, msg.len() as u32);
+            buffer.push(msg);
+        }
         Ok(data.len())
     }
 
@@ -1066,25 +2145,41 @@ impl Socket for NetlinkSocketState {
         Ok(0)
     }
 
+    fn get_option(&self, name: SocketOptionName) -> Result<SocketOption, SysError> {
+        match name {
+            SocketOptionName::Error => Ok(SocketOption::Error(0)),
+            SocketOptionName::Type => Ok(SocketOption::Type(SocketType::Raw)),
+            _ => Err(SysError::ENOPROTOOPT),
+        }
+    }
+
     fn box_clone(&self) -> Box<dyn Socket> {
         Box::new(self.clone())
     }
 }
 
-fn get_ephemeral_port() -> u16 {
-    // TODO selects non-conflict high port
-    static mut EPHEMERAL_PORT: u16 = 0;
-    unsafe {
-        if EPHEMERAL_PORT == 0 {
-            EPHEMERAL_PORT = (49152 + rand::rand() % (65536 - 49152)) as u16;
-        }
-        if EPHEMERAL_PORT == 65535 {
-            EPHEMERAL_PORT = 49152;
-        } else {
-            EPHEMERAL_PORT = EPHEMERAL_PORT + 1;
-        }
-        EPHEMERAL_PORT
-    }
+/// The interface on the network of `addr`, or of the route to it, or the first one
+fn route_iface<'a>(ifaces: &'a [Arc<dyn NetDriver>], addr: &IpAddress) -> &'a Arc<dyn NetDriver> {
+    &ifaces[route_index(ifaces, addr)]
+}
+
+/// Index of `route_iface` in `ifaces`
+fn route_index(ifaces: &[Arc<dyn NetDriver>], addr: &IpAddress) -> usize {
+    ifaces
+        .iter()
+        .position(|iface| {
+            let addrs = iface.get_ip_addresses();
+            // an unspecified address is no network to route to
+            addrs
+                .iter()
+                .any(|cidr| !cidr.address().is_unspecified() && cidr.contains_addr(addr))
+        })
+        .or_else(|| {
+            lookup_route(addr)
+                .map(|route| route.iface)
+                .filter(|&index| index < ifaces.len())
+        })
+        .unwrap_or(0)
 }
 
 /// Safety: call this without SOCKETS locked
@@ -1094,6 +2189,33 @@ fn poll_ifaces() {
     }
 }
 
+// what waiting operations wait for, see `wait_socket`
+
+fn tcp_readable(sockets: &mut SocketSet<'static, 'static, 'static>, handle: SocketHandle) -> bool {
+    let socket = sockets.get::<TcpSocket>(handle);
+    socket.can_recv() || !socket.may_recv()
+}
+
+fn tcp_connected(sockets: &mut SocketSet<'static, 'static, 'static>, handle: SocketHandle) -> bool {
+    sockets.get::<TcpSocket>(handle).state() != TcpState::SynSent
+}
+
+fn tcp_acceptable(
+    sockets: &mut SocketSet<'static, 'static, 'static>,
+    handle: SocketHandle,
+) -> bool {
+    sockets.get::<TcpSocket>(handle).is_active()
+}
+
+fn udp_readable(sockets: &mut SocketSet<'static, 'static, 'static>, handle: SocketHandle) -> bool {
+    let socket = sockets.get::<UdpSocket>(handle);
+    socket.can_recv() || !socket.is_open()
+}
+
+fn raw_readable(sockets: &mut SocketSet<'static, 'static, 'static>, handle: SocketHandle) -> bool {
+    sockets.get::<RawSocket>(handle).can_recv()
+}
+
 pub const TCP_SENDBUF: usize = 512 * 1024; // 512K
 pub const TCP_RECVBUF: usize = 512 * 1024; // 512K
 
@@ -1104,3 +2226,36 @@ const UDP_RECVBUF: usize = 64 * 1024; // 64K
 const RAW_METADATA_BUF: usize = 1024;
 const RAW_SENDBUF: usize = 64 * 1024; // 64K
 const RAW_RECVBUF: usize = 64 * 1024; // 64K
+
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Hop limit of packets, unless set by IP_TTL
+const DEFAULT_TTL: u8 = 64;
+/// Seconds idle before keep-alive probes, unless set by TCP_KEEPIDLE
+const TCP_KEEPIDLE_DEFAULT: u32 = 7200;
+
+// scopes of netlink addresses and routes
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RT_SCOPE_HOST: u8 = 254;
+
+// link flags
+const IFF_UP: u32 = 0x1;
+const IFF_RUNNING: u32 = 0x40;
+
+// address attributes
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+// route attributes
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_PREFSRC: u16 = 7;
+
+const RT_TABLE_MAIN: u8 = 254;
+const RTN_UNICAST: u8 = 1;
+// routes from addresses, and added by users
+const RTPROT_KERNEL: u8 = 2;
+const RTPROT_BOOT: u8 = 3;
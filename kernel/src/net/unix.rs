@@ -0,0 +1,397 @@
+//! Unnamed unix sockets created by socketpair
+//!
+//! The two ends share a queue of messages for each direction.
+//! A message keeps the ancillary data sent with it, so files passed by SCM_RIGHTS
+//! are received with the bytes they were sent with.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+use crate::drivers::SOCKET_ACTIVITY;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::*;
+
+use super::{
+    wait_queue, Ancillary, Endpoint, QueueWakers, Socket, SocketFuture, SocketOption,
+    SocketOptionName, UCred, WaitQueue,
+};
+
+/// Max bytes queued to an end
+const UNIX_BUF: usize = 208 * 1024;
+
+#[derive(Debug)]
+struct UnixMessage {
+    data: Vec<u8>,
+    /// Bytes already read by stream sockets
+    offset: usize,
+    ancillary: Ancillary,
+}
+
+#[derive(Debug, Default)]
+struct UnixQueue {
+    messages: VecDeque<UnixMessage>,
+    /// Bytes of `messages` not read yet
+    len: usize,
+    /// No more messages are sent to the queue
+    write_closed: bool,
+    /// No more messages are read from the queue
+    read_closed: bool,
+    /// Tasks waiting to read from or write to the queue
+    wakers: QueueWakers,
+}
+
+impl WaitQueue for UnixQueue {
+    fn wakers(&mut self) -> &mut QueueWakers {
+        &mut self.wakers
+    }
+}
+
+/// A message being sent, in pieces if it is a stream one
+struct UnixSend<'a> {
+    data: &'a [u8],
+    sent: usize,
+    /// Sent with the first piece
+    ancillary: Option<Ancillary>,
+    /// Sent with the other pieces
+    credentials: Option<UCred>,
+}
+
+/// An end of a pair. It is shared by the dups of its file,
+/// and closes its side of both queues when the last one is gone.
+#[derive(Debug)]
+struct UnixEnd {
+    /// Messages to this end
+    rx: Arc<Mutex<UnixQueue>>,
+    /// Messages to the peer
+    tx: Arc<Mutex<UnixQueue>>,
+    /// Options set on any of the dups
+    options: Mutex<UnixOptions>,
+}
+
+#[derive(Debug, Default)]
+struct UnixOptions {
+    /// SO_PASSCRED
+    passcred: bool,
+    /// O_NONBLOCK
+    nonblock: bool,
+}
+
+impl UnixEnd {
+    fn close(&self) {
+        let mut tx = self.tx.lock();
+        tx.write_closed = true;
+        tx.wakers.wake();
+        drop(tx);
+        let mut rx = self.rx.lock();
+        rx.read_closed = true;
+        rx.wakers.wake();
+        drop(rx);
+        SOCKET_ACTIVITY.notify_all();
+    }
+}
+
+impl Drop for UnixEnd {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnixSocketState {
+    end: Arc<UnixEnd>,
+    socket_type: SocketType,
+    /// Byte stream, or datagrams which are read one at a time
+    stream: bool,
+}
+
+impl UnixSocketState {
+    /// A connected pair of sockets
+    pub fn new_pair(socket_type: SocketType) -> (Self, Self) {
+        let a = Arc::new(Mutex::new(UnixQueue::default()));
+        let b = Arc::new(Mutex::new(UnixQueue::default()));
+        let new = |rx, tx| UnixSocketState {
+            end: Arc::new(UnixEnd {
+                rx,
+                tx,
+                options: Mutex::new(UnixOptions::default()),
+            }),
+            socket_type,
+            stream: socket_type == SocketType::Stream,
+        };
+        (new(a.clone(), b.clone()), new(b, a))
+    }
+
+    /// Take bytes from the messages at the front of `queue`, which is not empty.
+    /// A stream read does not go past a message with files or other credentials,
+    /// so that the ancillary data is received with the bytes it was sent with.
+    fn take(&self, queue: &mut UnixQueue, data: &mut [u8]) -> (usize, Ancillary) {
+        if !self.stream {
+            // the rest of a datagram which does not fit is dropped
+            let message = queue.messages.pop_front().unwrap();
+            queue.len -= message.data.len();
+            let len = min(data.len(), message.data.len());
+            data[..len].copy_from_slice(&message.data[..len]);
+            return (len, message.ancillary);
+        }
+        let mut ancillary = Ancillary::default();
+        let mut read = 0;
+        let mut first = true;
+        while read < data.len() {
+            let message = match queue.messages.front_mut() {
+                Some(message) => message,
+                None => break,
+            };
+            if first {
+                ancillary.files = core::mem::replace(&mut message.ancillary.files, Vec::new());
+                ancillary.credentials = message.ancillary.credentials;
+                first = false;
+            } else if !message.ancillary.files.is_empty()
+                || message.ancillary.credentials != ancillary.credentials
+            {
+                break;
+            }
+            let len = min(data.len() - read, message.data.len() - message.offset);
+            data[read..read + len]
+                .copy_from_slice(&message.data[message.offset..message.offset + len]);
+            message.offset += len;
+            read += len;
+            if message.offset == message.data.len() {
+                queue.messages.pop_front();
+            }
+        }
+        queue.len -= read;
+        (read, ancillary)
+    }
+
+    /// Receive from `queue`, or None to wait for it
+    fn try_recvmsg(
+        &self,
+        queue: &mut UnixQueue,
+        data: &mut [u8],
+        nonblock: bool,
+    ) -> Option<(SysResult, Endpoint, Ancillary)> {
+        if !queue.messages.is_empty() && !queue.read_closed {
+            let (len, mut ancillary) = self.take(queue, data);
+            queue.wakers.wake();
+            SOCKET_ACTIVITY.notify_all();
+            if !self.end.options.lock().passcred {
+                ancillary.credentials = None;
+            }
+            return Some((Ok(len), Endpoint::Unix, ancillary));
+        }
+        if queue.write_closed || queue.read_closed {
+            // end of file
+            return Some((Ok(0), Endpoint::Unix, Ancillary::default()));
+        }
+        if nonblock || self.end.options.lock().nonblock {
+            return Some((Err(SysError::EAGAIN), Endpoint::Unix, Ancillary::default()));
+        }
+        None
+    }
+
+    /// Check a message to send and start sending it
+    fn begin_send<'a>(
+        &self,
+        data: &'a [u8],
+        sendto_endpoint: Option<Endpoint>,
+        ancillary: Ancillary,
+    ) -> Result<UnixSend<'a>, SysError> {
+        if sendto_endpoint.is_some() {
+            return Err(SysError::EISCONN);
+        }
+        if !self.stream && data.len() > UNIX_BUF {
+            return Err(SysError::EMSGSIZE);
+        }
+        // the rest of a stream write is from the same sender, without the files
+        Ok(UnixSend {
+            data,
+            sent: 0,
+            credentials: ancillary.credentials,
+            ancillary: Some(ancillary),
+        })
+    }
+
+    /// Queue what fits of `send` to `queue`, or None to wait for room
+    fn try_sendmsg(
+        &self,
+        queue: &mut UnixQueue,
+        send: &mut UnixSend,
+        nonblock: bool,
+    ) -> Option<SysResult> {
+        if self.stream && send.data.is_empty() {
+            return Some(Ok(0));
+        }
+        if queue.write_closed || queue.read_closed {
+            return Some(Err(SysError::EPIPE));
+        }
+        let data = send.data;
+        let room = UNIX_BUF - queue.len;
+        // a datagram is queued as a whole, and bytes of a stream as they fit
+        if (self.stream && room > 0) || room >= data.len() {
+            let len = min(room, data.len() - send.sent);
+            let credentials = send.credentials;
+            queue.messages.push_back(UnixMessage {
+                data: data[send.sent..send.sent + len].to_vec(),
+                offset: 0,
+                ancillary: send.ancillary.take().unwrap_or(Ancillary {
+                    credentials,
+                    ..Ancillary::default()
+                }),
+            });
+            queue.len += len;
+            send.sent += len;
+            queue.wakers.wake();
+            SOCKET_ACTIVITY.notify_all();
+            if send.sent == data.len() {
+                return Some(Ok(send.sent));
+            }
+        }
+        if nonblock || self.end.options.lock().nonblock {
+            // a stream write which is cut short tells how much it wrote
+            return Some(if send.sent > 0 {
+                Ok(send.sent)
+            } else {
+                Err(SysError::EAGAIN)
+            });
+        }
+        None
+    }
+}
+
+impl Socket for UnixSocketState {
+    fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        let (result, endpoint, _) = self.recvmsg(data);
+        (result, endpoint)
+    }
+
+    fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
+        self.sendmsg(data, sendto_endpoint, Ancillary::default())
+    }
+
+    fn recvmsg(&self, data: &mut [u8]) -> (SysResult, Endpoint, Ancillary) {
+        let mut queue = self.end.rx.lock();
+        loop {
+            if let Some(result) = self.try_recvmsg(&mut queue, data, false) {
+                return result;
+            }
+            queue = SOCKET_ACTIVITY.wait(queue);
+        }
+    }
+
+    fn async_read<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint)> {
+        Box::pin(async move {
+            let (result, endpoint, _) = self.async_recvmsg(data, nonblock).await;
+            (result, endpoint)
+        })
+    }
+
+    fn async_recvmsg<'a>(
+        &'a self,
+        data: &'a mut [u8],
+        nonblock: bool,
+    ) -> SocketFuture<'a, (SysResult, Endpoint, Ancillary)> {
+        Box::pin(wait_queue(&self.end.rx, None, move |queue| {
+            self.try_recvmsg(queue, data, nonblock)
+        }))
+    }
+
+    fn sendmsg(
+        &self,
+        data: &[u8],
+        sendto_endpoint: Option<Endpoint>,
+        ancillary: Ancillary,
+    ) -> SysResult {
+        let mut send = self.begin_send(data, sendto_endpoint, ancillary)?;
+        let mut queue = self.end.tx.lock();
+        loop {
+            if let Some(result) = self.try_sendmsg(&mut queue, &mut send, false) {
+                return result;
+            }
+            queue = SOCKET_ACTIVITY.wait(queue);
+        }
+    }
+
+    fn async_sendmsg<'a>(
+        &'a self,
+        data: &'a [u8],
+        sendto_endpoint: Option<Endpoint>,
+        ancillary: Ancillary,
+        nonblock: bool,
+    ) -> SocketFuture<'a, SysResult> {
+        Box::pin(async move {
+            let mut send = self.begin_send(data, sendto_endpoint, ancillary)?;
+            wait_queue(&self.end.tx, None, move |queue| {
+                self.try_sendmsg(queue, &mut send, nonblock)
+            })
+            .await
+        })
+    }
+
+    fn poll(&self) -> (bool, bool, bool) {
+        let (input, hangup) = {
+            let queue = self.end.rx.lock();
+            let hangup = queue.write_closed || queue.read_closed;
+            (!queue.messages.is_empty() || hangup, hangup)
+        };
+        let output = {
+            let queue = self.end.tx.lock();
+            queue.len < UNIX_BUF || queue.read_closed
+        };
+        (input, output, hangup)
+    }
+
+    fn connect(&mut self, _endpoint: Endpoint) -> SysResult {
+        Err(SysError::EISCONN)
+    }
+
+    fn shutdown(&self) -> SysResult {
+        self.end.close();
+        Ok(0)
+    }
+
+    fn endpoint(&self) -> Option<Endpoint> {
+        Some(Endpoint::Unix)
+    }
+
+    fn remote_endpoint(&self) -> Option<Endpoint> {
+        Some(Endpoint::Unix)
+    }
+
+    fn set_option(&mut self, option: SocketOption) -> SysResult {
+        match option {
+            SocketOption::PassCred(on) => {
+                self.end.options.lock().passcred = on;
+                Ok(0)
+            }
+            SocketOption::NonBlock(on) => {
+                self.end.options.lock().nonblock = on;
+                Ok(0)
+            }
+            _ => Err(SysError::ENOPROTOOPT),
+        }
+    }
+
+    fn get_option(&self, name: SocketOptionName) -> Result<SocketOption, SysError> {
+        let options = self.end.options.lock();
+        match name {
+            SocketOptionName::PassCred => Ok(SocketOption::PassCred(options.passcred)),
+            SocketOptionName::NonBlock => Ok(SocketOption::NonBlock(options.nonblock)),
+            SocketOptionName::SendBuffer => Ok(SocketOption::SendBuffer(UNIX_BUF)),
+            SocketOptionName::RecvBuffer => Ok(SocketOption::RecvBuffer(UNIX_BUF)),
+            SocketOptionName::Error => Ok(SocketOption::Error(0)),
+            SocketOptionName::Type => Ok(SocketOption::Type(self.socket_type)),
+            _ => Err(SysError::ENOPROTOOPT),
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn Socket> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,217 @@
+//! Wakers of tasks waiting for smoltcp sockets
+//!
+//! A blocked socket operation registers the waker of its task for the handle of its socket,
+//! with a check of what it waits for. The interfaces call `wake_sockets` after each poll,
+//! which wakes only the tasks whose sockets are ready, so waiting takes no CPU.
+//! Sockets with queues of their own, like unix and packet ones, wait on them with `wait_queue`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use smoltcp::socket::{SocketHandle, SocketSet};
+
+use crate::arch::timer::timer_now;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::trap::NAIVE_TIMER;
+
+/// Whether the socket at the handle is ready for the operation waiting on it
+pub type Readiness = fn(&mut SocketSet<'static, 'static, 'static>, SocketHandle) -> bool;
+
+/// Max time between tries of a waiting operation.
+/// The timers of TCP and the timeouts of sockets only go on when the interfaces are polled,
+/// which the operation does on each try.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+struct Waiter {
+    id: usize,
+    handle: SocketHandle,
+    ready: Readiness,
+    waker: Waker,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref WAITERS: Mutex<Vec<Waiter>> = Mutex::new(Vec::new());
+}
+
+/// Wake the tasks waiting for sockets which are ready.
+/// Called by the interfaces with `SOCKETS` locked, after they poll it.
+pub fn wake_sockets(sockets: &mut SocketSet<'static, 'static, 'static>) {
+    let mut woken = Vec::new();
+    let mut waiters = WAITERS.lock();
+    let mut i = 0;
+    while i < waiters.len() {
+        if (waiters[i].ready)(sockets, waiters[i].handle) {
+            woken.push(waiters.swap_remove(i).waker);
+        } else {
+            i += 1;
+        }
+    }
+    drop(waiters);
+    for waker in woken {
+        waker.wake();
+    }
+}
+
+/// The async `spin_and_wait` of a socket:
+/// a future trying `action` until it gives a result,
+/// and sleeping until the socket at `handle` is `ready` in between.
+///
+/// `action` polls the interfaces, and gives up by itself on timeouts and O_NONBLOCK.
+pub fn wait_socket<T, F>(handle: SocketHandle, ready: Readiness, action: F) -> SocketWait<F>
+where
+    F: FnMut() -> Option<T> + Unpin,
+{
+    SocketWait {
+        handle,
+        ready,
+        action,
+        id: None,
+    }
+}
+
+#[must_use = "future does nothing unless polled/`await`-ed"]
+pub struct SocketWait<F> {
+    handle: SocketHandle,
+    ready: Readiness,
+    action: F,
+    /// Id of the registered waiter
+    id: Option<usize>,
+}
+
+impl<F> SocketWait<F> {
+    /// Register the waker, or replace the one registered before
+    fn register(&mut self, waker: &Waker) {
+        let mut waiters = WAITERS.lock();
+        if let Some(id) = self.id {
+            if let Some(waiter) = waiters.iter_mut().find(|waiter| waiter.id == id) {
+                waiter.waker = waker.clone();
+                return;
+            }
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        waiters.push(Waiter {
+            id,
+            handle: self.handle,
+            ready: self.ready,
+            waker: waker.clone(),
+        });
+        self.id = Some(id);
+    }
+}
+
+impl<T, F> Future for SocketWait<F>
+where
+    F: FnMut() -> Option<T> + Unpin,
+{
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // register first, so that a socket becoming ready during the try is not missed
+        self.register(cx.waker());
+        if let Some(result) = (self.action)() {
+            return Poll::Ready(result);
+        }
+        let waker = cx.waker().clone();
+        NAIVE_TIMER.lock().add(
+            timer_now() + RETRY_INTERVAL,
+            Box::new(move |_| waker.wake()),
+        );
+        Poll::Pending
+    }
+}
+
+impl<F> Drop for SocketWait<F> {
+    fn drop(&mut self) {
+        // the handle may be released after the future is gone
+        if let Some(id) = self.id {
+            WAITERS.lock().retain(|waiter| waiter.id != id);
+        }
+    }
+}
+
+/// Wakers of the tasks waiting on a queue of a socket which is not in `SOCKETS`,
+/// like the queues of unix and packet sockets
+#[derive(Debug, Default)]
+pub struct QueueWakers(Vec<Waker>);
+
+impl QueueWakers {
+    fn register(&mut self, waker: &Waker) {
+        if !self.0.iter().any(|registered| registered.will_wake(waker)) {
+            self.0.push(waker.clone());
+        }
+    }
+
+    /// Wake the waiting tasks, called with the queue locked after it changes
+    pub fn wake(&mut self) {
+        for waker in self.0.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A queue with the wakers of the tasks waiting on it
+pub trait WaitQueue {
+    fn wakers(&mut self) -> &mut QueueWakers;
+}
+
+/// `wait_socket` for a queue:
+/// a future trying `action` with the queue locked until it gives a result,
+/// and sleeping until the queue changes or `deadline` passes in between.
+pub fn wait_queue<Q, T, F>(
+    queue: &Mutex<Q>,
+    deadline: Option<Duration>,
+    action: F,
+) -> QueueWait<'_, Q, F>
+where
+    Q: WaitQueue,
+    F: FnMut(&mut Q) -> Option<T> + Unpin,
+{
+    QueueWait {
+        queue,
+        deadline,
+        action,
+        timer_added: false,
+    }
+}
+
+#[must_use = "future does nothing unless polled/`await`-ed"]
+pub struct QueueWait<'a, Q, F> {
+    queue: &'a Mutex<Q>,
+    deadline: Option<Duration>,
+    action: F,
+    timer_added: bool,
+}
+
+impl<'a, Q, T, F> Future for QueueWait<'a, Q, F>
+where
+    Q: WaitQueue,
+    F: FnMut(&mut Q) -> Option<T> + Unpin,
+{
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        // the waker is registered with the queue still locked, so no change is missed
+        let mut queue = this.queue.lock();
+        if let Some(result) = (this.action)(&mut queue) {
+            return Poll::Ready(result);
+        }
+        queue.wakers().register(cx.waker());
+        drop(queue);
+        if let (Some(deadline), false) = (this.deadline, this.timer_added) {
+            let waker = cx.waker().clone();
+            NAIVE_TIMER
+                .lock()
+                .add(deadline, Box::new(move |_| waker.wake()));
+            this.timer_added = true;
+        }
+        Poll::Pending
+    }
+}
@@ -4,17 +4,27 @@ use alloc::vec::Vec;
 use core::ptr::null;
 
 pub struct ProcInitInfo {
+    /// Path of the executable file, pointed by AT_EXECFN
+    pub execfn: String,
     pub args: Vec<String>,
     pub envs: Vec<String>,
     pub auxv: BTreeMap<u8, usize>,
+    /// Bytes pointed by AT_RANDOM
+    pub random: [u8; 16],
 }
 
 impl ProcInitInfo {
-    pub unsafe fn push_at(&self, stack_top: usize) -> usize {
+    /// Push the init info to user stack at `stack_top`, return the new stack top.
+    /// Entries pointing into the stack are added to `auxv`.
+    pub unsafe fn push_at(&mut self, stack_top: usize) -> usize {
         let mut writer = StackWriter { sp: stack_top };
         // from stack_top:
         // program name
-        writer.push_str(&self.args[0]);
+        writer.push_str(&self.execfn);
+        self.auxv.insert(AT_EXECFN, writer.sp);
+        // random bytes for AT_RANDOM
+        writer.push_slice(&self.random);
+        self.auxv.insert(AT_RANDOM, writer.sp);
         // environment strings
         let envs: Vec<_> = self
             .envs
@@ -76,3 +86,36 @@ pub const AT_PHNUM: u8 = 5;
 pub const AT_PAGESZ: u8 = 6;
 pub const AT_BASE: u8 = 7;
 pub const AT_ENTRY: u8 = 9;
+pub const AT_UID: u8 = 11;
+pub const AT_EUID: u8 = 12;
+pub const AT_GID: u8 = 13;
+pub const AT_EGID: u8 = 14;
+pub const AT_HWCAP: u8 = 16;
+pub const AT_CLKTCK: u8 = 17;
+pub const AT_SECURE: u8 = 23;
+pub const AT_RANDOM: u8 = 25;
+pub const AT_EXECFN: u8 = 31;
+
+/// Clock ticks per second reported to user, i.e. `USER_HZ`
+pub const CLOCKS_PER_SEC: usize = 100;
+
+/// Hardware capabilities reported by AT_HWCAP
+#[cfg(target_arch = "x86_64")]
+pub fn hwcap() -> usize {
+    // EDX of CPUID leaf 1, same as Linux
+    unsafe { core::arch::x86_64::__cpuid(1).edx as usize }
+}
+
+/// Hardware capabilities reported by AT_HWCAP
+#[cfg(riscv)]
+pub fn hwcap() -> usize {
+    // one bit per single-letter ISA extension: IMAFDC
+    let isa = |c: u8| 1 << (c - b'a');
+    isa(b'i') | isa(b'm') | isa(b'a') | isa(b'f') | isa(b'd') | isa(b'c')
+}
+
+/// Hardware capabilities reported by AT_HWCAP
+#[cfg(any(target_arch = "aarch64", target_arch = "mips"))]
+pub fn hwcap() -> usize {
+    0
+}
@@ -0,0 +1,93 @@
+//! Address space layout randomization for user processes
+
+use crate::arch::{rand::rand, timer::timer_now};
+use crate::sync::SpinNoIrqLock as Mutex;
+use rcore_memory::PAGE_SIZE;
+
+/// `personality` flag: disable randomization of the virtual address space
+pub const ADDR_NO_RANDOMIZE: usize = 0x0040000;
+
+// Range of random shifts, in pages.
+// Keep them small on 32-bit targets where user address space is tight.
+#[cfg(target_pointer_width = "64")]
+const STACK_RND_PAGES: usize = 1 << 12;
+#[cfg(target_pointer_width = "32")]
+const STACK_RND_PAGES: usize = 1 << 8;
+
+#[cfg(target_pointer_width = "64")]
+const MMAP_RND_PAGES: usize = 1 << 16;
+#[cfg(target_pointer_width = "32")]
+const MMAP_RND_PAGES: usize = 1 << 8;
+
+#[cfg(target_pointer_width = "64")]
+const PIE_RND_PAGES: usize = 1 << 12;
+#[cfg(target_pointer_width = "32")]
+const PIE_RND_PAGES: usize = 1 << 8;
+
+#[cfg(target_pointer_width = "64")]
+const INTERP_RND_PAGES: usize = 1 << 12;
+#[cfg(target_pointer_width = "32")]
+const INTERP_RND_PAGES: usize = 1 << 8;
+
+lazy_static! {
+    static ref SEED: Mutex<u64> = Mutex::new(0x853c_49e6_748f_ea9b);
+}
+
+/// Get a pseudo random number.
+///
+/// The arch `rand` is a constant on some platforms,
+/// so mix it with the timer into a splitmix64 sequence.
+pub fn random() -> u64 {
+    let mut seed = SEED.lock();
+    *seed = seed
+        .wrapping_add(0x9e37_79b9_7f4a_7c15)
+        .wrapping_add(rand() ^ timer_now().as_nanos() as u64);
+    let mut z = *seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Fill `buf` with pseudo random bytes
+pub fn random_bytes(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = random().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+/// Random offset of at most `pages` pages
+fn random_pages(pages: usize) -> usize {
+    (random() as usize % pages) * PAGE_SIZE
+}
+
+/// Random offsets applied when building a new user address space
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AddrLayout {
+    /// Shift down from the default user stack top, 16-byte aligned
+    pub stack_offset: usize,
+    /// Shift up from the end of the executable where mmap starts searching
+    pub mmap_offset: usize,
+    /// Load bias of position independent executables
+    pub pie_bias: usize,
+    /// Shift up from the end of the executable where the interpreter is loaded
+    pub interp_offset: usize,
+    /// Whether the layout is randomized
+    pub randomized: bool,
+}
+
+impl AddrLayout {
+    /// Make a layout according to the `personality` of the process
+    pub fn new(personality: usize) -> Self {
+        if personality & ADDR_NO_RANDOMIZE != 0 {
+            return AddrLayout::default();
+        }
+        AddrLayout {
+            stack_offset: random_pages(STACK_RND_PAGES) + (random() as usize % PAGE_SIZE & !0xf),
+            mmap_offset: random_pages(MMAP_RND_PAGES),
+            pie_bias: PAGE_SIZE + random_pages(PIE_RND_PAGES),
+            interp_offset: random_pages(INTERP_RND_PAGES),
+            randomized: true,
+        }
+    }
+}
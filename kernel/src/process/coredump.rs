@@ -0,0 +1,539 @@
+//! ELF core dumps of processes killed by signals
+//!
+//! The layout follows Linux so that gdb can load the file:
+//! a PT_NOTE segment with NT_PRSTATUS for each thread, NT_PRPSINFO and NT_AUXV,
+//! then a PT_LOAD segment for each memory area.
+
+use super::rlimit::{limit_to_usize, RLIMIT_CORE};
+use super::{yield_now, Pid, Process, Thread, THREADS};
+use crate::arch::signal::{elf_gregs_from_tf, ELF_NGREG};
+use crate::fs::FOLLOW_MAX_DEPTH;
+use crate::memory::{phys_to_virt, MemorySet};
+use crate::signal::Signal;
+use crate::sync::{wait_for_event, Event, SpinNoIrqLock as Mutex};
+use crate::syscall::{SysError, TimeSpec};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::mem::size_of;
+use core::slice;
+use rcore_fs::vfs::{FileType, FsError, INode};
+use rcore_memory::paging::PageTable;
+use rcore_memory::PAGE_SIZE;
+use spin::RwLock;
+use trapframe::UserContext;
+
+lazy_static! {
+    /// Name of core files, see `core(5)`.
+    ///
+    /// Supports `%p` (pid), `%e` (executable name), `%s` (signal number),
+    /// `%t` (time of dump) and `%%`.
+    /// A relative path is relative to the cwd of the dumping process.
+    pub static ref CORE_PATTERN: RwLock<String> = RwLock::new(String::from("core"));
+}
+
+const ET_CORE: u16 = 4;
+const EV_CURRENT: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+#[cfg(target_pointer_width = "64")]
+const ELFCLASS: u8 = 2;
+#[cfg(target_pointer_width = "32")]
+const ELFCLASS: u8 = 1;
+
+#[cfg(target_arch = "x86_64")]
+const EM_ARCH: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_ARCH: u16 = 183;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+const EM_ARCH: u16 = 243;
+#[cfg(target_arch = "mips")]
+const EM_ARCH: u16 = 8;
+
+/// RVC | double float ABI
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+const EF_ARCH: u32 = 0x5;
+/// MIPS32r2 | o32 ABI
+#[cfg(target_arch = "mips")]
+const EF_ARCH: u32 = 0x7000_1000;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const EF_ARCH: u32 = 0;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+
+/// ELF file header, `usize` fields are 32 or 64 bits according to the class
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    type_: u16,
+    machine: u16,
+    version: u32,
+    entry: usize,
+    phoff: usize,
+    shoff: usize,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+struct ProgramHeader {
+    type_: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    paddr: usize,
+    filesz: usize,
+    memsz: usize,
+    align: usize,
+}
+
+#[cfg(target_pointer_width = "32")]
+#[repr(C)]
+struct ProgramHeader {
+    type_: u32,
+    offset: usize,
+    vaddr: usize,
+    paddr: usize,
+    filesz: usize,
+    memsz: usize,
+    flags: u32,
+    align: usize,
+}
+
+impl ProgramHeader {
+    fn new(
+        type_: u32,
+        flags: u32,
+        offset: usize,
+        vaddr: usize,
+        filesz: usize,
+        memsz: usize,
+    ) -> Self {
+        ProgramHeader {
+            type_,
+            flags,
+            offset,
+            vaddr,
+            paddr: 0,
+            filesz,
+            memsz,
+            align: if type_ == PT_LOAD { PAGE_SIZE } else { 4 },
+        }
+    }
+}
+
+/// Linux struct elf_prstatus
+#[repr(C)]
+struct PrStatus {
+    si_signo: i32,
+    si_code: i32,
+    si_errno: i32,
+    cursig: i16,
+    sigpend: usize,
+    sighold: usize,
+    pid: i32,
+    ppid: i32,
+    pgrp: i32,
+    sid: i32,
+    /// utime, stime, cutime, cstime as timeval
+    times: [usize; 8],
+    reg: [usize; ELF_NGREG],
+    fpvalid: i32,
+}
+
+/// Linux struct elf_prpsinfo
+#[repr(C)]
+struct PrPsInfo {
+    state: u8,
+    sname: u8,
+    zomb: u8,
+    nice: i8,
+    flag: usize,
+    uid: u32,
+    gid: u32,
+    pid: i32,
+    ppid: i32,
+    pgrp: i32,
+    sid: i32,
+    fname: [u8; 16],
+    psargs: [u8; 80],
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Append a note with name "CORE"
+fn push_note(buf: &mut Vec<u8>, type_: u32, desc: &[u8]) {
+    const NAME: &[u8] = b"CORE\0";
+    buf.extend_from_slice(&(NAME.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&type_.to_le_bytes());
+    buf.extend_from_slice(NAME);
+    buf.resize(align4(buf.len()), 0);
+    buf.extend_from_slice(desc);
+    buf.resize(align4(buf.len()), 0);
+}
+
+/// Writes sequentially to the core file, silently dropping bytes beyond `limit`
+struct CoreWriter {
+    inode: Arc<dyn INode>,
+    offset: usize,
+    limit: usize,
+}
+
+impl CoreWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<(), FsError> {
+        let len = buf.len().min(self.limit.saturating_sub(self.offset));
+        if len > 0 {
+            self.inode.write_at(self.offset, &buf[..len])?;
+        }
+        self.offset += buf.len();
+        Ok(())
+    }
+
+    fn pad_to(&mut self, offset: usize) -> Result<(), FsError> {
+        const ZEROS: [u8; 64] = [0; 64];
+        while self.offset < offset {
+            let len = (offset - self.offset).min(ZEROS.len());
+            self.write(&ZEROS[..len])?;
+        }
+        Ok(())
+    }
+}
+
+/// Expand `CORE_PATTERN` for the process
+fn core_file_path(proc: &Process, signal: Signal) -> String {
+    let exec_name = proc.exec_path.rsplit('/').next().unwrap_or("");
+    let mut path = String::new();
+    let pattern = CORE_PATTERN.read().clone();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('p') => path += &format!("{}", proc.pid),
+            Some('e') => path += exec_name,
+            Some('s') => path += &format!("{}", signal as usize),
+            Some('t') => path += &format!("{}", TimeSpec::get_epoch().sec),
+            Some('%') => path.push('%'),
+            // unknown specifiers are dropped
+            _ => {}
+        }
+    }
+    path
+}
+
+/// Open the core file at the absolute `path` under `root` for writing,
+/// truncating an existing one
+fn create_core_file(root: &Arc<dyn INode>, path: &str) -> Result<Arc<dyn INode>, SysError> {
+    let mut split = path.trim_end_matches('/').rsplitn(2, '/');
+    let file_name = split.next().unwrap();
+    let dir_path = split.next().unwrap_or("");
+    let dir_inode = root.lookup_follow(dir_path.trim_start_matches('/'), FOLLOW_MAX_DEPTH)?;
+    match dir_inode.find(file_name) {
+        Ok(inode) => {
+            if inode.metadata()?.type_ != FileType::File {
+                return Err(SysError::EISDIR);
+            }
+            inode.resize(0)?;
+            Ok(inode)
+        }
+        Err(FsError::EntryNotFound) => Ok(dir_inode.create(file_name, FileType::File, 0o600)?),
+        Err(e) => Err(SysError::from(e)),
+    }
+}
+
+/// Make NT_PRSTATUS for a thread
+fn prstatus(proc: &Process, tid: usize, signal: Signal, tf: Option<&UserContext>) -> PrStatus {
+    PrStatus {
+        si_signo: signal as i32,
+        si_code: 0,
+        si_errno: 0,
+        cursig: signal as i16,
+        sigpend: 0,
+        sighold: 0,
+        pid: tid as i32,
+        ppid: proc.parent.0.get() as i32,
+        pgrp: proc.pgid,
+        sid: 0,
+        times: [0; 8],
+        reg: tf.map_or([0; ELF_NGREG], elf_gregs_from_tf),
+        fpvalid: 0,
+    }
+}
+
+/// Make NT_PRPSINFO for the process
+fn prpsinfo(proc: &Process) -> PrPsInfo {
+    let mut info = PrPsInfo {
+        state: 0,
+        sname: b'R',
+        zomb: 0,
+        nice: 0,
+        flag: 0,
+        uid: 0,
+        gid: 0,
+        pid: proc.pid.get() as i32,
+        ppid: proc.parent.0.get() as i32,
+        pgrp: proc.pgid,
+        sid: 0,
+        fname: [0; 16],
+        psargs: [0; 80],
+    };
+    let exec_name = proc.exec_path.rsplit('/').next().unwrap_or("").as_bytes();
+    let len = exec_name.len().min(info.fname.len() - 1);
+    info.fname[..len].copy_from_slice(&exec_name[..len]);
+    let len = proc.exec_path.len().min(info.psargs.len() - 1);
+    info.psargs[..len].copy_from_slice(&proc.exec_path.as_bytes()[..len]);
+    info
+}
+
+/// Whether contents of an area go into the core file.
+/// Read-only file mappings can be recovered from the files.
+fn should_dump(name: &str, readonly: bool) -> bool {
+    !readonly || !["elf", "elf-interp", "mmap_file"].contains(&name)
+}
+
+/// Threads of a process stopped for its core dump
+#[derive(Default)]
+pub struct CoreState {
+    /// User contexts of the threads stopped before running user code
+    regs: Mutex<BTreeMap<usize, UserContext>>,
+}
+
+/// Stop the other threads of `proc` before they run user code again, for a core dump.
+/// Those running user code now stop at their next trap, see `wait_stopped`.
+pub fn stop_threads(proc: &mut Process) {
+    proc.core_state = Some(Arc::new(CoreState::default()));
+}
+
+/// Wait until no other thread of the process of `thread` runs user code
+pub async fn wait_stopped(thread: &Arc<Thread>) {
+    loop {
+        let running = {
+            let proc = thread.proc.lock();
+            let threads = THREADS.read();
+            proc.threads
+                .iter()
+                .filter(|&&tid| tid != thread.tid)
+                .filter_map(|tid| threads.get(tid))
+                .any(|other| other.inner.lock().in_user)
+        };
+        if !running {
+            return;
+        }
+        yield_now().await;
+    }
+}
+
+/// Mark `thread` as running user code, unless its process is dumping core.
+/// Then leave `cx` to the dump, wait for the process to exit and return false.
+pub async fn enter_user(thread: &Arc<Thread>, cx: &UserContext) -> bool {
+    let proc = thread.proc.lock();
+    let core = match &proc.core_state {
+        Some(core) => core.clone(),
+        None => {
+            thread.inner.lock().in_user = true;
+            return true;
+        }
+    };
+    let eventbus = proc.eventbus.clone();
+    drop(proc);
+    core.regs.lock().insert(thread.tid, cx.clone());
+    wait_for_event(eventbus, Event::PROCESS_QUIT).await;
+    false
+}
+
+/// A core file made by `prepare`, written by `write` without the process locked
+pub struct Core {
+    /// Root directory of the process
+    root: Arc<dyn INode>,
+    /// Absolute path of the file under `root`
+    path: String,
+    notes: Vec<u8>,
+    vm: Arc<Mutex<MemorySet>>,
+    /// RLIMIT_CORE
+    limit: usize,
+    pid: Pid,
+}
+
+/// Make the core file of `proc` killed by `signal` in thread `tid`,
+/// after the other threads are stopped by `stop_threads`.
+///
+/// `tf` is the user context of thread `tid`, those of other threads are taken
+/// where they stopped, or from their saved context if blocked in the kernel.
+pub fn prepare(
+    proc: &Process,
+    tid: usize,
+    tf: &UserContext,
+    signal: Signal,
+) -> Result<Core, SysError> {
+    if proc.rlimit(RLIMIT_CORE) == 0 {
+        return Err(SysError::EFBIG);
+    }
+    let path = proc.absolute_path(&core_file_path(proc, signal));
+    let root = proc.root_inode()?;
+
+    // notes, the current thread goes first
+    let mut notes = Vec::new();
+    push_note(
+        &mut notes,
+        NT_PRSTATUS,
+        as_bytes(&prstatus(proc, tid, signal, Some(tf))),
+    );
+    push_note(&mut notes, NT_PRPSINFO, as_bytes(&prpsinfo(proc)));
+    let mut auxv: Vec<usize> = Vec::new();
+    for (&key, &value) in proc.saved_auxv.iter() {
+        auxv.push(key as usize);
+        auxv.push(value);
+    }
+    // AT_NULL
+    auxv.extend_from_slice(&[0, 0]);
+    let auxv_bytes = unsafe {
+        slice::from_raw_parts(auxv.as_ptr() as *const u8, auxv.len() * size_of::<usize>())
+    };
+    push_note(&mut notes, NT_AUXV, auxv_bytes);
+    let stopped = match &proc.core_state {
+        Some(core) => core.regs.lock().clone(),
+        None => BTreeMap::new(),
+    };
+    let threads = THREADS.read();
+    for &other in proc.threads.iter().filter(|&&other| other != tid) {
+        let cx = match stopped.get(&other) {
+            Some(cx) => Some(cx.clone()),
+            None => threads.get(&other).and_then(|thread| thread.user_context()),
+        };
+        push_note(
+            &mut notes,
+            NT_PRSTATUS,
+            as_bytes(&prstatus(proc, other, signal, cx.as_ref())),
+        );
+    }
+    drop(threads);
+
+    Ok(Core {
+        root,
+        path,
+        notes,
+        vm: proc.vm.clone(),
+        limit: limit_to_usize(proc.rlimit(RLIMIT_CORE)),
+        pid: proc.pid,
+    })
+}
+
+impl Core {
+    /// Write the core file, return its path
+    pub fn write(self) -> Result<String, SysError> {
+        let inode = create_core_file(&self.root, &self.path)?;
+        let areas: Vec<(usize, usize, u32, bool)> = self
+            .vm
+            .lock()
+            .iter()
+            .filter(|area| !area.attr().is_mmio())
+            .map(|area| {
+                let attr = area.attr();
+                let mut flags = PF_R;
+                if !attr.is_readonly() {
+                    flags |= PF_W;
+                }
+                if attr.is_execute() {
+                    flags |= PF_X;
+                }
+                let dump = should_dump(area.name(), attr.is_readonly());
+                let start = area.start_addr() & !(PAGE_SIZE - 1);
+                let end = (area.end_addr() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+                (start, end, flags, dump)
+            })
+            .collect();
+
+        // layout: header, program headers, notes, then page aligned segments
+        let phnum = areas.len() + 1;
+        let notes_offset = size_of::<ElfHeader>() + phnum * size_of::<ProgramHeader>();
+        let mut data_offset = (notes_offset + self.notes.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        let mut ident = [0u8; 16];
+        ident[..4].copy_from_slice(b"\x7fELF");
+        ident[4] = ELFCLASS;
+        ident[5] = ELFDATA2LSB;
+        ident[6] = EV_CURRENT;
+        let header = ElfHeader {
+            ident,
+            type_: ET_CORE,
+            machine: EM_ARCH,
+            version: EV_CURRENT as u32,
+            entry: 0,
+            phoff: size_of::<ElfHeader>(),
+            shoff: 0,
+            flags: EF_ARCH,
+            ehsize: size_of::<ElfHeader>() as u16,
+            phentsize: size_of::<ProgramHeader>() as u16,
+            phnum: phnum as u16,
+            shentsize: 0,
+            shnum: 0,
+            shstrndx: 0,
+        };
+
+        let mut writer = CoreWriter {
+            inode,
+            offset: 0,
+            limit: self.limit,
+        };
+        writer.write(as_bytes(&header))?;
+        let note_header = ProgramHeader::new(PT_NOTE, 0, notes_offset, 0, self.notes.len(), 0);
+        writer.write(as_bytes(&note_header))?;
+        for &(start, end, flags, dump) in areas.iter() {
+            let filesz = if dump { end - start } else { 0 };
+            let header =
+                ProgramHeader::new(PT_LOAD, flags, data_offset, start, filesz, end - start);
+            writer.write(as_bytes(&header))?;
+            data_offset += filesz;
+        }
+        writer.write(&self.notes)?;
+
+        // copy user pages through the page table, pages never touched are zeros,
+        // the address space is only locked while copying a page
+        let mut page_buf = vec![0u8; PAGE_SIZE];
+        writer.pad_to((writer.offset + PAGE_SIZE - 1) & !(PAGE_SIZE - 1))?;
+        for &(start, end, _, dump) in areas.iter() {
+            if !dump {
+                continue;
+            }
+            for page in (start..end).step_by(PAGE_SIZE) {
+                if writer.offset >= writer.limit {
+                    break;
+                }
+                match self.vm.lock().get_page_table_mut().get_entry(page) {
+                    Some(entry) if entry.present() => page_buf.copy_from_slice(unsafe {
+                        slice::from_raw_parts(phys_to_virt(entry.target()) as *const u8, PAGE_SIZE)
+                    }),
+                    _ => page_buf.iter_mut().for_each(|byte| *byte = 0),
+                }
+                writer.write(&page_buf)?;
+            }
+        }
+        info!(
+            "process {} dumped core to {} ({} bytes)",
+            self.pid,
+            self.path,
+            writer.offset.min(writer.limit)
+        );
+        Ok(self.path)
+    }
+}
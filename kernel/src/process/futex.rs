@@ -1,3 +1,6 @@
+use super::{sched, Thread, Tid, THREADS};
+use crate::sync::EventBus;
+use crate::syscall::UserInPtr;
 use crate::trap::NAIVE_TIMER;
 use crate::{
     arch::timer::timer_now,
@@ -5,100 +8,782 @@ use crate::{
     syscall::{SysError, SysResult},
 };
 use alloc::boxed::Box;
-use alloc::{collections::VecDeque, sync::Arc};
+use alloc::{
+    collections::BTreeMap,
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, task::Waker, time::Duration};
+use rcore_memory::{PhysAddr, PAGE_SIZE};
+
+/// Bitset of a waiter matching any wake
+pub const FUTEX_BITSET_MATCH_ANY: u32 = !0;
+/// Set in the futex word when there are waiters
+pub const FUTEX_WAITERS: u32 = 0x8000_0000;
+/// Set in the futex word when its owner exited without unlocking it
+pub const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+/// Bits of the owner tid in the futex word
+pub const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+
+/// Max length of a chain of PI futex owners boosted
+const MAX_LOCK_DEPTH: usize = 1024;
+
+/// Max number of entries walked in a robust list
+const ROBUST_LIST_LIMIT: usize = 2048;
 
 pub struct Waiter {
     waker: Option<Waker>,
     woken: bool,
     futex: Arc<Futex>,
+    bitset: u32,
+    /// Global tid of the waiting thread
+    tid: Tid,
+    /// Tid of the waiting thread in futex words
+    word_tid: u32,
+    /// Priority of the waiting thread, for PI futexes
+    priority: usize,
+    /// Woken as the new owner of a PI futex
+    owner: bool,
 }
 
 pub struct FutexInner {
     waiters: VecDeque<Arc<Mutex<Waiter>>>,
+    /// Global tid of the owner, for PI futexes
+    pi_owner: Tid,
 }
 
 pub struct Futex {
     pub inner: Mutex<FutexInner>,
 }
 
+lazy_static! {
+    /// Process-shared futexes by the memory they are in
+    static ref SHARED_FUTEXES: Mutex<BTreeMap<SharedKey, Weak<Futex>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+impl Waiter {
+    fn new(futex: &Arc<Futex>, thread: &Thread, bitset: u32) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Waiter {
+            waker: None,
+            woken: false,
+            futex: futex.clone(),
+            bitset,
+            tid: thread.tid,
+            word_tid: word_tid(thread),
+            priority: thread.pi_priority(),
+            owner: false,
+        }))
+    }
+}
+
+impl FutexInner {
+    fn wake_bitset(&mut self, wake_count: usize, bitset: u32) -> usize {
+        let mut woken = 0;
+        self.waiters.retain(|waiter| {
+            let mut waiter = waiter.lock();
+            // timed out or interrupted while being requeued
+            if waiter.woken {
+                return false;
+            }
+            if woken >= wake_count || waiter.bitset & bitset == 0 {
+                return true;
+            }
+            waiter.woken = true;
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+            woken += 1;
+            false
+        });
+        woken
+    }
+
+    /// Take at most `count` waiters, which will wait on `target`
+    fn take_waiters(&mut self, count: usize, target: &Arc<Futex>) -> Vec<Arc<Mutex<Waiter>>> {
+        let mut moved = Vec::new();
+        while moved.len() < count {
+            let waiter = match self.waiters.pop_front() {
+                Some(waiter) => waiter,
+                None => break,
+            };
+            let mut w = waiter.lock();
+            if w.woken {
+                continue;
+            }
+            w.futex = target.clone();
+            drop(w);
+            moved.push(waiter);
+        }
+        moved
+    }
+}
+
 impl Futex {
     pub fn new() -> Self {
         Futex {
             inner: Mutex::new(FutexInner {
                 waiters: VecDeque::new(),
+                pi_owner: 0,
             }),
         }
     }
 
     pub fn wake(&self, wake_count: usize) -> usize {
+        self.wake_bitset(wake_count, FUTEX_BITSET_MATCH_ANY)
+    }
+
+    /// Wake at most `wake_count` waiters whose bitset intersects `bitset`
+    pub fn wake_bitset(&self, wake_count: usize, bitset: u32) -> usize {
+        self.inner.lock().wake_bitset(wake_count, bitset)
+    }
+
+    /// Wake at most `wake_count` waiters,
+    /// then move at most `requeue_count` of the rest to `target`,
+    /// if `check` passes, else fail with EAGAIN.
+    /// `check` is done with the futex locked, so no wake can be missed in between.
+    /// Return the number of woken and requeued waiters.
+    pub fn requeue(
+        &self,
+        wake_count: usize,
+        requeue_count: usize,
+        target: &Arc<Futex>,
+        check: impl FnOnce() -> bool,
+    ) -> Result<(usize, usize), SysError> {
+        let (woken, moved) = {
+            let mut inner = self.inner.lock();
+            if !check() {
+                return Err(SysError::EAGAIN);
+            }
+            let woken = inner.wake_bitset(wake_count, FUTEX_BITSET_MATCH_ANY);
+            (woken, inner.take_waiters(requeue_count, target))
+        };
+        let requeued = moved.len();
+        target.inner.lock().waiters.extend(moved);
+        Ok((woken, requeued))
+    }
+
+    /// Put `waiter` taken for another futex back to the front of the queue,
+    /// unless it stopped waiting meanwhile
+    fn put_back(self: &Arc<Self>, waiter: Arc<Mutex<Waiter>>) {
         let mut inner = self.inner.lock();
-        for i in 0..wake_count {
-            if let Some(waiter) = inner.waiters.pop_front() {
-                let mut waiter = waiter.lock();
-                waiter.woken = true;
-                if let Some(waker) = waiter.waker.take() {
+        let mut w = waiter.lock();
+        if w.woken {
+            return;
+        }
+        w.futex = self.clone();
+        drop(w);
+        inner.waiters.push_front(waiter);
+    }
+
+    /// Wait for a wake matching `bitset` if `check` passes,
+    /// else fail with EAGAIN.
+    /// `check` is done with the futex locked, so no wake can be missed in between.
+    /// Fail with ETIMEDOUT after `deadline` of `timer_now`, or EINTR on signals to `thread`.
+    pub fn wait(
+        self: &Arc<Self>,
+        bitset: u32,
+        deadline: Option<Duration>,
+        thread: &Arc<Thread>,
+        check: impl FnOnce() -> bool,
+    ) -> impl Future<Output = SysResult> {
+        let waiter = Waiter::new(self, thread, bitset);
+        let queued = {
+            let mut inner = self.inner.lock();
+            if check() {
+                inner.waiters.push_back(waiter.clone());
+                true
+            } else {
+                false
+            }
+        };
+        FutexFuture::new(queued, waiter, deadline, thread, true)
+    }
+
+    /// FUTEX_WAIT_REQUEUE_PI: wait like `wait` until requeued to PI futex
+    /// with word `target_atomic` by `requeue_pi` and given its ownership.
+    /// Fail with EAGAIN if woken before being requeued.
+    pub async fn wait_requeue_pi(
+        self: &Arc<Self>,
+        target_atomic: &AtomicU32,
+        deadline: Option<Duration>,
+        thread: &Arc<Thread>,
+        check: impl FnOnce() -> bool,
+    ) -> SysResult {
+        let waiter = Waiter::new(self, thread, FUTEX_BITSET_MATCH_ANY);
+        let queued = {
+            let mut inner = self.inner.lock();
+            if check() {
+                inner.waiters.push_back(waiter.clone());
+                true
+            } else {
+                false
+            }
+        };
+        FutexFuture::new(queued, waiter.clone(), deadline, thread, true).await?;
+        let (owner, target) = {
+            let waiter = waiter.lock();
+            (waiter.owner, waiter.futex.clone())
+        };
+        if owner {
+            return Ok(0);
+        }
+        if Arc::ptr_eq(&target, self) {
+            return Err(SysError::EAGAIN);
+        }
+        // woken on the PI futex as its robust owner died, take it
+        target.lock_pi(target_atomic, thread, deadline, false).await
+    }
+
+    /// FUTEX_CMP_REQUEUE_PI: make the first waiter own PI futex `target`
+    /// with word `atomic` if it is free,
+    /// then move the rest to wait on `target`, at most `requeue_count` in all,
+    /// if `check` passes, else fail with EAGAIN.
+    /// `check` is done with the futex locked, so no wake can be missed in between.
+    /// Return the number of woken and requeued waiters.
+    pub fn requeue_pi(
+        self: &Arc<Self>,
+        requeue_count: usize,
+        target: &Arc<Futex>,
+        atomic: &AtomicU32,
+        check: impl FnOnce() -> bool,
+    ) -> SysResult {
+        // one more than requeued, in case the first takes the lock
+        let mut moved = {
+            let mut inner = self.inner.lock();
+            if !check() {
+                return Err(SysError::EAGAIN);
+            }
+            inner.take_waiters(requeue_count + 1, target)
+        };
+        let mut woken = 0;
+        if !moved.is_empty() && atomic.load(Ordering::Acquire) & FUTEX_TID_MASK == 0 {
+            let waiter = moved.remove(0);
+            let waiters = if requeue_count > 0 && !moved.is_empty() {
+                FUTEX_WAITERS
+            } else {
+                0
+            };
+            let mut inner = target.inner.lock();
+            let word = atomic.load(Ordering::Acquire);
+            let mut w = waiter.lock();
+            let new = w.word_tid | (word & FUTEX_OWNER_DIED) | waiters;
+            if word & FUTEX_TID_MASK == 0
+                && atomic
+                    .compare_exchange(word, new, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            {
+                inner.pi_owner = w.tid;
+                w.woken = true;
+                w.owner = true;
+                if let Some(waker) = w.waker.take() {
                     waker.wake();
                 }
+                woken = 1;
             } else {
-                return i;
+                // locked meanwhile, wait for it with the rest
+                drop(w);
+                drop(inner);
+                moved.insert(0, waiter);
             }
         }
-        wake_count
-    }
 
-    pub fn wait(self: &Arc<Self>, timeout: Option<Duration>) -> impl Future<Output = SysResult> {
-        #[must_use = "future does nothing unless polled/`await`-ed"]
-        struct FutexFuture {
-            waiter: Arc<Mutex<Waiter>>,
-            deadline: Option<Duration>,
+        // the rest wait for unlocking
+        while moved.len() > requeue_count {
+            self.put_back(moved.pop().unwrap());
         }
+        let requeued = moved.len();
+        if requeued > 0 {
+            atomic.fetch_or(FUTEX_WAITERS, Ordering::AcqRel);
+            target.inner.lock().waiters.extend(moved);
+        }
+        if requeued > 0 {
+            target.add_to_owner();
+        }
+        Ok(woken + requeued)
+    }
 
-        impl Future for FutexFuture {
-            type Output = SysResult;
+    /// FUTEX_LOCK_PI and FUTEX_TRYLOCK_PI: lock the PI futex with word `atomic` for `thread`,
+    /// boosting the owner while waiting for it.
+    /// Fail with ETIMEDOUT after `deadline` of `timer_now`.
+    pub async fn lock_pi(
+        self: &Arc<Self>,
+        atomic: &AtomicU32,
+        thread: &Arc<Thread>,
+        deadline: Option<Duration>,
+        trylock: bool,
+    ) -> SysResult {
+        let tid = word_tid(thread);
+        loop {
+            let waiter = Waiter::new(self, thread, FUTEX_BITSET_MATCH_ANY);
+            let word = atomic.load(Ordering::Acquire);
+            let owner_tid = word & FUTEX_TID_MASK;
+            if owner_tid == tid {
+                return Err(SysError::EDEADLK);
+            }
+            let owner = if owner_tid == 0 {
+                None
+            } else {
+                let gid = thread.proc.lock().ns.pid.global(owner_tid as usize);
+                let owner = gid.and_then(|gid| THREADS.read().get(&gid).cloned());
+                Some(owner.ok_or(SysError::ESRCH)?)
+            };
 
-            fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-                let mut inner = self.waiter.lock();
-                // check wakeup
-                if inner.woken {
-                    return Poll::Ready(Ok(0));
-                }
-                if let Some(deadline) = self.deadline {
-                    if timer_now() >= deadline {
-                        inner.woken = true;
-                        return Poll::Ready(Err(SysError::ETIMEDOUT));
+            // queue it unless the futex is taken
+            {
+                let mut inner = self.inner.lock();
+                let owner = match owner {
+                    // free, or its robust owner died
+                    None => {
+                        let waiters = if inner.waiters.is_empty() {
+                            0
+                        } else {
+                            FUTEX_WAITERS
+                        };
+                        let new = tid | (word & FUTEX_OWNER_DIED) | waiters;
+                        if atomic
+                            .compare_exchange(word, new, Ordering::AcqRel, Ordering::Acquire)
+                            .is_err()
+                        {
+                            continue;
+                        }
+                        inner.pi_owner = thread.tid;
+                        drop(inner);
+                        if waiters != 0 {
+                            self.add_to_owner();
+                        }
+                        return Ok(0);
                     }
+                    Some(owner) => owner,
+                };
+                if trylock {
+                    return Err(SysError::EAGAIN);
                 }
+                if atomic
+                    .compare_exchange(
+                        word,
+                        word | FUTEX_WAITERS,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_err()
+                {
+                    continue;
+                }
+                inner.waiters.push_back(waiter.clone());
+                // the owner may have locked it in user space
+                inner.pi_owner = owner.tid;
+            }
 
-                // first time?
-                if inner.waker.is_none() {
-                    // futex
-                    let mut futex = inner.futex.inner.lock();
-                    futex.waiters.push_back(self.waiter.clone());
-                    drop(futex);
-                    inner.waker.replace(cx.waker().clone());
-
-                    // timer
-                    if let Some(deadline) = self.deadline {
-                        let waker = cx.waker().clone();
-                        NAIVE_TIMER
-                            .lock()
-                            .add(deadline, Box::new(move |_| waker.wake()));
-                    }
+            // boost the owner
+            thread.inner.lock().pi_blocked_on = Some(self.clone());
+            self.add_to_owner();
+
+            // a signal would leave the lock taken for libc, so only a kill stops waiting
+            let res = FutexFuture::new(true, waiter.clone(), deadline, thread, false).await;
+            thread.inner.lock().pi_blocked_on = None;
+            if waiter.lock().owner {
+                return Ok(0);
+            }
+            // deboost the owner
+            self.update_owner_boost();
+            res?;
+            // woken as its owner died, try again
+        }
+    }
+
+    /// FUTEX_UNLOCK_PI: unlock the PI futex with word `atomic` owned by `thread`,
+    /// handing it to the waiter of the highest priority
+    pub fn unlock_pi(self: &Arc<Self>, atomic: &AtomicU32, thread: &Arc<Thread>) -> SysResult {
+        let tid = word_tid(thread);
+        let mut inner = self.inner.lock();
+        let word = atomic.load(Ordering::Acquire);
+        if word & FUTEX_TID_MASK != tid {
+            return Err(SysError::EPERM);
+        }
+        inner.waiters.retain(|waiter| !waiter.lock().woken);
+        // the first one of the highest priority
+        let next = inner
+            .waiters
+            .iter()
+            .enumerate()
+            .max_by_key(|&(idx, waiter)| (waiter.lock().priority, !idx))
+            .map(|(idx, _)| idx);
+        let new_owner = match next {
+            Some(idx) => {
+                let waiter = inner.waiters.remove(idx).unwrap();
+                let mut w = waiter.lock();
+                let waiters = if inner.waiters.is_empty() {
+                    0
+                } else {
+                    FUTEX_WAITERS
+                };
+                atomic.store(w.word_tid | waiters, Ordering::Release);
+                inner.pi_owner = w.tid;
+                w.woken = true;
+                w.owner = true;
+                if let Some(waker) = w.waker.take() {
+                    waker.wake();
                 }
-                Poll::Pending
+                waiters != 0
             }
+            None => {
+                atomic.store(0, Ordering::Release);
+                inner.pi_owner = 0;
+                false
+            }
+        };
+        drop(inner);
+
+        thread
+            .inner
+            .lock()
+            .pi_futexes
+            .retain(|f| !Arc::ptr_eq(f, self));
+        update_pi_boost(thread);
+        if new_owner {
+            self.add_to_owner();
         }
+        Ok(0)
+    }
+
+    /// Highest priority of waiters
+    fn top_priority(&self) -> usize {
+        self.inner
+            .lock()
+            .waiters
+            .iter()
+            .filter_map(|waiter| {
+                let waiter = waiter.lock();
+                if waiter.woken {
+                    None
+                } else {
+                    Some(waiter.priority)
+                }
+            })
+            .max()
+            .unwrap_or(0)
+    }
 
+    /// Let the owner be boosted by waiters
+    fn add_to_owner(self: &Arc<Self>) {
+        let owner = self.inner.lock().pi_owner;
+        let owner = THREADS.read().get(&owner).cloned();
+        if let Some(owner) = owner {
+            {
+                let mut inner = owner.inner.lock();
+                if !inner.pi_futexes.iter().any(|f| Arc::ptr_eq(f, self)) {
+                    inner.pi_futexes.push(self.clone());
+                }
+            }
+            update_pi_boost(&owner);
+        }
+    }
+
+    /// Recompute the boost of the owner after waiters change
+    fn update_owner_boost(&self) {
+        let owner = self.inner.lock().pi_owner;
+        let owner = THREADS.read().get(&owner).cloned();
+        if let Some(owner) = owner {
+            update_pi_boost(&owner);
+        }
+    }
+}
+
+/// Recompute the priority boost of `thread` from waiters of PI futexes it owns,
+/// and pass it along the chain of owners it waits on
+fn update_pi_boost(thread: &Arc<Thread>) {
+    let mut thread = thread.clone();
+    for _ in 0..MAX_LOCK_DEPTH {
+        let futexes = thread.inner.lock().pi_futexes.clone();
+        let boost = futexes.iter().map(|f| f.top_priority()).max().unwrap_or(0);
+        let blocked_on = {
+            let mut inner = thread.inner.lock();
+            if inner.pi_boost == boost {
+                return;
+            }
+            inner.pi_boost = boost;
+            inner.pi_blocked_on.clone()
+        };
+        sched::update_priority(&thread);
+        let futex = match blocked_on {
+            Some(futex) => futex,
+            None => return,
+        };
+        // the futex it waits on sees its new priority
+        let priority = thread.pi_priority();
+        let owner = {
+            let inner = futex.inner.lock();
+            for waiter in inner.waiters.iter() {
+                let mut waiter = waiter.lock();
+                if waiter.tid == thread.tid {
+                    waiter.priority = priority;
+                }
+            }
+            inner.pi_owner
+        };
+        thread = match THREADS.read().get(&owner).cloned() {
+            Some(owner) => owner,
+            None => return,
+        };
+    }
+}
+
+/// Tid of `thread` in futex words, as it sees in its PID namespace
+fn word_tid(thread: &Thread) -> u32 {
+    thread.proc.lock().ns.pid.local(thread.tid).unwrap_or(0) as u32
+}
+
+#[must_use = "future does nothing unless polled/`await`-ed"]
+struct FutexFuture {
+    /// None if not queued
+    waiter: Option<Arc<Mutex<Waiter>>>,
+    deadline: Option<Duration>,
+    timer_added: bool,
+    thread: Arc<Thread>,
+    eventbus: Arc<Mutex<EventBus>>,
+    /// Stop waiting on any signal, otherwise only on SIGKILL
+    interruptible: bool,
+}
+
+impl FutexFuture {
+    fn new(
+        queued: bool,
+        waiter: Arc<Mutex<Waiter>>,
+        deadline: Option<Duration>,
+        thread: &Arc<Thread>,
+        interruptible: bool,
+    ) -> Self {
         FutexFuture {
-            waiter: Arc::new(Mutex::new(Waiter {
-                waker: None,
-                woken: false,
-                futex: self.clone(),
-            })),
-            deadline: timeout.map(|t| timer_now() + t),
+            waiter: if queued { Some(waiter) } else { None },
+            deadline,
+            timer_added: false,
+            thread: thread.clone(),
+            eventbus: thread.proc.lock().eventbus.clone(),
+            interruptible,
         }
     }
+
+    /// Leave the futex, return whether it has been woken meanwhile
+    fn cancel(waiter: &Arc<Mutex<Waiter>>) -> bool {
+        loop {
+            // the waiter may be requeued before the futex is locked
+            let futex = waiter.lock().futex.clone();
+            let mut inner = futex.inner.lock();
+            let mut w = waiter.lock();
+            if w.woken {
+                return true;
+            }
+            if !Arc::ptr_eq(&w.futex, &futex) {
+                continue;
+            }
+            w.woken = true;
+            w.waker = None;
+            drop(w);
+            inner.waiters.retain(|other| !Arc::ptr_eq(other, waiter));
+            return false;
+        }
+    }
+}
+
+impl Future for FutexFuture {
+    type Output = SysResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waiter = match &self.waiter {
+            Some(waiter) => waiter.clone(),
+            None => return Poll::Ready(Err(SysError::EAGAIN)),
+        };
+        // check wakeup
+        {
+            let mut inner = waiter.lock();
+            if inner.woken {
+                return Poll::Ready(Ok(0));
+            }
+            inner.waker.replace(cx.waker().clone());
+        }
+
+        let interrupted = if self.interruptible {
+            self.thread.has_signal_to_handle()
+        } else {
+            self.thread.has_kill_signal()
+        };
+        let error = match self.deadline {
+            Some(deadline) if timer_now() >= deadline => Some(SysError::ETIMEDOUT),
+            _ if interrupted => Some(SysError::EINTR),
+            _ => None,
+        };
+        if let Some(error) = error {
+            return if Self::cancel(&waiter) {
+                Poll::Ready(Ok(0))
+            } else {
+                Poll::Ready(Err(error))
+            };
+        }
+
+        // timer
+        if let Some(deadline) = self.deadline {
+            if !self.timer_added {
+                self.timer_added = true;
+                let waker = cx.waker().clone();
+                NAIVE_TIMER
+                    .lock()
+                    .add(deadline, Box::new(move |_| waker.wake()));
+            }
+        }
+        // signals
+        let waker = cx.waker().clone();
+        self.eventbus.lock().subscribe(Box::new({
+            move |_| {
+                waker.wake_by_ref();
+                true
+            }
+        }));
+        Poll::Pending
+    }
+}
+
+/// Get the futex at `uaddr` of `thread`.
+/// Identity of a futex in memory shared between processes
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+enum SharedKey {
+    /// Physical address, in memory mapping the same frames
+    Frame(PhysAddr),
+    /// File and offset in it, in a shared mapping of the file
+    File(usize, usize),
+}
+
+/// Unless `private`, futexes in memory shared between processes are keyed by
+/// the object backing it, or by physical address if there is none,
+/// so all processes mapping it get the same one.
+/// The page of `uaddr` should have been accessed.
+pub fn get_futex(thread: &Thread, uaddr: usize, private: bool) -> Arc<Futex> {
+    if !private {
+        let mut vm = thread.vm.lock();
+        let backing = vm
+            .iter()
+            .find(|area| area.contains(uaddr) && area.attr().is_shared())
+            .map(|area| area.backing(uaddr));
+        let key = match backing {
+            Some(Some((file, offset))) => Some(SharedKey::File(file, offset)),
+            Some(None) => {
+                if vm.translate(uaddr).is_none() {
+                    vm.handle_page_fault(uaddr);
+                }
+                vm.translate(uaddr)
+                    .map(|frame| SharedKey::Frame(frame + uaddr % PAGE_SIZE))
+            }
+            None => None,
+        };
+        drop(vm);
+        if let Some(key) = key {
+            return shared_futex(key);
+        }
+    }
+    thread.proc.lock().get_futex(uaddr)
+}
+
+/// Get the process-shared futex at `key`
+fn shared_futex(key: SharedKey) -> Arc<Futex> {
+    let mut futexes = SHARED_FUTEXES.lock();
+    if let Some(futex) = futexes.get(&key).and_then(Weak::upgrade) {
+        return futex;
+    }
+    // drop the ones nobody waits on
+    let unused: Vec<_> = futexes
+        .iter()
+        .filter(|(_, futex)| futex.strong_count() == 0)
+        .map(|(&key, _)| key)
+        .collect();
+    for key in unused {
+        futexes.remove(&key);
+    }
+    let futex = Arc::new(Futex::new());
+    futexes.insert(key, Arc::downgrade(&futex));
+    futex
+}
+
+// struct robust_list_head
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RobustListHead {
+    /// The first entry, pointing back to the head when empty
+    pub next: usize,
+    /// Offset of the futex word from an entry
+    pub futex_offset: isize,
+    /// The entry being added or removed
+    pub list_op_pending: usize,
+}
+
+/// Clean up futexes of exiting `thread`
+pub fn exit_futexes(thread: &Thread) {
+    exit_robust_list(thread);
+    // PI futexes still owned and not robust stay locked
+    let mut inner = thread.inner.lock();
+    inner.pi_futexes.clear();
+    inner.pi_blocked_on = None;
+}
+
+/// Release the futexes on the robust list of exiting `thread`,
+/// marking them FUTEX_OWNER_DIED and waking a waiter of each
+fn exit_robust_list(thread: &Thread) {
+    let head_addr = thread.inner.lock().robust_list;
+    if head_addr == 0 {
+        return;
+    }
+    let head: RobustListHead = match UserInPtr::from(head_addr).read() {
+        Ok(head) => head,
+        Err(_) => return,
+    };
+    let tid = thread.proc.lock().ns.pid.local(thread.tid).unwrap_or(0) as u32;
+    // bit 0 of entries marks PI futexes
+    let futex_of = |entry: usize| ((entry & !1) as isize + head.futex_offset) as usize;
+
+    let mut entry = head.next;
+    for _ in 0..ROBUST_LIST_LIMIT {
+        if entry & !1 == head_addr || entry == 0 {
+            break;
+        }
+        let next: usize = match UserInPtr::from(entry & !1).read() {
+            Ok(next) => next,
+            Err(_) => return,
+        };
+        // the pending one is handled below
+        if entry != head.list_op_pending {
+            futex_owner_died(thread, futex_of(entry), tid);
+        }
+        entry = next;
+    }
+    if head.list_op_pending != 0 {
+        futex_owner_died(thread, futex_of(head.list_op_pending), tid);
+    }
+}
+
+/// Mark the futex at `uaddr` owned by `tid` FUTEX_OWNER_DIED, waking a waiter
+fn futex_owner_died(thread: &Thread, uaddr: usize, tid: u32) {
+    if uaddr % 4 != 0 {
+        return;
+    }
+    let atomic = match unsafe { thread.vm.lock().check_write_ptr(uaddr as *mut AtomicU32) } {
+        Ok(atomic) => atomic,
+        Err(_) => return,
+    };
+    let mut word = atomic.load(Ordering::Acquire);
+    loop {
+        if word & FUTEX_TID_MASK != tid {
+            return;
+        }
+        let new = (word & FUTEX_WAITERS) | FUTEX_OWNER_DIED;
+        match atomic.compare_exchange(word, new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => break,
+            Err(current) => word = current,
+        }
+    }
+    if word & FUTEX_WAITERS != 0 {
+        get_futex(thread, uaddr, false).wake(1);
+    }
 }
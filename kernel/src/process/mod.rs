@@ -10,8 +10,15 @@ use log::*;
 use trapframe::UserContext;
 
 mod abi;
+pub mod aslr;
+pub mod coredump;
 pub mod futex;
+pub mod namespace;
 pub mod proc;
+pub mod ptrace;
+pub mod rlimit;
+pub mod sched;
+pub mod seccomp;
 pub mod structs;
 pub mod thread;
 
@@ -0,0 +1,315 @@
+//! PID, mount and UTS namespaces, see `namespaces(7)`
+//!
+//! Threads keep their global tid in `THREADS`, a PID namespace only maps
+//! them to local ids. A thread has an id in the namespace of its process
+//! and in every ancestor of it. Orphans are reparented to the init of
+//! their namespace. Unlike Linux, the namespace is not killed when its init
+//! exits, its orphans go to the init of the parent namespace instead.
+
+use super::{process, Pid, Process};
+use crate::fs::{MountNamespace, ROOT_MNT_NS};
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::{CloneFlags, SysError};
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rcore_fs::vfs::*;
+use spin::RwLock;
+
+/// Namespaces of a process
+#[derive(Clone)]
+pub struct Namespaces {
+    pub pid: Arc<PidNamespace>,
+    /// PID namespace of new children, changed by unshare and setns
+    pub pid_for_children: Arc<PidNamespace>,
+    pub mnt: Arc<MountNamespace>,
+    pub uts: Arc<UtsNamespace>,
+}
+
+/// Ids of namespaces shown in /proc/[pid]/ns, from the same range as Linux
+static NEXT_NS_ID: AtomicUsize = AtomicUsize::new(0xF000_0000);
+
+/// Allocate the id of a new namespace, unique among all types
+pub fn new_ns_id() -> usize {
+    NEXT_NS_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+lazy_static! {
+    static ref ROOT_PID_NS: Arc<PidNamespace> = Arc::new(PidNamespace::new(None));
+    static ref ROOT_UTS_NS: Arc<UtsNamespace> = Arc::new(UtsNamespace::new("orz", "domain"));
+}
+
+impl Namespaces {
+    /// Namespaces of the init process
+    pub fn root() -> Self {
+        Namespaces {
+            pid: ROOT_PID_NS.clone(),
+            pid_for_children: ROOT_PID_NS.clone(),
+            mnt: ROOT_MNT_NS.clone(),
+            uts: ROOT_UTS_NS.clone(),
+        }
+    }
+
+    /// Namespaces of a child created with clone `flags`
+    pub fn for_child(&self, flags: CloneFlags) -> Result<Self, SysError> {
+        let pid = if flags.contains(CloneFlags::NEWPID) {
+            Arc::new(PidNamespace::new(Some(self.pid_for_children.clone())))
+        } else {
+            self.pid_for_children.clone()
+        };
+        let mut ns = Namespaces {
+            pid: pid.clone(),
+            pid_for_children: pid,
+            mnt: self.mnt.clone(),
+            uts: self.uts.clone(),
+        };
+        ns.unshare(flags & (CloneFlags::NEWNS | CloneFlags::NEWUTS))?;
+        Ok(ns)
+    }
+
+    /// Move into new namespaces of `flags`.
+    /// The process stays in its PID namespace, only new children enter the new one.
+    pub fn unshare(&mut self, flags: CloneFlags) -> Result<(), SysError> {
+        if flags.contains(CloneFlags::NEWNS) {
+            self.mnt = Arc::new(self.mnt.copy()?);
+        }
+        if flags.contains(CloneFlags::NEWUTS) {
+            self.uts = Arc::new(self.uts.copy());
+        }
+        if flags.contains(CloneFlags::NEWPID) {
+            self.pid_for_children =
+                Arc::new(PidNamespace::new(Some(self.pid_for_children.clone())));
+        }
+        Ok(())
+    }
+
+    /// Namespace of `kind` as in /proc/[pid]/ns, None if unsupported
+    pub fn get(&self, kind: &str) -> Option<Namespace> {
+        match kind {
+            "pid" => Some(Namespace::Pid(self.pid.clone())),
+            "pid_for_children" => Some(Namespace::Pid(self.pid_for_children.clone())),
+            "mnt" => Some(Namespace::Mnt(self.mnt.clone())),
+            "uts" => Some(Namespace::Uts(self.uts.clone())),
+            _ => None,
+        }
+    }
+}
+
+pub struct PidNamespace {
+    pub id: usize,
+    parent: Option<Arc<PidNamespace>>,
+    ids: Mutex<PidMap>,
+}
+
+#[derive(Default)]
+struct PidMap {
+    /// Last allocated local id
+    last: usize,
+    /// Global id of the init, which got local id 1
+    init: Option<usize>,
+    /// global id -> local id
+    local: BTreeMap<usize, usize>,
+    /// local id -> global id
+    global: BTreeMap<usize, usize>,
+}
+
+impl PidNamespace {
+    /// A new namespace nested in `parent`, None for the root namespace
+    pub fn new(parent: Option<Arc<PidNamespace>>) -> Self {
+        PidNamespace {
+            id: new_ns_id(),
+            parent,
+            ids: Mutex::new(PidMap::default()),
+        }
+    }
+
+    /// Allocate local ids for the new thread `gid` in this namespace and its ancestors
+    pub fn attach(&self, gid: usize) {
+        let parent = match &self.parent {
+            Some(parent) => parent,
+            // the root namespace uses global ids
+            None => return,
+        };
+        {
+            let mut ids = self.ids.lock();
+            let mut id = ids.last + 1;
+            while ids.global.contains_key(&id) {
+                id += 1;
+            }
+            ids.last = id;
+            if id == 1 {
+                ids.init = Some(gid);
+            }
+            ids.local.insert(gid, id);
+            ids.global.insert(id, gid);
+        }
+        parent.attach(gid);
+    }
+
+    /// Release the ids of the exited thread `gid`
+    pub fn detach(&self, gid: usize) {
+        if let Some(parent) = &self.parent {
+            let mut ids = self.ids.lock();
+            if let Some(id) = ids.local.remove(&gid) {
+                ids.global.remove(&id);
+            }
+            drop(ids);
+            parent.detach(gid);
+        }
+    }
+
+    /// Id of the global `gid` in this namespace, None if it is not visible
+    pub fn local(&self, gid: usize) -> Option<usize> {
+        match self.parent {
+            Some(_) => self.ids.lock().local.get(&gid).cloned(),
+            None => Some(gid),
+        }
+    }
+
+    /// Global id of `id` in this namespace
+    pub fn global(&self, id: usize) -> Option<usize> {
+        match self.parent {
+            Some(_) => self.ids.lock().global.get(&id).cloned(),
+            None => Some(id),
+        }
+    }
+
+    /// The init adopting the orphans of the exiting process `gid`,
+    /// that of the nearest ancestor if the init of this namespace is it or is gone
+    pub fn reaper(&self, gid: usize) -> Option<(Pid, Arc<Mutex<Process>>)> {
+        let init = match self.parent {
+            Some(_) => self.ids.lock().init,
+            None => Some(Pid::INIT),
+        };
+        if let Some(init) = init.filter(|&init| init != gid) {
+            if let Some(proc) = process(init).filter(|proc| !proc.lock().exited()) {
+                return Some((Pid(init), proc));
+            }
+        }
+        self.parent.as_ref()?.reaper(gid)
+    }
+
+    /// Whether this is `other` or one of its ancestors
+    pub fn is_ancestor_of(self: &Arc<Self>, other: &Arc<Self>) -> bool {
+        let mut ns = Some(other);
+        while let Some(cur) = ns {
+            if Arc::ptr_eq(self, cur) {
+                return true;
+            }
+            ns = cur.parent.as_ref();
+        }
+        false
+    }
+}
+
+/// Host and domain names of a UTS namespace
+pub struct UtsNamespace {
+    pub id: usize,
+    pub hostname: RwLock<String>,
+    pub domainname: RwLock<String>,
+}
+
+impl UtsNamespace {
+    fn new(hostname: &str, domainname: &str) -> Self {
+        UtsNamespace {
+            id: new_ns_id(),
+            hostname: RwLock::new(hostname.to_string()),
+            domainname: RwLock::new(domainname.to_string()),
+        }
+    }
+
+    fn copy(&self) -> Self {
+        Self::new(&self.hostname.read(), &self.domainname.read())
+    }
+}
+
+/// A namespace referred to by a file, for setns
+#[derive(Clone)]
+pub enum Namespace {
+    Pid(Arc<PidNamespace>),
+    Mnt(Arc<MountNamespace>),
+    Uts(Arc<UtsNamespace>),
+}
+
+impl Namespace {
+    /// CLONE_NEW* flag of the namespace type
+    pub fn flag(&self) -> CloneFlags {
+        match self {
+            Namespace::Pid(_) => CloneFlags::NEWPID,
+            Namespace::Mnt(_) => CloneFlags::NEWNS,
+            Namespace::Uts(_) => CloneFlags::NEWUTS,
+        }
+    }
+
+    /// Link content of /proc/[pid]/ns files like "mnt:[id]",
+    /// equal for the same namespace
+    fn link(&self) -> String {
+        let (name, id) = match self {
+            Namespace::Pid(ns) => ("pid", ns.id),
+            Namespace::Mnt(ns) => ("mnt", ns.id()),
+            Namespace::Uts(ns) => ("uts", ns.id),
+        };
+        format!("{}:[{}]", name, id)
+    }
+}
+
+/// INode of /proc/[pid]/ns/*
+pub struct NamespaceINode {
+    pub ns: Namespace,
+    link: Vec<u8>,
+}
+
+impl NamespaceINode {
+    pub fn new(ns: Namespace) -> Self {
+        let link = ns.link().into_bytes();
+        NamespaceINode { ns, link }
+    }
+}
+
+impl INode for NamespaceINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.link.len() {
+            return Ok(0);
+        }
+        let len = (self.link.len() - offset).min(buf.len());
+        buf[..len].copy_from_slice(&self.link[offset..offset + len]);
+        Ok(len)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: false,
+            error: false,
+        })
+    }
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: 0,
+            size: self.link.len(),
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::SymLink,
+            mode: 0o777,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -1,9 +1,13 @@
 use super::{
     abi::{self, ProcInitInfo},
+    coredump::CoreState,
+    namespace::{Namespaces, PidNamespace},
+    ptrace,
+    rlimit::{self, RLimit, RLIMIT_NOFILE, RLIM_NLIMITS},
     Futex, Tid,
 };
 use crate::arch::paging::*;
-use crate::fs::{FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
+use crate::fs::{lock, FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
 use crate::ipc::{SemProc, ShmProc};
 use crate::memory::{
     phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemoryAttr, MemorySet, Read,
@@ -12,7 +16,7 @@ use crate::process::thread::THREADS;
 use crate::sync::{Event, EventBus, SpinLock, SpinNoIrqLock as Mutex};
 use crate::{
     signal::{Siginfo, Signal, SignalAction, SignalStack, Sigset},
-    syscall::handle_syscall,
+    syscall::{handle_syscall, SysError},
 };
 use alloc::{
     boxed::Box, collections::BTreeMap, collections::VecDeque, string::String, sync::Arc,
@@ -80,6 +84,12 @@ pub struct Process {
     /// Current working dirctory
     pub cwd: String,
 
+    /// Root directory as a path in the mount namespace, see `chroot(2)`
+    pub root: String,
+
+    /// PID, mount and UTS namespaces
+    pub ns: Namespaces,
+
     /// Executable path
     pub exec_path: String,
 
@@ -122,6 +132,29 @@ pub struct Process {
 
     /// shared memory
     pub shm_identifiers: ShmProc,
+
+    /// Execution domain flags, see `personality(2)`
+    pub personality: usize,
+
+    /// Lowest address to search for free area in mmap
+    pub mmap_base: usize,
+
+    /// Auxiliary vector passed at exec, for core dumps
+    pub saved_auxv: BTreeMap<u8, usize>,
+
+    /// Resource limits, indexed by RLIMIT_*
+    pub rlimits: [RLimit; RLIM_NLIMITS],
+
+    /// CPU time used in user mode, in timer ticks, for RLIMIT_CPU
+    pub cpu_ticks: u64,
+
+    /// Signal which stopped the process by job control, 0 when running
+    pub stop_signal: usize,
+    /// Whether the job control stop has been reported by wait4
+    pub stop_reported: bool,
+
+    /// Set while dumping core, which stops the threads, see `coredump::stop_threads`
+    pub core_state: Option<Arc<CoreState>>,
 }
 
 lazy_static! {
@@ -165,22 +198,78 @@ pub fn add_to_process_table(proc: Arc<Mutex<Process>>, pid: Pid) {
     process_table.insert(pid.get(), proc.clone());
 }
 
+/// What `Process::exit` leaves to do after the process is unlocked,
+/// as locking other processes with it locked could deadlock
+#[must_use]
+pub struct Exited {
+    /// Tracees to kill as PTRACE_O_EXITKILL is set
+    tracees: Vec<Arc<Mutex<Process>>>,
+    /// Children to be adopted by the init of the namespace
+    orphans: Vec<(Pid, Weak<Mutex<Process>>)>,
+    pid: Pid,
+    pid_ns: Arc<PidNamespace>,
+}
+
+impl Exited {
+    pub fn finish(self) {
+        ptrace::kill_tracees(self.tracees);
+        if self.orphans.is_empty() {
+            return;
+        }
+        let (reaper_pid, reaper) = match self.pid_ns.reaper(self.pid.get()) {
+            Some(reaper) => reaper,
+            None => return,
+        };
+        // a zombie may have notified the exited parent already
+        let mut zombie = false;
+        for (_, child) in self.orphans.iter() {
+            if let Some(child) = child.upgrade() {
+                let mut child = child.lock();
+                child.parent = (reaper_pid, Arc::downgrade(&reaper));
+                zombie |= child.exited();
+            }
+        }
+        let mut reaper = reaper.lock();
+        reaper.children.extend(self.orphans);
+        if zombie {
+            reaper.eventbus.lock().set(Event::CHILD_PROCESS_QUIT);
+        }
+    }
+}
+
 impl Process {
     /// Get lowest free fd
-    fn get_free_fd(&self) -> usize {
-        (0..).find(|i| !self.files.contains_key(i)).unwrap()
+    fn get_free_fd(&self) -> Result<usize, SysError> {
+        self.get_free_fd_from(0)
+    }
+
+    /// get the lowest available fd great than or equal to arg,
+    /// EMFILE if there is none below RLIMIT_NOFILE
+    pub fn get_free_fd_from(&self, arg: usize) -> Result<usize, SysError> {
+        (arg..self.fd_limit())
+            .find(|i| !self.files.contains_key(i))
+            .ok_or(SysError::EMFILE)
     }
 
-    /// get the lowest available fd great than or equal to arg
-    pub fn get_free_fd_from(&self, arg: usize) -> usize {
-        (arg..).find(|i| !self.files.contains_key(i)).unwrap()
+    /// Fds must be below this, RLIMIT_NOFILE
+    pub fn fd_limit(&self) -> usize {
+        rlimit::limit_to_usize(self.rlimit(RLIMIT_NOFILE))
     }
 
     /// Add a file to the process, return its fd.
-    pub fn add_file(&mut self, file_like: FileLike) -> usize {
-        let fd = self.get_free_fd();
+    pub fn add_file(&mut self, file_like: FileLike) -> Result<usize, SysError> {
+        let fd = self.get_free_fd()?;
         self.files.insert(fd, file_like);
-        fd
+        Ok(fd)
+    }
+
+    /// Close `fd`, releasing the POSIX locks of the process on the file
+    pub fn close_file(&mut self, fd: usize) -> Result<FileLike, SysError> {
+        let file_like = self.files.remove(&fd).ok_or(SysError::EBADF)?;
+        if let FileLike::File(file) = &file_like {
+            lock::release_process(&file.inode(), self.pid.get());
+        }
+        Ok(file_like)
     }
 
     /// Get futex by addr
@@ -193,13 +282,14 @@ impl Process {
 
     /// Exit the process.
     /// Kill all threads and notify parent with the exit code.
-    pub fn exit(&mut self, exit_code: usize) {
+    /// Return what is left to do by `Exited::finish` once the process is unlocked.
+    pub fn exit(&mut self, exit_code: usize) -> Exited {
         // avoid some strange dead lock
         // self.files.clear(); this does not work sometime, for unknown reason
         // manually drop
         let fds = self.files.iter().map(|(fd, _)| *fd).collect::<Vec<_>>();
         for fd in fds.iter() {
-            let file = self.files.remove(fd).unwrap();
+            let file = self.close_file(*fd).unwrap();
             drop(file);
         }
 
@@ -210,16 +300,31 @@ impl Process {
         }
         self.exit_code = exit_code;
 
+        // let tracees go and wake up our threads stopped by tracers
+        let tracees = ptrace::ptrace_detach_all(self.pid.get());
+
         // quit all threads
         // this must be after setting the value of subprocess, or the threads will be treated exit before actually exits
         // remove from thread table
         let mut thread_table = THREADS.write();
         for tid in self.threads.iter() {
-            thread_table.remove(tid);
+            if let Some(thread) = thread_table.remove(tid) {
+                ptrace::ptrace_wake(&thread);
+            }
+            // the pid is released when the parent reaps us
+            if *tid != self.pid.get() {
+                self.ns.pid.detach(*tid);
+            }
         }
         self.threads.clear();
 
         info!("process {} exit with {}", self.pid.get(), exit_code);
+        Exited {
+            tracees,
+            orphans: core::mem::take(&mut self.children),
+            pid: self.pid,
+            pid_ns: self.ns.pid.clone(),
+        }
     }
 
     pub fn exited(&self) -> bool {
@@ -0,0 +1,385 @@
+//! Process tracing, see `ptrace(2)`
+//!
+//! A traced thread stops at syscall entry and exit, on signal delivery
+//! and at events like fork and exec. While it is stopped, its registers
+//! are kept in `PtraceState` where the tracer can read and change them.
+
+use super::{process, Process, Thread, THREADS};
+use crate::arch::signal::set_single_step;
+use crate::signal::{send_signal, Siginfo, Signal, CLD_TRAPPED, SI_KERNEL};
+use crate::sync::{wait_for_event, Event, EventBus, SpinNoIrqLock as Mutex};
+use alloc::{sync::Arc, vec::Vec};
+use bitflags::*;
+use log::*;
+use trapframe::UserContext;
+
+pub const PTRACE_EVENT_FORK: usize = 1;
+pub const PTRACE_EVENT_VFORK: usize = 2;
+pub const PTRACE_EVENT_CLONE: usize = 3;
+pub const PTRACE_EVENT_EXEC: usize = 4;
+pub const PTRACE_EVENT_VFORK_DONE: usize = 5;
+pub const PTRACE_EVENT_EXIT: usize = 6;
+pub const PTRACE_EVENT_SECCOMP: usize = 7;
+
+bitflags! {
+    /// Set by PTRACE_SETOPTIONS
+    pub struct PtraceOptions: usize {
+        /// Report syscall stops as SIGTRAP | 0x80
+        const TRACESYSGOOD = 1;
+        const TRACEFORK = 1 << 1;
+        const TRACEVFORK = 1 << 2;
+        const TRACECLONE = 1 << 3;
+        const TRACEEXEC = 1 << 4;
+        const TRACEVFORKDONE = 1 << 5;
+        const TRACEEXIT = 1 << 6;
+        const TRACESECCOMP = 1 << 7;
+        /// Kill tracees when the tracer exits
+        const EXITKILL = 1 << 20;
+        const SUSPEND_SECCOMP = 1 << 21;
+    }
+}
+
+impl PtraceOptions {
+    /// The option enabling an event
+    fn of_event(event: usize) -> Self {
+        match event {
+            PTRACE_EVENT_FORK => PtraceOptions::TRACEFORK,
+            PTRACE_EVENT_VFORK => PtraceOptions::TRACEVFORK,
+            PTRACE_EVENT_CLONE => PtraceOptions::TRACECLONE,
+            PTRACE_EVENT_EXEC => PtraceOptions::TRACEEXEC,
+            PTRACE_EVENT_VFORK_DONE => PtraceOptions::TRACEVFORKDONE,
+            PTRACE_EVENT_EXIT => PtraceOptions::TRACEEXIT,
+            PTRACE_EVENT_SECCOMP => PtraceOptions::TRACESECCOMP,
+            _ => PtraceOptions::empty(),
+        }
+    }
+}
+
+/// How a tracee runs until its next stop
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResumeMode {
+    /// PTRACE_CONT
+    Cont,
+    /// PTRACE_SYSCALL, also stop at the next syscall entry or exit
+    Syscall,
+    /// PTRACE_SINGLESTEP
+    SingleStep,
+}
+
+/// Why a tracee is stopped
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PtraceStop {
+    /// Signal-delivery-stop, the tracer decides which signal to deliver
+    Signal(Signal),
+    SyscallEntry,
+    SyscallExit,
+    /// PTRACE_EVENT_* stop
+    Event(usize),
+}
+
+/// Tracing state of a thread
+pub struct PtraceState {
+    /// Pid of the tracer process
+    pub tracer: usize,
+    pub options: PtraceOptions,
+    pub mode: ResumeMode,
+    /// Current stop, None when running
+    pub stop: Option<PtraceStop>,
+    /// Whether the current stop has been reported by wait4
+    pub reported: bool,
+    /// Registers of the stopped tracee
+    pub regs: UserContext,
+    /// Signal of the current signal-delivery-stop, see PTRACE_GETSIGINFO
+    pub siginfo: Option<Siginfo>,
+    /// Signal to deliver on resume, 0 for none
+    pub resume_signal: usize,
+    /// See PTRACE_GETEVENTMSG
+    pub event_msg: usize,
+    /// Event stop to enter at the end of the current syscall, with its message
+    pub pending_event: Option<(usize, usize)>,
+    /// Stop tracing on resume
+    pub detach: bool,
+    /// Notified when the tracer resumes the tracee
+    pub eventbus: Arc<Mutex<EventBus>>,
+}
+
+impl PtraceState {
+    pub fn new(tracer: usize, options: PtraceOptions) -> Self {
+        PtraceState {
+            tracer,
+            options,
+            mode: ResumeMode::Cont,
+            stop: None,
+            reported: false,
+            regs: UserContext::default(),
+            siginfo: None,
+            resume_signal: 0,
+            event_msg: 0,
+            pending_event: None,
+            detach: false,
+            eventbus: EventBus::new(),
+        }
+    }
+
+    /// Status reported by wait4 for the current stop
+    pub fn wait_status(&self) -> Option<i32> {
+        let sigtrap = Signal::SIGTRAP as i32;
+        let signo = match self.stop? {
+            PtraceStop::Signal(signal) => signal as i32,
+            PtraceStop::SyscallEntry | PtraceStop::SyscallExit => {
+                if self.options.contains(PtraceOptions::TRACESYSGOOD) {
+                    sigtrap | 0x80
+                } else {
+                    sigtrap
+                }
+            }
+            PtraceStop::Event(event) => sigtrap | (event as i32) << 8,
+        };
+        Some(signo << 8 | 0x7f)
+    }
+
+    /// Resume the stopped tracee
+    pub fn resume(&mut self, mode: ResumeMode, signal: usize) {
+        self.mode = mode;
+        self.resume_signal = signal;
+        self.eventbus.lock().set(Event::TRACEE_RESUME);
+    }
+}
+
+impl Thread {
+    /// Whether the thread is traced
+    pub fn is_traced(&self) -> bool {
+        self.inner.lock().ptrace.is_some()
+    }
+
+    /// Make process `tracer` trace this thread.
+    /// Return false if it is already traced.
+    pub fn ptrace_attach(&self, tracer: usize, options: PtraceOptions) -> bool {
+        let mut inner = self.inner.lock();
+        if inner.ptrace.is_some() {
+            return false;
+        }
+        inner.ptrace = Some(PtraceState::new(tracer, options));
+        true
+    }
+
+    /// Tracer and options for a child created with `event`,
+    /// if the tracer asked to trace it too
+    pub fn ptrace_inherit(&self, event: usize) -> Option<(usize, PtraceOptions)> {
+        let inner = self.inner.lock();
+        let state = inner.ptrace.as_ref()?;
+        if state.options.contains(PtraceOptions::of_event(event)) {
+            Some((state.tracer, state.options))
+        } else {
+            None
+        }
+    }
+
+    /// Request a stop for `event` at the end of the current syscall.
+    /// Return false if the tracer did not ask for the event.
+    pub fn ptrace_event(&self, event: usize, msg: usize) -> bool {
+        let mut inner = self.inner.lock();
+        match inner.ptrace.as_mut() {
+            Some(state) if state.options.contains(PtraceOptions::of_event(event)) => {
+                state.pending_event = Some((event, msg));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the thread stops at syscall entry and exit
+    fn ptrace_syscall(&self) -> bool {
+        self.inner
+            .lock()
+            .ptrace
+            .as_ref()
+            .map_or(false, |state| state.mode == ResumeMode::Syscall)
+    }
+}
+
+/// Stop the thread and wait for the tracer to resume it.
+///
+/// Return the signal given by the tracer with the (possibly changed) siginfo,
+/// or None if the thread is not traced.
+async fn ptrace_stop(
+    thread: &Arc<Thread>,
+    cx: &mut UserContext,
+    stop: PtraceStop,
+    siginfo: Option<Siginfo>,
+) -> Option<(usize, Option<Siginfo>)> {
+    let (eventbus, tracer) = {
+        let mut inner = thread.inner.lock();
+        let state = inner.ptrace.as_mut()?;
+        state.stop = Some(stop);
+        state.reported = false;
+        state.siginfo = siginfo;
+        state.resume_signal = 0;
+        state.regs = cx.clone();
+        state.eventbus.lock().clear(Event::TRACEE_RESUME);
+        (state.eventbus.clone(), state.tracer)
+    };
+    info!("thread {} ptrace stop: {:?}", thread.tid, stop);
+    if let Some(tracer) = process(tracer) {
+        tracer.lock().eventbus.lock().set(Event::CHILD_PROCESS_STOP);
+        send_signal(
+            tracer,
+            -1,
+            Siginfo {
+                signo: Signal::SIGCHLD as i32,
+                errno: 0,
+                code: CLD_TRAPPED,
+                field: Default::default(),
+            },
+        );
+    }
+
+    wait_for_event(eventbus, Event::TRACEE_RESUME).await;
+
+    let mut inner = thread.inner.lock();
+    // the state is not removed by others while stopped
+    let state = inner.ptrace.as_mut().unwrap();
+    *cx = state.regs.clone();
+    if set_single_step(cx, state.mode == ResumeMode::SingleStep).is_err() {
+        warn!("single step is not supported");
+    }
+    state.stop = None;
+    let signal = state.resume_signal;
+    let siginfo = state.siginfo.take();
+    if state.detach {
+        info!("thread {} detached", thread.tid);
+        inner.ptrace = None;
+    }
+    Some((signal, siginfo))
+}
+
+/// Syscall-enter-stop or syscall-exit-stop, if the tracer asked for it by PTRACE_SYSCALL
+pub async fn ptrace_syscall_stop(thread: &Arc<Thread>, cx: &mut UserContext, stop: PtraceStop) {
+    if thread.ptrace_syscall() {
+        ptrace_stop(thread, cx, stop, None).await;
+    }
+}
+
+/// Enter the event stop requested by `Thread::ptrace_event`
+pub async fn ptrace_event_stop(thread: &Arc<Thread>, cx: &mut UserContext) {
+    let event = {
+        let mut inner = thread.inner.lock();
+        match inner.ptrace.as_mut() {
+            Some(state) => state.pending_event.take().map(|(event, msg)| {
+                state.event_msg = msg;
+                event
+            }),
+            None => None,
+        }
+    };
+    if let Some(event) = event {
+        ptrace_stop(thread, cx, PtraceStop::Event(event), None).await;
+    }
+}
+
+/// Signal-delivery-stop of a traced thread.
+///
+/// Return the signal to deliver as decided by the tracer, None to suppress it.
+/// Untraced threads get `info` back.
+pub async fn ptrace_signal_stop(
+    thread: &Arc<Thread>,
+    cx: &mut UserContext,
+    info: Siginfo,
+) -> Option<Siginfo> {
+    let signal = <Signal as num::FromPrimitive>::from_i32(info.signo).unwrap();
+    match ptrace_stop(thread, cx, PtraceStop::Signal(signal), Some(info)).await {
+        None => Some(info),
+        Some((0, _)) => None,
+        Some((signo, Some(siginfo))) if signo == siginfo.signo as usize => Some(siginfo),
+        Some((signo, _)) => Some(Siginfo {
+            signo: signo as i32,
+            errno: 0,
+            code: SI_KERNEL,
+            field: Default::default(),
+        }),
+    }
+}
+
+/// Resume a stopped tracee without asking its tracer,
+/// so that it can notice being killed
+pub fn ptrace_wake(thread: &Thread) {
+    if let Some(state) = thread.inner.lock().ptrace.as_mut() {
+        if state.stop.is_some() {
+            state.resume(ResumeMode::Cont, 0);
+        }
+    }
+}
+
+/// Find a stopped tracee of process `tracer` whose stop is not reported yet,
+/// with tid `target` if given. Mark the stop reported and return the tid and wait status.
+pub fn ptrace_wait(tracer: usize, target: Option<usize>) -> Option<(usize, i32)> {
+    for (&tid, thread) in THREADS.read().iter() {
+        if target.map_or(false, |target| target != tid) {
+            continue;
+        }
+        let mut inner = thread.inner.lock();
+        if let Some(state) = inner.ptrace.as_mut() {
+            if state.tracer == tracer && !state.reported {
+                if let Some(status) = state.wait_status() {
+                    state.reported = true;
+                    return Some((tid, status));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Whether process `tracer` traces some thread, with tid `target` if given
+pub fn has_tracee(tracer: usize, target: Option<usize>) -> bool {
+    THREADS.read().iter().any(|(&tid, thread)| {
+        target.map_or(true, |target| target == tid)
+            && thread
+                .inner
+                .lock()
+                .ptrace
+                .as_ref()
+                .map_or(false, |state| state.tracer == tracer)
+    })
+}
+
+/// Detach all tracees of the exiting process `tracer`.
+/// Return the processes of the tracees to kill as PTRACE_O_EXITKILL is set,
+/// which are signaled by `kill_tracees` after the tracer is unlocked.
+pub fn ptrace_detach_all(tracer: usize) -> Vec<Arc<Mutex<Process>>> {
+    let mut killed = Vec::new();
+    for thread in THREADS.read().values() {
+        let mut inner = thread.inner.lock();
+        let state = match inner.ptrace.as_mut() {
+            Some(state) if state.tracer == tracer => state,
+            _ => continue,
+        };
+        if state.options.contains(PtraceOptions::EXITKILL) {
+            killed.push(thread.proc.clone());
+        }
+        if state.stop.is_some() {
+            state.detach = true;
+            state.resume(ResumeMode::Cont, 0);
+        } else {
+            inner.ptrace = None;
+        }
+    }
+    killed
+}
+
+/// Kill the tracees left by `Process::exit`, called by `Exited::finish`.
+/// Locking them with the tracer locked could deadlock with a tracee exiting at the same time.
+pub fn kill_tracees(tracees: Vec<Arc<Mutex<Process>>>) {
+    for proc in tracees {
+        send_signal(
+            proc,
+            -1,
+            Siginfo {
+                signo: Signal::SIGKILL as i32,
+                errno: 0,
+                code: SI_KERNEL,
+                field: Default::default(),
+            },
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Resource limits, see `getrlimit(2)`
+
+use super::{Process, Thread};
+use crate::consts::{MAX_PROCESS_NUM, USEC_PER_TICK, USER_STACK_SIZE};
+use crate::signal::{send_signal, Siginfo, Signal, SI_KERNEL};
+use crate::syscall::SysError;
+use alloc::sync::Arc;
+
+pub const RLIMIT_CPU: usize = 0;
+pub const RLIMIT_FSIZE: usize = 1;
+pub const RLIMIT_DATA: usize = 2;
+pub const RLIMIT_STACK: usize = 3;
+pub const RLIMIT_CORE: usize = 4;
+pub const RLIMIT_RSS: usize = 5;
+pub const RLIMIT_NPROC: usize = 6;
+pub const RLIMIT_NOFILE: usize = 7;
+pub const RLIMIT_MEMLOCK: usize = 8;
+pub const RLIMIT_AS: usize = 9;
+pub const RLIMIT_LOCKS: usize = 10;
+pub const RLIMIT_SIGPENDING: usize = 11;
+pub const RLIMIT_MSGQUEUE: usize = 12;
+pub const RLIMIT_NICE: usize = 13;
+pub const RLIMIT_RTPRIO: usize = 14;
+pub const RLIMIT_RTTIME: usize = 15;
+pub const RLIM_NLIMITS: usize = 16;
+
+/// `rlim_t` value of no limit
+pub const RLIM_INFINITY: u64 = u64::max_value();
+
+const TICKS_PER_SEC: u64 = 1_000_000 / USEC_PER_TICK as u64;
+
+/// Linux struct rlimit64
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct RLimit {
+    /// soft limit
+    pub cur: u64,
+    /// hard limit
+    pub max: u64,
+}
+
+impl RLimit {
+    const INFINITY: RLimit = RLimit {
+        cur: RLIM_INFINITY,
+        max: RLIM_INFINITY,
+    };
+}
+
+/// Linux struct rlimit of getrlimit and setrlimit, which is
+/// two `unsigned long` and so only 32-bit on riscv32 and mipsel
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct OldRLimit {
+    pub cur: usize,
+    pub max: usize,
+}
+
+impl From<RLimit> for OldRLimit {
+    fn from(limit: RLimit) -> Self {
+        // limits beyond `unsigned long` read as RLIM_INFINITY
+        OldRLimit {
+            cur: limit_to_usize(limit.cur),
+            max: limit_to_usize(limit.max),
+        }
+    }
+}
+
+impl From<OldRLimit> for RLimit {
+    fn from(limit: OldRLimit) -> Self {
+        let widen = |limit: usize| {
+            if limit == usize::max_value() {
+                RLIM_INFINITY
+            } else {
+                limit as u64
+            }
+        };
+        RLimit {
+            cur: widen(limit.cur),
+            max: widen(limit.max),
+        }
+    }
+}
+
+/// Limits of the init process
+pub fn default_rlimits() -> [RLimit; RLIM_NLIMITS] {
+    let mut limits = [RLimit::INFINITY; RLIM_NLIMITS];
+    limits[RLIMIT_STACK].cur = USER_STACK_SIZE as u64;
+    limits[RLIMIT_NPROC] = RLimit {
+        cur: MAX_PROCESS_NUM as u64,
+        max: MAX_PROCESS_NUM as u64,
+    };
+    limits[RLIMIT_NOFILE] = RLimit {
+        cur: 1024,
+        max: 4096,
+    };
+    limits[RLIMIT_MEMLOCK] = RLimit {
+        cur: 64 * 1024,
+        max: 64 * 1024,
+    };
+    limits[RLIMIT_SIGPENDING] = RLimit {
+        cur: 1024,
+        max: 1024,
+    };
+    limits[RLIMIT_MSGQUEUE] = RLimit {
+        cur: 819200,
+        max: 819200,
+    };
+    limits[RLIMIT_NICE] = RLimit { cur: 0, max: 0 };
+    limits[RLIMIT_RTPRIO] = RLimit { cur: 0, max: 0 };
+    limits
+}
+
+/// Convert a limit to usize, saturating on 32-bit targets
+pub fn limit_to_usize(limit: u64) -> usize {
+    limit.min(usize::max_value() as u64) as usize
+}
+
+impl Process {
+    /// Soft limit of `resource`
+    pub fn rlimit(&self, resource: usize) -> u64 {
+        self.rlimits[resource].cur
+    }
+
+    /// Make the address space keep to RLIMIT_AS and RLIMIT_DATA
+    pub fn update_vm_limits(&self) {
+        self.vm.lock().set_limits(
+            limit_to_usize(self.rlimit(RLIMIT_AS)),
+            limit_to_usize(self.rlimit(RLIMIT_DATA)),
+        );
+    }
+}
+
+/// Charge a timer tick to the process of `thread` and enforce RLIMIT_CPU:
+/// SIGXCPU every second beyond the soft limit, SIGKILL at the hard limit
+pub fn charge_cpu_tick(thread: &Arc<Thread>) {
+    let signal = {
+        let mut proc = thread.proc.lock();
+        proc.cpu_ticks += 1;
+        if proc.cpu_ticks % TICKS_PER_SEC != 0 {
+            return;
+        }
+        let secs = proc.cpu_ticks / TICKS_PER_SEC;
+        let limit = proc.rlimits[RLIMIT_CPU];
+        if secs >= limit.max {
+            Signal::SIGKILL
+        } else if secs >= limit.cur {
+            Signal::SIGXCPU
+        } else {
+            return;
+        }
+    };
+    send_signal(
+        thread.proc.clone(),
+        -1,
+        Siginfo {
+            signo: signal as i32,
+            errno: 0,
+            code: SI_KERNEL,
+            field: Default::default(),
+        },
+    );
+}
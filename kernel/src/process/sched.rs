@@ -0,0 +1,179 @@
+//! Priorities of threads on top of the executor
+//!
+//! The executor polls woken tasks in order, so priorities are honored where threads
+//! give up the CPU: at timer ticks, and before they run user code.
+//! A thread with a real-time priority, its own or inherited through PI futexes,
+//! keeps the CPU at a tick unless a thread of a higher priority is ready,
+//! or one of the same priority under SCHED_RR.
+//! A thread does not run user code while one of a higher priority is ready.
+//!
+//! Threads are ready from being woken until being polled, which their wakers count.
+//! Like the RT throttling of Linux, real-time threads give up one tick
+//! in `RT_THROTTLE_TICKS` to the others, and no thread waits for its turn forever.
+
+use super::{yield_now, Thread};
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::sync::Arc;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{RawWaker, RawWakerVTable, Waker};
+
+pub const SCHED_OTHER: usize = 0;
+pub const SCHED_FIFO: usize = 1;
+pub const SCHED_RR: usize = 2;
+pub const SCHED_BATCH: usize = 3;
+pub const SCHED_IDLE: usize = 5;
+pub const SCHED_RESET_ON_FORK: usize = 0x4000_0000;
+
+/// Highest real-time priority
+pub const MAX_RT_PRIO: usize = 99;
+
+/// Real-time threads keep the CPU for at most this many ticks in a row
+const RT_THROTTLE_TICKS: usize = 20;
+
+/// `SchedState::ready` of a thread which has exited
+const EXITED: usize = usize::max_value();
+
+lazy_static! {
+    /// Number of ready threads of each priority
+    static ref READY: Mutex<[usize; MAX_RT_PRIO + 1]> = Mutex::new([0; MAX_RT_PRIO + 1]);
+}
+
+/// Scheduling state of a thread, which its wakers use without locking the thread
+#[derive(Debug, Default)]
+pub struct SchedState {
+    /// Priority with the inherited one, see `Thread::pi_priority`
+    priority: AtomicUsize,
+    /// 1 + the priority it is counted with in `READY`, 0 if it is not ready
+    ready: AtomicUsize,
+    /// Ticks it has kept the CPU with a real-time priority
+    rt_ticks: AtomicUsize,
+}
+
+impl SchedState {
+    pub fn new(priority: usize) -> Self {
+        SchedState {
+            priority: AtomicUsize::new(priority),
+            ..SchedState::default()
+        }
+    }
+
+    fn set_ready(&self) {
+        let mut ready = READY.lock();
+        if self.ready.load(Ordering::Relaxed) == 0 {
+            let priority = self.priority.load(Ordering::Relaxed);
+            ready[priority] += 1;
+            self.ready.store(priority + 1, Ordering::Relaxed);
+        }
+    }
+
+    fn clear_ready(&self, exited: bool) {
+        let mut ready = READY.lock();
+        let new = if exited { EXITED } else { 0 };
+        match self.ready.swap(new, Ordering::Relaxed) {
+            0 => {}
+            EXITED => self.ready.store(EXITED, Ordering::Relaxed),
+            priority => ready[priority - 1] -= 1,
+        }
+    }
+}
+
+/// Recompute the priority of `thread` after its real-time priority or its boost changes
+pub fn update_priority(thread: &Thread) {
+    let priority = thread.pi_priority();
+    thread.sched.priority.store(priority, Ordering::Relaxed);
+}
+
+/// Whether a thread of a priority of at least `priority` is ready
+fn ready_from(priority: usize) -> bool {
+    READY.lock()[priority.min(MAX_RT_PRIO + 1)..]
+        .iter()
+        .any(|&count| count > 0)
+}
+
+/// Whether `thread` gives up the CPU at a timer tick
+pub fn should_yield(thread: &Thread) -> bool {
+    let priority = thread.sched.priority.load(Ordering::Relaxed);
+    if priority == 0 {
+        return true;
+    }
+    let ticks = thread.sched.rt_ticks.fetch_add(1, Ordering::Relaxed) + 1;
+    if ticks >= RT_THROTTLE_TICKS {
+        thread.sched.rt_ticks.store(0, Ordering::Relaxed);
+        return true;
+    }
+    let round_robin = thread.inner.lock().sched_policy == SCHED_RR && !thread.pi_boosted();
+    let preempted = if round_robin {
+        ready_from(priority)
+    } else {
+        ready_from(priority + 1)
+    };
+    if preempted {
+        thread.sched.rt_ticks.store(0, Ordering::Relaxed);
+    }
+    preempted
+}
+
+/// Wait until no thread of a higher priority than `thread` is ready,
+/// for at most `RT_THROTTLE_TICKS` turns so that real-time threads can not starve it
+pub async fn wait_turn(thread: &Thread) {
+    for _ in 0..RT_THROTTLE_TICKS {
+        if !ready_from(thread.sched.priority.load(Ordering::Relaxed) + 1) {
+            break;
+        }
+        yield_now().await;
+    }
+}
+
+/// Called when the task of `thread` is spawned
+pub fn set_spawned(thread: &Thread) {
+    thread.sched.set_ready();
+}
+
+/// Called before the task of `thread` is polled
+pub fn set_polled(thread: &Thread) {
+    thread.sched.clear_ready(false);
+}
+
+/// Called when the task of `thread` has finished
+pub fn set_exited(thread: &Thread) {
+    thread.sched.clear_ready(true);
+}
+
+/// A waker of the task of a thread, which makes the thread ready before waking the task
+struct ReadyWaker {
+    state: Arc<SchedState>,
+    waker: Waker,
+}
+
+/// Wrap `waker` of the task of `thread`
+pub fn ready_waker(thread: &Thread, waker: &Waker) -> Waker {
+    let waker = Arc::new(ReadyWaker {
+        state: thread.sched.clone(),
+        waker: waker.clone(),
+    });
+    unsafe { Waker::from_raw(RawWaker::new(Arc::into_raw(waker) as *const (), &VTABLE)) }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    let waker = ManuallyDrop::new(Arc::from_raw(data as *const ReadyWaker));
+    let cloned = Arc::clone(&waker);
+    RawWaker::new(Arc::into_raw(cloned) as *const (), &VTABLE)
+}
+
+unsafe fn wake(data: *const ()) {
+    wake_by_ref(data);
+    drop_waker(data);
+}
+
+unsafe fn wake_by_ref(data: *const ()) {
+    let waker = &*(data as *const ReadyWaker);
+    waker.state.set_ready();
+    waker.waker.wake_by_ref();
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(Arc::from_raw(data as *const ReadyWaker));
+}
@@ -0,0 +1,125 @@
+//! Syscall filtering, see `seccomp(2)`
+//!
+//! Each thread has a list of cBPF filters, inherited by its children.
+//! Every syscall is checked against all of them, and the most severe
+//! action wins.
+
+use crate::arch::syscall::{AUDIT_ARCH, SYS_EXIT, SYS_READ, SYS_RT_SIGRETURN, SYS_WRITE};
+use crate::util::bpf::{self, SockFilter, BPF_ABS, BPF_IND, BPF_LD, BPF_LDX, BPF_MSH, BPF_W};
+use alloc::{sync::Arc, vec::Vec};
+use core::mem::size_of;
+use core::slice;
+
+pub const SECCOMP_MODE_DISABLED: usize = 0;
+pub const SECCOMP_MODE_STRICT: usize = 1;
+pub const SECCOMP_MODE_FILTER: usize = 2;
+
+pub const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+pub const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+pub const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+pub const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+pub const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+pub const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+pub const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+pub const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+pub const SECCOMP_RET_ACTION_FULL: u32 = 0xffff_0000;
+pub const SECCOMP_RET_DATA: u32 = 0x0000_ffff;
+
+/// Linux struct seccomp_data, the input of filters
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct SeccompData {
+    pub nr: i32,
+    pub arch: u32,
+    pub instruction_pointer: u64,
+    pub args: [u64; 6],
+}
+
+impl SeccompData {
+    pub fn new(nr: usize, pc: usize, args: [usize; 6]) -> Self {
+        let mut data = SeccompData {
+            nr: nr as i32,
+            arch: AUDIT_ARCH,
+            instruction_pointer: pc as u64,
+            args: [0; 6],
+        };
+        for (arg, &value) in data.args.iter_mut().zip(args.iter()) {
+            *arg = value as u64;
+        }
+        data
+    }
+
+    /// Native endian word at `offset`, as seen by BPF_LD|BPF_W|BPF_ABS
+    fn load(&self, offset: u32, size: u32) -> Option<u32> {
+        let offset = offset as usize;
+        if size != 4 || offset % 4 != 0 || offset >= size_of::<Self>() {
+            return None;
+        }
+        let bytes =
+            unsafe { slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) };
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&bytes[offset..offset + 4]);
+        Some(u32::from_ne_bytes(word))
+    }
+}
+
+/// Seccomp state of a thread
+#[derive(Clone, Default)]
+pub struct Seccomp {
+    /// SECCOMP_MODE_STRICT, only read, write, exit and sigreturn are allowed
+    pub strict: bool,
+    /// Attached filters, shared with the children
+    pub filters: Vec<Arc<Vec<SockFilter>>>,
+}
+
+impl Seccomp {
+    pub fn mode(&self) -> usize {
+        if self.strict {
+            SECCOMP_MODE_STRICT
+        } else if !self.filters.is_empty() {
+            SECCOMP_MODE_FILTER
+        } else {
+            SECCOMP_MODE_DISABLED
+        }
+    }
+
+    /// The SECCOMP_RET_* action for a syscall
+    pub fn check(&self, data: &SeccompData) -> u32 {
+        if self.strict {
+            return match data.nr as usize {
+                SYS_READ | SYS_WRITE | SYS_EXIT | SYS_RT_SIGRETURN => SECCOMP_RET_ALLOW,
+                _ => SECCOMP_RET_KILL_THREAD,
+            };
+        }
+        let mut ret = SECCOMP_RET_ALLOW;
+        for filter in self.filters.iter() {
+            let cur = bpf::run(filter, size_of::<SeccompData>() as u32, |offset, size| {
+                data.load(offset, size)
+            });
+            // lower action values are more severe
+            if ((cur & SECCOMP_RET_ACTION_FULL) as i32) < ((ret & SECCOMP_RET_ACTION_FULL) as i32) {
+                ret = cur;
+            }
+        }
+        ret
+    }
+}
+
+/// Check a filter program on top of `bpf::check`:
+/// seccomp_data can only be loaded by aligned words.
+pub fn check_filter(prog: &[SockFilter]) -> bool {
+    bpf::check(prog)
+        && prog
+            .iter()
+            .all(|ins| match (bpf::class(ins.code), bpf::mode(ins.code)) {
+                (BPF_LD, BPF_ABS) => {
+                    bpf::size(ins.code) == BPF_W
+                        && ins.k % 4 == 0
+                        && (ins.k as usize) < size_of::<SeccompData>()
+                }
+                // there is no packet to index into
+                (BPF_LD, BPF_IND) | (BPF_LDX, BPF_MSH) => false,
+                _ => true,
+            })
+}
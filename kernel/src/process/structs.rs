@@ -26,7 +26,7 @@ use core::{
 use log::*;
 use pc_keyboard::KeyCode::BackTick;
 use rcore_fs::vfs::INode;
-use rcore_memory::{Page, PAGE_SIZE};
+use rcore_memory::{Page, VMResult, PAGE_SIZE};
 use spin::RwLock;
 use trapframe::TrapFrame;
 use trapframe::UserContext;
@@ -55,8 +55,17 @@ impl ToMemoryAttr for Flags {
 
 /// Helper functions to process ELF file
 pub trait ElfExt {
-    /// Setup MemorySet according to the ELF file.
-    fn make_memory_set(&self, ms: &mut MemorySet, inode: &Arc<dyn INode>) -> usize;
+    /// Setup MemorySet according to the ELF file, loaded at `bias`.
+    /// Return the first page after the loaded segments.
+    fn make_memory_set(
+        &self,
+        ms: &mut MemorySet,
+        inode: &Arc<dyn INode>,
+        bias: usize,
+    ) -> VMResult<usize>;
+
+    /// Bytes of the pages the loaded segments take, and of the writable ones
+    fn memory_size(&self) -> (usize, usize);
 
     /// Get interpreter string if it has.
     fn get_interpreter(&self) -> Result<&str, &str>;
@@ -68,41 +77,120 @@ pub trait ElfExt {
         inode: &Arc<dyn INode>,
         memory_set: &mut MemorySet,
         bias: usize,
-    );
+    ) -> VMResult<()>;
 
     /// Get virtual address of PHDR section if it has.
     fn get_phdr_vaddr(&self) -> Option<u64>;
+
+    /// Whether the stack should be executable according to PT_GNU_STACK.
+    /// Without PT_GNU_STACK, the stack is executable as Linux does.
+    fn stack_executable(&self) -> bool;
+}
+
+/// `p_type` of PT_GNU_STACK, which is OS specific
+const PT_GNU_STACK: u32 = 0x6474_e551;
+
+/// The most bytes of headers to read from an ELF file
+const MAX_ELF_HEADER_SIZE: usize = 0x10000;
+
+/// Read the beginning of the ELF file at `inode`,
+/// which covers the ELF header, the program headers and the interpreter path.
+pub fn read_elf_header(inode: &Arc<dyn INode>) -> Result<Vec<u8>, &'static str> {
+    // 0x3c0: magic number from ld-musl.so, enough for most files
+    let mut size = 0x3c0;
+    loop {
+        let mut data = vec![0u8; size];
+        let len = inode
+            .read_at(0, &mut data)
+            .map_err(|_| "failed to read from INode")?;
+        let needed = {
+            let elf = ElfFile::new(&data)?;
+            let pt2 = &elf.header.pt2;
+            let ph_end =
+                pt2.ph_offset() as usize + pt2.ph_count() as usize * pt2.ph_entry_size() as usize;
+            if ph_end > size {
+                ph_end
+            } else {
+                // PT_INTERP is not always next to the program headers
+                elf.program_iter()
+                    .filter(|ph| ph.get_type() == Ok(Type::Interp))
+                    .map(|ph| (ph.offset() + ph.file_size()) as usize)
+                    .fold(ph_end, usize::max)
+            }
+        };
+        if needed <= size {
+            return Ok(data);
+        }
+        if len < size {
+            return Err("ELF headers are truncated");
+        }
+        if needed > MAX_ELF_HEADER_SIZE {
+            return Err("ELF headers are too large");
+        }
+        size = needed;
+    }
 }
 
 impl ElfExt for ElfFile<'_> {
-    fn make_memory_set(&self, ms: &mut MemorySet, inode: &Arc<dyn INode>) -> usize {
-        debug!("creating MemorySet from ELF");
+    fn make_memory_set(
+        &self,
+        ms: &mut MemorySet,
+        inode: &Arc<dyn INode>,
+        bias: usize,
+    ) -> VMResult<usize> {
+        debug!("creating MemorySet from ELF, bias={:#x}", bias);
         let mut farthest_memory: usize = 0;
         for ph in self.program_iter() {
+            // PT_TLS is covered by a PT_LOAD segment,
+            // libc finds the TLS image through AT_PHDR.
             if ph.get_type() != Ok(Type::Load) {
                 continue;
             }
+            let start = ph.virtual_addr() as usize + bias;
+            let end = start + ph.mem_size() as usize;
             ms.push(
-                ph.virtual_addr() as usize,
-                ph.virtual_addr() as usize + ph.mem_size() as usize,
+                start,
+                end,
                 ph.flags().to_attr(),
                 File {
                     file: INodeForMap(inode.clone()),
-                    mem_start: ph.virtual_addr() as usize,
+                    mem_start: start,
                     file_start: ph.offset() as usize,
                     file_end: ph.offset() as usize + ph.file_size() as usize,
                     allocator: GlobalFrameAlloc,
                 },
                 "elf",
-            );
-            if ph.virtual_addr() as usize + ph.mem_size() as usize > farthest_memory {
-                farthest_memory = ph.virtual_addr() as usize + ph.mem_size() as usize;
+            )?;
+            if end > farthest_memory {
+                farthest_memory = end;
             }
         }
 
-        Page::of_addr(farthest_memory + PAGE_SIZE).start_address()
+        Ok(Page::of_addr(farthest_memory + PAGE_SIZE).start_address())
+    }
+    fn memory_size(&self) -> (usize, usize) {
+        let mut total = 0;
+        let mut writable = 0;
+        for ph in self.program_iter() {
+            if ph.get_type() != Ok(Type::Load) {
+                continue;
+            }
+            let start = ph.virtual_addr() as usize & !(PAGE_SIZE - 1);
+            let end = (ph.virtual_addr() + ph.mem_size()) as usize;
+            let size = (end - start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            total += size;
+            if ph.flags().is_write() {
+                writable += size;
+            }
+        }
+        (total, writable)
     }
-    fn append_as_interpreter(&self, inode: &Arc<dyn INode>, ms: &mut MemorySet, bias: usize) {
+    fn append_as_interpreter(
+        &self,
+        inode: &Arc<dyn INode>,
+        ms: &mut MemorySet,
+        bias: usize,
+    ) -> VMResult<()> {
         debug!("inserting interpreter from ELF");
 
         for ph in self.program_iter() {
@@ -121,8 +209,9 @@ impl ElfExt for ElfFile<'_> {
                     allocator: GlobalFrameAlloc,
                 },
                 "elf-interp",
-            )
+            )?;
         }
+        Ok(())
     }
     fn get_interpreter(&self) -> Result<&str, &str> {
         let header = self
@@ -160,6 +249,12 @@ impl ElfExt for ElfFile<'_> {
             None
         }
     }
+
+    fn stack_executable(&self) -> bool {
+        self.program_iter()
+            .find(|ph| ph.get_type() == Ok(Type::OsSpecific(PT_GNU_STACK)))
+            .map_or(true, |ph| ph.flags().is_execute())
+    }
 }
 
 #[derive(Clone)]
@@ -169,4 +264,8 @@ impl Read for INodeForMap {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
         self.0.read_at(offset, buf).unwrap()
     }
+
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.0) as *const u8 as usize
+    }
 }
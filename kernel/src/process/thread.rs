@@ -1,9 +1,18 @@
 use super::{
     abi::{self, ProcInitInfo},
-    add_to_process_table, Pid, Process, PROCESSORS,
+    add_to_process_table,
+    aslr::{self, AddrLayout},
+    coredump,
+    futex::{self, Futex},
+    namespace::Namespaces,
+    ptrace::PtraceState,
+    rlimit::{self, RLIMIT_STACK},
+    sched::{self, SchedState},
+    seccomp::Seccomp,
+    Pid, Process, PROCESSORS,
 };
 use crate::arch::interrupt::consts::{
-    is_intr, is_page_fault, is_reserved_inst, is_syscall, is_timer_intr,
+    is_breakpoint, is_intr, is_page_fault, is_reserved_inst, is_syscall, is_timer_intr,
 };
 use crate::arch::interrupt::{get_trap_num, handle_reserved_inst};
 use crate::arch::{
@@ -18,11 +27,14 @@ use crate::ipc::{SemProc, ShmProc};
 use crate::memory::{
     phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemoryAttr, MemorySet, Read,
 };
-use crate::process::structs::ElfExt;
+use crate::process::structs::{read_elf_header, ElfExt};
 use crate::sync::{EventBus, SpinLock, SpinNoIrqLock as Mutex};
 use crate::{
-    signal::{handle_signal, Siginfo, Signal, SignalAction, SignalStack, Sigset},
-    syscall::handle_syscall,
+    signal::{
+        handle_signal, send_signal, Siginfo, Signal, SignalAction, SignalStack, Sigset, SIG_DFL,
+        SIG_IGN, SI_KERNEL,
+    },
+    syscall::{handle_syscall, SysError},
 };
 use alloc::{
     boxed::Box, collections::BTreeMap, collections::VecDeque, string::String, sync::Arc,
@@ -33,9 +45,9 @@ use core::fmt;
 use core::str;
 use core::{
     future::Future,
-    mem::MaybeUninit,
+    mem::size_of,
     pin::Pin,
-    task::{Context, Poll},
+    task::{Context, Poll, Waker},
 };
 use log::*;
 use num::FromPrimitive;
@@ -54,6 +66,18 @@ use xmas_elf::{
 /// Tid type
 pub type Tid = usize;
 
+/// User address space built by `Thread::new_user_vm`
+pub struct UserVmInfo {
+    /// Entry point
+    pub entry_addr: usize,
+    /// Initial stack pointer
+    pub ustack_top: usize,
+    /// Lowest address to search for free area in mmap
+    pub mmap_base: usize,
+    /// Auxiliary vector passed to the program
+    pub auxv: BTreeMap<u8, usize>,
+}
+
 pub struct ThreadContext {
     user: Box<UserContext>,
     /// TODO: lazy fp
@@ -69,10 +93,31 @@ pub struct ThreadInner {
     /// Kernel performs futex wake when thread exits.
     /// Ref: [http://man7.org/linux/man-pages/man2/set_tid_address.2.html]
     pub clear_child_tid: usize,
+    /// Head of the robust futex list, released when thread exits.
+    /// Ref: [http://man7.org/linux/man-pages/man2/set_robust_list.2.html]
+    pub robust_list: usize,
+    /// Scheduling policy
+    pub sched_policy: usize,
+    /// Static priority of SCHED_FIFO and SCHED_RR, 0 for others
+    pub rt_priority: usize,
+    /// Priority inherited from waiters of PI futexes it owns
+    pub pi_boost: usize,
+    /// PI futexes it owns with waiters
+    pub pi_futexes: Vec<Arc<Futex>>,
+    /// PI futex it waits to lock
+    pub pi_blocked_on: Option<Arc<Futex>>,
     /// Signal mask
     pub sig_mask: Sigset,
     /// signal alternate stack
     pub signal_alternate_stack: SignalStack,
+    /// Tracing state, None when not traced
+    pub ptrace: Option<PtraceState>,
+    /// Syscall filters
+    pub seccomp: Seccomp,
+    /// Set by PR_SET_NO_NEW_PRIVS, never cleared
+    pub no_new_privs: bool,
+    /// Running user code, which a core dump waits to trap
+    pub in_user: bool,
 }
 
 #[allow(dead_code)]
@@ -85,6 +130,8 @@ pub struct Thread {
     pub proc: Arc<Mutex<Process>>,
     /// Thread id
     pub tid: Tid,
+    /// Scheduling state, shared with the wakers of its task
+    pub sched: Arc<SchedState>,
 }
 
 lazy_static! {
@@ -112,29 +159,36 @@ impl Thread {
     }
 
     /// Construct virtual memory of a new user process from ELF at `inode`.
-    /// Return `(MemorySet, entry_point, ustack_top)`
+    /// The layout is randomized unless `personality` has `ADDR_NO_RANDOMIZE`.
+    /// The interpreter is looked up from the root directory `root`.
+    /// The stack is at most `stack_limit` bytes, RLIMIT_STACK.
+    /// `vm` is left as it is if the program can not be loaded,
+    /// or the new image would exceed the limits of `vm`.
     pub fn new_user_vm(
         inode: &Arc<dyn INode>,
+        root: &Arc<dyn INode>,
+        exec_path: &str,
         args: Vec<String>,
         envs: Vec<String>,
         vm: &mut MemorySet,
-    ) -> Result<(usize, usize), &'static str> {
+        personality: usize,
+        stack_limit: u64,
+    ) -> Result<UserVmInfo, SysError> {
+        use crate::consts::{USER_STACK_OFFSET, USER_STACK_SIZE};
+
         // Read ELF header
-        // 0x3c0: magic number from ld-musl.so
-        let mut data = [0u8; 0x3c0];
-        inode
-            .read_at(0, &mut data)
-            .map_err(|_| "failed to read from INode")?;
+        let data = read_elf_header(inode).map_err(|_| SysError::ENOEXEC)?;
 
         // Parse ELF
-        let elf = ElfFile::new(&data)?;
+        let elf = ElfFile::new(&data).map_err(|_| SysError::ENOEXEC)?;
 
         // Check ELF type
-        match elf.header.pt2.type_().as_type() {
-            header::Type::Executable => {}
-            header::Type::SharedObject => {}
-            _ => return Err("ELF is not executable or shared object"),
-        }
+        let is_pie = match elf.header.pt2.type_().as_type() {
+            header::Type::Executable => false,
+            header::Type::SharedObject => true,
+            // not executable or shared object
+            _ => return Err(SysError::ENOEXEC),
+        };
 
         // Check ELF arch
         match elf.header.pt2.machine().as_machine() {
@@ -146,85 +200,160 @@ impl Thread {
             header::Machine::Other(243) => {}
             #[cfg(target_arch = "mips")]
             header::Machine::Mips => {}
-            _ => return Err("invalid ELF arch"),
+            // invalid ELF arch
+            _ => return Err(SysError::ENOEXEC),
         }
 
+        let layout = AddrLayout::new(personality);
+        // PIE is loaded at a random bias, or at its link address when not randomized
+        let elf_bias = if is_pie && layout.randomized {
+            layout.pie_bias
+        } else {
+            0
+        };
+
         // auxiliary vector
         let mut auxv = {
             let mut map = BTreeMap::new();
             if let Some(phdr_vaddr) = elf.get_phdr_vaddr() {
-                map.insert(abi::AT_PHDR, phdr_vaddr as usize);
+                map.insert(abi::AT_PHDR, phdr_vaddr as usize + elf_bias);
             }
             map.insert(abi::AT_PHENT, elf.header.pt2.ph_entry_size() as usize);
             map.insert(abi::AT_PHNUM, elf.header.pt2.ph_count() as usize);
             map.insert(abi::AT_PAGESZ, PAGE_SIZE);
+            map.insert(abi::AT_HWCAP, abi::hwcap());
+            map.insert(abi::AT_CLKTCK, abi::CLOCKS_PER_SEC);
+            // there is no user management, everyone is root
+            map.insert(abi::AT_UID, 0);
+            map.insert(abi::AT_EUID, 0);
+            map.insert(abi::AT_GID, 0);
+            map.insert(abi::AT_EGID, 0);
+            map.insert(abi::AT_SECURE, 0);
             map
         };
 
+        // Check interpreter (for dynamic link), before the old image is gone
+        let interp_file = match elf.get_interpreter() {
+            Ok(loader_path) => {
+                // assuming absolute path
+                let interp_inode =
+                    root.lookup_follow(loader_path.trim_start_matches('/'), FOLLOW_MAX_DEPTH)?;
+                let interp_data = read_elf_header(&interp_inode).map_err(|_| SysError::ENOEXEC)?;
+                Some((interp_inode, interp_data))
+            }
+            Err(_) => None,
+        };
+        let interp = match &interp_file {
+            Some((interp_inode, interp_data)) => {
+                let elf_interp = ElfFile::new(interp_data).map_err(|_| SysError::ENOEXEC)?;
+                Some((interp_inode, elf_interp))
+            }
+            None => None,
+        };
+
+        // leave room for the eagerly mapped pages below
+        let ustack_size = (rlimit::limit_to_usize(stack_limit).min(USER_STACK_SIZE)
+            & !(PAGE_SIZE - 1))
+            .max(PAGE_SIZE * 8);
+        // arguments and environment take at most a quarter of the stack, as on Linux
+        let strings_size: usize = args
+            .iter()
+            .chain(envs.iter())
+            .map(|string| string.len() + 1 + size_of::<usize>())
+            .sum();
+        if strings_size > ustack_size / 4 {
+            return Err(SysError::E2BIG);
+        }
+
+        // the new image takes the place of the whole old one
+        let (mut image_size, mut image_data) = elf.memory_size();
+        if let Some((_, elf_interp)) = &interp {
+            let (size, data) = elf_interp.memory_size();
+            image_size += size;
+            image_data += data;
+        }
+        vm.check_limits(image_size + ustack_size, image_data, 0..usize::max_value())?;
+
         // entry point
-        let mut entry_addr = elf.header.pt2.entry_point() as usize;
+        let mut entry_addr = elf.header.pt2.entry_point() as usize + elf_bias;
         // Make page table
         vm.clear();
-        let bias = elf.make_memory_set(vm, inode);
+        let elf_end = elf.make_memory_set(vm, inode, elf_bias)?;
 
-        // Check interpreter (for dynamic link)
         // When interpreter is used, map both dynamic linker and executable
-        if let Ok(loader_path) = elf.get_interpreter() {
+        if let Some((interp_inode, elf_interp)) = interp {
+            let bias = elf_end + layout.interp_offset;
             info!("Handling interpreter... offset={:x}", bias);
-            // assuming absolute path
-            let interp_inode = crate::fs::ROOT_INODE
-                .lookup_follow(loader_path, FOLLOW_MAX_DEPTH)
-                .map_err(|_| "interpreter not found")?;
             // load loader by bias and set aux vector.
-            let mut interp_data: [u8; 0x3c0] = unsafe { MaybeUninit::zeroed().assume_init() };
-            interp_inode
-                .read_at(0, &mut interp_data)
-                .map_err(|_| "failed to read from INode")?;
-            let elf_interp = ElfFile::new(&interp_data)?;
-            elf_interp.append_as_interpreter(&interp_inode, vm, bias);
+            elf_interp.append_as_interpreter(interp_inode, vm, bias)?;
 
             // update auxiliary vector
-            auxv.insert(abi::AT_ENTRY, elf.header.pt2.entry_point() as usize);
+            auxv.insert(abi::AT_ENTRY, entry_addr);
             auxv.insert(abi::AT_BASE, bias);
 
             // use interpreter as actual entry point
-            debug!("entry point: {:x}", elf.header.pt2.entry_point() as usize);
+            debug!("entry point: {:x}", entry_addr);
             entry_addr = elf_interp.header.pt2.entry_point() as usize + bias;
         }
 
-        // User stack
-        use crate::consts::{USER_STACK_OFFSET, USER_STACK_SIZE};
+        // mmap starts searching above the executable when randomized
+        let mmap_base = if layout.randomized {
+            elf_end + layout.mmap_offset
+        } else {
+            PAGE_SIZE
+        };
+
+        // User stack, executable only if PT_GNU_STACK asks for it
+        let ustack_attr = if elf.stack_executable() {
+            MemoryAttr::default().user().execute()
+        } else {
+            MemoryAttr::default().user()
+        };
         let mut ustack_top = {
-            let ustack_buttom = USER_STACK_OFFSET;
-            let ustack_top = USER_STACK_OFFSET + USER_STACK_SIZE;
+            let ustack_top =
+                USER_STACK_OFFSET + USER_STACK_SIZE - (layout.stack_offset & !(PAGE_SIZE - 1));
+            let ustack_buttom = ustack_top - ustack_size;
 
             // user stack except top 4 pages
             vm.push(
                 ustack_buttom,
                 ustack_top - PAGE_SIZE * 4,
-                MemoryAttr::default().user().execute(),
+                ustack_attr,
                 Delay::new(GlobalFrameAlloc),
                 "user_stack_delay",
-            );
+            )?;
 
             // We are going to write init info now. So map the last 4 pages eagerly.
             vm.push(
                 ustack_top - PAGE_SIZE * 4,
                 ustack_top,
-                MemoryAttr::default().user().execute(), // feature
+                ustack_attr,
                 ByFrame::new(GlobalFrameAlloc),
                 "user_stack",
-            );
-            ustack_top
+            )?;
+            ustack_top - layout.stack_offset % PAGE_SIZE
         };
 
         // Make init info
-        let init_info = ProcInitInfo { args, envs, auxv };
+        let mut random = [0u8; 16];
+        aslr::random_bytes(&mut random);
+        let mut init_info = ProcInitInfo {
+            execfn: String::from(exec_path),
+            args,
+            envs,
+            auxv,
+            random,
+        };
         unsafe {
             vm.with(|| ustack_top = init_info.push_at(ustack_top));
         }
 
-        Ok((entry_addr, ustack_top))
+        Ok(UserVmInfo {
+            entry_addr,
+            ustack_top,
+            mmap_base,
+            auxv: init_info.auxv,
+        })
     }
 
     /// Make a new user process from ELF `data`
@@ -236,7 +365,11 @@ impl Thread {
     ) -> Arc<Thread> {
         // get virtual memory info
         let mut vm = MemorySet::new();
-        let (entry_addr, ustack_top) = Self::new_user_vm(inode, args, envs, &mut vm).unwrap();
+        let root = crate::fs::ROOT_INODE.clone();
+        let rlimits = rlimit::default_rlimits();
+        let stack_limit = rlimits[RLIMIT_STACK].cur;
+        let info = Self::new_user_vm(inode, &root, exec_path, args, envs, &mut vm, 0, stack_limit)
+            .unwrap();
 
         let vm_token = vm.token();
         let vm = Arc::new(Mutex::new(vm));
@@ -291,8 +424,8 @@ impl Thread {
 
         // user context
         let mut context = UserContext::default();
-        context.set_ip(entry_addr);
-        context.set_sp(ustack_top);
+        context.set_ip(info.entry_addr);
+        context.set_sp(info.ustack_top);
 
         // arch specific
         #[cfg(target_arch = "x86_64")]
@@ -327,14 +460,26 @@ impl Thread {
                     fp: Box::new(FpState::new()),
                 }),
                 clear_child_tid: 0,
+                robust_list: 0,
+                sched_policy: 0,
+                rt_priority: 0,
+                pi_boost: 0,
+                pi_futexes: Vec::new(),
+                pi_blocked_on: None,
                 sig_mask: Sigset::default(),
                 signal_alternate_stack: SignalStack::default(),
+                ptrace: None,
+                seccomp: Seccomp::default(),
+                no_new_privs: false,
+                in_user: false,
             }),
             vm: vm.clone(),
             proc: Arc::new(Mutex::new(Process {
                 vm,
                 files,
                 cwd: String::from("/"),
+                root: String::from("/"),
+                ns: Namespaces::root(),
                 exec_path: String::from(exec_path),
                 futexes: BTreeMap::default(),
                 semaphores: SemProc::default(),
@@ -349,7 +494,16 @@ impl Thread {
                 dispositions: [SignalAction::default(); Signal::RTMAX + 1],
                 eventbus: EventBus::new(),
                 shm_identifiers: ShmProc::default(),
+                personality: 0,
+                mmap_base: info.mmap_base,
+                saved_auxv: info.auxv,
+                rlimits,
+                cpu_ticks: 0,
+                stop_signal: 0,
+                stop_reported: false,
+                core_state: None,
             })),
+            sched: Arc::new(SchedState::new(0)),
         };
 
         let res = thread.add_to_table();
@@ -360,9 +514,9 @@ impl Thread {
         res
     }
 
-    /// Fork a new process from current one
+    /// Fork a new process from current one, in namespaces `ns`
     /// Only current process is persisted
-    pub fn fork(&self, tf: &UserContext) -> Arc<Thread> {
+    pub fn fork(&self, tf: &UserContext, ns: Namespaces) -> Arc<Thread> {
         // clone virtual memory
         let vm = self.vm.lock().clone();
         let vm_token = vm.token();
@@ -378,6 +532,8 @@ impl Thread {
             vm: vm.clone(),
             files: proc.files.clone(), // share open file descriptions
             cwd: proc.cwd.clone(),
+            root: proc.root.clone(),
+            ns,
             exec_path: proc.exec_path.clone(),
             futexes: BTreeMap::default(),
             semaphores: proc.semaphores.clone(),
@@ -392,6 +548,14 @@ impl Thread {
             dispositions: proc.dispositions.clone(),
             eventbus: EventBus::new(),
             shm_identifiers: proc.shm_identifiers.clone(),
+            personality: proc.personality,
+            mmap_base: proc.mmap_base,
+            saved_auxv: proc.saved_auxv.clone(),
+            rlimits: proc.rlimits,
+            cpu_ticks: 0,
+            stop_signal: 0,
+            stop_reported: false,
+            core_state: None,
         }));
 
         // new thread
@@ -401,6 +565,12 @@ impl Thread {
         // mask; the signal mask is preserved across execve(2).
         let sig_mask = self.inner.lock().sig_mask;
         let sigaltstack = self.inner.lock().signal_alternate_stack;
+        let seccomp = self.inner.lock().seccomp.clone();
+        let no_new_privs = self.inner.lock().no_new_privs;
+        let (sched_policy, rt_priority) = {
+            let inner = self.inner.lock();
+            (inner.sched_policy, inner.rt_priority)
+        };
         let new_thread = Thread {
             tid: 0, // allocated below
             inner: Mutex::new(ThreadInner {
@@ -409,18 +579,32 @@ impl Thread {
                     fp: Box::new(FpState::new()),
                 }),
                 clear_child_tid: 0,
+                robust_list: 0,
+                sched_policy,
+                rt_priority,
+                pi_boost: 0,
+                pi_futexes: Vec::new(),
+                pi_blocked_on: None,
                 sig_mask,
                 signal_alternate_stack: sigaltstack,
+                ptrace: None,
+                seccomp,
+                no_new_privs,
+                in_user: false,
             }),
             vm,
             proc: new_proc,
+            sched: Arc::new(SchedState::new(rt_priority)),
         }
         .add_to_table();
 
         // link thread and process
         let child_pid = Pid(new_thread.tid);
         add_to_process_table(new_thread.proc.clone(), Pid(new_thread.tid));
-        new_thread.proc.lock().threads.push(new_thread.tid);
+        let mut new_proc = new_thread.proc.lock();
+        new_proc.threads.push(new_thread.tid);
+        new_proc.ns.pid.attach(new_thread.tid);
+        drop(new_proc);
 
         // link to parent
         proc.children
@@ -449,19 +633,39 @@ impl Thread {
 
         let sig_mask = self.inner.lock().sig_mask;
         let sigaltstack = self.inner.lock().signal_alternate_stack;
+        let seccomp = self.inner.lock().seccomp.clone();
+        let no_new_privs = self.inner.lock().no_new_privs;
+        let (sched_policy, rt_priority) = {
+            let inner = self.inner.lock();
+            (inner.sched_policy, inner.rt_priority)
+        };
         let thread = Thread {
             tid: 0,
             inner: Mutex::new(ThreadInner {
                 clear_child_tid,
+                robust_list: 0,
+                sched_policy,
+                rt_priority,
+                pi_boost: 0,
+                pi_futexes: Vec::new(),
+                pi_blocked_on: None,
                 context: Some(thread_context),
                 sig_mask,
                 signal_alternate_stack: sigaltstack,
+                ptrace: None,
+                seccomp,
+                no_new_privs,
+                in_user: false,
             }),
             vm: self.vm.clone(),
             proc: self.proc.clone(),
+            sched: Arc::new(SchedState::new(rt_priority)),
         };
         let res = thread.add_to_table();
-        res.proc.lock().threads.push(res.tid);
+        let mut proc = res.proc.lock();
+        proc.threads.push(res.tid);
+        proc.ns.pid.attach(res.tid);
+        drop(proc);
         res
     }
 
@@ -473,6 +677,34 @@ impl Thread {
         self.inner.lock().context = Some(cx);
     }
 
+    /// Copy of the saved user context, None when running in user
+    pub fn user_context(&self) -> Option<UserContext> {
+        self.inner
+            .lock()
+            .context
+            .as_ref()
+            .map(|cx| cx.user.as_ref().clone())
+    }
+
+    /// Priority with the inherited one, for PI futexes
+    pub fn pi_priority(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.rt_priority.max(inner.pi_boost)
+    }
+
+    /// Whether it is boosted by waiters of PI futexes it owns
+    pub fn pi_boosted(&self) -> bool {
+        let inner = self.inner.lock();
+        inner.pi_boost > inner.rt_priority
+    }
+
+    /// SIGKILL is pending for this thread
+    pub fn has_kill_signal(&self) -> bool {
+        self.proc.lock().sig_queue.iter().any(|(info, tid)| {
+            (*tid == -1 || *tid as usize == self.tid) && info.signo == Signal::SIGKILL as i32
+        })
+    }
+
     /// this thread has signal to handle
     pub fn has_signal_to_handle(&self) -> bool {
         self.proc
@@ -497,13 +729,36 @@ pub fn spawn(thread: Arc<Thread>) {
     let vmtoken = thread.vm.lock().token();
     let temp = thread.clone();
     let future = async move {
+        // a new tracee stops for its initial SIGSTOP before running any user code
+        if thread.is_traced() {
+            let mut thread_context = thread.begin_running();
+            let exit = handle_signal(&thread, &mut thread_context.user).await;
+            thread.end_running(thread_context);
+            if exit {
+                info!("thread {} stopped", thread.tid);
+                return;
+            }
+        }
         loop {
+            // threads of higher priorities run user code first
+            sched::wait_turn(&thread).await;
             let mut thread_context = thread.begin_running();
             let cx = &mut thread_context.user;
 
+            // a core dump of the process stops its threads before they run user code
+            if !coredump::enter_user(&thread, cx).await {
+                thread.end_running(thread_context);
+                info!("thread {} stopped for a core dump", thread.tid);
+                futex::exit_futexes(&thread);
+                break;
+            }
+
             trace!("go to user: {:#x?}", cx);
             thread_context.fp.restore();
+            #[cfg(target_arch = "aarch64")]
+            crate::arch::signal::arm_single_step(cx);
             cx.run();
+            thread.inner.lock().in_user = false;
             thread_context.fp.save();
             let trap_num = get_trap_num(&cx);
             trace!("back from user: {:#x?} trap_num {:#x}", cx, trap_num);
@@ -534,16 +789,14 @@ pub fn spawn(thread: Arc<Thread>) {
                             _ => unreachable!(),
                         };
                         if !handle_user_page_fault_ext(&thread, addr, access_type) {
-                            // TODO: SIGSEGV
-                            panic!("page fault handle failed");
+                            send_sigsegv(&thread, addr);
                         }
                     }
                     #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
                     {
                         use crate::arch::interrupt::handle_user_page_fault;
                         if !handle_user_page_fault(&thread, addr) {
-                            // TODO: SIGSEGV
-                            panic!("page fault handle failed");
+                            send_sigsegv(&thread, addr);
                         }
                     }
                 }
@@ -552,11 +805,25 @@ pub fn spawn(thread: Arc<Thread>) {
                     crate::arch::interrupt::ack(trap_num);
                     trace!("handle irq {:#x}", trap_num);
                     if is_timer_intr(trap_num) {
-                        do_yield = true;
+                        do_yield = sched::should_yield(&thread);
                         crate::arch::interrupt::timer();
+                        rlimit::charge_cpu_tick(&thread);
                     }
                     IRQ_MANAGER.read().try_handle_interrupt(Some(trap_num));
                 }
+                _ if is_breakpoint(trap_num) => {
+                    info!("breakpoint in thread {}", thread.tid);
+                    send_signal(
+                        thread.proc.clone(),
+                        thread.tid as isize,
+                        Siginfo {
+                            signo: Signal::SIGTRAP as i32,
+                            errno: 0,
+                            code: SI_KERNEL,
+                            field: Default::default(),
+                        },
+                    );
+                }
                 _ if is_reserved_inst(trap_num) => {
                     if !handle_reserved_inst(cx) {
                         panic!(
@@ -575,12 +842,13 @@ pub fn spawn(thread: Arc<Thread>) {
 
             // check signals
             if !exit {
-                exit = handle_signal(&thread, cx);
+                exit = handle_signal(&thread, cx).await;
             }
 
             thread.end_running(thread_context);
             if exit {
                 info!("thread {} stopped", thread.tid);
+                futex::exit_futexes(&thread);
                 break;
             } else if do_yield {
                 yield_now().await;
@@ -591,15 +859,49 @@ pub fn spawn(thread: Arc<Thread>) {
     spawn_thread(Box::pin(future), vmtoken, temp);
 }
 
+/// Deliver SIGSEGV to the thread on an unresolved page fault
+fn send_sigsegv(thread: &Arc<Thread>, addr: usize) {
+    warn!(
+        "thread {} segmentation fault @ {:#x}, sending SIGSEGV",
+        thread.tid, addr
+    );
+    force_signal(
+        thread,
+        Siginfo {
+            signo: Signal::SIGSEGV as i32,
+            errno: 0,
+            code: SI_KERNEL,
+            field: Default::default(),
+        },
+    );
+}
+
+/// Deliver a signal caused by the thread itself.
+/// Like `force_sig` of Linux, it can be neither blocked nor ignored.
+pub fn force_signal(thread: &Arc<Thread>, info: Siginfo) {
+    let signal: Signal = <Signal as FromPrimitive>::from_i32(info.signo).unwrap();
+    thread.inner.lock().sig_mask.remove(signal);
+    {
+        let mut proc = thread.proc.lock();
+        let action = &mut proc.dispositions[signal as usize];
+        if action.handler == SIG_IGN {
+            action.handler = SIG_DFL;
+        }
+    }
+    send_signal(thread.proc.clone(), thread.tid as isize, info);
+}
+
 fn spawn_thread(
     future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
     vmtoken: usize,
     thread: Arc<Thread>,
 ) {
+    sched::set_spawned(&thread);
     executor::spawn(PageTableSwitchWrapper {
         inner: Mutex::new(future),
         vmtoken,
         thread,
+        waker: Mutex::new(None),
     });
 }
 
@@ -608,6 +910,8 @@ struct PageTableSwitchWrapper {
     inner: Mutex<Pin<Box<dyn Future<Output = ()> + Send>>>,
     vmtoken: usize,
     thread: Arc<Thread>,
+    /// Waker of the executor, and the one wrapping it by `sched::ready_waker`
+    waker: Mutex<Option<(Waker, Waker)>>,
 }
 
 impl Future for PageTableSwitchWrapper {
@@ -621,7 +925,29 @@ impl Future for PageTableSwitchWrapper {
         }
         // vmtoken won't change
         set_page_table(self.vmtoken);
-        let res = self.inner.lock().as_mut().poll(cx);
+        // wake the task through a waker making the thread ready
+        sched::set_polled(&self.thread);
+        let waker = {
+            let mut cached = self.waker.lock();
+            match &*cached {
+                Some((waker, ready_waker)) if waker.will_wake(cx.waker()) => ready_waker.clone(),
+                _ => {
+                    let ready_waker = sched::ready_waker(&self.thread, cx.waker());
+                    *cached = Some((cx.waker().clone(), ready_waker.clone()));
+                    ready_waker
+                }
+            }
+        };
+        let res = self
+            .inner
+            .lock()
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker));
+        if res.is_ready() {
+            sched::set_exited(&self.thread);
+            // the waker of the executor refers to this task
+            *self.waker.lock() = None;
+        }
         unsafe {
             PROCESSORS[cpu_id] = None;
         }
@@ -4,7 +4,7 @@ use alloc::sync::Arc;
 use spin::Mutex;
 
 use rcore_memory::{memory_set::MemoryAttr, PAGE_SIZE};
-use rvm::{DefaultGuestPhysMemorySet, GuestPhysAddr, HostVirtAddr, RvmResult};
+use rvm::{DefaultGuestPhysMemorySet, GuestPhysAddr, HostVirtAddr, RvmError, RvmResult};
 use rvm::{Guest as GuestInner, Vcpu as VcpuInner};
 
 use super::memory::RvmPageTableHandlerDelay;
@@ -38,13 +38,17 @@ impl Guest {
         let hvaddr = thread.vm.lock().find_free_area(PAGE_SIZE, size);
         let handler =
             RvmPageTableHandlerDelay::new(gpaddr, hvaddr, self.gpm.clone(), GlobalFrameAlloc);
-        thread.vm.lock().push(
-            hvaddr,
-            hvaddr + size,
-            MemoryAttr::default().user().writable(),
-            handler,
-            "rvm_guest_physical",
-        );
+        thread
+            .vm
+            .lock()
+            .push(
+                hvaddr,
+                hvaddr + size,
+                MemoryAttr::default().user().writable(),
+                handler,
+                "rvm_guest_physical",
+            )
+            .map_err(|_| RvmError::NoMemory)?;
         Ok(hvaddr)
     }
 }
@@ -7,6 +7,17 @@ pub const SIG_ERR: usize = usize::max_value() - 1;
 pub const SIG_DFL: usize = 0;
 pub const SIG_IGN: usize = 1;
 
+// si_code of SIGCHLD
+pub const CLD_EXITED: i32 = 1;
+pub const CLD_KILLED: i32 = 2;
+pub const CLD_DUMPED: i32 = 3;
+pub const CLD_TRAPPED: i32 = 4;
+pub const CLD_STOPPED: i32 = 5;
+pub const CLD_CONTINUED: i32 = 6;
+
+// si_code of SIGSYS
+pub const SYS_SECCOMP: i32 = 1;
+
 pub const SI_ASYNCNL: i32 = -60;
 pub const SI_TKILL: i32 = -6;
 pub const SI_SIGIO: i32 = -5;
@@ -74,9 +85,32 @@ impl Debug for SignalAction {
 #[derive(Copy, Clone)]
 pub union SiginfoFields {
     pad: [u8; Self::PAD_SIZE],
+    pub sigsys: SigsysFields,
+    pub rt: RtFields,
     // TODO: fill this union
 }
 
+/// Fields of SIGSYS sent by seccomp
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SigsysFields {
+    /// Address of the syscall instruction
+    pub call_addr: usize,
+    pub syscall: i32,
+    /// AUDIT_ARCH_*
+    pub arch: u32,
+}
+
+/// Fields of real-time signals, and of SI_MESGQ
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct RtFields {
+    pub pid: i32,
+    pub uid: u32,
+    /// `sigval`
+    pub value: usize,
+}
+
 impl SiginfoFields {
     const PAD_SIZE: usize = 128 - 2 * core::mem::size_of::<i32>() - core::mem::size_of::<usize>();
 }
@@ -2,8 +2,12 @@ use crate::arch::{
     signal::{set_signal_handler, MachineContext, RET_CODE},
     syscall::SYS_RT_SIGRETURN,
 };
-use crate::process::{process, process_of, Process, Thread};
-use crate::sync::{Event, MutexGuard, SpinNoIrq, SpinNoIrqLock as Mutex};
+use crate::process::{
+    coredump, process, process_of,
+    ptrace::{ptrace_signal_stop, ptrace_wake},
+    Process, Thread, THREADS,
+};
+use crate::sync::{wait_for_event, Event, MutexGuard, SpinNoIrq, SpinNoIrqLock as Mutex};
 use alloc::sync::Arc;
 use bitflags::*;
 use num::FromPrimitive;
@@ -89,6 +93,33 @@ impl Signal {
     pub fn is_standard(self) -> bool {
         (self as usize) < Self::RTMIN
     }
+
+    /// Action taken when the disposition is SIG_DFL, see signal(7)
+    pub fn default_action(self) -> DefaultAction {
+        use Signal::*;
+        match self {
+            SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU
+            | SIGXFSZ | SIGSYS => DefaultAction::Core,
+            SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ign,
+            SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+            SIGCONT => DefaultAction::Cont,
+            _ => DefaultAction::Term,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum DefaultAction {
+    /// Terminate the process
+    Term,
+    /// Ignore the signal
+    Ign,
+    /// Terminate the process and dump core
+    Core,
+    /// Stop the process
+    Stop,
+    /// Continue the process if it is stopped
+    Cont,
 }
 
 // process and tid must be checked
@@ -101,6 +132,20 @@ pub fn send_signal(process: Arc<Mutex<Process>>, tid: isize, info: Siginfo) {
     process.sig_queue.push_back((info, tid));
     process.pending_sigset.add(signal);
     process.eventbus.lock().set(Event::RECEIVE_SIGNAL);
+    if signal == Signal::SIGCONT || signal == Signal::SIGKILL {
+        // resume a process stopped by job control
+        process.stop_signal = 0;
+        process.eventbus.lock().set(Event::PROCESS_CONTINUE);
+    }
+    if signal == Signal::SIGKILL {
+        // tracees can not wait for their tracer to die
+        let threads = THREADS.read();
+        for tid in process.threads.iter() {
+            if let Some(thread) = threads.get(tid) {
+                ptrace_wake(thread);
+            }
+        }
+    }
     info!(
         "send signal {} to pid {} tid {}",
         info.signo, process.pid, tid
@@ -129,10 +174,26 @@ pub struct SignalFrame {
 }
 
 /// return whether this thread exits
-pub fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
-    let mut process = thread.proc.lock();
-    while let Some((idx, info)) =
-        process
+pub async fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
+    loop {
+        let mut process = thread.proc.lock();
+        if process.exited() {
+            return true;
+        }
+        // another thread is dumping core, stop before running user code
+        if process.core_state.is_some() {
+            return false;
+        }
+
+        // stopped by job control, wait for SIGCONT
+        if process.stop_signal != 0 {
+            let eventbus = process.eventbus.clone();
+            drop(process);
+            wait_for_event(eventbus, Event::PROCESS_CONTINUE).await;
+            continue;
+        }
+
+        let found = process
             .sig_queue
             .iter()
             .enumerate()
@@ -148,10 +209,13 @@ pub fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
                 } else {
                     None
                 }
-            })
-    {
+            });
+        let (idx, info) = match found {
+            Some(found) => found,
+            None => return false,
+        };
+
         use crate::signal::SignalActionFlags;
-        use Signal::*;
 
         let signal: Signal = <Signal as FromPrimitive>::from_i32(info.signo).unwrap();
         info!(
@@ -162,21 +226,78 @@ pub fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
         process.sig_queue.remove(idx);
         process.pending_sigset.remove(signal);
 
+        // let the tracer decide which signal to deliver
+        let info = if signal != Signal::SIGKILL && thread.is_traced() {
+            drop(process);
+            let info = match ptrace_signal_stop(thread, tf, info).await {
+                Some(info) => info,
+                None => continue,
+            };
+            process = thread.proc.lock();
+            if process.exited() {
+                return true;
+            }
+            info
+        } else {
+            info
+        };
+        let signal: Signal = <Signal as FromPrimitive>::from_i32(info.signo).unwrap();
+
         let action = process.dispositions[info.signo as usize];
         let action_flags = SignalActionFlags::from_bits_truncate(action.flags);
 
         // enter signal handler
         match action.handler {
-            // TODO: complete default actions
             x if x == SIG_DFL => {
-                match signal {
-                    SIGALRM | SIGHUP | SIGINT => {
+                match signal.default_action() {
+                    DefaultAction::Term => {
                         info!("default action: Term");
                         // TODO: exit code ref please?
-                        process.exit(info.signo as usize + 128);
+                        let exited = process.exit(info.signo as usize + 128);
+                        drop(process);
+                        exited.finish();
                         return true;
                     }
-                    _ => (),
+                    DefaultAction::Core => {
+                        info!("default action: Core");
+                        // stop the other threads, then write the core file unlocked
+                        coredump::stop_threads(&mut process);
+                        drop(process);
+                        coredump::wait_stopped(thread).await;
+                        let core = coredump::prepare(&thread.proc.lock(), thread.tid, tf, signal);
+                        if let Err(err) = core.and_then(|core| core.write()) {
+                            warn!("failed to dump core: {:?}", err);
+                        }
+                        process = thread.proc.lock();
+                        if process.exited() {
+                            return true;
+                        }
+                        let exited = process.exit(info.signo as usize + 128);
+                        drop(process);
+                        exited.finish();
+                        return true;
+                    }
+                    DefaultAction::Stop => {
+                        info!("default action: Stop");
+                        process.stop_signal = info.signo as usize;
+                        process.stop_reported = false;
+                        process.eventbus.lock().clear(Event::PROCESS_CONTINUE);
+                        if let Some(parent) = process.parent.1.upgrade() {
+                            parent.lock().eventbus.lock().set(Event::CHILD_PROCESS_STOP);
+                            send_signal(
+                                parent,
+                                -1,
+                                Siginfo {
+                                    signo: Signal::SIGCHLD as i32,
+                                    errno: 0,
+                                    code: CLD_STOPPED,
+                                    field: Default::default(),
+                                },
+                            );
+                        }
+                        // wait for SIGCONT in the next round
+                    }
+                    action => info!("default action: {:?}, ignored", action),
                 }
             }
             x if x == SIG_IGN => {
@@ -266,7 +387,6 @@ pub fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
             }
         }
     }
-    return false;
 }
 
 bitflags! {
@@ -21,6 +21,9 @@ bitflags! {
         const PROCESS_QUIT                  = 1 << 10;
         const CHILD_PROCESS_QUIT            = 1 << 11;
         const RECEIVE_SIGNAL                = 1 << 12;
+        const CHILD_PROCESS_STOP            = 1 << 13;
+        const PROCESS_CONTINUE              = 1 << 14;
+        const TRACEE_RESUME                 = 1 << 15;
 
         /// Semaphore
         const SEMAPHORE_REMOVED             = 1 << 20;
@@ -31,7 +31,7 @@ impl Syscall<'_> {
             attr,
             Linear::new(base as isize - virt_addr as isize),
             "pci",
-        );
+        )?;
         Ok(virt_addr)
     }
 
@@ -19,14 +19,22 @@ use core::pin::Pin;
 use core::task::{Context, Poll};
 
 use bitvec::prelude::{BitSlice, BitVec, Lsb0};
+use rcore_fs::vfs::FileSystem;
+use rcore_fs_ramfs::RamFS;
 
 use super::*;
 use crate::fs::epoll::EpollInstance;
-use crate::fs::fcntl::{FD_CLOEXEC, F_SETFD, O_CLOEXEC, O_NONBLOCK};
+use crate::fs::fcntl::{FD_CLOEXEC, F_SETFD, F_SETFL, O_CLOEXEC, O_NONBLOCK};
+use crate::fs::inotify::{self, InotifyINode, InotifyMask};
+use crate::fs::lock::{self, LockOwner, LockType, RecordLock};
 use crate::fs::FileLike;
+use crate::process::namespace::{Namespace, NamespaceINode};
+use crate::process::rlimit::RLIMIT_FSIZE;
 use crate::process::Process;
+use crate::signal::{send_signal, Siginfo, Signal, SI_KERNEL};
 use crate::syscall::SysError::{EINTR, EINVAL, ESPIPE};
 use rcore_fs::vfs::PollStatus;
+use rcore_memory::PAGE_SIZE;
 
 impl Syscall<'_> {
     pub async fn sys_read(&mut self, fd: usize, base: UserOutPtr<u8>, len: usize) -> SysResult {
@@ -38,6 +46,12 @@ impl Syscall<'_> {
         let slice = unsafe { self.vm().check_write_array(base.ptr(), len)? };
 
         let file_like = proc.get_file_like(fd)?;
+        if let FileLike::Socket { .. } = file_like {
+            // a socket waits without the process locked, on a clone sharing its connection
+            let mut socket = file_like.clone();
+            drop(proc);
+            return socket.read(slice).await;
+        }
         let len = file_like.read(slice).await?;
         Ok(len)
     }
@@ -49,9 +63,10 @@ impl Syscall<'_> {
             info!("write: fd: {}, base: {:?}, len: {:#x}", fd, base, len);
         }
         let slice = unsafe { self.vm().check_read_array(base, len)? };
-        let file_like = proc.get_file_like(fd)?;
-        let len = file_like.write(slice)?;
-        Ok(len)
+        let limit = proc.rlimit(RLIMIT_FSIZE);
+        let res = proc.get_file_like(fd)?.write_limited(slice, limit);
+        drop(proc);
+        self.check_fsize(res)
     }
 
     pub async fn sys_pread(
@@ -84,8 +99,42 @@ impl Syscall<'_> {
         );
         let mut proc = self.process();
         let slice = unsafe { self.vm().check_read_array(base, len)? };
-        let len = proc.get_file(fd)?.write_at(offset, slice)?;
-        Ok(len)
+        let limit = proc.rlimit(RLIMIT_FSIZE);
+        let res = proc.get_file(fd)?.write_at_limited(offset, slice, limit);
+        drop(proc);
+        self.check_fsize(res)
+    }
+
+    /// Raise SIGXFSZ if a write failed for RLIMIT_FSIZE
+    fn check_fsize(&self, res: SysResult) -> SysResult {
+        if let Err(SysError::EFBIG) = res {
+            send_signal(
+                self.thread.proc.clone(),
+                self.thread.tid as isize,
+                Siginfo {
+                    signo: Signal::SIGXFSZ as i32,
+                    errno: 0,
+                    code: SI_KERNEL,
+                    field: Default::default(),
+                },
+            );
+        }
+        res
+    }
+
+    /// Fail with EPIPE and raise SIGPIPE, for a write to a pipe whose read end is closed
+    fn broken_pipe(&self) -> SysResult {
+        send_signal(
+            self.thread.proc.clone(),
+            self.thread.tid as isize,
+            Siginfo {
+                signo: Signal::SIGPIPE as i32,
+                errno: 0,
+                code: SI_KERNEL,
+                field: Default::default(),
+            },
+        );
+        Err(SysError::EPIPE)
     }
 
     /// sys_ppoll function is for handling the third argument of sys_poll.
@@ -311,7 +360,7 @@ impl Syscall<'_> {
         info!("epoll_create1: flags: {:?}", flags);
         let mut proc = self.process();
         let epoll_instance = EpollInstance::new(flags);
-        let fd = proc.add_file(FileLike::EpollInstance(epoll_instance));
+        let fd = proc.add_file(FileLike::EpollInstance(epoll_instance))?;
         Ok(fd)
     }
 
@@ -404,13 +453,8 @@ impl Syscall<'_> {
             match proc.files.get(&fd) {
                 Some(file_like) => {
                     match file_like {
-                        FileLike::File(_file) => {
-                            callbacks.push((
-                                0, 0, // thread::current().id(),
-                                epfd, *fd,
-                            ));
-                        }
-                        FileLike::Socket(_socket) => {
+                        // devices like TUN files notify SOCKET_ACTIVITY as sockets do
+                        FileLike::File(_) | FileLike::Socket { .. } => {
                             &(*crate::drivers::SOCKET_ACTIVITY).register_epoll_list(
                                 self.thread.proc.clone(),
                                 0,
@@ -544,7 +588,14 @@ impl Syscall<'_> {
         // read all data to a buf
         let file_like = proc.get_file_like(fd)?;
         let mut buf = iovs.new_buf(true);
-        let len = file_like.read(buf.as_mut_slice()).await?;
+        let len = if let FileLike::Socket { .. } = file_like {
+            // as in `sys_read`
+            let mut socket = file_like.clone();
+            drop(proc);
+            socket.read(buf.as_mut_slice()).await?
+        } else {
+            file_like.read(buf.as_mut_slice()).await?
+        };
         // copy data to user
         iovs.write_all_from_slice(&buf[..len]);
         Ok(len)
@@ -567,9 +618,10 @@ impl Syscall<'_> {
         let iovs = unsafe { IoVecs::check_and_new(iov_ptr, iov_count, &self.vm(), false)? };
 
         let buf = iovs.read_all_to_vec();
-        let file_like = proc.get_file_like(fd)?;
-        let len = file_like.write(buf.as_slice())?;
-        Ok(len)
+        let limit = proc.rlimit(RLIMIT_FSIZE);
+        let res = proc.get_file_like(fd)?.write_limited(buf.as_slice(), limit);
+        drop(proc);
+        self.check_fsize(res)
     }
 
     pub fn sys_open(&mut self, path: *const u8, flags: usize, mode: usize) -> SysResult {
@@ -591,11 +643,11 @@ impl Syscall<'_> {
             dir_fd as isize, path, flags, mode
         );
 
-        let inode = if flags.contains(OpenFlags::CREATE) {
-            let (dir_path, file_name) = split_path(&path);
+        let (dir_path, file_name) = split_path(&path);
+        let (inode, dir_inode) = if flags.contains(OpenFlags::CREATE) {
             // relative to cwd
             let dir_inode = proc.lookup_inode_at(dir_fd, dir_path, true)?;
-            match dir_inode.find(file_name) {
+            let inode = match dir_inode.find(file_name) {
                 Ok(file_inode) => {
                     if flags.contains(OpenFlags::EXCLUSIVE) {
                         return Err(SysError::EEXIST);
@@ -611,28 +663,50 @@ impl Syscall<'_> {
                     let inode = dir_inode.create(file_name, FileType::File, mode as u32)?;
                     TimeSpec::update(&inode);
                     TimeSpec::update(&dir_inode);
+                    inotify::notify_entry(&dir_inode, file_name, InotifyMask::CREATE, 0);
                     inode
                 }
                 Err(e) => return Err(SysError::from(e)),
-            }
+            };
+            (inode, Some(dir_inode))
         } else {
-            proc.lookup_inode_at(dir_fd, &path, true)?
+            let inode = proc.lookup_inode_at(dir_fd, &path, true)?;
+            // the directory holding it, unless it is reached through a symbolic link.
+            // It only tells watches of the directory, so it is not looked up without any.
+            let dir_inode = if inotify::watching() {
+                let dir_inode = proc.lookup_inode_at(dir_fd, dir_path, true).ok();
+                dir_inode.filter(|dir| match dir.find(file_name) {
+                    Ok(found) => same_inode(&found, &inode),
+                    Err(_) => false,
+                })
+            } else {
+                None
+            };
+            (inode, dir_inode)
+        };
+        // every open of /dev/net/tun gets a device of its own
+        let inode: Arc<dyn INode> = if inode.as_any_ref().is::<TunCloneINode>() {
+            Arc::new(TunINode::default())
+        } else {
+            inode
         };
 
-        let file = FileHandle::new(
+        let mut file = FileHandle::new(
             inode,
             flags.to_options(),
-            String::from(path),
+            String::from(path.as_str()),
             false,
             flags.contains(OpenFlags::CLOEXEC),
         );
+        file.parent = dir_inode.map(|dir| (dir, String::from(file_name)));
+        file.notify(InotifyMask::OPEN);
 
         // for debugging
         if cfg!(debug_assertions) {
             debug!("files before open {:#?}", proc.files);
         }
 
-        let fd = proc.add_file(FileLike::File(file));
+        let fd = proc.add_file(FileLike::File(file))?;
         Ok(fd)
     }
 
@@ -645,7 +719,7 @@ impl Syscall<'_> {
             debug!("files before close {:#?}", proc.files);
         }
 
-        proc.files.remove(&fd).ok_or(SysError::EBADF)?;
+        proc.close_file(fd)?;
         Ok(0)
     }
 
@@ -786,7 +860,7 @@ impl Syscall<'_> {
         Ok(0)
     }
 
-    pub fn sys_flock(&mut self, fd: usize, operation: usize) -> SysResult {
+    pub async fn sys_flock(&mut self, fd: usize, operation: usize) -> SysResult {
         bitflags! {
             struct Operation: u8 {
                 const LOCK_SH = 1;
@@ -795,11 +869,24 @@ impl Syscall<'_> {
                 const LOCK_UN = 8;
             }
         }
-        let operation = Operation::from_bits(operation as u8).unwrap();
+        let operation = Operation::from_bits(operation as u8).ok_or(SysError::EINVAL)?;
         info!("flock: fd: {}, operation: {:?}", fd, operation);
-        let mut proc = self.process();
-        // let file_like = proc.get_file_like(fd)?;
-        let file = proc.get_file(fd)?;
+        let (inode, owner) = {
+            let mut proc = self.process();
+            let file = proc.get_file(fd)?;
+            (file.inode(), LockOwner::File(file.description_id()))
+        };
+        let wait = !operation.contains(Operation::LOCK_NB);
+        let type_ = match operation - Operation::LOCK_NB {
+            Operation::LOCK_SH => LockType::Read,
+            Operation::LOCK_EX => LockType::Write,
+            Operation::LOCK_UN => {
+                lock::unlock_flock(&inode, owner);
+                return Ok(0);
+            }
+            _ => return Err(SysError::EINVAL),
+        };
+        lock::lock_flock(&self.thread, &inode, owner, type_, wait).await?;
         Ok(0)
     }
 
@@ -813,13 +900,24 @@ impl Syscall<'_> {
         let proc = self.process();
         let path = check_and_clone_cstr(path)?;
         info!("truncate: path: {:?}, len: {}", path, len);
-        proc.lookup_inode(&path)?.resize(len)?;
+        if len as u64 > proc.rlimit(RLIMIT_FSIZE) {
+            drop(proc);
+            return self.check_fsize(Err(SysError::EFBIG));
+        }
+        let inode = proc.lookup_inode(&path)?;
+        inode.resize(len)?;
+        inotify::notify_inode(&inode, InotifyMask::MODIFY, 0);
         Ok(0)
     }
 
     pub fn sys_ftruncate(&mut self, fd: usize, len: usize) -> SysResult {
         info!("ftruncate: fd: {}, len: {}", fd, len);
-        self.process().get_file(fd)?.set_len(len as u64)?;
+        let mut proc = self.process();
+        if len as u64 > proc.rlimit(RLIMIT_FSIZE) {
+            drop(proc);
+            return self.check_fsize(Err(SysError::EFBIG));
+        }
+        proc.get_file(fd)?.set_len(len as u64)?;
         Ok(0)
     }
 
@@ -866,8 +964,11 @@ impl Syscall<'_> {
 
     fn dup_impl(&mut self, fd1: usize, fd2: usize, flags: usize) -> SysResult {
         let mut proc = self.process();
+        if fd2 >= proc.fd_limit() {
+            return Err(SysError::EBADF);
+        }
         // close fd2 first if it is opened
-        proc.files.remove(&fd2);
+        proc.close_file(fd2).ok();
 
         let mut file_like = proc.get_file_like(fd1)?.dup(flags != 0);
         proc.files.insert(fd2, file_like);
@@ -893,15 +994,15 @@ impl Syscall<'_> {
         );
         use crate::fs::ioctl::*;
         match request {
-            FIOCLEX => self.sys_fcntl(fd, F_SETFD, FD_CLOEXEC),
-            FIONCLEX => self.sys_fcntl(fd, F_SETFD, 0),
+            FIOCLEX => self.fcntl(fd, F_SETFD, FD_CLOEXEC),
+            FIONCLEX => self.fcntl(fd, F_SETFD, 0),
             FIONBIO => {
                 let data = arg1 as *const i32;
                 let val = unsafe { *data };
                 if val == 0 {
-                    self.sys_fcntl(fd, F_SETFD, 0)
+                    self.fcntl(fd, F_SETFL, 0)
                 } else {
-                    self.sys_fcntl(fd, F_SETFD, O_NONBLOCK)
+                    self.fcntl(fd, F_SETFL, O_NONBLOCK)
                 }
             }
             _ => {
@@ -928,29 +1029,7 @@ impl Syscall<'_> {
 
         // BUGFIX: '..' and '.'
         if path.len() > 0 {
-            let cwd = match path.as_bytes()[0] {
-                b'/' => String::from("/"),
-                _ => proc.cwd.clone(),
-            };
-            let mut cwd_vec: Vec<_> = cwd.split("/").filter(|&x| x != "").collect();
-            let path_split = path.split("/").filter(|&x| x != "");
-            for seg in path_split {
-                if seg == ".." {
-                    cwd_vec.pop();
-                } else if seg == "." {
-                    // nothing to do here.
-                } else {
-                    cwd_vec.push(seg);
-                }
-            }
-            proc.cwd = String::from("");
-            for seg in cwd_vec {
-                proc.cwd.push_str("/");
-                proc.cwd.push_str(seg);
-            }
-            if proc.cwd == "" {
-                proc.cwd = String::from("/");
-            }
+            proc.cwd = proc.absolute_path(&path);
         }
         Ok(0)
     }
@@ -978,7 +1057,28 @@ impl Syscall<'_> {
         let (new_dir_path, new_file_name) = split_path(&newpath);
         let old_dir_inode = proc.lookup_inode_at(olddirfd, old_dir_path, false)?;
         let new_dir_inode = proc.lookup_inode_at(newdirfd, new_dir_path, false)?;
+        let inode = old_dir_inode.find(old_file_name)?;
+        let replaced = new_dir_inode.find(new_file_name).ok();
         old_dir_inode.move_(old_file_name, &new_dir_inode, new_file_name)?;
+
+        let mask = inotify::type_mask(&inode);
+        let cookie = inotify::new_cookie();
+        inotify::notify_entry(
+            &old_dir_inode,
+            old_file_name,
+            InotifyMask::MOVED_FROM | mask,
+            cookie,
+        );
+        inotify::notify_entry(
+            &new_dir_inode,
+            new_file_name,
+            InotifyMask::MOVED_TO | mask,
+            cookie,
+        );
+        inotify::notify_inode(&inode, InotifyMask::MOVE_SELF, 0);
+        if let Some(replaced) = replaced {
+            inotify::notify_unlinked(&replaced);
+        }
         Ok(0)
     }
 
@@ -1003,6 +1103,12 @@ impl Syscall<'_> {
         let inode = dir_inode.create(file_name, FileType::Dir, mode as u32)?;
         TimeSpec::update(&inode);
         TimeSpec::update(&dir_inode);
+        inotify::notify_entry(
+            &dir_inode,
+            file_name,
+            InotifyMask::CREATE | InotifyMask::ISDIR,
+            0,
+        );
         Ok(0)
     }
 
@@ -1018,6 +1124,13 @@ impl Syscall<'_> {
             return Err(SysError::ENOTDIR);
         }
         dir_inode.unlink(file_name)?;
+        inotify::notify_entry(
+            &dir_inode,
+            file_name,
+            InotifyMask::DELETE | InotifyMask::ISDIR,
+            0,
+        );
+        inotify::notify_unlinked(&file_inode);
         Ok(0)
     }
 
@@ -1046,6 +1159,8 @@ impl Syscall<'_> {
         let inode = proc.lookup_inode_at(olddirfd, &oldpath, true)?;
         let new_dir_inode = proc.lookup_inode_at(newdirfd, new_dir_path, true)?;
         new_dir_inode.link(new_file_name, &inode)?;
+        inotify::notify_entry(&new_dir_inode, new_file_name, InotifyMask::CREATE, 0);
+        inotify::notify_inode(&inode, InotifyMask::ATTRIB, 0);
         Ok(0)
     }
 
@@ -1082,6 +1197,7 @@ impl Syscall<'_> {
                     symlink.write_at(0, target.as_bytes())?;
                     TimeSpec::update(&symlink);
                     TimeSpec::update(&dir_inode);
+                    inotify::notify_entry(&dir_inode, filename, InotifyMask::CREATE, 0);
                     Ok(0)
                 }
                 _ => Err(e.into()),
@@ -1105,6 +1221,8 @@ impl Syscall<'_> {
             return Err(SysError::EISDIR);
         }
         dir_inode.unlink(file_name)?;
+        inotify::notify_entry(&dir_inode, file_name, InotifyMask::DELETE, 0);
+        inotify::notify_unlinked(&file_inode);
         Ok(0)
     }
 
@@ -1130,9 +1248,9 @@ impl Syscall<'_> {
             String::from("pipe_r:[]"),
             true,
             (flags & O_CLOEXEC) != 0,
-        )));
+        )))?;
 
-        let write_fd = proc.add_file(FileLike::File(FileHandle::new(
+        let write_fd = match proc.add_file(FileLike::File(FileHandle::new(
             Arc::new(write),
             OpenOptions {
                 read: false,
@@ -1143,7 +1261,13 @@ impl Syscall<'_> {
             String::from("pipe_w:[]"),
             true,
             (flags & O_CLOEXEC) != 0,
-        )));
+        ))) {
+            Ok(fd) => fd,
+            Err(err) => {
+                proc.files.remove(&read_fd);
+                return Err(err);
+            }
+        };
 
         fds[0] = read_fd as u32;
         fds[1] = write_fd as u32;
@@ -1153,6 +1277,61 @@ impl Syscall<'_> {
         Ok(0)
     }
 
+    pub fn sys_inotify_init(&mut self) -> SysResult {
+        self.sys_inotify_init1(0)
+    }
+
+    pub fn sys_inotify_init1(&mut self, flags: usize) -> SysResult {
+        info!("inotify_init1: flags: {:#x}", flags);
+        if flags & !(O_NONBLOCK | O_CLOEXEC) != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let mut proc = self.process();
+        let file = FileHandle::new(
+            Arc::new(InotifyINode::new()),
+            OpenOptions {
+                read: true,
+                write: false,
+                append: false,
+                nonblock: (flags & O_NONBLOCK) != 0,
+            },
+            String::from("anon_inode:inotify"),
+            false,
+            (flags & O_CLOEXEC) != 0,
+        );
+        let fd = proc.add_file(FileLike::File(file))?;
+        Ok(fd)
+    }
+
+    pub fn sys_inotify_add_watch(&mut self, fd: usize, path: *const u8, mask: u32) -> SysResult {
+        let mut proc = self.process();
+        let path = check_and_clone_cstr(path)?;
+        let mask = InotifyMask::from_bits_truncate(mask);
+        info!(
+            "inotify_add_watch: fd: {}, path: {:?}, mask: {:?}",
+            fd, path, mask
+        );
+        if (mask & InotifyMask::ALL_EVENTS).is_empty()
+            || mask.contains(InotifyMask::MASK_ADD | InotifyMask::MASK_CREATE)
+        {
+            return Err(SysError::EINVAL);
+        }
+        let follow = !mask.contains(InotifyMask::DONT_FOLLOW);
+        let inode = proc.lookup_inode_at(AT_FDCWD, &path, follow)?;
+        if mask.contains(InotifyMask::ONLYDIR) && inode.metadata()?.type_ != FileType::Dir {
+            return Err(SysError::ENOTDIR);
+        }
+        let wd = as_inotify(&proc.get_file(fd)?.inode())?.add_watch(inode, mask)?;
+        Ok(wd as usize)
+    }
+
+    pub fn sys_inotify_rm_watch(&mut self, fd: usize, wd: usize) -> SysResult {
+        info!("inotify_rm_watch: fd: {}, wd: {}", fd, wd);
+        let mut proc = self.process();
+        as_inotify(&proc.get_file(fd)?.inode())?.rm_watch(wd as i32)?;
+        Ok(0)
+    }
+
     pub fn sys_utimensat(
         &mut self,
         dirfd: usize,
@@ -1219,6 +1398,82 @@ impl Syscall<'_> {
         Ok(0)
     }
 
+    /// Mount a new tmpfs at `target` in the mount namespace.
+    /// Mount propagation does not exist, so changing it does nothing.
+    pub fn sys_mount(
+        &mut self,
+        source: *const u8,
+        target: *const u8,
+        fstype: *const u8,
+        flags: usize,
+        _data: usize,
+    ) -> SysResult {
+        const MS_REMOUNT: usize = 1 << 5;
+        const MS_BIND: usize = 1 << 12;
+        const MS_MOVE: usize = 1 << 13;
+        const MS_UNBINDABLE: usize = 1 << 17;
+        const MS_PRIVATE: usize = 1 << 18;
+        const MS_SLAVE: usize = 1 << 19;
+        const MS_SHARED: usize = 1 << 20;
+        let target = check_and_clone_cstr(target)?;
+        info!(
+            "mount: source: {:?}, target: {:?}, fstype: {:?}, flags: {:#x}",
+            source, target, fstype, flags
+        );
+        if flags & (MS_REMOUNT | MS_UNBINDABLE | MS_PRIVATE | MS_SLAVE | MS_SHARED) != 0 {
+            return Ok(0);
+        }
+        if flags & (MS_BIND | MS_MOVE) != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let fs: Arc<dyn FileSystem> = match check_and_clone_cstr(fstype)?.as_str() {
+            "tmpfs" | "ramfs" => RamFS::new(),
+            "mqueue" => MQUEUE_FS.clone(),
+            _ => return Err(SysError::ENODEV),
+        };
+        let proc = self.process();
+        let path = proc.ns_path(&target);
+        proc.ns.mnt.mount(&path, fs)?;
+        Ok(0)
+    }
+
+    pub fn sys_umount2(&mut self, target: *const u8, flags: usize) -> SysResult {
+        let target = check_and_clone_cstr(target)?;
+        info!("umount2: target: {:?}, flags: {:#x}", target, flags);
+        let proc = self.process();
+        let path = proc.ns_path(&target);
+        proc.ns.mnt.umount(&path)?;
+        Ok(0)
+    }
+
+    /// Change the root directory of current process
+    pub fn sys_chroot(&mut self, path: *const u8) -> SysResult {
+        let path = check_and_clone_cstr(path)?;
+        info!("chroot: path: {:?}", path);
+        let mut proc = self.process();
+        if proc.lookup_inode(&path)?.metadata()?.type_ != FileType::Dir {
+            return Err(SysError::ENOTDIR);
+        }
+        proc.root = proc.ns_path(&path);
+        Ok(0)
+    }
+
+    /// Make the mount at `new_root` the root of the mount namespace,
+    /// and move the old root to `put_old`
+    pub fn sys_pivot_root(&mut self, new_root: *const u8, put_old: *const u8) -> SysResult {
+        let new_root = check_and_clone_cstr(new_root)?;
+        let put_old = check_and_clone_cstr(put_old)?;
+        info!(
+            "pivot_root: new_root: {:?}, put_old: {:?}",
+            new_root, put_old
+        );
+        let proc = self.process();
+        let new_root = proc.ns_path(&new_root);
+        let put_old = proc.ns_path(&put_old);
+        proc.ns.mnt.pivot_root(&new_root, &put_old)?;
+        Ok(0)
+    }
+
     pub async fn sys_sendfile(
         &mut self,
         out_fd: usize,
@@ -1314,7 +1569,338 @@ impl Syscall<'_> {
         return Ok(total_written);
     }
 
-    pub fn sys_fcntl(&mut self, fd: usize, cmd: usize, arg: usize) -> SysResult {
+    pub async fn sys_splice(
+        &mut self,
+        fd_in: usize,
+        mut off_in: UserInOutPtr<i64>,
+        fd_out: usize,
+        mut off_out: UserInOutPtr<i64>,
+        len: usize,
+        flags: usize,
+    ) -> SysResult {
+        let flags = SpliceFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        info!(
+            "splice: in: {}, off_in: {:?}, out: {}, off_out: {:?}, len: {}, flags: {:?}",
+            fd_in, off_in, fd_out, off_out, len, flags
+        );
+        let (mut file_in, mut file_out, limit) = {
+            let mut proc = self.process();
+            let file_in = proc.get_file_like(fd_in)?.clone();
+            let file_out = proc.get_file_like(fd_out)?.clone();
+            (file_in, file_out, proc.rlimit(RLIMIT_FSIZE))
+        };
+        let nonblock = flags.contains(SpliceFlags::NONBLOCK);
+        match (pipe_of(&file_in), pipe_of(&file_out)) {
+            (Some(inode_in), Some(inode_out)) => {
+                let (pipe_in, pipe_out) = (as_pipe(&inode_in), as_pipe(&inode_out));
+                if !off_in.is_null() || !off_out.is_null() {
+                    return Err(ESPIPE);
+                }
+                if !pipe_in.is_read_end() || !pipe_out.is_write_end() {
+                    return Err(SysError::EBADF);
+                }
+                if pipe_in.same_pipe(pipe_out) {
+                    return Err(EINVAL);
+                }
+                if len == 0 {
+                    return Ok(0);
+                }
+                if pipe_out.read_end_closed() {
+                    return self.broken_pipe();
+                }
+                // pages move from one pipe to the other
+                let bufs = wait_pipe(&file_in, nonblock, || pipe_in.take(len)).await?;
+                Ok(pipe_out.give(bufs))
+            }
+            (Some(inode_in), None) => {
+                let pipe_in = as_pipe(&inode_in);
+                if !off_in.is_null() {
+                    return Err(ESPIPE);
+                }
+                if !pipe_in.is_read_end() {
+                    return Err(SysError::EBADF);
+                }
+                let mut offset = match (&file_out, off_out.is_null()) {
+                    (_, true) => None,
+                    (FileLike::File(_), false) => Some(splice_offset(off_out.read()?)?),
+                    _ => return Err(ESPIPE),
+                };
+                if len == 0 {
+                    return Ok(0);
+                }
+                let mut bufs = wait_pipe(&file_in, nonblock, || pipe_in.take(len)).await?;
+                // SPLICE_F_MORE is only a hint, as sockets send what is written at once
+                let mut written = 0;
+                let mut result = Ok(0);
+                'pages: for buf in bufs.iter_mut() {
+                    while buf.len() > 0 {
+                        result = match (&mut file_out, offset) {
+                            (FileLike::File(file), Some(offset)) => {
+                                file.write_at_limited(offset, buf.as_slice(), limit)
+                            }
+                            (file_out, _) => file_out.write_limited(buf.as_slice(), limit),
+                        };
+                        match &result {
+                            Ok(len) if *len > 0 => {
+                                let len = *len;
+                                buf.advance(len);
+                                written += len;
+                                offset = offset.map(|offset| offset + len);
+                            }
+                            _ => break 'pages,
+                        }
+                    }
+                }
+                // what is not written stays in the pipe
+                pipe_in.put_back(bufs);
+                if let Some(offset) = offset {
+                    off_out.write(offset as i64)?;
+                }
+                match written {
+                    0 => self.check_fsize(result),
+                    _ => Ok(written),
+                }
+            }
+            (None, Some(inode_out)) => {
+                let pipe_out = as_pipe(&inode_out);
+                if !off_out.is_null() {
+                    return Err(ESPIPE);
+                }
+                if !pipe_out.is_write_end() {
+                    return Err(SysError::EBADF);
+                }
+                let mut offset = match (&file_in, off_in.is_null()) {
+                    (_, true) => None,
+                    (FileLike::File(_), false) => Some(splice_offset(off_in.read()?)?),
+                    _ => return Err(ESPIPE),
+                };
+                if pipe_out.read_end_closed() {
+                    return self.broken_pipe();
+                }
+                // read page by page, until a short read
+                let mut bufs = Vec::new();
+                let mut read = 0;
+                while read < len {
+                    let mut page = vec![0u8; min(PAGE_SIZE, len - read)];
+                    let result = match (&mut file_in, offset) {
+                        (FileLike::File(file), Some(offset)) => {
+                            file.read_at(offset, &mut page).await.map_err(Into::into)
+                        }
+                        (file_in, _) => file_in.read(&mut page).await,
+                    };
+                    let page_len = match result {
+                        Ok(page_len) => page_len,
+                        Err(err) if read == 0 => return Err(err),
+                        Err(_) => break,
+                    };
+                    read += page_len;
+                    offset = offset.map(|offset| offset + page_len);
+                    let full = page_len == page.len();
+                    page.truncate(page_len);
+                    bufs.push(PipeBuffer::new(page));
+                    // a socket may block on the next read
+                    if !full || !matches!(file_in, FileLike::File(_)) {
+                        break;
+                    }
+                }
+                if let Some(offset) = offset {
+                    off_in.write(offset as i64)?;
+                }
+                Ok(pipe_out.give(bufs))
+            }
+            (None, None) => Err(EINVAL),
+        }
+    }
+
+    pub async fn sys_tee(
+        &mut self,
+        fd_in: usize,
+        fd_out: usize,
+        len: usize,
+        flags: usize,
+    ) -> SysResult {
+        let flags = SpliceFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        info!(
+            "tee: in: {}, out: {}, len: {}, flags: {:?}",
+            fd_in, fd_out, len, flags
+        );
+        let (file_in, file_out) = {
+            let mut proc = self.process();
+            let file_in = proc.get_file_like(fd_in)?.clone();
+            (file_in, proc.get_file_like(fd_out)?.clone())
+        };
+        let (inode_in, inode_out) = match (pipe_of(&file_in), pipe_of(&file_out)) {
+            (Some(inode_in), Some(inode_out)) => (inode_in, inode_out),
+            _ => return Err(EINVAL),
+        };
+        let (pipe_in, pipe_out) = (as_pipe(&inode_in), as_pipe(&inode_out));
+        if !pipe_in.is_read_end() || !pipe_out.is_write_end() {
+            return Err(SysError::EBADF);
+        }
+        if pipe_in.same_pipe(pipe_out) {
+            return Err(EINVAL);
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        if pipe_out.read_end_closed() {
+            return self.broken_pipe();
+        }
+        // pages are shared by both pipes
+        let nonblock = flags.contains(SpliceFlags::NONBLOCK);
+        let bufs = wait_pipe(&file_in, nonblock, || pipe_in.peek(len)).await?;
+        Ok(pipe_out.give(bufs))
+    }
+
+    pub async fn sys_vmsplice(
+        &mut self,
+        fd: usize,
+        iov_ptr: UserInPtr<IoVec>,
+        iov_count: usize,
+        flags: usize,
+    ) -> SysResult {
+        let flags = SpliceFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        info!(
+            "vmsplice: fd: {}, iov: {:?}, count: {}, flags: {:?}",
+            fd, iov_ptr, iov_count, flags
+        );
+        let file = self.process().get_file_like(fd)?.clone();
+        let inode = pipe_of(&file).ok_or(SysError::EBADF)?;
+        let pipe = as_pipe(&inode);
+        if pipe.is_write_end() {
+            if pipe.read_end_closed() {
+                return self.broken_pipe();
+            }
+            let iovs =
+                unsafe { IoVecs::check_and_new(iov_ptr.ptr(), iov_count, &self.vm(), false)? };
+            // user pages are copied, SPLICE_F_GIFT can not hand them over
+            let bufs = iovs
+                .0
+                .iter()
+                .flat_map(|slice| slice.chunks(PAGE_SIZE))
+                .map(|chunk| PipeBuffer::new(chunk.to_vec()))
+                .collect();
+            Ok(pipe.give(bufs))
+        } else {
+            let mut iovs =
+                unsafe { IoVecs::check_and_new(iov_ptr.ptr(), iov_count, &self.vm(), true)? };
+            let len = iovs.0.iter().map(|slice| slice.len()).sum();
+            if len == 0 {
+                return Ok(0);
+            }
+            let nonblock = flags.contains(SpliceFlags::NONBLOCK);
+            let bufs = wait_pipe(&file, nonblock, || pipe.take(len)).await?;
+            let data: Vec<u8> = bufs
+                .iter()
+                .flat_map(|buf| buf.as_slice().iter().cloned())
+                .collect();
+            iovs.write_all_from_slice(&data);
+            Ok(data.len())
+        }
+    }
+
+    pub async fn sys_fcntl(&mut self, fd: usize, cmd: usize, arg: usize) -> SysResult {
+        use crate::fs::fcntl::*;
+        match cmd {
+            F_GETLK | F_SETLK | F_SETLKW | F_OFD_GETLK | F_OFD_SETLK | F_OFD_SETLKW => {
+                self.fcntl_lock(fd, cmd, arg).await
+            }
+            _ => self.fcntl(fd, cmd, arg),
+        }
+    }
+
+    /// Record locks of fcntl, traditional ones owned by the process or OFD ones
+    async fn fcntl_lock(&mut self, fd: usize, cmd: usize, arg: usize) -> SysResult {
+        use crate::fs::fcntl::*;
+        let mut flock: Flock = UserInPtr::from(arg).read()?;
+        info!("fcntl: fd: {}, cmd: {}, flock: {:?}", fd, cmd, flock);
+        let getlk = cmd == F_GETLK || cmd == F_OFD_GETLK;
+        let (inode, owner, type_, start, end) = {
+            let mut proc = self.process();
+            let pid = proc.pid.get();
+            let file = proc.get_file(fd)?;
+            let base = match flock.whence as u8 {
+                SEEK_SET => 0,
+                SEEK_CUR => file.seek(SeekFrom::Current(0))? as i64,
+                SEEK_END => file.metadata()?.size as i64,
+                _ => return Err(SysError::EINVAL),
+            };
+            let start = base + flock.start;
+            // a zero length locks to the end of file, a negative one locks before start
+            let (start, end) = match flock.len {
+                0 => (start, None),
+                len if len > 0 => (start, Some(start.saturating_add(len))),
+                len => (start + len, Some(start)),
+            };
+            if start < 0 {
+                return Err(SysError::EINVAL);
+            }
+            let options = file.options();
+            let type_ = match flock.type_ {
+                F_RDLCK if !getlk && !options.read => return Err(SysError::EBADF),
+                F_WRLCK if !getlk && !options.write => return Err(SysError::EBADF),
+                F_RDLCK => Some(LockType::Read),
+                F_WRLCK => Some(LockType::Write),
+                F_UNLCK if !getlk => None,
+                _ => return Err(SysError::EINVAL),
+            };
+            let owner = if cmd >= F_OFD_GETLK {
+                if flock.pid != 0 {
+                    return Err(SysError::EINVAL);
+                }
+                LockOwner::File(file.description_id())
+            } else {
+                LockOwner::Process(pid)
+            };
+            let end = end.map_or(u64::max_value(), |end| end as u64);
+            (file.inode(), owner, type_, start as u64, end)
+        };
+        match type_ {
+            Some(type_) if getlk => {
+                let lock = RecordLock {
+                    owner,
+                    type_,
+                    start,
+                    end,
+                };
+                match lock::get_posix(&inode, &lock)? {
+                    Some(conflict) => {
+                        flock.type_ = match conflict.type_ {
+                            LockType::Read => F_RDLCK,
+                            LockType::Write => F_WRLCK,
+                        };
+                        flock.whence = SEEK_SET as i16;
+                        flock.start = conflict.start as i64;
+                        flock.len = if conflict.end == u64::max_value() {
+                            0
+                        } else {
+                            (conflict.end - conflict.start) as i64
+                        };
+                        flock.pid = match conflict.owner {
+                            LockOwner::Process(pid) => pid as i32,
+                            LockOwner::File(_) => -1,
+                        };
+                    }
+                    None => flock.type_ = F_UNLCK,
+                }
+                UserOutPtr::from(arg).write(flock)?;
+            }
+            None => lock::unlock_posix(&inode, owner, start, end),
+            Some(type_) => {
+                let lock = RecordLock {
+                    owner,
+                    type_,
+                    start,
+                    end,
+                };
+                let wait = cmd == F_SETLKW || cmd == F_OFD_SETLKW;
+                lock::lock_posix(&self.thread, &inode, lock, wait).await?;
+            }
+        }
+        Ok(0)
+    }
+
+    fn fcntl(&mut self, fd: usize, cmd: usize, arg: usize) -> SysResult {
         info!("fcntl: fd: {}, cmd: {:#x}, arg: {}", fd, cmd, arg);
         let mut proc = self.process();
         let file_like = proc.get_file_like(fd)?;
@@ -1335,16 +1921,31 @@ impl Syscall<'_> {
                     F_DUPFD_CLOEXEC => {
                         info!("fcntl: dupfd_cloexec: arg: {:#x}", arg);
                         // let file_like = proc.get_file_like(fd1)?.clone();
-                        let new_fd = proc.get_free_fd_from(arg);
+                        if arg >= proc.fd_limit() {
+                            return Err(SysError::EINVAL);
+                        }
+                        let new_fd = proc.get_free_fd_from(arg)?;
                         core::mem::drop(proc);
                         self.dup_impl(fd, new_fd, 1)
                     }
                     _ => Ok(0),
                 }
             }
-            FileLike::Socket(_) => {
-                Ok(0)
-                //TODO
+            FileLike::Socket { socket, fd_cloexec } => {
+                use crate::fs::fcntl::*;
+                match cmd {
+                    F_SETFD => {
+                        *fd_cloexec = (arg & 1) != 0;
+                        Ok(0)
+                    }
+                    F_GETFD => Ok(*fd_cloexec as usize),
+                    F_SETFL => {
+                        set_nonblock(socket, arg & O_NONBLOCK != 0);
+                        Ok(0)
+                    }
+                    F_GETFL if socket.nonblock() => Ok(O_NONBLOCK),
+                    _ => Ok(0),
+                }
             }
             FileLike::EpollInstance(_) => Ok(0),
         }
@@ -1402,13 +2003,23 @@ impl Process {
                 let fd_path = &self.get_file_const(fd)?.path;
                 return Ok(Arc::new(Pseudo::new(fd_path, FileType::SymLink)));
             }
+            _ if fd_dir_path.starts_with("/proc/") && fd_dir_path.ends_with("/ns") => {
+                let pid = &fd_dir_path["/proc/".len()..fd_dir_path.len() - "/ns".len()];
+                let ns = self.namespace_of(pid, fd_name)?;
+                return Ok(Arc::new(NamespaceINode::new(ns)));
+            }
             _ => {}
         }
 
         let follow_max_depth = if follow { FOLLOW_MAX_DEPTH } else { 0 };
-        if dirfd == AT_FDCWD {
-            Ok(ROOT_INODE
-                .lookup(&self.cwd)?
+        if path.starts_with('/') {
+            Ok(self
+                .root_inode()?
+                .lookup_follow(path.trim_start_matches('/'), follow_max_depth)?)
+        } else if dirfd == AT_FDCWD {
+            Ok(self
+                .root_inode()?
+                .lookup(self.cwd.trim_start_matches('/'))?
                 .lookup_follow(path, follow_max_depth)?)
         } else {
             let file = match self.files.get(&dirfd).ok_or(SysError::EBADF)? {
@@ -1422,6 +2033,71 @@ impl Process {
     pub fn lookup_inode(&self, path: &str) -> Result<Arc<dyn INode>, SysError> {
         self.lookup_inode_at(AT_FDCWD, path, true)
     }
+
+    /// Root directory of the process, see `chroot(2)`
+    pub fn root_inode(&self) -> Result<Arc<dyn INode>, SysError> {
+        Ok(self
+            .ns
+            .mnt
+            .root()
+            .lookup(self.root.trim_start_matches('/'))?)
+    }
+
+    /// Normalized absolute `path` as seen by the process.
+    /// Symbolic links are not resolved.
+    pub fn absolute_path(&self, path: &str) -> String {
+        let cwd = match path.as_bytes().first() {
+            Some(b'/') => "/",
+            _ => &self.cwd,
+        };
+        let mut segs: Vec<_> = cwd.split("/").filter(|&x| x != "").collect();
+        for seg in path.split("/").filter(|&x| x != "") {
+            if seg == ".." {
+                segs.pop();
+            } else if seg != "." {
+                segs.push(seg);
+            }
+        }
+        let mut res = String::new();
+        for seg in segs {
+            res.push_str("/");
+            res.push_str(seg);
+        }
+        if res == "" {
+            res = String::from("/");
+        }
+        res
+    }
+
+    /// Absolute `path` in the mount namespace, below the root directory
+    pub fn ns_path(&self, path: &str) -> String {
+        let path = self.absolute_path(path);
+        if self.root == "/" {
+            path
+        } else if path == "/" {
+            self.root.clone()
+        } else {
+            self.root.clone() + &path
+        }
+    }
+
+    /// Namespace `kind` of process `pid` ("self" or a number), for /proc/[pid]/ns
+    fn namespace_of(&self, pid: &str, kind: &str) -> Result<Namespace, SysError> {
+        let ns = if pid == "self" {
+            self.ns.clone()
+        } else {
+            let pid: usize = pid.parse().map_err(|_| SysError::ENOENT)?;
+            let pid = self.ns.pid.global(pid).ok_or(SysError::ENOENT)?;
+            if pid == self.pid.get() {
+                self.ns.clone()
+            } else {
+                let proc = process(pid).ok_or(SysError::ENOENT)?;
+                let ns = proc.lock().ns.clone();
+                ns
+            }
+        };
+        ns.get(kind).ok_or(SysError::ENOENT)
+    }
 }
 
 /// Split a `path` str to `(base_path, file_name)`
@@ -1435,6 +2111,63 @@ fn split_path(path: &str) -> (&str, &str) {
     (dir_path, file_name)
 }
 
+/// The inotify instance of a descriptor, EINVAL if it is not one
+fn as_inotify(inode: &Arc<dyn INode>) -> Result<&InotifyINode, SysError> {
+    inode
+        .as_any_ref()
+        .downcast_ref::<InotifyINode>()
+        .ok_or(SysError::EINVAL)
+}
+
+/// The inode of a descriptor if it is a pipe
+fn pipe_of(file_like: &FileLike) -> Option<Arc<dyn INode>> {
+    match file_like {
+        FileLike::File(file) if file.inode().as_any_ref().is::<Pipe>() => Some(file.inode()),
+        _ => None,
+    }
+}
+
+fn as_pipe(inode: &Arc<dyn INode>) -> &Pipe {
+    inode.as_any_ref().downcast_ref::<Pipe>().unwrap()
+}
+
+/// Retry `op` on a pipe while it fails with Again, waiting for the pipe `file` to be
+/// readable unless it or the operation is non-blocking
+async fn wait_pipe<T>(
+    file: &FileLike,
+    nonblock: bool,
+    mut op: impl FnMut() -> Result<T, FsError>,
+) -> Result<T, SysError> {
+    let nonblock = match file {
+        FileLike::File(file) => nonblock || file.options().nonblock,
+        _ => nonblock,
+    };
+    loop {
+        match op() {
+            Err(FsError::Again) if !nonblock => {
+                file.async_poll().await?;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Whether `a` and `b` are the same file
+fn same_inode(a: &Arc<dyn INode>, b: &Arc<dyn INode>) -> bool {
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev == b.dev && a.inode == b.inode,
+        _ => false,
+    }
+}
+
+/// A file offset of splice, which is a 64-bit loff_t on every arch
+fn splice_offset(offset: i64) -> Result<usize, SysError> {
+    if offset < 0 {
+        return Err(SysError::EINVAL);
+    }
+    Ok(offset as usize)
+}
+
 impl From<FsError> for SysError {
     fn from(error: FsError) -> Self {
         match error {
@@ -1469,7 +2202,20 @@ bitflags! {
 }
 
 bitflags! {
-    struct OpenFlags: usize {
+    struct SpliceFlags: usize {
+        /// move pages instead of copying, only a hint
+        const MOVE = 1;
+        /// do not block on pipes
+        const NONBLOCK = 2;
+        /// more data follows in a later splice, only a hint
+        const MORE = 4;
+        /// pages of vmsplice are gifted to the pipe
+        const GIFT = 8;
+    }
+}
+
+bitflags! {
+    pub(super) struct OpenFlags: usize {
         /// read only
         const RDONLY = 0;
         /// write only
@@ -1490,11 +2236,11 @@ bitflags! {
 }
 
 impl OpenFlags {
-    fn readable(&self) -> bool {
+    pub(super) fn readable(&self) -> bool {
         let b = self.bits() & 0b11;
         b == OpenFlags::RDONLY.bits() || b == OpenFlags::RDWR.bits()
     }
-    fn writable(&self) -> bool {
+    pub(super) fn writable(&self) -> bool {
         let b = self.bits() & 0b11;
         b == OpenFlags::WRONLY.bits() || b == OpenFlags::RDWR.bits()
     }
@@ -1878,13 +2624,24 @@ const SEEK_SET: u8 = 0;
 const SEEK_CUR: u8 = 1;
 const SEEK_END: u8 = 2;
 
+// struct flock
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Flock {
+    type_: i16,
+    whence: i16,
+    start: i64,
+    len: i64,
+    pid: i32,
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
 pub struct IoVec {
     /// Starting address
-    base: *mut u8,
+    pub base: *mut u8,
     /// Number of bytes to transfer
-    len: usize,
+    pub len: usize,
 }
 
 /// A valid IoVecs request from user
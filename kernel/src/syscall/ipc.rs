@@ -4,50 +4,67 @@ use bitflags::*;
 
 pub use crate::ipc::*;
 
+use crate::arch::timer::timer_now;
 use crate::memory::GlobalFrameAlloc;
-use rcore_memory::memory_set::handler::{Shared, SharedGuard};
+use core::mem::size_of;
+use rcore_memory::memory_set::handler::Shared;
 use rcore_memory::memory_set::MemoryAttr;
-use rcore_memory::{PhysAddr, VirtAddr, PAGE_SIZE};
+use rcore_memory::{VirtAddr, PAGE_SIZE};
 
 use super::*;
 
+const IPC_RMID: usize = 0;
+const IPC_SET: usize = 1;
+const IPC_STAT: usize = 2;
+const IPC_INFO: usize = 3;
+/// Newer layout of the structures, which is the only one here
+const IPC_64: usize = 0x100;
+
 impl Syscall<'_> {
     pub fn sys_semget(&self, key: usize, nsems: usize, flags: usize) -> SysResult {
         info!("semget: key: {} nsems: {} flags: {:#x}", key, nsems, flags);
+        SemArray::get_or_create(key as u32, nsems, flags)
+    }
 
-        /// The maximum semaphores per semaphore set
-        const SEMMSL: usize = 256;
+    pub async fn sys_semop(&self, id: usize, ops: UserInPtr<SemBuf>, num_ops: usize) -> SysResult {
+        self.sys_semtimedop(id, ops, num_ops, 0).await
+    }
 
-        if nsems > SEMMSL {
+    pub async fn sys_semtimedop(
+        &self,
+        id: usize,
+        ops: UserInPtr<SemBuf>,
+        num_ops: usize,
+        timeout: usize,
+    ) -> SysResult {
+        info!(
+            "semtimedop: id: {}, num_ops: {}, timeout: {:#x}",
+            id, num_ops, timeout
+        );
+        if num_ops == 0 {
             return Err(SysError::EINVAL);
         }
-
-        let sem_array = SemArray::get_or_create(key as u32, nsems, flags)?;
-        let id = self.process().semaphores.add(sem_array);
-        Ok(id)
-    }
-
-    pub async fn sys_semop(&self, id: usize, ops: UserInPtr<SemBuf>, num_ops: usize) -> SysResult {
-        info!("semop: id: {}", id);
+        if num_ops > SEMOPM {
+            return Err(SysError::E2BIG);
+        }
         let ops = ops.read_array(num_ops)?;
-
-        let sem_array = self.process().semaphores.get(id).ok_or(SysError::EINVAL)?;
-        sem_array.otime();
-        for &SemBuf { num, op, flags } in ops.iter() {
-            let flags = SemFlags::from_bits_truncate(flags);
-            if flags.contains(SemFlags::IPC_NOWAIT) {
-                unimplemented!("Semaphore: semop.IPC_NOWAIT");
+        let deadline = if timeout != 0 {
+            let timeout: TimeSpec = UserInPtr::from(timeout).read()?;
+            if timeout.nsec >= 1_000_000_000 {
+                return Err(SysError::EINVAL);
             }
-            let sem = &sem_array[num as usize];
+            Some(timer_now() + timeout.to_duration())
+        } else {
+            None
+        };
 
-            let _result = match op {
-                1 => sem.release(),
-                -1 => sem.acquire().await?,
-                _ => unimplemented!("Semaphore: semop.(Not 1/-1)"),
-            };
-            sem.set_pid(self.process().pid.get());
-            if flags.contains(SemFlags::SEM_UNDO) {
-                self.process().semaphores.add_undo(id, num, op);
+        let sem_array = SemArray::get(id).ok_or(SysError::EINVAL)?;
+        let pid = self.process().pid.get();
+        sem_array.op(&self.thread, &ops, deadline, pid).await?;
+        let mut proc = self.process();
+        for op in ops.iter() {
+            if SemFlags::from_bits_truncate(op.flags).contains(SemFlags::SEM_UNDO) {
+                proc.semaphores.add_undo(id, op.num, op.op);
             }
         }
         Ok(0)
@@ -58,10 +75,6 @@ impl Syscall<'_> {
             "semctl: id: {}, num: {}, cmd: {} arg: {:#x}",
             id, num, cmd, arg
         );
-        let sem_array = self.process().semaphores.get(id).ok_or(SysError::EINVAL)?;
-        const IPC_RMID: usize = 0;
-        const IPC_SET: usize = 1;
-        const IPC_STAT: usize = 2;
         const GETPID: usize = 11;
         const GETVAL: usize = 12;
         const GETALL: usize = 13;
@@ -69,94 +82,295 @@ impl Syscall<'_> {
         const GETZCNT: usize = 15;
         const SETVAL: usize = 16;
         const SETALL: usize = 17;
+        const SEM_STAT: usize = 18;
+        const SEM_INFO: usize = 19;
+        const SEM_STAT_ANY: usize = 20;
 
+        // arg is union semun, passed by value
+        let cmd = cmd & !IPC_64;
+        match cmd {
+            IPC_INFO | SEM_INFO => {
+                let (info, max_index) = SemArray::info(cmd == SEM_INFO);
+                UserOutPtr::from(arg).write(info)?;
+                return Ok(max_index);
+            }
+            SEM_STAT | SEM_STAT_ANY => {
+                // id is an index here
+                let (id, sem_array) = SemArray::get_by_index(id).ok_or(SysError::EINVAL)?;
+                UserOutPtr::from(arg).write(sem_array.stat())?;
+                return Ok(id);
+            }
+            _ => {}
+        }
+
+        let sem_array = SemArray::get(id).ok_or(SysError::EINVAL)?;
+        let pid = self.process().pid.get();
         match cmd {
             IPC_RMID => {
-                sem_array.remove();
-                self.process().semaphores.remove(id);
+                SemArray::remove(id);
                 Ok(0)
             }
             IPC_SET => {
                 // arg is struct semid_ds
-                let ptr = UserInPtr::from(arg);
-                let ds: SemidDs = ptr.read()?;
-                // update IpcPerm
+                let ds: SemidDs = UserInPtr::from(arg).read()?;
                 sem_array.set(&ds);
-                sem_array.ctime();
                 Ok(0)
             }
             IPC_STAT => {
-                // arg is struct semid_ds
-                let mut ptr = UserOutPtr::from(arg);
-                ptr.write(*sem_array.semid_ds.lock())?;
+                UserOutPtr::from(arg).write(sem_array.stat())?;
                 Ok(0)
             }
-            _ => {
-                let sem = &sem_array[num as usize];
-                match cmd {
-                    GETPID => Ok(sem.get_pid()),
-                    GETVAL => Ok(sem.get() as usize),
-                    GETNCNT => Ok(sem.get_ncnt()),
-                    GETZCNT => Ok(0),
-                    SETVAL => {
-                        sem.set(arg as isize);
-                        sem.set_pid(self.process().pid.get());
-                        sem_array.ctime();
-                        Ok(0)
-                    }
-                    _ => unimplemented!("Semaphore Semctl cmd: {}", cmd),
-                }
+            GETPID => sem_array.get_pid(num),
+            GETVAL => sem_array.get_val(num),
+            GETNCNT => sem_array.get_ncnt(num),
+            GETZCNT => sem_array.get_zcnt(num),
+            GETALL => {
+                // arg is unsigned short *
+                UserOutPtr::<u16>::from(arg).write_array(&sem_array.get_all())?;
+                Ok(0)
             }
+            SETVAL => {
+                sem_array.set_val(num, arg as i32, pid)?;
+                Ok(0)
+            }
+            SETALL => {
+                let vals = UserInPtr::<u16>::from(arg).read_array(sem_array.nsems())?;
+                sem_array.set_all(&vals, pid)?;
+                Ok(0)
+            }
+            _ => Err(SysError::EINVAL),
         }
     }
 
-    pub fn sys_shmget(&self, key: usize, size: usize, shmflg: usize) -> SysResult {
-        info!("shmget: key: {}", key);
+    pub fn sys_msgget(&self, key: usize, flags: usize) -> SysResult {
+        info!("msgget: key: {}, flags: {:#x}", key, flags);
+        MsgQueue::get_or_create(key as u32, flags)
+    }
 
-        let shared_guard = ShmIdentifier::new_shared_guard(key, size);
-        let id = self.process().shm_identifiers.add(shared_guard);
-        Ok(id)
+    pub async fn sys_msgsnd(
+        &self,
+        id: usize,
+        msgp: usize,
+        msgsz: usize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "msgsnd: id: {}, msgp: {:#x}, msgsz: {}, flags: {:#x}",
+            id, msgp, msgsz, flags
+        );
+        if msgsz > MSGMAX {
+            return Err(SysError::EINVAL);
+        }
+        let queue = MsgQueue::get(id).ok_or(SysError::EINVAL)?;
+        // struct msgbuf { long mtype; char mtext[]; }
+        let mtype: isize = UserInPtr::from(msgp).read()?;
+        if mtype <= 0 {
+            return Err(SysError::EINVAL);
+        }
+        let data = UserInPtr::<u8>::from(msgp + size_of::<isize>()).read_array(msgsz)?;
+        let nowait = MsgFlags::from_bits_truncate(flags).contains(MsgFlags::IPC_NOWAIT);
+        let pid = self.process().pid.get();
+        queue.send(&self.thread, mtype, data, nowait, pid).await?;
+        Ok(0)
+    }
+
+    pub async fn sys_msgrcv(
+        &self,
+        id: usize,
+        msgp: usize,
+        msgsz: usize,
+        msgtyp: isize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "msgrcv: id: {}, msgp: {:#x}, msgsz: {}, msgtyp: {}, flags: {:#x}",
+            id, msgp, msgsz, msgtyp, flags
+        );
+        let flags = MsgFlags::from_bits_truncate(flags);
+        if (msgsz as isize) < 0 {
+            return Err(SysError::EINVAL);
+        }
+        let selector = match msgtyp {
+            0 => MsgSelector::First,
+            t if t > 0 && flags.contains(MsgFlags::MSG_EXCEPT) => MsgSelector::Except(t),
+            t if t > 0 => MsgSelector::Type(t),
+            // the lowest type not above |msgtyp|
+            t => MsgSelector::AtMost(t.checked_neg().unwrap_or(isize::max_value())),
+        };
+        let queue = MsgQueue::get(id).ok_or(SysError::EINVAL)?;
+        let pid = self.process().pid.get();
+        let (mtype, data) = queue
+            .receive(
+                &self.thread,
+                selector,
+                msgsz,
+                flags.contains(MsgFlags::IPC_NOWAIT),
+                flags.contains(MsgFlags::MSG_NOERROR),
+                pid,
+            )
+            .await?;
+        UserOutPtr::from(msgp).write(mtype)?;
+        UserOutPtr::<u8>::from(msgp + size_of::<isize>()).write_array(&data)?;
+        Ok(data.len())
+    }
+
+    pub fn sys_msgctl(&self, id: usize, cmd: usize, buf: usize) -> SysResult {
+        info!("msgctl: id: {}, cmd: {}, buf: {:#x}", id, cmd, buf);
+        const MSG_STAT: usize = 11;
+        const MSG_INFO: usize = 12;
+        const MSG_STAT_ANY: usize = 13;
+
+        let cmd = cmd & !IPC_64;
+        match cmd {
+            IPC_INFO | MSG_INFO => {
+                let (info, max_index) = MsgQueue::info(cmd == MSG_INFO);
+                UserOutPtr::from(buf).write(info)?;
+                return Ok(max_index);
+            }
+            MSG_STAT | MSG_STAT_ANY => {
+                // id is an index here
+                let (id, queue) = MsgQueue::get_by_index(id).ok_or(SysError::EINVAL)?;
+                UserOutPtr::from(buf).write(queue.stat())?;
+                return Ok(id);
+            }
+            _ => {}
+        }
+
+        let queue = MsgQueue::get(id).ok_or(SysError::EINVAL)?;
+        match cmd {
+            IPC_RMID => {
+                MsgQueue::remove(id);
+                Ok(0)
+            }
+            IPC_SET => {
+                // buf is struct msqid_ds
+                let ds: MsqidDs = UserInPtr::from(buf).read()?;
+                queue.set(&ds)?;
+                Ok(0)
+            }
+            IPC_STAT => {
+                UserOutPtr::from(buf).write(queue.stat())?;
+                Ok(0)
+            }
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    pub fn sys_shmget(&self, key: usize, size: usize, shmflg: usize) -> SysResult {
+        info!(
+            "shmget: key: {}, size: {}, shmflg: {:#x}",
+            key, size, shmflg
+        );
+        let pid = self.process().pid.get();
+        ShmSegment::get_or_create(key as u32, size, shmflg, pid)
     }
 
     pub fn sys_shmat(&self, id: usize, mut addr: VirtAddr, shmflg: usize) -> SysResult {
-        let mut shm_identifier = self
-            .process()
-            .shm_identifiers
-            .get(id)
-            .ok_or(SysError::EINVAL)?;
+        let flags = ShmFlags::from_bits_truncate(shmflg);
+        let segment = ShmSegment::get(id).ok_or(SysError::EINVAL)?;
+        let size = segment.size();
+        info!(
+            "shmat: id: {}, addr = {:#x}, size = {}, shmflg: {:#x}",
+            id, addr, size, shmflg
+        );
 
         let mut proc = self.process();
+        let mut vm = self.vm();
         if addr == 0 {
             // although NULL can be a valid address
             // but in C, NULL is regarded as allocation failure
-            // so just skip it
-            addr = PAGE_SIZE;
+            // so start from mmap base
+            addr = vm.find_free_area(proc.mmap_base, size);
+        } else {
+            if flags.contains(ShmFlags::SHM_RND) {
+                addr &= !(PAGE_SIZE - 1);
+            }
+            // attach exactly at addr
+            if addr % PAGE_SIZE != 0 || vm.find_free_area(addr, size) != addr {
+                return Err(SysError::EINVAL);
+            }
         }
-        let size = shm_identifier.shared_guard.lock().size;
-        info!("shmat: id: {}, addr = {:#x}, size = {}", id, addr, size);
-        addr = self.vm().find_free_area(addr, size);
-        self.vm().push(
+        let mut attr = MemoryAttr::default().user().shared();
+        if !flags.contains(ShmFlags::SHM_RDONLY) {
+            attr = attr.writable();
+        }
+        if flags.contains(ShmFlags::SHM_EXEC) {
+            attr = attr.execute();
+        }
+        vm.push(
             addr,
             addr + size,
-            MemoryAttr::default().user().execute().writable(),
-            Shared::new_with_guard(GlobalFrameAlloc, shm_identifier.shared_guard.clone()),
+            attr,
+            Shared::new_with_guard(GlobalFrameAlloc, segment.guard()),
             "shmat",
-        );
-        shm_identifier.addr = addr;
-        proc.shm_identifiers.set(id, shm_identifier);
-        //self.process().shmIdentifiers.setVirtAddr(id, addr);
-        return Ok(addr);
+        )?;
+        let pid = proc.pid.get();
+        proc.shm_identifiers.attach(addr, segment, pid);
+        Ok(addr)
     }
 
-    pub fn sys_shmdt(&self, id: usize, addr: VirtAddr, shmflg: usize) -> SysResult {
+    pub fn sys_shmdt(&self, addr: VirtAddr) -> SysResult {
         info!("shmdt: addr={:#x}", addr);
         let mut proc = self.process();
-        let opt_id = proc.shm_identifiers.get_id(addr);
-        if let Some(id) = opt_id {
-            proc.shm_identifiers.pop(id);
-        }
+        let pid = proc.pid.get();
+        let size = proc
+            .shm_identifiers
+            .detach(addr, pid)
+            .ok_or(SysError::EINVAL)?;
+        self.vm().pop_with_split(addr, addr + size);
         Ok(0)
     }
+
+    pub fn sys_shmctl(&self, id: usize, cmd: usize, buf: usize) -> SysResult {
+        info!("shmctl: id: {}, cmd: {}, buf: {:#x}", id, cmd, buf);
+        const SHM_LOCK: usize = 11;
+        const SHM_UNLOCK: usize = 12;
+        const SHM_STAT: usize = 13;
+        const SHM_INFO: usize = 14;
+        const SHM_STAT_ANY: usize = 15;
+
+        let cmd = cmd & !IPC_64;
+        match cmd {
+            IPC_INFO => {
+                let (info, max_index) = ShmSegment::info();
+                UserOutPtr::from(buf).write(info)?;
+                return Ok(max_index);
+            }
+            SHM_INFO => {
+                let (usage, max_index) = ShmSegment::usage();
+                UserOutPtr::from(buf).write(usage)?;
+                return Ok(max_index);
+            }
+            SHM_STAT | SHM_STAT_ANY => {
+                // id is an index here
+                let (id, segment) = ShmSegment::get_by_index(id).ok_or(SysError::EINVAL)?;
+                UserOutPtr::from(buf).write(segment.stat())?;
+                return Ok(id);
+            }
+            _ => {}
+        }
+
+        let segment = ShmSegment::get(id).ok_or(SysError::EINVAL)?;
+        match cmd {
+            IPC_RMID => {
+                segment.remove();
+                Ok(0)
+            }
+            IPC_SET => {
+                // buf is struct shmid_ds
+                let ds: ShmidDs = UserInPtr::from(buf).read()?;
+                segment.set(&ds);
+                Ok(0)
+            }
+            IPC_STAT => {
+                UserOutPtr::from(buf).write(segment.stat())?;
+                Ok(0)
+            }
+            // pages are never swapped out
+            SHM_LOCK | SHM_UNLOCK => Ok(0),
+            _ => Err(SysError::EINVAL),
+        }
+    }
 }
 
 /// An operation to be performed on a single semaphore
@@ -164,9 +378,9 @@ impl Syscall<'_> {
 /// Ref: [http://man7.org/linux/man-pages/man2/semop.2.html]
 #[repr(C)]
 pub struct SemBuf {
-    num: u16,
-    op: i16,
-    flags: i16,
+    pub num: u16,
+    pub op: i16,
+    pub flags: i16,
 }
 
 pub union SemctlUnion {
@@ -175,6 +389,28 @@ pub union SemctlUnion {
     array: usize, // short*, unimplemented
 } // unused
 
+bitflags! {
+    pub struct MsgFlags: usize {
+        /// Return immediately instead of blocking
+        const IPC_NOWAIT = 0o4000;
+        /// Truncate long messages in msgrcv
+        const MSG_NOERROR = 0o10000;
+        /// Receive the first message not of `msgtyp`
+        const MSG_EXCEPT = 0o20000;
+    }
+}
+
+bitflags! {
+    pub struct ShmFlags: usize {
+        /// Attach read-only
+        const SHM_RDONLY = 0o10000;
+        /// Round the address down to SHMLBA
+        const SHM_RND = 0o20000;
+        /// Allow executing the segment
+        const SHM_EXEC = 0o100000;
+    }
+}
+
 bitflags! {
     pub struct SemFlags: i16 {
         /// For SemOP
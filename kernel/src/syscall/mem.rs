@@ -28,10 +28,19 @@ impl Syscall<'_> {
         if addr == 0 {
             // although NULL can be a valid address
             // but in C, NULL is regarded as allocation failure
-            // so just skip it
-            addr = PAGE_SIZE;
+            // so start from mmap base, which is randomized unless ADDR_NO_RANDOMIZE
+            addr = proc.mmap_base;
         }
 
+        let writable_private = prot.contains(MmapProt::WRITE) && !flags.contains(MmapFlags::SHARED);
+        let replaced = if flags.contains(MmapFlags::FIXED) {
+            addr..addr + len
+        } else {
+            0..0
+        };
+        let data_len = if writable_private { len } else { 0 };
+        self.vm().check_limits(len, data_len, replaced)?;
+
         if flags.contains(MmapFlags::FIXED) {
             // we have to map it to addr, so remove the old mapping first
             self.vm().pop_with_split(addr, addr + len);
@@ -44,10 +53,10 @@ impl Syscall<'_> {
                 self.vm().push(
                     addr,
                     addr + len,
-                    prot.to_attr(),
+                    prot.to_attr().shared(),
                     Shared::new(GlobalFrameAlloc),
                     "mmap_anon_shared",
-                );
+                )?;
                 return Ok(addr);
             } else {
                 self.vm().push(
@@ -56,7 +65,7 @@ impl Syscall<'_> {
                     prot.to_attr(),
                     Delay::new(GlobalFrameAlloc),
                     "mmap_anon",
-                );
+                )?;
                 return Ok(addr);
             }
         } else {
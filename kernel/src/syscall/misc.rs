@@ -2,11 +2,19 @@
 
 use super::*;
 use crate::arch::cpu;
-use crate::consts::{ARCH, USER_STACK_SIZE};
+use crate::arch::timer::timer_now;
+use crate::consts::ARCH;
+use crate::process::futex::{get_futex, RobustListHead, FUTEX_BITSET_MATCH_ANY};
+use crate::process::rlimit::{OldRLimit, RLimit, RLIMIT_AS, RLIMIT_DATA, RLIM_NLIMITS};
+use crate::process::sched::{
+    self, SCHED_BATCH, SCHED_FIFO, SCHED_IDLE, SCHED_OTHER, SCHED_RESET_ON_FORK, SCHED_RR,
+};
+use crate::process::seccomp::{SECCOMP_MODE_FILTER, SECCOMP_MODE_STRICT};
 use crate::syscall::SysError::ETIMEDOUT;
 use crate::trap::TICK_ACTIVITY;
 use core::mem::size_of;
-use core::sync::atomic::{AtomicI32, Ordering};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
 
 impl Syscall<'_> {
     #[cfg(target_arch = "x86_64")]
@@ -22,11 +30,49 @@ impl Syscall<'_> {
         }
     }
 
+    pub fn sys_prctl(&mut self, option: usize, arg2: usize, arg3: usize) -> SysResult {
+        const PR_GET_SECCOMP: usize = 21;
+        const PR_SET_SECCOMP: usize = 22;
+        const PR_SET_NO_NEW_PRIVS: usize = 38;
+        const PR_GET_NO_NEW_PRIVS: usize = 39;
+        info!(
+            "prctl: option: {}, arg2: {:#x}, arg3: {:#x}",
+            option, arg2, arg3
+        );
+        match option {
+            PR_SET_NO_NEW_PRIVS => {
+                if arg2 != 1 {
+                    return Err(SysError::EINVAL);
+                }
+                self.thread.inner.lock().no_new_privs = true;
+                Ok(0)
+            }
+            PR_GET_NO_NEW_PRIVS => Ok(self.thread.inner.lock().no_new_privs as usize),
+            PR_GET_SECCOMP => Ok(self.thread.inner.lock().seccomp.mode()),
+            PR_SET_SECCOMP => match arg2 {
+                SECCOMP_MODE_STRICT => self.seccomp_set_strict(),
+                SECCOMP_MODE_FILTER => self.seccomp_add_filter(arg3, false),
+                _ => Err(SysError::EINVAL),
+            },
+            _ => self.unimplemented("prctl", Ok(0)),
+        }
+    }
+
     pub fn sys_uname(&mut self, buf: *mut u8) -> SysResult {
         info!("uname: buf: {:?}", buf);
 
         let offset = 65;
-        let strings = ["Linux", "orz", "0.1.0", "1", ARCH, "domain"];
+        let uts = self.process().ns.uts.clone();
+        let hostname = uts.hostname.read();
+        let domainname = uts.domainname.read();
+        let strings = [
+            "Linux",
+            hostname.as_str(),
+            "0.1.0",
+            "1",
+            ARCH,
+            domainname.as_str(),
+        ];
         let buf = unsafe { self.vm().check_write_array(buf, strings.len() * offset)? };
 
         for i in 0..strings.len() {
@@ -53,6 +99,87 @@ impl Syscall<'_> {
         Ok(0)
     }
 
+    /// Set the scheduling policy and priority of thread `pid`
+    pub fn sys_sched_setscheduler(
+        &mut self,
+        pid: usize,
+        policy: usize,
+        param: UserInPtr<SchedParam>,
+    ) -> SysResult {
+        info!(
+            "sched_setscheduler: pid: {}, policy: {}, param: {:?}",
+            pid, policy, param
+        );
+        let policy = policy & !SCHED_RESET_ON_FORK;
+        let priority = param.read()?.priority;
+        check_sched_param(policy, priority)?;
+        let thread = self.sched_thread(pid)?;
+        let mut inner = thread.inner.lock();
+        inner.sched_policy = policy;
+        inner.rt_priority = priority as usize;
+        drop(inner);
+        sched::update_priority(&thread);
+        Ok(0)
+    }
+
+    pub fn sys_sched_getscheduler(&mut self, pid: usize) -> SysResult {
+        info!("sched_getscheduler: pid: {}", pid);
+        let thread = self.sched_thread(pid)?;
+        let policy = thread.inner.lock().sched_policy;
+        Ok(policy)
+    }
+
+    /// Set the priority of thread `pid` in its policy
+    pub fn sys_sched_setparam(&mut self, pid: usize, param: UserInPtr<SchedParam>) -> SysResult {
+        info!("sched_setparam: pid: {}, param: {:?}", pid, param);
+        let priority = param.read()?.priority;
+        let thread = self.sched_thread(pid)?;
+        let mut inner = thread.inner.lock();
+        check_sched_param(inner.sched_policy, priority)?;
+        inner.rt_priority = priority as usize;
+        drop(inner);
+        sched::update_priority(&thread);
+        Ok(0)
+    }
+
+    pub fn sys_sched_getparam(
+        &mut self,
+        pid: usize,
+        mut param: UserOutPtr<SchedParam>,
+    ) -> SysResult {
+        info!("sched_getparam: pid: {}, param: {:?}", pid, param);
+        let thread = self.sched_thread(pid)?;
+        let priority = thread.inner.lock().rt_priority as i32;
+        param.write(SchedParam { priority })?;
+        Ok(0)
+    }
+
+    pub fn sys_sched_get_priority_max(&mut self, policy: usize) -> SysResult {
+        match policy {
+            SCHED_FIFO | SCHED_RR => Ok(99),
+            SCHED_OTHER | SCHED_BATCH | SCHED_IDLE => Ok(0),
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    pub fn sys_sched_get_priority_min(&mut self, policy: usize) -> SysResult {
+        match policy {
+            SCHED_FIFO | SCHED_RR => Ok(1),
+            SCHED_OTHER | SCHED_BATCH | SCHED_IDLE => Ok(0),
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    /// Thread `pid` of sched_* syscalls, 0 for the current one
+    fn sched_thread(&self, pid: usize) -> Result<Arc<Thread>, SysError> {
+        if pid == 0 {
+            return Ok(self.thread.clone());
+        }
+        let tid = self.global_pid(pid)?;
+        let thread = THREADS.read().get(&tid).cloned();
+        thread.ok_or(SysError::ESRCH)
+    }
+
     pub fn sys_sysinfo(&mut self, sys_info: *mut SysInfo) -> SysResult {
         let sys_info = unsafe { self.vm().check_write_ptr(sys_info)? };
 
@@ -66,48 +193,125 @@ impl Syscall<'_> {
         uaddr: usize,
         op: u32,
         val: i32,
-        timeout: UserInPtr<TimeSpec>,
+        timeout: usize,
+        uaddr2: usize,
+        val3: u32,
     ) -> SysResult {
         info!(
-            "futex: [{}] uaddr: {:#x}, op: {:#x}, val: {}, timeout_ptr: {:?}",
-            self.thread.tid, uaddr, op, val, timeout
+            "futex: [{}] uaddr: {:#x}, op: {:#x}, val: {}, timeout: {:#x}, uaddr2: {:#x}, val3: {:#x}",
+            self.thread.tid, uaddr, op, val, timeout, uaddr2, val3
         );
-        if op & OP_PRIVATE == 0 {
-            warn!("process-shared futex is unimplemented");
-        }
-        if uaddr % size_of::<u32>() != 0 {
-            return Err(SysError::EINVAL);
-        }
-        let atomic = unsafe { self.vm().check_write_ptr(uaddr as *mut AtomicI32)? };
-
         const OP_WAIT: u32 = 0;
         const OP_WAKE: u32 = 1;
+        const OP_REQUEUE: u32 = 3;
+        const OP_CMP_REQUEUE: u32 = 4;
+        const OP_WAKE_OP: u32 = 5;
+        const OP_LOCK_PI: u32 = 6;
+        const OP_UNLOCK_PI: u32 = 7;
+        const OP_TRYLOCK_PI: u32 = 8;
+        const OP_WAIT_BITSET: u32 = 9;
+        const OP_WAKE_BITSET: u32 = 10;
+        const OP_WAIT_REQUEUE_PI: u32 = 11;
+        const OP_CMP_REQUEUE_PI: u32 = 12;
+        const OP_LOCK_PI2: u32 = 13;
         const OP_PRIVATE: u32 = 0x80;
+        const OP_CLOCK_REALTIME: u32 = 0x100;
 
-        let mut proc = self.process();
-        let queue = proc.get_futex(uaddr);
+        let private = op & OP_PRIVATE != 0;
+        let cmd = op & !(OP_PRIVATE | OP_CLOCK_REALTIME);
+        if op & OP_CLOCK_REALTIME != 0
+            && ![OP_WAIT_BITSET, OP_WAIT_REQUEUE_PI, OP_LOCK_PI2].contains(&cmd)
+        {
+            return Err(SysError::ENOSYS);
+        }
+        let atomic = self.futex_word(uaddr)?;
+        let futex = get_futex(&self.thread, uaddr, private);
+        // the timeout argument is a count for requeue and wake_op
+        let val2 = timeout;
 
-        match op & 0xf {
-            OP_WAIT => {
-                if atomic.load(Ordering::Acquire) != val {
-                    return Err(SysError::EAGAIN);
+        match cmd {
+            OP_WAIT | OP_WAIT_BITSET => {
+                let bitset = if cmd == OP_WAIT {
+                    FUTEX_BITSET_MATCH_ANY
+                } else {
+                    val3
+                };
+                if bitset == 0 {
+                    return Err(SysError::EINVAL);
+                }
+                let deadline = futex_deadline(timeout, cmd == OP_WAIT)?;
+                futex
+                    .wait(bitset, deadline, &self.thread, || {
+                        atomic.load(Ordering::Acquire) == val as u32
+                    })
+                    .await?;
+                Ok(0)
+            }
+            OP_WAKE => Ok(futex.wake(val as usize)),
+            OP_WAKE_BITSET => {
+                if val3 == 0 {
+                    return Err(SysError::EINVAL);
+                }
+                Ok(futex.wake_bitset(val as usize, val3))
+            }
+            OP_REQUEUE | OP_CMP_REQUEUE => {
+                if (val as isize) < 0 || (val2 as isize) < 0 {
+                    return Err(SysError::EINVAL);
+                }
+                self.futex_word(uaddr2)?;
+                let target = get_futex(&self.thread, uaddr2, private);
+                let (woken, requeued) = futex.requeue(val as usize, val2, &target, || {
+                    cmd != OP_CMP_REQUEUE || atomic.load(Ordering::Acquire) == val3
+                })?;
+                // FUTEX_REQUEUE returns only the woken ones
+                if cmd == OP_CMP_REQUEUE {
+                    Ok(woken + requeued)
+                } else {
+                    Ok(woken)
+                }
+            }
+            OP_WAKE_OP => {
+                let atomic2 = self.futex_word(uaddr2)?;
+                let futex2 = get_futex(&self.thread, uaddr2, private);
+                let old = futex_atomic_op(atomic2, val3)?;
+                let mut woken = futex.wake(val as usize);
+                if futex_op_cmp(old, val3)? {
+                    woken += futex2.wake(val2);
                 }
-                // avoid deadlock
-                drop(proc);
-                if timeout.is_null() {
-                    queue.wait(None).await?;
-                    Ok(0)
+                Ok(woken)
+            }
+            OP_LOCK_PI | OP_LOCK_PI2 | OP_TRYLOCK_PI => {
+                let trylock = cmd == OP_TRYLOCK_PI;
+                let deadline = if trylock {
+                    None
                 } else {
-                    // TODO: timeout
-                    let timeout = timeout.read()?;
-                    info!("futex wait timeout: {:?}", timeout);
-                    queue.wait(Some(timeout.to_duration())).await?;
-                    Ok(0)
+                    futex_deadline(timeout, false)?
+                };
+                futex.lock_pi(atomic, &self.thread, deadline, trylock).await
+            }
+            OP_UNLOCK_PI => futex.unlock_pi(atomic, &self.thread),
+            OP_WAIT_REQUEUE_PI => {
+                if uaddr == uaddr2 {
+                    return Err(SysError::EINVAL);
                 }
+                let atomic2 = self.futex_word(uaddr2)?;
+                let deadline = futex_deadline(timeout, false)?;
+                futex
+                    .wait_requeue_pi(atomic2, deadline, &self.thread, || {
+                        atomic.load(Ordering::Acquire) == val as u32
+                    })
+                    .await
             }
-            OP_WAKE => {
-                let woken_up_count = queue.wake(val as usize);
-                Ok(woken_up_count)
+            OP_CMP_REQUEUE_PI => {
+                // only the first waiter can take the lock
+                if val != 1 || (val2 as isize) < 0 || uaddr == uaddr2 {
+                    return Err(SysError::EINVAL);
+                }
+                let atomic2 = self.futex_word(uaddr2)?;
+                let target = get_futex(&self.thread, uaddr2, private);
+                futex.requeue_pi(val2, &target, atomic2, || {
+                    atomic.load(Ordering::Acquire) == val3
+                })
             }
             _ => {
                 warn!("unsupported futex operation: {}", op);
@@ -116,6 +320,49 @@ impl Syscall<'_> {
         }
     }
 
+    /// The aligned futex word at `uaddr`, with its page faulted in
+    fn futex_word(&self, uaddr: usize) -> Result<&'static AtomicU32, SysError> {
+        if uaddr % size_of::<u32>() != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let atomic = unsafe { self.vm().check_write_ptr(uaddr as *mut AtomicU32)? };
+        // fault in the page to find its frame
+        atomic.load(Ordering::Relaxed);
+        Ok(atomic)
+    }
+
+    pub fn sys_set_robust_list(&mut self, head: usize, len: usize) -> SysResult {
+        info!("set_robust_list: head: {:#x}, len: {}", head, len);
+        if len != size_of::<RobustListHead>() {
+            return Err(SysError::EINVAL);
+        }
+        self.thread.inner.lock().robust_list = head;
+        Ok(0)
+    }
+
+    pub fn sys_get_robust_list(
+        &mut self,
+        pid: usize,
+        mut head_ptr: UserOutPtr<usize>,
+        mut len_ptr: UserOutPtr<usize>,
+    ) -> SysResult {
+        info!(
+            "get_robust_list: pid: {}, head_ptr: {:?}, len_ptr: {:?}",
+            pid, head_ptr, len_ptr
+        );
+        let head = if pid == 0 {
+            self.thread.inner.lock().robust_list
+        } else {
+            let tid = self.global_pid(pid)?;
+            let thread = THREADS.read().get(&tid).cloned().ok_or(SysError::ESRCH)?;
+            let head = thread.inner.lock().robust_list;
+            head
+        };
+        head_ptr.write(head)?;
+        len_ptr.write(size_of::<RobustListHead>())?;
+        Ok(0)
+    }
+
     pub fn sys_reboot(
         &mut self,
         _magic: u32,
@@ -147,40 +394,61 @@ impl Syscall<'_> {
             "prlimit64: pid: {}, resource: {}, new_limit: {:x?}, old_limit: {:x?}",
             pid, resource, new_limit, old_limit
         );
-        match resource {
-            RLIMIT_STACK => {
-                if !old_limit.is_null() {
-                    let old_limit = unsafe { self.vm().check_write_ptr(old_limit)? };
-                    *old_limit = RLimit {
-                        cur: USER_STACK_SIZE as u64,
-                        max: USER_STACK_SIZE as u64,
-                    };
-                }
-                Ok(0)
-            }
-            RLIMIT_NOFILE => {
-                if !old_limit.is_null() {
-                    let old_limit = unsafe { self.vm().check_write_ptr(old_limit)? };
-                    *old_limit = RLimit {
-                        cur: 1024,
-                        max: 1024,
-                    };
-                }
-                Ok(0)
+        let new_limit = if new_limit.is_null() {
+            None
+        } else {
+            Some(unsafe { *self.vm().check_read_ptr(new_limit)? })
+        };
+        let limit = self.prlimit(pid, resource, new_limit)?;
+        if !old_limit.is_null() {
+            let old_limit = unsafe { self.vm().check_write_ptr(old_limit)? };
+            *old_limit = limit;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_getrlimit(&mut self, resource: usize, old_limit: *mut OldRLimit) -> SysResult {
+        let old_limit = unsafe { self.vm().check_write_ptr(old_limit)? };
+        *old_limit = self.prlimit(0, resource, None)?.into();
+        Ok(0)
+    }
+
+    pub fn sys_setrlimit(&mut self, resource: usize, new_limit: *const OldRLimit) -> SysResult {
+        let new_limit = unsafe { *self.vm().check_read_ptr(new_limit)? };
+        self.prlimit(0, resource, Some(new_limit.into()))?;
+        Ok(0)
+    }
+
+    /// Set the limit of `resource` of process `pid` if `new_limit` is given,
+    /// and return the old limit
+    fn prlimit(
+        &mut self,
+        pid: usize,
+        resource: usize,
+        new_limit: Option<RLimit>,
+    ) -> Result<RLimit, SysError> {
+        if resource >= RLIM_NLIMITS {
+            return Err(SysError::EINVAL);
+        }
+        if let Some(new_limit) = new_limit {
+            if new_limit.cur > new_limit.max {
+                return Err(SysError::EINVAL);
             }
-            RLIMIT_RSS | RLIMIT_AS => {
-                if !old_limit.is_null() {
-                    let old_limit = unsafe { self.vm().check_write_ptr(old_limit)? };
-                    // 1GB
-                    *old_limit = RLimit {
-                        cur: 1024 * 1024 * 1024,
-                        max: 1024 * 1024 * 1024,
-                    };
-                }
-                Ok(0)
+        }
+        let proc = if pid == 0 {
+            self.thread.proc.clone()
+        } else {
+            process(self.global_pid(pid)?).ok_or(SysError::ESRCH)?
+        };
+        let mut proc = proc.lock();
+        let old_limit = proc.rlimits[resource];
+        if let Some(new_limit) = new_limit {
+            proc.rlimits[resource] = new_limit;
+            if resource == RLIMIT_AS || resource == RLIMIT_DATA {
+                proc.update_vm_limits();
             }
-            _ => Err(SysError::ENOSYS),
         }
+        Ok(old_limit)
     }
 
     pub fn sys_getrandom(&mut self, buf: *mut u8, len: usize, _flag: u32) -> SysResult {
@@ -225,14 +493,97 @@ pub struct SysInfo {
     mem_unit: u32,
 }
 
-const RLIMIT_STACK: usize = 3;
-const RLIMIT_RSS: usize = 5;
-const RLIMIT_NOFILE: usize = 7;
-const RLIMIT_AS: usize = 9;
+/// Deadline of futex `timeout`, relative or absolute since epoch
+fn futex_deadline(timeout: usize, relative: bool) -> Result<Option<Duration>, SysError> {
+    if timeout == 0 {
+        return Ok(None);
+    }
+    let timeout: TimeSpec = UserInPtr::from(timeout).read()?;
+    if timeout.nsec >= 1_000_000_000 {
+        return Err(SysError::EINVAL);
+    }
+    info!("futex timeout: {:?}", timeout);
+    // all clocks count from the epoch
+    if relative {
+        Ok(Some(timer_now() + timeout.to_duration()))
+    } else {
+        Ok(Some(timeout.to_deadline()))
+    }
+}
 
+/// Apply the operation encoded in `val3` of FUTEX_WAKE_OP to `atomic`,
+/// return the old value
+fn futex_atomic_op(atomic: &AtomicU32, val3: u32) -> Result<i32, SysError> {
+    const FUTEX_OP_SET: u32 = 0;
+    const FUTEX_OP_ADD: u32 = 1;
+    const FUTEX_OP_OR: u32 = 2;
+    const FUTEX_OP_ANDN: u32 = 3;
+    const FUTEX_OP_XOR: u32 = 4;
+    const FUTEX_OP_OPARG_SHIFT: u32 = 8;
+
+    let op = (val3 >> 28) & 0xf;
+    // sign extended 12 bits
+    let mut oparg = ((val3 << 8) as i32) >> 20;
+    if op & FUTEX_OP_OPARG_SHIFT != 0 {
+        oparg = 1 << (oparg & 31);
+    }
+    let apply = |old: i32| match op & !FUTEX_OP_OPARG_SHIFT {
+        FUTEX_OP_SET => Ok(oparg),
+        FUTEX_OP_ADD => Ok(old.wrapping_add(oparg)),
+        FUTEX_OP_OR => Ok(old | oparg),
+        FUTEX_OP_ANDN => Ok(old & !oparg),
+        FUTEX_OP_XOR => Ok(old ^ oparg),
+        _ => Err(SysError::ENOSYS),
+    };
+    let mut old = atomic.load(Ordering::Acquire);
+    loop {
+        let new = apply(old as i32)? as u32;
+        match atomic.compare_exchange(old, new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return Ok(old as i32),
+            Err(current) => old = current,
+        }
+    }
+}
+
+/// Compare `old` as encoded in `val3` of FUTEX_WAKE_OP
+fn futex_op_cmp(old: i32, val3: u32) -> Result<bool, SysError> {
+    const FUTEX_OP_CMP_EQ: u32 = 0;
+    const FUTEX_OP_CMP_NE: u32 = 1;
+    const FUTEX_OP_CMP_LT: u32 = 2;
+    const FUTEX_OP_CMP_LE: u32 = 3;
+    const FUTEX_OP_CMP_GT: u32 = 4;
+    const FUTEX_OP_CMP_GE: u32 = 5;
+
+    // sign extended 12 bits
+    let cmparg = ((val3 << 20) as i32) >> 20;
+    match (val3 >> 24) & 0xf {
+        FUTEX_OP_CMP_EQ => Ok(old == cmparg),
+        FUTEX_OP_CMP_NE => Ok(old != cmparg),
+        FUTEX_OP_CMP_LT => Ok(old < cmparg),
+        FUTEX_OP_CMP_LE => Ok(old <= cmparg),
+        FUTEX_OP_CMP_GT => Ok(old > cmparg),
+        FUTEX_OP_CMP_GE => Ok(old >= cmparg),
+        _ => Err(SysError::ENOSYS),
+    }
+}
+
+// struct sched_param
 #[repr(C)]
-#[derive(Debug, Default)]
-pub struct RLimit {
-    cur: u64, // soft limit
-    max: u64, // hard limit
+#[derive(Debug, Clone, Copy)]
+pub struct SchedParam {
+    pub priority: i32,
+}
+
+/// Priorities are 1 to 99 for real-time policies, 0 for others
+fn check_sched_param(policy: usize, priority: i32) -> Result<(), SysError> {
+    let valid = match policy {
+        SCHED_FIFO | SCHED_RR => (1..=99).contains(&priority),
+        SCHED_OTHER | SCHED_BATCH | SCHED_IDLE => priority == 0,
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(SysError::EINVAL)
+    }
 }
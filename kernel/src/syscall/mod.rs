@@ -3,7 +3,12 @@
 use crate::arch::cpu;
 use crate::arch::syscall::*;
 use crate::fs::epoll::EpollEvent;
+use crate::fs::MqAttr;
 use crate::memory::{copy_from_user, MemorySet};
+use crate::process::ptrace::{
+    ptrace_event_stop, ptrace_syscall_stop, PtraceStop, PTRACE_EVENT_EXIT,
+};
+use crate::process::rlimit::{OldRLimit, RLimit};
 use crate::process::*;
 use crate::signal::{Signal, SignalAction, SignalFrame, SignalStack, SignalUserContext, Sigset};
 use crate::sync::{Condvar, MutexGuard, SpinNoIrq};
@@ -23,6 +28,7 @@ pub use self::ipc::*;
 pub use self::lkm::*;
 pub use self::mem::*;
 pub use self::misc::*;
+pub use self::mqueue::*;
 pub use self::net::*;
 pub use self::proc::*;
 pub use self::signal::*;
@@ -35,8 +41,12 @@ mod ipc;
 mod lkm;
 mod mem;
 mod misc;
+mod mqueue;
+mod namespace;
 mod net;
 mod proc;
+mod ptrace;
+mod seccomp;
 mod signal;
 mod time;
 mod user;
@@ -53,10 +63,6 @@ lazy_static! {
 
 /// System call dispatcher
 pub async fn handle_syscall(thread: &Arc<Thread>, context: &mut UserContext) -> bool {
-    let regs = &context.general;
-    let num = context.get_syscall_num();
-    let args = context.get_syscall_args();
-
     // add before fork
     #[cfg(riscv)]
     {
@@ -67,14 +73,35 @@ pub async fn handle_syscall(thread: &Arc<Thread>, context: &mut UserContext) ->
         context.epc = context.epc + 4;
     }
 
-    let mut syscall = Syscall {
-        thread,
-        context,
-        exit: false,
+    // the tracer may change the syscall number and arguments here
+    ptrace_syscall_stop(thread, context, PtraceStop::SyscallEntry).await;
+    let num = context.get_syscall_num();
+    let args = context.get_syscall_args();
+
+    if (num == SYS_EXIT || num == SYS_EXIT_GROUP)
+        && thread.ptrace_event(PTRACE_EVENT_EXIT, (args[0] & 0xff) << 8)
+    {
+        ptrace_event_stop(thread, context).await;
+    }
+
+    // a syscall number of -1 set by the tracer skips the syscall
+    let (ret, exit) = if num == usize::max_value() {
+        (-(SysError::ENOSYS as isize), false)
+    } else {
+        let mut syscall = Syscall {
+            thread,
+            context,
+            exit: false,
+        };
+        let ret = syscall.syscall(num, args).await;
+        (ret, syscall.exit)
     };
-    let ret = syscall.syscall(num, args).await;
-    let exit = syscall.exit;
     context.set_syscall_ret(ret as usize);
+
+    if !exit {
+        ptrace_event_stop(thread, context).await;
+        ptrace_syscall_stop(thread, context, PtraceStop::SyscallExit).await;
+    }
     exit
 }
 
@@ -113,6 +140,17 @@ impl Syscall<'_> {
             debug!("{}:{}:{} syscall id {} begin", cid, pid, tid, id);
         }
 
+        // seccomp filters may forbid the syscall, and a tracer they ask may change it
+        let (id, args) = match self.seccomp_check(id, args).await {
+            Ok(syscall) => syscall,
+            Err(ret) => {
+                if !pid.is_init() {
+                    info!("=> {:x?} by seccomp", ret);
+                }
+                return ret;
+            }
+        };
+
         // use platform-specific syscal numbers
         // See https://filippo.io/linux-syscall-table/
         // And https://fedora.juszkiewicz.com.pl/syscalls.html.
@@ -145,8 +183,8 @@ impl Syscall<'_> {
                 self.sys_sendfile(args[0], args[1], UserInOutPtr::from(args[2]), args[3])
                     .await
             }
-            SYS_FCNTL => self.sys_fcntl(args[0], args[1], args[2]),
-            SYS_FLOCK => self.sys_flock(args[0], args[1]),
+            SYS_FCNTL => self.sys_fcntl(args[0], args[1], args[2]).await,
+            SYS_FLOCK => self.sys_flock(args[0], args[1]).await,
             SYS_FSYNC => self.sys_fsync(args[0]),
             SYS_FDATASYNC => self.sys_fdatasync(args[0]),
             SYS_TRUNCATE => self.sys_truncate(args[0] as *const u8, args[1]),
@@ -179,8 +217,12 @@ impl Syscall<'_> {
             SYS_FACCESSAT => self.sys_faccessat(args[0], args[1] as *const u8, args[2], args[3]),
             SYS_DUP3 => self.sys_dup3(args[0], args[1], args[2]),
             SYS_PIPE2 => self.sys_pipe2(args[0] as *mut u32, args[1]), // TODO: handle `flags`
-            SYS_SET_ROBUST_LIST => self.unimplemented("set_robuts_list", Ok(0)),
-            SYS_GET_ROBUST_LIST => self.unimplemented("get_robust_list", Ok(0)),
+            SYS_SET_ROBUST_LIST => self.sys_set_robust_list(args[0], args[1]),
+            SYS_GET_ROBUST_LIST => self.sys_get_robust_list(
+                args[0],
+                UserOutPtr::from(args[1]),
+                UserOutPtr::from(args[2]),
+            ),
             SYS_UTIMENSAT => self.sys_utimensat(
                 args[0],
                 args[1] as *const u8,
@@ -198,6 +240,22 @@ impl Syscall<'_> {
                 )
                 .await
             }
+            SYS_SPLICE => {
+                self.sys_splice(
+                    args[0],
+                    UserInOutPtr::from(args[1]),
+                    args[2],
+                    UserInOutPtr::from(args[3]),
+                    args[4],
+                    args[5],
+                )
+                .await
+            }
+            SYS_TEE => self.sys_tee(args[0], args[1], args[2], args[3]).await,
+            SYS_VMSPLICE => {
+                self.sys_vmsplice(args[0], UserInPtr::from(args[1]), args[2], args[3])
+                    .await
+            }
 
             // io multiplexing
             SYS_PSELECT6 => self.sys_pselect6(
@@ -228,14 +286,27 @@ impl Syscall<'_> {
                 args[4],
             ),
             SYS_EVENTFD2 => self.unimplemented("eventfd2", Err(SysError::EACCES)),
+            SYS_INOTIFY_INIT1 => self.sys_inotify_init1(args[0]),
+            SYS_INOTIFY_ADD_WATCH => {
+                self.sys_inotify_add_watch(args[0], args[1] as *const u8, args[2] as u32)
+            }
+            SYS_INOTIFY_RM_WATCH => self.sys_inotify_rm_watch(args[0], args[1]),
 
-            SYS_SOCKETPAIR => self.unimplemented("socketpair", Err(SysError::EACCES)),
+            SYS_SOCKETPAIR => self.sys_socketpair(args[0], args[1], args[2], args[3] as *mut u32),
             // file system
             SYS_STATFS => self.unimplemented("statfs", Err(SysError::EACCES)),
             SYS_FSTATFS => self.unimplemented("fstatfs", Err(SysError::EACCES)),
             SYS_SYNC => self.sys_sync(),
-            SYS_MOUNT => self.unimplemented("mount", Err(SysError::EACCES)),
-            SYS_UMOUNT2 => self.unimplemented("umount2", Err(SysError::EACCES)),
+            SYS_MOUNT => self.sys_mount(
+                args[0] as *const u8,
+                args[1] as *const u8,
+                args[2] as *const u8,
+                args[3],
+                args[4],
+            ),
+            SYS_UMOUNT2 => self.sys_umount2(args[0] as *const u8, args[1]),
+            SYS_CHROOT => self.sys_chroot(args[0] as *const u8),
+            SYS_PIVOT_ROOT => self.sys_pivot_root(args[0] as *const u8, args[1] as *const u8),
 
             // memory
             SYS_BRK => self.unimplemented("brk", Err(SysError::ENOMEM)),
@@ -268,30 +339,64 @@ impl Syscall<'_> {
             SYS_SCHED_GETAFFINITY => {
                 self.sys_sched_getaffinity(args[0], args[1], args[2] as *mut u32)
             }
+            SYS_SCHED_SETSCHEDULER => {
+                self.sys_sched_setscheduler(args[0], args[1], UserInPtr::from(args[2]))
+            }
+            SYS_SCHED_GETSCHEDULER => self.sys_sched_getscheduler(args[0]),
+            SYS_SCHED_SETPARAM => self.sys_sched_setparam(args[0], UserInPtr::from(args[1])),
+            SYS_SCHED_GETPARAM => self.sys_sched_getparam(args[0], UserOutPtr::from(args[1])),
+            SYS_SCHED_GET_PRIORITY_MAX => self.sys_sched_get_priority_max(args[0]),
+            SYS_SCHED_GET_PRIORITY_MIN => self.sys_sched_get_priority_min(args[0]),
 
             // socket
             SYS_SOCKET => self.sys_socket(args[0], args[1], args[2]),
-            SYS_CONNECT => self.sys_connect(args[0], args[1] as *const SockAddr, args[2]),
-            SYS_ACCEPT => self.sys_accept(args[0], args[1] as *mut SockAddr, args[2] as *mut u32),
-            SYS_ACCEPT4 => self.sys_accept(args[0], args[1] as *mut SockAddr, args[2] as *mut u32), // use accept for accept4
-            SYS_SENDTO => self.sys_sendto(
-                args[0],
-                args[1] as *const u8,
-                args[2],
-                args[3],
-                args[4] as *const SockAddr,
-                args[5],
-            ),
-            SYS_RECVFROM => self.sys_recvfrom(
-                args[0],
-                args[1] as *mut u8,
-                args[2],
-                args[3],
-                args[4] as *mut SockAddr,
-                args[5] as *mut u32,
-            ),
-            //        SYS_SENDMSG => self.sys_sendmsg(),
-            SYS_RECVMSG => self.sys_recvmsg(args[0], args[1] as *mut MsgHdr, args[2]),
+            SYS_CONNECT => {
+                self.sys_connect(args[0], args[1] as *const SockAddr, args[2])
+                    .await
+            }
+            SYS_ACCEPT => {
+                self.sys_accept(args[0], args[1] as *mut SockAddr, args[2] as *mut u32, 0)
+                    .await
+            }
+            SYS_ACCEPT4 => {
+                self.sys_accept(
+                    args[0],
+                    args[1] as *mut SockAddr,
+                    args[2] as *mut u32,
+                    args[3],
+                )
+                .await
+            }
+            SYS_SENDTO => {
+                self.sys_sendto(
+                    args[0],
+                    args[1] as *const u8,
+                    args[2],
+                    args[3],
+                    args[4] as *const SockAddr,
+                    args[5],
+                )
+                .await
+            }
+            SYS_RECVFROM => {
+                self.sys_recvfrom(
+                    args[0],
+                    args[1] as *mut u8,
+                    args[2],
+                    args[3],
+                    args[4] as *mut SockAddr,
+                    args[5] as *mut u32,
+                )
+                .await
+            }
+            SYS_SENDMSG => {
+                self.sys_sendmsg(args[0], args[1] as *const MsgHdr, args[2])
+                    .await
+            }
+            SYS_RECVMSG => {
+                self.sys_recvmsg(args[0], args[1] as *mut MsgHdr, args[2])
+                    .await
+            }
             SYS_SHUTDOWN => self.sys_shutdown(args[0], args[1]),
             SYS_BIND => self.sys_bind(args[0], args[1] as *const SockAddr, args[2]),
             SYS_LISTEN => self.sys_listen(args[0], args[1]),
@@ -328,16 +433,19 @@ impl Syscall<'_> {
             SYS_EXIT => self.sys_exit(args[0] as usize),
             SYS_EXIT_GROUP => self.sys_exit_group(args[0]),
             SYS_WAIT4 => {
-                self.sys_wait4(args[0] as isize, UserInOutPtr::from(args[1]))
+                self.sys_wait4(args[0] as isize, UserInOutPtr::from(args[1]), args[2])
                     .await
             } // TODO: wait4
             SYS_SET_TID_ADDRESS => self.sys_set_tid_address(args[0] as *mut u32),
+            SYS_PERSONALITY => self.sys_personality(args[0]),
             SYS_FUTEX => {
                 self.sys_futex(
                     args[0],
                     args[1] as u32,
                     args[2] as i32,
-                    UserInPtr::from(args[3]),
+                    args[3],
+                    args[4],
+                    args[5] as u32,
                 )
                 .await
             }
@@ -360,13 +468,58 @@ impl Syscall<'_> {
                     .await
             }
             #[cfg(not(target_arch = "mips"))]
+            SYS_SEMTIMEDOP => {
+                self.sys_semtimedop(args[0], UserInPtr::from(args[1]), args[2], args[3])
+                    .await
+            }
+            #[cfg(not(target_arch = "mips"))]
             SYS_SEMCTL => self.sys_semctl(args[0], args[1], args[2], args[3]),
 
             // msg
             #[cfg(not(target_arch = "mips"))]
-            SYS_MSGGET => self.unimplemented("msgget", Ok(0)),
+            SYS_MSGGET => self.sys_msgget(args[0], args[1]),
             #[cfg(not(target_arch = "mips"))]
-            SYS_MSGCTL => self.unimplemented("msgctl", Ok(0)),
+            SYS_MSGSND => self.sys_msgsnd(args[0], args[1], args[2], args[3]).await,
+            #[cfg(not(target_arch = "mips"))]
+            SYS_MSGRCV => {
+                self.sys_msgrcv(args[0], args[1], args[2], args[3] as isize, args[4])
+                    .await
+            }
+            #[cfg(not(target_arch = "mips"))]
+            SYS_MSGCTL => self.sys_msgctl(args[0], args[1], args[2]),
+
+            // mqueue
+            SYS_MQ_OPEN => self.sys_mq_open(
+                args[0] as *const u8,
+                args[1],
+                args[2],
+                args[3] as *const MqAttr,
+            ),
+            SYS_MQ_UNLINK => self.sys_mq_unlink(args[0] as *const u8),
+            SYS_MQ_TIMEDSEND => {
+                self.sys_mq_timedsend(
+                    args[0],
+                    args[1] as *const u8,
+                    args[2],
+                    args[3],
+                    args[4] as *const TimeSpec,
+                )
+                .await
+            }
+            SYS_MQ_TIMEDRECEIVE => {
+                self.sys_mq_timedreceive(
+                    args[0],
+                    args[1] as *mut u8,
+                    args[2],
+                    args[3] as *mut u32,
+                    args[4] as *const TimeSpec,
+                )
+                .await
+            }
+            SYS_MQ_NOTIFY => self.sys_mq_notify(args[0], args[1] as *const SigEvent),
+            SYS_MQ_GETSETATTR => {
+                self.sys_mq_getsetattr(args[0], args[1] as *const MqAttr, args[2] as *mut MqAttr)
+            }
 
             // shm
             #[cfg(not(target_arch = "mips"))]
@@ -374,14 +527,20 @@ impl Syscall<'_> {
             #[cfg(not(target_arch = "mips"))]
             SYS_SHMAT => self.sys_shmat(args[0], args[1], args[2]),
             #[cfg(not(target_arch = "mips"))]
-            SYS_SHMDT => self.sys_shmdt(args[0], args[1], args[2]),
+            SYS_SHMDT => self.sys_shmdt(args[0]),
+            #[cfg(not(target_arch = "mips"))]
+            SYS_SHMCTL => self.sys_shmctl(args[0], args[1], args[2]),
             // system
             SYS_GETPID => self.sys_getpid(),
             SYS_GETTID => self.sys_gettid(),
             SYS_UNAME => self.sys_uname(args[0] as *mut u8),
+            SYS_SETHOSTNAME => self.sys_sethostname(args[0] as *const u8, args[1]),
+            SYS_SETDOMAINNAME => self.sys_setdomainname(args[0] as *const u8, args[1]),
+            SYS_UNSHARE => self.sys_unshare(args[0]),
+            SYS_SETNS => self.sys_setns(args[0], args[1]),
             SYS_UMASK => self.unimplemented("umask", Ok(0o777)),
-            //        SYS_GETRLIMIT => self.sys_getrlimit(),
-            SYS_SETRLIMIT => self.unimplemented("setrlimit", Ok(0)),
+            SYS_GETRLIMIT => self.sys_getrlimit(args[0], args[1] as *mut OldRLimit),
+            SYS_SETRLIMIT => self.sys_setrlimit(args[0], args[1] as *const OldRLimit),
             SYS_GETRUSAGE => self.sys_getrusage(args[0], args[1] as *mut RUsage),
             SYS_SYSINFO => self.sys_sysinfo(args[0] as *mut SysInfo),
             SYS_TIMES => self.sys_times(args[0] as *mut Tms),
@@ -401,8 +560,10 @@ impl Syscall<'_> {
             SYS_SETRESGID => self.unimplemented("setresgid", Ok(0)),
             SYS_SETGID => self.unimplemented("setgid", Ok(0)),
             SYS_SETPRIORITY => self.sys_set_priority(args[0]),
-            SYS_PRCTL => self.unimplemented("prctl", Ok(0)),
+            SYS_PRCTL => self.sys_prctl(args[0], args[1], args[2]),
+            SYS_PTRACE => self.sys_ptrace(args[0], args[1], args[2], args[3]),
             SYS_MEMBARRIER => self.unimplemented("membarrier", Ok(0)),
+            SYS_SECCOMP => self.sys_seccomp(args[0], args[1], args[2]),
             SYS_PRLIMIT64 => self.sys_prlimit64(
                 args[0],
                 args[1],
@@ -514,19 +675,50 @@ impl Syscall<'_> {
                 self.context.tls = args[0];
                 Ok(0)
             }
-            SYS_IPC => match args[0] {
+            // ipc(call, first, second, third, ptr, fifth)
+            SYS_IPC => match args[0] & 0xffff {
                 1 => {
                     self.sys_semop(args[1], UserInPtr::from(args[2]), args[3])
                         .await
                 }
                 2 => self.sys_semget(args[1], args[2], args[3]),
                 3 => self.sys_semctl(args[1], args[2], args[3], args[4]),
+                4 => {
+                    self.sys_semtimedop(args[1], UserInPtr::from(args[4]), args[2], args[5])
+                        .await
+                }
+                11 => self.sys_msgsnd(args[1], args[4], args[2], args[3]).await,
+                12 => {
+                    // version 0 passes struct ipc_kludge { msgp, msgtyp } in ptr
+                    let (msgp, msgtyp) = if args[0] >> 16 == 0 {
+                        match UserInPtr::<[usize; 2]>::from(args[4]).read() {
+                            Ok([msgp, msgtyp]) => (msgp, msgtyp),
+                            Err(err) => return Some(Err(err)),
+                        }
+                    } else {
+                        (args[4], args[5])
+                    };
+                    self.sys_msgrcv(args[1], msgp, args[2], msgtyp as isize, args[3])
+                        .await
+                }
+                13 => self.sys_msgget(args[1], args[2]),
+                14 => self.sys_msgctl(args[1], args[2], args[4]),
+                21 => {
+                    // the address is returned in *third
+                    self.sys_shmat(args[1], args[4], args[2])
+                        .and_then(|addr| UserOutPtr::<usize>::from(args[3]).write(addr))
+                        .map(|_| 0)
+                }
+                22 => self.sys_shmdt(args[4]),
+                23 => self.sys_shmget(args[1], args[2], args[3]),
+                24 => self.sys_shmctl(args[1], args[2], args[4]),
                 _ => return None,
             },
             SYS_EPOLL_CREATE => self.sys_epoll_create(args[0]),
             SYS_EPOLL_WAIT => {
                 self.sys_epoll_wait(args[0], args[1] as *mut EpollEvent, args[2], args[3])
             }
+            SYS_INOTIFY_INIT => self.sys_inotify_init(),
 
             _ => return None,
         };
@@ -571,6 +763,7 @@ impl Syscall<'_> {
             SYS_EPOLL_WAIT => {
                 self.sys_epoll_wait(args[0], args[1] as *mut EpollEvent, args[2], args[3])
             }
+            SYS_INOTIFY_INIT => self.sys_inotify_init(),
             _ => return None,
         };
         Some(ret)
@@ -624,16 +817,26 @@ pub enum SysError {
     ENOSYS = 38,
     ENOTEMPTY = 39,
     ELOOP = 40,
+    ENOMSG = 42,
     EIDRM = 43,
     ENOTSOCK = 80,
+    EDESTADDRREQ = 89,
+    EMSGSIZE = 90,
     ENOPROTOOPT = 92,
+    EPROTONOSUPPORT = 93,
+    EOPNOTSUPP = 95,
     EPFNOSUPPORT = 96,
     EAFNOSUPPORT = 97,
+    EADDRINUSE = 98,
+    EADDRNOTAVAIL = 99,
+    ENETUNREACH = 101,
     ENOBUFS = 105,
     EISCONN = 106,
     ENOTCONN = 107,
     ETIMEDOUT = 110,
     ECONNREFUSED = 111,
+    EALREADY = 114,
+    EINPROGRESS = 115,
 }
 
 #[allow(non_snake_case)]
@@ -684,14 +887,25 @@ impl fmt::Display for SysError {
                 ENOSYS => "Function not implemented",
                 ENOTEMPTY => "Directory not empty",
                 ELOOP => "Too many symbolic links encountered",
+                ENOMSG => "No message of desired type",
+                EIDRM => "Identifier removed",
                 ENOTSOCK => "Socket operation on non-socket",
+                EDESTADDRREQ => "Destination address required",
+                EMSGSIZE => "Message too long",
                 ENOPROTOOPT => "Protocol not available",
+                EPROTONOSUPPORT => "Protocol not supported",
+                EOPNOTSUPP => "Operation not supported on transport endpoint",
                 EPFNOSUPPORT => "Protocol family not supported",
                 EAFNOSUPPORT => "Address family not supported by protocol",
+                EADDRINUSE => "Address already in use",
+                EADDRNOTAVAIL => "Cannot assign requested address",
+                ENETUNREACH => "Network is unreachable",
                 ENOBUFS => "No buffer space available",
                 EISCONN => "Transport endpoint is already connected",
                 ENOTCONN => "Transport endpoint is not connected",
                 ECONNREFUSED => "Connection refused",
+                EALREADY => "Operation already in progress",
+                EINPROGRESS => "Operation now in progress",
                 _ => "Unknown error",
             },
         )
@@ -699,8 +913,11 @@ impl fmt::Display for SysError {
 }
 
 impl From<VMError> for SysError {
-    fn from(_: VMError) -> Self {
-        SysError::EFAULT
+    fn from(error: VMError) -> Self {
+        match error {
+            VMError::InvalidPtr => SysError::EFAULT,
+            VMError::NoMem => SysError::ENOMEM,
+        }
     }
 }
 
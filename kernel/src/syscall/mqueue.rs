@@ -0,0 +1,225 @@
+//! Syscalls for POSIX message queues
+
+use super::*;
+use crate::fs::fcntl::O_NONBLOCK;
+use crate::fs::{FileHandle, FileLike, MqNotify, MqueueINode, OpenOptions, MQUEUE_FS};
+use crate::process::rlimit::RLIMIT_MSGQUEUE;
+use alloc::format;
+use core::time::Duration;
+
+/// Notify with a signal
+const SIGEV_SIGNAL: i32 = 0;
+/// Register without notifying
+const SIGEV_NONE: i32 = 1;
+
+// struct sigevent
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SigEvent {
+    pub value: usize,
+    pub signo: i32,
+    pub notify: i32,
+    /// Thread id of SIGEV_THREAD_ID, or function and attributes of SIGEV_THREAD
+    _pad: [usize; 6],
+}
+
+impl Syscall<'_> {
+    pub fn sys_mq_open(
+        &mut self,
+        name: *const u8,
+        oflag: usize,
+        mode: usize,
+        attr: *const MqAttr,
+    ) -> SysResult {
+        let name = check_and_clone_cstr(name)?;
+        let flags = OpenFlags::from_bits_truncate(oflag);
+        info!(
+            "mq_open: name: {:?}, flags: {:?}, mode: {:#o}, attr: {:?}",
+            name, flags, mode, attr
+        );
+        if !name.starts_with('/') || oflag & 0b11 == 0b11 {
+            return Err(SysError::EINVAL);
+        }
+        let name = &name[1..];
+        let queue = match MQUEUE_FS.find(name) {
+            Ok(queue) => {
+                if flags.contains(OpenFlags::CREATE) && flags.contains(OpenFlags::EXCLUSIVE) {
+                    return Err(SysError::EEXIST);
+                }
+                queue
+            }
+            Err(_) if flags.contains(OpenFlags::CREATE) => {
+                let attr = if attr.is_null() {
+                    None
+                } else {
+                    Some(UserInPtr::<MqAttr>::from(attr as usize).read()?)
+                };
+                let limit = self.process().rlimit(RLIMIT_MSGQUEUE);
+                MQUEUE_FS.create(name, mode as u32, attr, limit)?
+            }
+            Err(_) => return Err(SysError::ENOENT),
+        };
+        let file = FileHandle::new(
+            queue,
+            OpenOptions {
+                read: flags.readable(),
+                write: flags.writable(),
+                append: false,
+                nonblock: oflag & O_NONBLOCK != 0,
+            },
+            format!("/dev/mqueue/{}", name),
+            false,
+            flags.contains(OpenFlags::CLOEXEC),
+        );
+        let fd = self.process().add_file(FileLike::File(file))?;
+        Ok(fd)
+    }
+
+    pub fn sys_mq_unlink(&mut self, name: *const u8) -> SysResult {
+        let name = check_and_clone_cstr(name)?;
+        info!("mq_unlink: name: {:?}", name);
+        if !name.starts_with('/') {
+            return Err(SysError::EINVAL);
+        }
+        MQUEUE_FS.unlink(&name[1..])?;
+        Ok(0)
+    }
+
+    pub async fn sys_mq_timedsend(
+        &mut self,
+        mqdes: usize,
+        msg: *const u8,
+        len: usize,
+        prio: usize,
+        timeout: *const TimeSpec,
+    ) -> SysResult {
+        info!(
+            "mq_timedsend: mqdes: {}, msg: {:?}, len: {}, prio: {}",
+            mqdes, msg, len, prio
+        );
+        let file = self.process().get_file(mqdes)?.clone();
+        let inode = file.inode();
+        let queue = as_mqueue(&inode)?;
+        let options = file.options();
+        if !options.write {
+            return Err(SysError::EBADF);
+        }
+        if len > queue.attr().msgsize as usize {
+            return Err(SysError::EMSGSIZE);
+        }
+        let data = UserInPtr::<u8>::from(msg as usize).read_array(len)?;
+        let deadline = mq_deadline(timeout)?;
+        queue
+            .send(&self.thread, data, prio as u32, options.nonblock, deadline)
+            .await?;
+        Ok(0)
+    }
+
+    pub async fn sys_mq_timedreceive(
+        &mut self,
+        mqdes: usize,
+        msg: *mut u8,
+        len: usize,
+        prio: *mut u32,
+        timeout: *const TimeSpec,
+    ) -> SysResult {
+        info!(
+            "mq_timedreceive: mqdes: {}, msg: {:?}, len: {}",
+            mqdes, msg, len
+        );
+        let file = self.process().get_file(mqdes)?.clone();
+        let inode = file.inode();
+        let queue = as_mqueue(&inode)?;
+        let options = file.options();
+        if !options.read {
+            return Err(SysError::EBADF);
+        }
+        let deadline = mq_deadline(timeout)?;
+        let (data, msg_prio) = queue
+            .receive(&self.thread, len, options.nonblock, deadline)
+            .await?;
+        UserOutPtr::<u8>::from(msg as usize).write_array(&data)?;
+        if !prio.is_null() {
+            UserOutPtr::from(prio as usize).write(msg_prio)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Register or remove the notification of a message to the empty queue
+    pub fn sys_mq_notify(&mut self, mqdes: usize, sevp: *const SigEvent) -> SysResult {
+        info!("mq_notify: mqdes: {}, sevp: {:?}", mqdes, sevp);
+        let mut proc = self.process();
+        let pid = proc.pid.get();
+        let inode = proc.get_file(mqdes)?.inode();
+        drop(proc);
+        let queue = as_mqueue(&inode)?;
+        let notify = if sevp.is_null() {
+            None
+        } else {
+            let event: SigEvent = UserInPtr::from(sevp as usize).read()?;
+            let signo = match event.notify {
+                SIGEV_NONE => None,
+                SIGEV_SIGNAL => {
+                    Signal::from_i32(event.signo).ok_or(SysError::EINVAL)?;
+                    Some(event.signo)
+                }
+                // SIGEV_THREAD is done by libc with SIGEV_SIGNAL
+                _ => return Err(SysError::EINVAL),
+            };
+            Some(MqNotify {
+                pid,
+                signo,
+                value: event.value,
+            })
+        };
+        queue.set_notify(pid, notify)?;
+        Ok(0)
+    }
+
+    pub fn sys_mq_getsetattr(
+        &mut self,
+        mqdes: usize,
+        new_attr: *const MqAttr,
+        old_attr: *mut MqAttr,
+    ) -> SysResult {
+        info!(
+            "mq_getsetattr: mqdes: {}, new_attr: {:?}, old_attr: {:?}",
+            mqdes, new_attr, old_attr
+        );
+        let file = self.process().get_file(mqdes)?.clone();
+        let inode = file.inode();
+        let mut attr = as_mqueue(&inode)?.attr();
+        if file.options().nonblock {
+            attr.flags = O_NONBLOCK as isize;
+        }
+        if !new_attr.is_null() {
+            // only O_NONBLOCK can be changed
+            let new_attr: MqAttr = UserInPtr::from(new_attr as usize).read()?;
+            file.set_options(new_attr.flags as usize);
+        }
+        if !old_attr.is_null() {
+            UserOutPtr::from(old_attr as usize).write(attr)?;
+        }
+        Ok(0)
+    }
+}
+
+/// The queue of a descriptor, EBADF if it is not one
+fn as_mqueue(inode: &Arc<dyn INode>) -> Result<&MqueueINode, SysError> {
+    inode
+        .as_any_ref()
+        .downcast_ref::<MqueueINode>()
+        .ok_or(SysError::EBADF)
+}
+
+/// Deadline of an absolute CLOCK_REALTIME `timeout`, for `timer_now`
+fn mq_deadline(timeout: *const TimeSpec) -> Result<Option<Duration>, SysError> {
+    if timeout.is_null() {
+        return Ok(None);
+    }
+    let timeout: TimeSpec = UserInPtr::from(timeout as usize).read()?;
+    if timeout.nsec >= 1_000_000_000 {
+        return Err(SysError::EINVAL);
+    }
+    Ok(Some(timeout.to_deadline()))
+}
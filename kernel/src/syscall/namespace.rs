@@ -0,0 +1,94 @@
+//! Syscalls for namespaces
+
+use super::*;
+use crate::process::namespace::{Namespace, NamespaceINode};
+
+/// Longest host and domain names, excluding the nul
+const HOST_NAME_MAX: usize = 64;
+
+impl Syscall<'_> {
+    /// Move current process into new namespaces of `flags`
+    pub fn sys_unshare(&mut self, flags: usize) -> SysResult {
+        let flags = CloneFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        info!("unshare: flags: {:?}", flags);
+        // files and fs info are never shared between processes
+        let supported = CloneFlags::NEWNS
+            | CloneFlags::NEWUTS
+            | CloneFlags::NEWPID
+            | CloneFlags::FILES
+            | CloneFlags::FS
+            | CloneFlags::SYSVSEM;
+        if !supported.contains(flags) {
+            return Err(SysError::EINVAL);
+        }
+        self.process().ns.unshare(flags)?;
+        Ok(0)
+    }
+
+    /// Move current process into the namespace referred to by `fd`.
+    /// `nstype` is the expected CLONE_NEW* flag, or 0 for any.
+    pub fn sys_setns(&mut self, fd: usize, nstype: usize) -> SysResult {
+        info!("setns: fd: {}, nstype: {:#x}", fd, nstype);
+        let mut proc = self.process();
+        let ns = proc
+            .get_file(fd)?
+            .inode()
+            .as_any_ref()
+            .downcast_ref::<NamespaceINode>()
+            .ok_or(SysError::EINVAL)?
+            .ns
+            .clone();
+        if nstype != 0 && nstype != ns.flag().bits() {
+            return Err(SysError::EINVAL);
+        }
+        match ns {
+            Namespace::Pid(pid) => {
+                // children can only be put below our own PID namespace
+                if !proc.ns.pid.is_ancestor_of(&pid) {
+                    return Err(SysError::EINVAL);
+                }
+                proc.ns.pid_for_children = pid;
+            }
+            Namespace::Mnt(mnt) => {
+                proc.ns.mnt = mnt;
+                proc.root = String::from("/");
+                proc.cwd = String::from("/");
+            }
+            Namespace::Uts(uts) => proc.ns.uts = uts,
+        }
+        Ok(0)
+    }
+
+    pub fn sys_sethostname(&mut self, name: *const u8, len: usize) -> SysResult {
+        let name = self.host_name(name, len)?;
+        info!("sethostname: name: {:?}", name);
+        *self.process().ns.uts.hostname.write() = name;
+        Ok(0)
+    }
+
+    pub fn sys_setdomainname(&mut self, name: *const u8, len: usize) -> SysResult {
+        let name = self.host_name(name, len)?;
+        info!("setdomainname: name: {:?}", name);
+        *self.process().ns.uts.domainname.write() = name;
+        Ok(0)
+    }
+
+    /// Read a host or domain name of `len` bytes, not terminated by nul
+    fn host_name(&self, name: *const u8, len: usize) -> Result<String, SysError> {
+        if len > HOST_NAME_MAX {
+            return Err(SysError::EINVAL);
+        }
+        let name = UserInPtr::<u8>::from(name as usize).read_array(len)?;
+        String::from_utf8(name).map_err(|_| SysError::EINVAL)
+    }
+
+    /// Global id of `pid` in the PID namespace of current process
+    pub(super) fn global_pid(&self, pid: usize) -> Result<usize, SysError> {
+        self.process().ns.pid.global(pid).ok_or(SysError::ESRCH)
+    }
+
+    /// Id of the global `pid` in the PID namespace of current process, 0 if invisible
+    pub(super) fn local_pid(&self, pid: usize) -> usize {
+        self.process().ns.pid.local(pid).unwrap_or(0)
+    }
+}
@@ -2,35 +2,53 @@
 
 use super::fs::IoVecs;
 use super::*;
+use crate::fs::fcntl::{O_CLOEXEC, O_NONBLOCK};
 use crate::fs::FileLike;
 use crate::memory::MemorySet;
 use crate::net::{
-    Endpoint, LinkLevelEndpoint, NetlinkEndpoint, NetlinkSocketState, PacketSocketState,
-    RawSocketState, Socket, TcpSocketState, UdpSocketState,
+    Ancillary, Endpoint, IpFamily, LinkLevelEndpoint, NetlinkEndpoint, NetlinkSocketState,
+    PacketSocketState, RawSocketState, Socket, SocketOption, SocketOptionName, TcpSocketState,
+    UCred, UdpSocketState, UnixSocketState,
 };
 use alloc::boxed::Box;
 use core::cmp::min;
 use core::mem::size_of;
+use core::time::Duration;
 use smoltcp::wire::*;
 
 impl Syscall<'_> {
     pub fn sys_socket(&mut self, domain: usize, socket_type: usize, protocol: usize) -> SysResult {
         let domain = AddressFamily::from(domain as u16);
+        let nonblock = socket_type & SOCK_NONBLOCK != 0;
+        let fd_cloexec = socket_type & SOCK_CLOEXEC != 0;
         let socket_type = SocketType::from(socket_type as u8 & SOCK_TYPE_MASK);
         info!(
             "socket: domain: {:?}, socket_type: {:?}, protocol: {}",
             domain, socket_type, protocol
         );
         let mut proc = self.process();
-        let socket: Box<dyn Socket> = match domain {
+        let mut socket: Box<dyn Socket> = match domain {
             AddressFamily::Internet | AddressFamily::Unix => match socket_type {
-                SocketType::Stream => Box::new(TcpSocketState::new()),
-                SocketType::Datagram => Box::new(UdpSocketState::new()),
-                SocketType::Raw => Box::new(RawSocketState::new(protocol as u8)),
+                SocketType::Stream => Box::new(TcpSocketState::new(IpFamily::V4)),
+                SocketType::Datagram => Box::new(UdpSocketState::new(IpFamily::V4)),
+                SocketType::Raw => Box::new(RawSocketState::new(IpFamily::V4, protocol as u8)),
                 _ => return Err(SysError::EINVAL),
             },
+            AddressFamily::Internet6 => {
+                let family = IpFamily::V6 { v6only: false };
+                match socket_type {
+                    SocketType::Stream => Box::new(TcpSocketState::new(family)),
+                    SocketType::Datagram => Box::new(UdpSocketState::new(family)),
+                    SocketType::Raw => Box::new(RawSocketState::new(family, protocol as u8)),
+                    _ => return Err(SysError::EINVAL),
+                }
+            }
             AddressFamily::Packet => match socket_type {
-                SocketType::Raw => Box::new(PacketSocketState::new()),
+                // the protocol is an ethernet type in network byte order
+                SocketType::Raw | SocketType::Datagram => Box::new(PacketSocketState::new(
+                    socket_type,
+                    u16::from_be(protocol as u16),
+                )),
                 _ => return Err(SysError::EINVAL),
             },
             AddressFamily::Netlink => match socket_type {
@@ -39,10 +57,67 @@ impl Syscall<'_> {
             },
             _ => return Err(SysError::EAFNOSUPPORT),
         };
-        let fd = proc.add_file(FileLike::Socket(socket));
+        if nonblock {
+            set_nonblock(&mut *socket, true);
+        }
+        let fd = proc.add_file(FileLike::Socket { socket, fd_cloexec })?;
         Ok(fd)
     }
 
+    pub fn sys_socketpair(
+        &mut self,
+        domain: usize,
+        socket_type: usize,
+        protocol: usize,
+        sv: *mut u32,
+    ) -> SysResult {
+        let domain = AddressFamily::from(domain as u16);
+        let nonblock = socket_type & SOCK_NONBLOCK != 0;
+        let fd_cloexec = socket_type & SOCK_CLOEXEC != 0;
+        let socket_type = SocketType::from(socket_type as u8 & SOCK_TYPE_MASK);
+        info!(
+            "socketpair: domain: {:?}, socket_type: {:?}, protocol: {}",
+            domain, socket_type, protocol
+        );
+        // only unix sockets are local
+        if domain != AddressFamily::Unix {
+            return Err(SysError::EOPNOTSUPP);
+        }
+        if protocol != 0 {
+            return Err(SysError::EPROTONOSUPPORT);
+        }
+        match socket_type {
+            SocketType::Stream | SocketType::Datagram | SocketType::SeqPacket => {}
+            _ => return Err(SysError::EINVAL),
+        }
+        let mut proc = self.process();
+        let sv = unsafe { self.vm().check_write_array(sv, 2)? };
+        let (a, b) = UnixSocketState::new_pair(socket_type);
+        let (mut a, mut b): (Box<dyn Socket>, Box<dyn Socket>) = (Box::new(a), Box::new(b));
+        if nonblock {
+            set_nonblock(&mut *a, true);
+            set_nonblock(&mut *b, true);
+        }
+
+        let fd_a = proc.add_file(FileLike::Socket {
+            socket: a,
+            fd_cloexec,
+        })?;
+        let fd_b = match proc.add_file(FileLike::Socket {
+            socket: b,
+            fd_cloexec,
+        }) {
+            Ok(fd) => fd,
+            Err(err) => {
+                proc.files.remove(&fd_a);
+                return Err(err);
+            }
+        };
+        sv[0] = fd_a as u32;
+        sv[1] = fd_b as u32;
+        Ok(0)
+    }
+
     pub fn sys_setsockopt(
         &mut self,
         fd: usize,
@@ -55,10 +130,15 @@ impl Syscall<'_> {
             "setsockopt: fd: {}, level: {}, optname: {}",
             fd, level, optname
         );
+        if (level, optname) == (IPPROTO_TCP, TCP_CONGESTION) {
+            // smoltcp has a single congestion control
+            return Ok(0);
+        }
         let mut proc = self.process();
         let data = unsafe { self.vm().check_read_array(optval, optlen)? };
+        let option = option_from_user(option_name(level, optname)?, data)?;
         let socket = proc.get_socket(fd)?;
-        socket.setsockopt(level, optname, data)
+        socket.set_option(option)
     }
 
     pub fn sys_getsockopt(
@@ -74,49 +154,51 @@ impl Syscall<'_> {
             fd, level, optname, optval, optlen
         );
         let optlen = unsafe { self.vm().check_write_ptr(optlen)? };
-        match level {
-            SOL_SOCKET => match optname {
-                SO_SNDBUF => {
-                    let optval = unsafe { self.vm().check_write_ptr(optval as *mut u32)? };
-                    *optval = crate::net::TCP_SENDBUF as u32;
-                    *optlen = 4;
-                    Ok(0)
-                }
-                SO_RCVBUF => {
-                    let optval = unsafe { self.vm().check_write_ptr(optval as *mut u32)? };
-                    *optval = crate::net::TCP_RECVBUF as u32;
-                    *optlen = 4;
-                    Ok(0)
-                }
-                _ => Err(SysError::ENOPROTOOPT),
-            },
-            IPPROTO_TCP => match optname {
-                TCP_CONGESTION => Ok(0),
-                _ => Err(SysError::ENOPROTOOPT),
-            },
-            _ => Err(SysError::ENOPROTOOPT),
+        if (level, optname) == (IPPROTO_TCP, TCP_CONGESTION) {
+            return Ok(0);
         }
+        let name = option_name(level, optname)?;
+        let option = {
+            let mut proc = self.process();
+            proc.get_socket(fd)?.get_option(name)?
+        };
+        let data = option_to_user(option);
+        let len = min(*optlen as usize, data.len());
+        let optval = unsafe { self.vm().check_write_array(optval, len)? };
+        optval.copy_from_slice(&data[..len]);
+        *optlen = len as u32;
+        Ok(0)
     }
 
-    pub fn sys_connect(&mut self, fd: usize, addr: *const SockAddr, addr_len: usize) -> SysResult {
+    pub async fn sys_connect(
+        &mut self,
+        fd: usize,
+        addr: *const SockAddr,
+        addr_len: usize,
+    ) -> SysResult {
         info!(
             "sys_connect: fd: {}, addr: {:?}, addr_len: {}",
             fd, addr, addr_len
         );
 
-        let mut proc = self.process();
-        let endpoint = sockaddr_to_endpoint(&mut self.vm(), addr, addr_len)?;
-        let socket = proc.get_socket(fd)?;
-        socket.connect(endpoint)?;
+        let (mut socket, endpoint) = {
+            let mut proc = self.process();
+            let endpoint = sockaddr_to_endpoint(&mut self.vm(), addr, addr_len)?;
+            let socket = proc.get_socket(fd)?;
+            let endpoint = endpoint_from_user(socket.ip_family(), endpoint)?;
+            (socket.clone(), endpoint)
+        };
+        // wait on a clone sharing the state of the socket, without the process locked
+        socket.async_connect(endpoint, false).await?;
         Ok(0)
     }
 
-    pub fn sys_sendto(
+    pub async fn sys_sendto(
         &mut self,
         fd: usize,
         base: *const u8,
         len: usize,
-        _flags: usize,
+        flags: usize,
         addr: *const SockAddr,
         addr_len: usize,
     ) -> SysResult {
@@ -135,11 +217,24 @@ impl Syscall<'_> {
             info!("sys_sendto: sending to endpoint {:?}", endpoint);
             Some(endpoint)
         };
-        let socket = proc.get_socket(fd)?;
-        socket.write(&slice, endpoint)
+        // as in `sys_recvfrom`
+        let socket = proc.get_socket(fd)?.clone();
+        drop(proc);
+        let endpoint = match endpoint {
+            Some(endpoint) => Some(endpoint_from_user(socket.ip_family(), endpoint)?),
+            None => None,
+        };
+        socket
+            .async_sendmsg(
+                &slice,
+                endpoint,
+                Ancillary::default(),
+                flags & MSG_DONTWAIT != 0,
+            )
+            .await
     }
 
-    pub fn sys_recvfrom(
+    pub async fn sys_recvfrom(
         &mut self,
         fd: usize,
         base: *mut u8,
@@ -153,13 +248,15 @@ impl Syscall<'_> {
             fd, base, len, flags, addr, addr_len
         );
 
-        let mut proc = self.process();
-
         let mut slice = unsafe { self.vm().check_write_array(base, len)? };
-        let socket = proc.get_socket(fd)?;
-        let (result, endpoint) = socket.read(&mut slice);
+        // as in `sys_read`
+        let socket = self.process().get_socket(fd)?.clone();
+        let (result, endpoint) = socket
+            .async_read(&mut slice, flags & MSG_DONTWAIT != 0)
+            .await;
 
         if result.is_ok() && !addr.is_null() {
+            let endpoint = endpoint_to_user(socket.ip_family(), endpoint);
             let sockaddr_in = SockAddr::from(endpoint);
             unsafe {
                 sockaddr_in.write_to(&mut self.vm(), addr, addr_len)?;
@@ -169,30 +266,83 @@ impl Syscall<'_> {
         result
     }
 
-    pub fn sys_recvmsg(&mut self, fd: usize, msg: *mut MsgHdr, flags: usize) -> SysResult {
-        info!("recvmsg: fd: {}, msg: {:?}, flags: {}", fd, msg, flags);
+    pub async fn sys_sendmsg(&mut self, fd: usize, msg: *const MsgHdr, flags: usize) -> SysResult {
+        info!("sendmsg: fd: {}, msg: {:?}, flags: {}", fd, msg, flags);
         let mut proc = self.process();
+        let hdr = unsafe { self.vm().check_read_ptr(msg)? };
+        let iovs =
+            unsafe { IoVecs::check_and_new(hdr.msg_iov, hdr.msg_iovlen, &self.vm(), false)? };
+        let buf = iovs.read_all_to_vec();
+        let endpoint = if hdr.msg_name.is_null() {
+            None
+        } else {
+            Some(sockaddr_to_endpoint(
+                &mut self.vm(),
+                hdr.msg_name,
+                hdr.msg_namelen as usize,
+            )?)
+        };
+        let control = if hdr.msg_controllen == 0 {
+            &[][..]
+        } else {
+            unsafe {
+                self.vm()
+                    .check_read_array(hdr.msg_control as *const u8, hdr.msg_controllen)?
+            }
+        };
+        let ancillary = ancillary_from_user(&proc, control)?;
+
+        let socket = proc.get_socket(fd)?.clone();
+        drop(proc);
+        let endpoint = match endpoint {
+            Some(endpoint) => Some(endpoint_from_user(socket.ip_family(), endpoint)?),
+            None => None,
+        };
+        socket
+            .async_sendmsg(&buf, endpoint, ancillary, flags & MSG_DONTWAIT != 0)
+            .await
+    }
+
+    pub async fn sys_recvmsg(&mut self, fd: usize, msg: *mut MsgHdr, flags: usize) -> SysResult {
+        info!("recvmsg: fd: {}, msg: {:?}, flags: {}", fd, msg, flags);
         let hdr = unsafe { self.vm().check_write_ptr(msg)? };
         let mut iovs =
             unsafe { IoVecs::check_and_new(hdr.msg_iov, hdr.msg_iovlen, &self.vm(), true)? };
+        let control = if hdr.msg_controllen == 0 {
+            &mut [][..]
+        } else {
+            unsafe {
+                self.vm()
+                    .check_write_array(hdr.msg_control as *mut u8, hdr.msg_controllen)?
+            }
+        };
 
         let mut buf = iovs.new_buf(true);
-        let socket = proc.get_socket(fd)?;
-        let (result, endpoint) = socket.read(&mut buf);
+        // as in `sys_read`
+        let socket = self.process().get_socket(fd)?.clone();
+        let family = socket.ip_family();
+        let (result, endpoint, ancillary) = socket
+            .async_recvmsg(&mut buf, flags & MSG_DONTWAIT != 0)
+            .await;
+        let len = result?;
 
-        if let Ok(len) = result {
-            // copy data to user
-            iovs.write_all_from_slice(&buf[..len]);
-            let sockaddr_in = SockAddr::from(endpoint);
-            unsafe {
-                sockaddr_in.write_to(
-                    &mut self.vm(),
-                    hdr.msg_name,
-                    &mut hdr.msg_namelen as *mut u32,
-                )?;
-            }
+        // copy data to user
+        iovs.write_all_from_slice(&buf[..len]);
+        let endpoint = endpoint_to_user(family, endpoint);
+        let sockaddr_in = SockAddr::from(endpoint);
+        unsafe {
+            sockaddr_in.write_to(
+                &mut self.vm(),
+                hdr.msg_name,
+                &mut hdr.msg_namelen as *mut u32,
+            )?;
         }
-        result
+        let cloexec = flags & MSG_CMSG_CLOEXEC != 0;
+        let (controllen, truncated) =
+            ancillary_to_user(&mut self.process(), ancillary, control, cloexec);
+        hdr.msg_controllen = controllen;
+        hdr.msg_flags = if truncated { MSG_CTRUNC } else { 0 };
+        Ok(len)
     }
 
     pub fn sys_bind(&mut self, fd: usize, addr: *const SockAddr, addr_len: usize) -> SysResult {
@@ -203,6 +353,7 @@ impl Syscall<'_> {
         info!("sys_bind: fd: {} bind to {:?}", fd, endpoint);
 
         let socket = proc.get_socket(fd)?;
+        let endpoint = endpoint_from_user(socket.ip_family(), endpoint)?;
         socket.bind(endpoint)
     }
 
@@ -224,19 +375,32 @@ impl Syscall<'_> {
         socket.shutdown()
     }
 
-    pub fn sys_accept(&mut self, fd: usize, addr: *mut SockAddr, addr_len: *mut u32) -> SysResult {
+    pub async fn sys_accept(
+        &mut self,
+        fd: usize,
+        addr: *mut SockAddr,
+        addr_len: *mut u32,
+        flags: usize,
+    ) -> SysResult {
         info!(
-            "sys_accept: fd: {} addr: {:?} addr_len: {:?}",
-            fd, addr, addr_len
+            "sys_accept: fd: {} addr: {:?} addr_len: {:?} flags: {:#x}",
+            fd, addr, addr_len, flags
         );
         // smoltcp tcp sockets do not support backlog
         // open multiple sockets for each connection
+        let mut socket = self.process().get_socket(fd)?.clone();
+        let (mut new_socket, remote_endpoint) = socket.async_accept(false).await?;
+        let family = socket.ip_family();
         let mut proc = self.process();
+        let remote_endpoint = endpoint_to_user(family, remote_endpoint);
+        if flags & SOCK_NONBLOCK != 0 {
+            set_nonblock(&mut *new_socket, true);
+        }
 
-        let socket = proc.get_socket(fd)?;
-        let (new_socket, remote_endpoint) = socket.accept()?;
-
-        let new_fd = proc.add_file(FileLike::Socket(new_socket));
+        let new_fd = proc.add_file(FileLike::Socket {
+            socket: new_socket,
+            fd_cloexec: flags & SOCK_CLOEXEC != 0,
+        })?;
 
         if !addr.is_null() {
             let sockaddr_in = SockAddr::from(remote_endpoint);
@@ -266,6 +430,7 @@ impl Syscall<'_> {
 
         let socket = proc.get_socket(fd)?;
         let endpoint = socket.endpoint().ok_or(SysError::EINVAL)?;
+        let endpoint = endpoint_to_user(socket.ip_family(), endpoint);
         let sockaddr_in = SockAddr::from(endpoint);
         unsafe {
             sockaddr_in.write_to(&mut self.vm(), addr, addr_len)?;
@@ -294,6 +459,7 @@ impl Syscall<'_> {
 
         let socket = proc.get_socket(fd)?;
         let remote_endpoint = socket.remote_endpoint().ok_or(SysError::EINVAL)?;
+        let remote_endpoint = endpoint_to_user(socket.ip_family(), remote_endpoint);
         let sockaddr_in = SockAddr::from(remote_endpoint);
         unsafe {
             sockaddr_in.write_to(&mut self.vm(), addr, addr_len)?;
@@ -305,12 +471,17 @@ impl Syscall<'_> {
 impl Process {
     fn get_socket(&mut self, fd: usize) -> Result<&mut Box<dyn Socket>, SysError> {
         match self.get_file_like(fd)? {
-            FileLike::Socket(socket) => Ok(socket),
+            FileLike::Socket { socket, .. } => Ok(socket),
             _ => Err(SysError::EBADF),
         }
     }
 }
 
+/// Set O_NONBLOCK of `socket`. Netlink sockets never block, and have no such flag.
+pub fn set_nonblock(socket: &mut dyn Socket, on: bool) {
+    let _ = socket.set_option(SocketOption::NonBlock(on));
+}
+
 #[repr(C)]
 pub struct SockAddrIn {
     pub sin_family: u16,
@@ -319,6 +490,15 @@ pub struct SockAddrIn {
     pub sin_zero: [u8; 8],
 }
 
+#[repr(C)]
+pub struct SockAddrIn6 {
+    pub sin6_family: u16,
+    pub sin6_port: u16,
+    pub sin6_flowinfo: u32,
+    pub sin6_addr: [u8; 16],
+    pub sin6_scope_id: u32,
+}
+
 #[repr(C)]
 pub struct SockAddrUn {
     pub sun_family: u16,
@@ -348,6 +528,7 @@ pub struct SockAddrNl {
 pub union SockAddr {
     pub family: u16,
     pub addr_in: SockAddrIn,
+    pub addr_in6: SockAddrIn6,
     pub addr_un: SockAddrUn,
     pub addr_ll: SockAddrLl,
     pub addr_nl: SockAddrNl,
@@ -372,24 +553,41 @@ impl From<Endpoint> for SockAddr {
                         sin_zero: [0; 8],
                     },
                 },
+                IpAddress::Ipv6(ipv6) => SockAddr {
+                    addr_in6: SockAddrIn6 {
+                        sin6_family: AddressFamily::Internet6.into(),
+                        sin6_port: u16::to_be(ip.port),
+                        sin6_flowinfo: 0,
+                        sin6_addr: ipv6.0,
+                        sin6_scope_id: 0,
+                    },
+                },
                 IpAddress::Unspecified => SockAddr {
                     addr_ph: SockAddrPlaceholder {
                         family: AddressFamily::Unspecified.into(),
                         data: [0; 14],
                     },
                 },
-                _ => unimplemented!("only ipv4"),
+                _ => unimplemented!("only ipv4 and ipv6"),
             }
         } else if let Endpoint::LinkLevel(link_level) = endpoint {
             SockAddr {
                 addr_ll: SockAddrLl {
                     sll_family: AddressFamily::Packet.into(),
-                    sll_protocol: 0,
-                    sll_ifindex: link_level.interface_index as u32,
-                    sll_hatype: 0,
-                    sll_pkttype: 0,
-                    sll_halen: 0,
-                    sll_addr: [0; 8],
+                    sll_protocol: u16::to_be(link_level.protocol),
+                    // ifindex counts from 1, as 0 means none or a removed interface
+                    sll_ifindex: link_level
+                        .interface_index
+                        .and_then(|index| index.checked_add(1))
+                        .map_or(0, |ifindex| ifindex as u32),
+                    sll_hatype: ARPHRD_ETHER,
+                    sll_pkttype: link_level.packet_type,
+                    sll_halen: 6,
+                    sll_addr: {
+                        let mut addr = [0; 8];
+                        addr[..6].copy_from_slice(link_level.address.as_bytes());
+                        addr
+                    },
                 },
             }
         } else if let Endpoint::Netlink(netlink) = endpoint {
@@ -401,6 +599,13 @@ impl From<Endpoint> for SockAddr {
                     nl_groups: netlink.multicast_groups_mask,
                 },
             }
+        } else if let Endpoint::Unix = endpoint {
+            SockAddr {
+                addr_un: SockAddrUn {
+                    sun_family: AddressFamily::Unix.into(),
+                    sun_path: [0; 108],
+                },
+            }
         } else {
             unimplemented!("only ip");
         }
@@ -430,10 +635,18 @@ fn sockaddr_to_endpoint(
                 ));
                 Ok(Endpoint::Ip((addr, port).into()))
             }
+            AddressFamily::Internet6 => {
+                let port = u16::from_be(addr.addr_in6.sin6_port);
+                let addr = IpAddress::Ipv6(Ipv6Address(addr.addr_in6.sin6_addr));
+                Ok(Endpoint::Ip((addr, port).into()))
+            }
             AddressFamily::Unix => Err(SysError::EINVAL),
-            AddressFamily::Packet => Ok(Endpoint::LinkLevel(LinkLevelEndpoint::new(
-                addr.addr_ll.sll_ifindex as usize,
-            ))),
+            AddressFamily::Packet => Ok(Endpoint::LinkLevel(LinkLevelEndpoint {
+                interface_index: (addr.addr_ll.sll_ifindex as usize).checked_sub(1),
+                protocol: u16::from_be(addr.addr_ll.sll_protocol),
+                packet_type: addr.addr_ll.sll_pkttype,
+                address: EthernetAddress::from_bytes(&addr.addr_ll.sll_addr[..6]),
+            })),
             AddressFamily::Netlink => Ok(Endpoint::Netlink(NetlinkEndpoint::new(
                 addr.addr_nl.nl_pid,
                 addr.addr_nl.nl_groups,
@@ -443,13 +656,69 @@ fn sockaddr_to_endpoint(
     }
 }
 
+/// Convert an endpoint from user to what the socket of `family` talks to.
+/// IPv6 sockets reach IPv4 peers by v4-mapped addresses.
+fn endpoint_from_user(family: Option<IpFamily>, endpoint: Endpoint) -> Result<Endpoint, SysError> {
+    let (family, mut ip) = match (family, endpoint) {
+        (Some(family), Endpoint::Ip(ip)) => (family, ip),
+        (_, endpoint) => return Ok(endpoint),
+    };
+    match (family, ip.addr) {
+        (IpFamily::V4, IpAddress::Ipv6(_)) => return Err(SysError::EAFNOSUPPORT),
+        (IpFamily::V6 { v6only }, IpAddress::Ipv6(addr)) => {
+            if let Some(v4) = ipv4_mapped(&addr) {
+                if v6only {
+                    return Err(SysError::ENETUNREACH);
+                }
+                ip.addr = IpAddress::Ipv4(v4);
+            } else if addr.is_unspecified() && !v6only {
+                // any address of both families
+                ip.addr = IpAddress::Unspecified;
+            }
+        }
+        _ => {}
+    }
+    Ok(Endpoint::Ip(ip))
+}
+
+/// Convert an endpoint to what user of a socket of `family` expects
+fn endpoint_to_user(family: Option<IpFamily>, endpoint: Endpoint) -> Endpoint {
+    match (family, endpoint) {
+        (Some(IpFamily::V6 { .. }), Endpoint::Ip(mut ip)) => {
+            match ip.addr {
+                IpAddress::Ipv4(v4) => {
+                    let mut addr = [0u8; 16];
+                    addr[10..12].copy_from_slice(&[0xff, 0xff]);
+                    addr[12..].copy_from_slice(v4.as_bytes());
+                    ip.addr = IpAddress::Ipv6(Ipv6Address(addr));
+                }
+                IpAddress::Unspecified => ip.addr = IpAddress::Ipv6(Ipv6Address::UNSPECIFIED),
+                _ => {}
+            }
+            Endpoint::Ip(ip)
+        }
+        (_, endpoint) => endpoint,
+    }
+}
+
+/// The IPv4 address of a v4-mapped address `::ffff:a.b.c.d`
+fn ipv4_mapped(addr: &Ipv6Address) -> Option<Ipv4Address> {
+    if addr.0[..10] == [0; 10] && addr.0[10..12] == [0xff, 0xff] {
+        Some(Ipv4Address::from_bytes(&addr.0[12..]))
+    } else {
+        None
+    }
+}
+
 impl SockAddr {
     fn len(&self) -> Result<usize, SysError> {
         match AddressFamily::from(unsafe { self.family }) {
             AddressFamily::Internet => Ok(size_of::<SockAddrIn>()),
+            AddressFamily::Internet6 => Ok(size_of::<SockAddrIn6>()),
             AddressFamily::Packet => Ok(size_of::<SockAddrLl>()),
             AddressFamily::Netlink => Ok(size_of::<SockAddrNl>()),
-            AddressFamily::Unix => Err(SysError::EINVAL),
+            // only unnamed ones
+            AddressFamily::Unix => Ok(size_of::<u16>()),
             _ => Err(SysError::EINVAL),
         }
     }
@@ -489,6 +758,289 @@ pub struct MsgHdr {
     msg_flags: usize,
 }
 
+/// The option of `optname` at `level` of setsockopt and getsockopt
+fn option_name(level: usize, optname: usize) -> Result<SocketOptionName, SysError> {
+    use SocketOptionName::*;
+    let name = match (level, optname) {
+        (SOL_SOCKET, SO_REUSEADDR) => ReuseAddr,
+        (SOL_SOCKET, SO_REUSEPORT) => ReusePort,
+        (SOL_SOCKET, SO_KEEPALIVE) => KeepAlive,
+        (SOL_SOCKET, SO_BROADCAST) => Broadcast,
+        (SOL_SOCKET, SO_RCVTIMEO) => RecvTimeout,
+        (SOL_SOCKET, SO_SNDTIMEO) => SendTimeout,
+        (SOL_SOCKET, SO_LINGER) => Linger,
+        (SOL_SOCKET, SO_SNDBUF) => SendBuffer,
+        (SOL_SOCKET, SO_RCVBUF) => RecvBuffer,
+        (SOL_SOCKET, SO_ERROR) => Error,
+        (SOL_SOCKET, SO_TYPE) => Type,
+        (SOL_SOCKET, SO_PASSCRED) => PassCred,
+        (IPPROTO_IP, IP_TTL) => Ttl,
+        (IPPROTO_IP, IP_HDRINCL) => HeaderIncluded,
+        (IPPROTO_IP, IP_PKTINFO) => PacketInfo,
+        (IPPROTO_IPV6, IPV6_V6ONLY) => V6Only,
+        (IPPROTO_TCP, TCP_NODELAY) => NoDelay,
+        (IPPROTO_TCP, TCP_KEEPIDLE) => KeepIdle,
+        _ => {
+            warn!("unknown socket option {} of level {}", optname, level);
+            return Err(SysError::ENOPROTOOPT);
+        }
+    };
+    Ok(name)
+}
+
+/// Parse the value of setsockopt
+fn option_from_user(name: SocketOptionName, data: &[u8]) -> Result<SocketOption, SysError> {
+    use SocketOptionName::*;
+    let int = || {
+        if data.len() < 4 {
+            return Err(SysError::EINVAL);
+        }
+        Ok(u32::from_ne_bytes([data[0], data[1], data[2], data[3]]))
+    };
+    let timeout = || {
+        if data.len() < size_of::<TimeVal>() {
+            return Err(SysError::EINVAL);
+        }
+        let timeout = unsafe { (data.as_ptr() as *const TimeVal).read_unaligned() };
+        if (timeout.sec as isize) < 0 || timeout.usec >= 1_000_000 {
+            return Err(SysError::EDOM);
+        }
+        // zero to block without a timeout
+        Ok(Some(timeout.to_duration()).filter(|timeout| *timeout != Duration::from_secs(0)))
+    };
+    let option = match name {
+        ReuseAddr => SocketOption::ReuseAddr(int()? != 0),
+        ReusePort => SocketOption::ReusePort(int()? != 0),
+        KeepAlive => SocketOption::KeepAlive(int()? != 0),
+        Broadcast => SocketOption::Broadcast(int()? != 0),
+        RecvTimeout => SocketOption::RecvTimeout(timeout()?),
+        SendTimeout => SocketOption::SendTimeout(timeout()?),
+        Linger => {
+            // struct linger
+            if data.len() < 8 {
+                return Err(SysError::EINVAL);
+            }
+            let on = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]) != 0;
+            let secs = u32::from_ne_bytes([data[4], data[5], data[6], data[7]]);
+            SocketOption::Linger(Some(secs).filter(|_| on))
+        }
+        SendBuffer => SocketOption::SendBuffer(int()? as usize),
+        RecvBuffer => SocketOption::RecvBuffer(int()? as usize),
+        // read only, and the file flag set by fcntl
+        Error | Type | NonBlock => return Err(SysError::ENOPROTOOPT),
+        PassCred => SocketOption::PassCred(int()? != 0),
+        Ttl => match int()? {
+            ttl @ 1..=255 => SocketOption::Ttl(ttl as u8),
+            _ => return Err(SysError::EINVAL),
+        },
+        HeaderIncluded => SocketOption::HeaderIncluded(int()? != 0),
+        PacketInfo => SocketOption::PacketInfo(int()? != 0),
+        V6Only => SocketOption::V6Only(int()? != 0),
+        NoDelay => SocketOption::NoDelay(int()? != 0),
+        KeepIdle => SocketOption::KeepIdle(int()?),
+    };
+    Ok(option)
+}
+
+/// The value of getsockopt
+fn option_to_user(option: SocketOption) -> Vec<u8> {
+    let int = |value: u32| value.to_ne_bytes().to_vec();
+    let timeout = |timeout: Option<Duration>| {
+        let timeout = TimeVal::from(timeout.unwrap_or_default());
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                &timeout as *const TimeVal as *const u8,
+                size_of::<TimeVal>(),
+            )
+        };
+        bytes.to_vec()
+    };
+    match option {
+        SocketOption::ReuseAddr(on)
+        | SocketOption::ReusePort(on)
+        | SocketOption::KeepAlive(on)
+        | SocketOption::Broadcast(on)
+        | SocketOption::PassCred(on)
+        | SocketOption::HeaderIncluded(on)
+        | SocketOption::PacketInfo(on)
+        | SocketOption::V6Only(on)
+        | SocketOption::NoDelay(on)
+        | SocketOption::NonBlock(on) => int(on as u32),
+        SocketOption::RecvTimeout(value) | SocketOption::SendTimeout(value) => timeout(value),
+        SocketOption::Linger(linger) => {
+            let mut data = int(linger.is_some() as u32);
+            data.extend(int(linger.unwrap_or(0)));
+            data
+        }
+        SocketOption::SendBuffer(size) | SocketOption::RecvBuffer(size) => int(size as u32),
+        SocketOption::Error(errno) => int(errno as u32),
+        SocketOption::Type(socket_type) => int(u8::from(socket_type) as u32),
+        SocketOption::Ttl(ttl) => int(ttl as u32),
+        SocketOption::KeepIdle(secs) => int(secs),
+    }
+}
+
+/// Header of a control message, as `struct cmsghdr`
+#[repr(C)]
+struct CMsgHdr {
+    cmsg_len: usize,
+    cmsg_level: i32,
+    cmsg_type: i32,
+}
+
+const CMSG_HDR_LEN: usize = size_of::<CMsgHdr>();
+
+/// Max files passed by a message
+const SCM_MAX_FD: usize = 253;
+
+/// Round up the length of a control message to the start of the next one
+fn cmsg_align(len: usize) -> usize {
+    (len + size_of::<usize>() - 1) & !(size_of::<usize>() - 1)
+}
+
+/// Parse the control messages of sendmsg.
+/// Passed files are dups of those of `proc`, credentials are checked against it.
+fn ancillary_from_user(proc: &Process, control: &[u8]) -> Result<Ancillary, SysError> {
+    let pid = proc.pid.get();
+    let mut ancillary = Ancillary {
+        credentials: Some(UCred {
+            pid: pid as u32,
+            uid: 0,
+            gid: 0,
+        }),
+        ..Ancillary::default()
+    };
+    let mut offset = 0;
+    while offset + CMSG_HDR_LEN <= control.len() {
+        let hdr = unsafe { (control[offset..].as_ptr() as *const CMsgHdr).read_unaligned() };
+        if hdr.cmsg_len < CMSG_HDR_LEN || hdr.cmsg_len > control.len() - offset {
+            return Err(SysError::EINVAL);
+        }
+        let data = &control[offset + CMSG_HDR_LEN..offset + hdr.cmsg_len];
+        let words: Vec<u32> = data
+            .chunks_exact(4)
+            .map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        match (hdr.cmsg_level as usize, hdr.cmsg_type as usize) {
+            (SOL_SOCKET, SCM_RIGHTS) => {
+                if ancillary.files.len() + words.len() > SCM_MAX_FD {
+                    return Err(SysError::EINVAL);
+                }
+                for &fd in words.iter() {
+                    let file = proc.files.get(&(fd as usize)).ok_or(SysError::EBADF)?;
+                    ancillary.files.push(file.dup(false));
+                }
+            }
+            (SOL_SOCKET, SCM_CREDENTIALS) => {
+                if words.len() < 3 {
+                    return Err(SysError::EINVAL);
+                }
+                // no one to pretend to be, as everyone is root
+                if proc.ns.pid.global(words[0] as usize) != Some(pid)
+                    || words[1] != 0
+                    || words[2] != 0
+                {
+                    return Err(SysError::EPERM);
+                }
+            }
+            // the source address is the one of the interface it goes out of
+            (IPPROTO_IP, IP_PKTINFO) => {}
+            _ => return Err(SysError::EINVAL),
+        }
+        offset += cmsg_align(hdr.cmsg_len);
+    }
+    Ok(ancillary)
+}
+
+/// Control messages written to the buffer of recvmsg
+struct CMsgWriter<'a> {
+    control: &'a mut [u8],
+    len: usize,
+    /// Some did not fit
+    truncated: bool,
+}
+
+impl CMsgWriter<'_> {
+    /// Bytes of data which fit in the next message
+    fn room(&self) -> usize {
+        (self.control.len() - self.len).saturating_sub(CMSG_HDR_LEN)
+    }
+
+    fn push(&mut self, level: usize, ty: usize, data: &[u8]) {
+        if data.len() > self.room() {
+            self.truncated = true;
+            return;
+        }
+        let hdr = CMsgHdr {
+            cmsg_len: CMSG_HDR_LEN + data.len(),
+            cmsg_level: level as i32,
+            cmsg_type: ty as i32,
+        };
+        let buf = &mut self.control[self.len..];
+        unsafe {
+            (buf.as_mut_ptr() as *mut CMsgHdr).write_unaligned(hdr);
+        }
+        buf[CMSG_HDR_LEN..CMSG_HDR_LEN + data.len()].copy_from_slice(data);
+        self.len = min(
+            self.len + cmsg_align(CMSG_HDR_LEN + data.len()),
+            self.control.len(),
+        );
+    }
+}
+
+/// Write the control messages of recvmsg, installing passed files in `proc`.
+/// Return the length written, and whether some messages or files did not fit.
+fn ancillary_to_user(
+    proc: &mut Process,
+    ancillary: Ancillary,
+    control: &mut [u8],
+    cloexec: bool,
+) -> (usize, bool) {
+    let mut writer = CMsgWriter {
+        control,
+        len: 0,
+        truncated: false,
+    };
+    if let Some(credentials) = ancillary.credentials {
+        let pid = proc.ns.pid.local(credentials.pid as usize).unwrap_or(0) as u32;
+        let mut data = Vec::new();
+        for word in [pid, credentials.uid, credentials.gid].iter() {
+            data.extend_from_slice(&word.to_ne_bytes());
+        }
+        writer.push(SOL_SOCKET, SCM_CREDENTIALS, &data);
+    }
+    if let Some((addr, index)) = ancillary.pktinfo {
+        // struct in_pktinfo, whose local and destination addresses are the same here
+        let mut data = Vec::new();
+        data.extend_from_slice(&(index as u32 + 1).to_ne_bytes());
+        data.extend_from_slice(addr.as_bytes());
+        data.extend_from_slice(addr.as_bytes());
+        writer.push(IPPROTO_IP, IP_PKTINFO, &data);
+    }
+    if !ancillary.files.is_empty() {
+        // install as many as fit, the others are closed
+        let max = writer.room() / 4;
+        let mut data = Vec::new();
+        for file in ancillary.files.iter() {
+            if data.len() / 4 == max {
+                writer.truncated = true;
+                break;
+            }
+            match proc.add_file(file.dup(cloexec)) {
+                Ok(fd) => data.extend_from_slice(&(fd as u32).to_ne_bytes()),
+                Err(_) => {
+                    writer.truncated = true;
+                    break;
+                }
+            }
+        }
+        if !data.is_empty() {
+            writer.push(SOL_SOCKET, SCM_RIGHTS, &data);
+        }
+    }
+    (writer.len, writer.truncated)
+}
+
 enum_with_unknown! {
     /// Address families
     pub doc enum AddressFamily(u16) {
@@ -498,6 +1050,8 @@ enum_with_unknown! {
         Unix = 1,
         /// Internet IP Protocol
         Internet = 2,
+        /// IP version 6
+        Internet6 = 10,
         /// Netlink
         Netlink = 16,
         /// Packet family
@@ -506,6 +1060,10 @@ enum_with_unknown! {
 }
 
 const SOCK_TYPE_MASK: u8 = 0xf;
+/// Flag of the socket type of socket and accept4, the same as O_NONBLOCK
+const SOCK_NONBLOCK: usize = O_NONBLOCK;
+/// Flag of the socket type of socket and accept4, the same as O_CLOEXEC
+const SOCK_CLOEXEC: usize = O_CLOEXEC;
 
 enum_with_unknown! {
     /// Socket types
@@ -516,18 +1074,47 @@ enum_with_unknown! {
         Datagram = 2,
         /// Raw
         Raw = 3,
+        /// Sequenced packets
+        SeqPacket = 5,
     }
 }
 
 pub const IPPROTO_IP: usize = 0;
 pub const IPPROTO_ICMP: usize = 1;
 pub const IPPROTO_TCP: usize = 6;
+pub const IPPROTO_IPV6: usize = 41;
+pub const IPPROTO_ICMPV6: usize = 58;
 
 pub const SOL_SOCKET: usize = 1;
+pub const SO_REUSEADDR: usize = 2;
+pub const SO_TYPE: usize = 3;
+pub const SO_ERROR: usize = 4;
+pub const SO_BROADCAST: usize = 6;
 pub const SO_SNDBUF: usize = 7;
 pub const SO_RCVBUF: usize = 8;
+pub const SO_KEEPALIVE: usize = 9;
 pub const SO_LINGER: usize = 13;
+pub const SO_REUSEPORT: usize = 15;
+pub const SO_PASSCRED: usize = 16;
+pub const SO_RCVTIMEO: usize = 20;
+pub const SO_SNDTIMEO: usize = 21;
+
+pub const SCM_RIGHTS: usize = 1;
+pub const SCM_CREDENTIALS: usize = 2;
 
+pub const TCP_NODELAY: usize = 1;
+pub const TCP_KEEPIDLE: usize = 4;
 pub const TCP_CONGESTION: usize = 13;
 
+pub const IP_TTL: usize = 2;
 pub const IP_HDRINCL: usize = 3;
+pub const IP_PKTINFO: usize = 8;
+
+pub const MSG_CTRUNC: usize = 0x8;
+pub const MSG_DONTWAIT: usize = 0x40;
+pub const MSG_CMSG_CLOEXEC: usize = 0x4000_0000;
+
+pub const IPV6_V6ONLY: usize = 26;
+
+/// Hardware type of sockaddr_ll, all interfaces are ethernet ones
+const ARPHRD_ETHER: u16 = 1;
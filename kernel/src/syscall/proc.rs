@@ -3,7 +3,12 @@
 use super::*;
 use crate::arch::timer::timer_now;
 use crate::fs::FileLike;
-use crate::signal::{send_signal, Signal};
+use crate::process::futex::get_futex;
+use crate::process::ptrace::{
+    has_tracee, ptrace_wait, PTRACE_EVENT_CLONE, PTRACE_EVENT_EXEC, PTRACE_EVENT_FORK,
+};
+use crate::process::rlimit::{RLIMIT_NPROC, RLIMIT_STACK};
+use crate::signal::{send_signal, Siginfo, Signal, SI_USER};
 use crate::{
     sync::{wait_for_event, Event, EventBus, SpinNoIrqLock as Mutex},
     syscall::SysError::{EINTR, ESRCH},
@@ -21,11 +26,19 @@ use core::{
 impl Syscall<'_> {
     /// Fork the current process. Return the child's PID.
     pub fn sys_fork(&mut self) -> SysResult {
-        let new_thread = self.thread.fork(self.context);
+        self.fork_with(CloneFlags::empty())
+    }
+
+    /// Fork the current process into new namespaces of `flags`
+    fn fork_with(&mut self, flags: CloneFlags) -> SysResult {
+        self.check_nproc()?;
+        let ns = self.process().ns.for_child(flags)?;
+        let new_thread = self.thread.fork(self.context, ns);
         let pid = new_thread.proc.lock().pid.get();
         info!("fork: {} -> {}", self.process().pid, pid);
+        self.ptrace_child(&new_thread, PTRACE_EVENT_FORK);
         spawn(new_thread);
-        Ok(pid)
+        Ok(self.local_pid(pid))
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -51,9 +64,15 @@ impl Syscall<'_> {
             "clone: flags: {:?} == {:#x}, newsp: {:#x}, parent_tid: {:?}, child_tid: {:?}, newtls: {:#x}",
             clone_flags, flags, newsp, parent_tid, child_tid, newtls
         );
+        let ns_flags = clone_flags & (CloneFlags::NEWNS | CloneFlags::NEWUTS | CloneFlags::NEWPID);
+        let flags = flags & !ns_flags.bits();
         if flags == 0x4111 || flags == 0x11 {
             warn!("sys_clone is calling sys_fork instead, ignoring other args");
-            return self.sys_fork();
+            return self.fork_with(ns_flags);
+        }
+        if !ns_flags.is_empty() {
+            // threads share the namespaces of their process
+            return Err(SysError::EINVAL);
         }
         if (flags != 0x7d0f00) && (flags != 0x5d0f00) {
             // 0x5d0f00 is the args from gcc of alpine linux
@@ -72,18 +91,60 @@ impl Syscall<'_> {
         if clone_flags.contains(CloneFlags::CHILD_CLEARTID) {
             new_thread.inner.lock().clear_child_tid = child_tid as usize;
         }
-        let tid: usize = new_thread.tid;
-        info!("clone: {} -> {}", self.thread.tid, tid);
+        info!("clone: {} -> {}", self.thread.tid, new_thread.tid);
+        let tid = self.local_pid(new_thread.tid);
         *parent_tid_ref = tid as u32;
         *child_tid_ref = tid as u32;
+        self.ptrace_child(&new_thread, PTRACE_EVENT_CLONE);
         spawn(new_thread);
         Ok(tid)
     }
 
+    /// EAGAIN if one more process of the caller's user would exceed RLIMIT_NPROC.
+    /// There is no user management, everyone is root, so all processes count.
+    fn check_nproc(&self) -> Result<(), SysError> {
+        let limit = self.process().rlimit(RLIMIT_NPROC);
+        if PROCESSES.read().len() as u64 >= limit {
+            return Err(SysError::EAGAIN);
+        }
+        Ok(())
+    }
+
+    /// Trace the new child too if the tracer asked for `event`,
+    /// it starts with a SIGSTOP like on PTRACE_ATTACH
+    fn ptrace_child(&self, child: &Arc<Thread>, event: usize) {
+        if let Some((tracer, options)) = self.thread.ptrace_inherit(event) {
+            child.ptrace_attach(tracer, options);
+            send_signal(
+                child.proc.clone(),
+                child.tid as isize,
+                Siginfo {
+                    signo: Signal::SIGSTOP as i32,
+                    errno: 0,
+                    code: SI_USER,
+                    field: Default::default(),
+                },
+            );
+            self.thread.ptrace_event(event, child.tid);
+        }
+    }
+
     /// Wait for the process exit.
     /// Return the PID. Store exit code to `wstatus` if it's not null.
-    pub async fn sys_wait4(&mut self, pid: isize, wstatus: UserInOutPtr<i32>) -> SysResult {
-        info!("wait4: pid: {}, code: {:?}", pid, wstatus);
+    /// Stopped tracees, and stopped children with WUNTRACED, are reported too.
+    pub async fn sys_wait4(
+        &mut self,
+        pid: isize,
+        wstatus: UserInOutPtr<i32>,
+        options: usize,
+    ) -> SysResult {
+        info!(
+            "wait4: pid: {}, code: {:?}, options: {:#x}",
+            pid, wstatus, options
+        );
+        const WNOHANG: usize = 1;
+        const WUNTRACED: usize = 2;
+        let untraced = options & WUNTRACED != 0;
         let wstatus = if !wstatus.is_null() {
             Some(wstatus)
         } else {
@@ -98,22 +159,41 @@ impl Syscall<'_> {
         let target = match pid {
             -1 => WaitFor::AnyChild,
             0 => WaitFor::AnyChildInGroup,
-            p if p > 0 => WaitFor::Pid(p as usize),
+            p if p > 0 => WaitFor::Pid(
+                self.process()
+                    .ns
+                    .pid
+                    .global(p as usize)
+                    .ok_or(SysError::ECHILD)?,
+            ),
             _ => unimplemented!(),
         };
+        let tracee = match target {
+            WaitFor::Pid(pid) => Some(pid),
+            _ => None,
+        };
         loop {
             info!("wait4 loop: pid: {}, code: {:?}", pid, wstatus);
             let mut proc = self.process();
 
+            // check tracee state
+            if let Some((tid, status)) = ptrace_wait(proc.pid.get(), tracee) {
+                info!("wait: tracee {} stopped", tid);
+                if let Some(mut wstatus) = wstatus {
+                    wstatus.write(status)?;
+                }
+                return Ok(proc.ns.pid.local(tid).unwrap_or(0));
+            }
+
             // check child state
             let find = match target {
                 WaitFor::AnyChild | WaitFor::AnyChildInGroup => {
                     let mut res = None;
                     for (pid, child) in &proc.children {
                         if let Some(c) = child.upgrade() {
-                            let p = c.lock();
-                            if p.exited() {
-                                res = Some((p.pid, p.exit_code));
+                            let mut p = c.lock();
+                            if let Some((status, exited)) = child_status(&mut p, untraced) {
+                                res = Some((p.pid, status, exited));
                                 break;
                             }
                         } else {
@@ -125,33 +205,37 @@ impl Syscall<'_> {
                 WaitFor::Pid(pid) => {
                     let mut res = None;
                     if let Some(c) = process(pid) {
-                        let p = c.lock();
-                        if p.exited() {
-                            res = Some((p.pid, p.exit_code));
+                        let mut p = c.lock();
+                        if let Some((status, exited)) = child_status(&mut p, untraced) {
+                            res = Some((p.pid, status, exited));
                         }
                     }
                     res
                 }
             };
             // if found, return
-            if let Some((pid, exit_code)) = find {
+            if let Some((pid, status, exited)) = find {
                 info!("wait: found pid {}", pid);
 
                 // write before removing to handle EFAULT
                 if let Some(mut wstatus) = wstatus {
-                    wstatus.write(exit_code as i32)?;
+                    wstatus.write(status)?;
+                }
+                let local_pid = proc.ns.pid.local(pid.get()).unwrap_or(0);
+                if !exited {
+                    return Ok(local_pid);
                 }
 
                 // remove from process table
-                if true {
-                    let mut process_table = PROCESSES.write();
-                    process_table.remove(&pid.get());
+                let child = PROCESSES.write().remove(&pid.get());
+                if let Some(child) = child {
+                    child.lock().ns.pid.detach(pid.get());
                 }
 
                 // remove from children
                 proc.children.retain(|(p, _)| *p != pid);
 
-                return Ok(pid.get());
+                return Ok(local_pid);
             }
             // if not, check pid
             let invalid = {
@@ -166,23 +250,28 @@ impl Syscall<'_> {
                         }
                     })
                     .collect::<Vec<_>>();
-                match target {
+                let no_child = match target {
                     WaitFor::AnyChild | WaitFor::AnyChildInGroup => children.len() == 0,
                     WaitFor::Pid(pid) => children.iter().find(|p| p.get() == pid).is_none(),
-                }
+                };
+                no_child && !has_tracee(proc.pid.get(), tracee)
             };
             if invalid {
                 info!("wait: no valid child proc");
                 return Err(SysError::ECHILD);
             }
+            if options & WNOHANG != 0 {
+                return Ok(0);
+            }
 
             info!("wait: thread {} -> {:?}, sleep", self.thread.tid, target);
 
             let eventbus = proc.eventbus.clone();
             drop(proc);
 
-            wait_for_event(eventbus.clone(), Event::CHILD_PROCESS_QUIT).await;
-            eventbus.lock().clear(Event::CHILD_PROCESS_QUIT);
+            let events = Event::CHILD_PROCESS_QUIT | Event::CHILD_PROCESS_STOP;
+            wait_for_event(eventbus.clone(), events).await;
+            eventbus.lock().clear(events);
         }
     }
 
@@ -217,14 +306,23 @@ impl Syscall<'_> {
 
         info!("exec: path: {:?}, args: {:?}, envs: {:?}", path, args, envs);
 
-        // Read program file
-        let inode = proc.lookup_inode(&path)?;
+        // Read program file, following `#!` interpreters of scripts
+        let (inode, args) = proc.lookup_executable(&path, args)?;
 
         // Make new Thread
         // Re-create vm
         let mut vm = self.vm();
-        let (entry_addr, ustack_top) =
-            Thread::new_user_vm(&inode, args, envs, &mut vm).map_err(|_| SysError::EINVAL)?;
+        let root = proc.root_inode()?;
+        let info = Thread::new_user_vm(
+            &inode,
+            &root,
+            &path,
+            args,
+            envs,
+            &mut vm,
+            proc.personality,
+            proc.rlimit(RLIMIT_STACK),
+        )?;
 
         // Kill other threads
         // TODO: stop and wait until they are finished
@@ -235,19 +333,15 @@ impl Syscall<'_> {
             .files
             .iter()
             .filter_map(|(fd, file_like)| {
-                if let FileLike::File(file) = file_like {
-                    if file.fd_cloexec {
-                        Some(*fd)
-                    } else {
-                        None
-                    }
+                if file_like.fd_cloexec() {
+                    Some(*fd)
                 } else {
                     None
                 }
             })
             .collect::<Vec<_>>();
         for fd in close_fds {
-            proc.files.remove(&fd);
+            proc.close_file(fd).ok();
         }
 
         // Activate new page table
@@ -258,6 +352,11 @@ impl Syscall<'_> {
 
         // Modify exec path
         proc.exec_path = path.clone();
+        proc.mmap_base = info.mmap_base;
+        proc.saved_auxv = info.auxv;
+
+        // shared memory segments are detached
+        proc.shm_identifiers = ShmProc::default();
 
         // reset disposition (man signal(7))
         for d in proc.dispositions.iter_mut() {
@@ -266,8 +365,23 @@ impl Syscall<'_> {
         drop(proc);
 
         // Modify the TrapFrame
-        self.context.set_ip(entry_addr);
-        self.context.set_sp(ustack_top);
+        self.context.set_ip(info.entry_addr);
+        self.context.set_sp(info.ustack_top);
+
+        // let the tracer see the new program before it runs
+        let pid = self.process().pid.get();
+        if !self.thread.ptrace_event(PTRACE_EVENT_EXEC, pid) && self.thread.is_traced() {
+            send_signal(
+                self.thread.proc.clone(),
+                self.thread.tid as isize,
+                Siginfo {
+                    signo: Signal::SIGTRAP as i32,
+                    errno: 0,
+                    code: SI_USER,
+                    field: Default::default(),
+                },
+            );
+        }
 
         info!("exec:END: path: {:?}", path);
         Ok(0)
@@ -281,28 +395,31 @@ impl Syscall<'_> {
     /// Get the current process id
     pub fn sys_getpid(&mut self) -> SysResult {
         info!("getpid");
-        Ok(self.process().pid.get())
+        let pid = self.process().pid.get();
+        Ok(self.local_pid(pid))
     }
 
     pub fn sys_getpgid(&self, mut pid: usize) -> SysResult {
         if pid == 0 {
             pid = self.process().pid.get();
+        } else {
+            pid = self.global_pid(pid)?;
         }
         info!("getpgid: get pgid of process {}", pid);
 
-        let process_table = PROCESSES.read();
-        let proc = process_table.get(&pid);
-        if let Some(proc) = proc {
-            let proc = proc.lock();
-            Ok(proc.pgid as usize)
-        } else {
-            Err(ESRCH)
-        }
+        let proc = process(pid).ok_or(ESRCH)?;
+        let pgid = proc.lock().pgid;
+        Ok(self.local_pid(pgid as usize))
     }
 
-    pub fn sys_setpgid(&self, mut pid: usize, pgid: usize) -> SysResult {
+    pub fn sys_setpgid(&self, mut pid: usize, mut pgid: usize) -> SysResult {
         if pid == 0 {
             pid = self.process().pid.get();
+        } else {
+            pid = self.global_pid(pid)?;
+        }
+        if pgid != 0 {
+            pgid = self.global_pid(pgid).map_err(|_| SysError::EPERM)?;
         }
         info!("setpgid: set pgid of process {} to {}", pid, pgid);
 
@@ -321,7 +438,7 @@ impl Syscall<'_> {
     /// Get the current thread id
     pub fn sys_gettid(&mut self) -> SysResult {
         info!("gettid");
-        Ok(self.thread.tid)
+        Ok(self.local_pid(self.thread.tid))
     }
 
     /// Get the parent process id
@@ -329,7 +446,7 @@ impl Syscall<'_> {
         info!("getppid");
         let (pid, parent) = self.process().parent.clone();
         if parent.upgrade().is_some() {
-            Ok(pid.get())
+            Ok(self.local_pid(pid.get()))
         } else {
             Ok(0)
         }
@@ -342,10 +459,21 @@ impl Syscall<'_> {
 
         let mut proc = self.process();
         proc.threads.retain(|&id| id != tid);
+        // the pid is released when the parent reaps the process
+        if tid != proc.pid.get() {
+            proc.ns.pid.detach(tid);
+        }
 
         // for last thread, exit the process
-        if proc.threads.len() == 0 {
-            proc.exit(exit_code);
+        let exited = if proc.threads.len() == 0 {
+            Some(proc.exit(exit_code))
+        } else {
+            None
+        };
+
+        drop(proc);
+        if let Some(exited) = exited {
+            exited.finish();
         }
 
         // perform futex wake 1
@@ -357,12 +485,11 @@ impl Syscall<'_> {
             info!("exit: futex {:#?} wake 1", clear_child_tid);
             if let Ok(clear_child_tid_ref) = unsafe { self.vm().check_write_ptr(clear_child_tid) } {
                 *clear_child_tid_ref = 0;
-                let futex = proc.get_futex(clear_child_tid as usize);
+                let futex = get_futex(&self.thread, clear_child_tid as usize, false);
                 futex.wake(1);
             }
         }
 
-        drop(proc);
         self.exit = true;
         Ok(0)
     }
@@ -372,8 +499,9 @@ impl Syscall<'_> {
         let mut proc = self.process();
         info!("exit_group: {}, code: {}", proc.pid, exit_code);
 
-        proc.exit(exit_code);
+        let exited = proc.exit(exit_code);
         drop(proc);
+        exited.finish();
         // TODO: quit other threads
         self.exit = true;
         Ok(0)
@@ -391,6 +519,18 @@ impl Syscall<'_> {
         Ok(0)
     }
 
+    /// Set the execution domain of the current process.
+    /// Return the previous one, `0xffffffff` only queries it.
+    pub fn sys_personality(&mut self, persona: usize) -> SysResult {
+        info!("personality: {:#x}", persona);
+        let mut proc = self.process();
+        let old = proc.personality;
+        if persona as u32 != 0xffffffff {
+            proc.personality = persona;
+        }
+        Ok(old)
+    }
+
     pub fn sys_set_priority(&mut self, priority: usize) -> SysResult {
         Ok(0)
     }
@@ -412,6 +552,57 @@ impl Syscall<'_> {
     }
 }
 
+/// Max depth of nested `#!` interpreters, same as Linux
+const MAX_SCRIPT_DEPTH: usize = 4;
+
+/// Max length of the `#!` line
+const SCRIPT_LINE_MAX: usize = 256;
+
+impl Process {
+    /// Lookup the executable to run for `path` with arguments `args`.
+    ///
+    /// If it is a script starting with `#!interpreter [arg]`,
+    /// the interpreter is executed instead with arguments
+    /// `[interpreter, arg, path, args[1..]]`.
+    pub fn lookup_executable(
+        &self,
+        path: &str,
+        mut args: Vec<String>,
+    ) -> Result<(Arc<dyn INode>, Vec<String>), SysError> {
+        let mut path = String::from(path);
+        for _ in 0..=MAX_SCRIPT_DEPTH {
+            let inode = self.lookup_inode(&path)?;
+            let mut buf = [0u8; SCRIPT_LINE_MAX];
+            let len = inode.read_at(0, &mut buf)?;
+            let buf = &buf[..len];
+            if !buf.starts_with(b"#!") {
+                return Ok((inode, args));
+            }
+            let line = buf[2..]
+                .split(|&c| c == b'\n')
+                .next()
+                .and_then(|line| str::from_utf8(line).ok())
+                .ok_or(SysError::ENOEXEC)?
+                .trim();
+            let mut split = line.splitn(2, |c: char| c == ' ' || c == '\t');
+            let interp = match split.next() {
+                Some(interp) if !interp.is_empty() => String::from(interp),
+                _ => return Err(SysError::ENOEXEC),
+            };
+            info!("exec: script {:?} with interpreter {:?}", path, line);
+            let mut new_args = vec![interp.clone()];
+            if let Some(arg) = split.next().map(str::trim).filter(|arg| !arg.is_empty()) {
+                new_args.push(String::from(arg));
+            }
+            new_args.push(path);
+            new_args.extend(args.drain(..).skip(1));
+            args = new_args;
+            path = interp;
+        }
+        Err(SysError::ELOOP)
+    }
+}
+
 #[must_use = "future does nothing unless polled/`await`-ed"]
 pub struct SleepFuture {
     deadline: Duration,
@@ -451,6 +642,19 @@ impl Future for SleepFuture {
     }
 }
 
+/// Status of child `p` to report by wait4, and whether it has exited.
+/// A stop by job control is reported once, and only with WUNTRACED.
+fn child_status(p: &mut Process, untraced: bool) -> Option<(i32, bool)> {
+    if p.exited() {
+        Some((p.exit_code as i32, true))
+    } else if untraced && p.stop_signal != 0 && !p.stop_reported {
+        p.stop_reported = true;
+        Some(((p.stop_signal as i32) << 8 | 0x7f, false))
+    } else {
+        None
+    }
+}
+
 bitflags! {
     pub struct CloneFlags: usize {
         const CSIGNAL =         0x000000ff;
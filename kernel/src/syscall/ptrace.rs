@@ -0,0 +1,264 @@
+//! Syscall for process tracing
+
+use super::*;
+use crate::arch::signal::{
+    elf_greg_index, elf_gregs_from_tf, set_elf_gregs, set_single_step, user_area_padding, ELF_NGREG,
+};
+use crate::process::ptrace::{PtraceOptions, PtraceState, ResumeMode};
+use crate::signal::{send_signal, Siginfo, SI_USER};
+use core::mem::size_of;
+
+const PTRACE_TRACEME: usize = 0;
+const PTRACE_PEEKTEXT: usize = 1;
+const PTRACE_PEEKDATA: usize = 2;
+const PTRACE_PEEKUSER: usize = 3;
+const PTRACE_POKETEXT: usize = 4;
+const PTRACE_POKEDATA: usize = 5;
+const PTRACE_POKEUSER: usize = 6;
+const PTRACE_CONT: usize = 7;
+const PTRACE_KILL: usize = 8;
+const PTRACE_SINGLESTEP: usize = 9;
+const PTRACE_GETREGS: usize = 12;
+const PTRACE_SETREGS: usize = 13;
+const PTRACE_ATTACH: usize = 16;
+const PTRACE_DETACH: usize = 17;
+const PTRACE_SYSCALL: usize = 24;
+const PTRACE_SETOPTIONS: usize = 0x4200;
+const PTRACE_GETEVENTMSG: usize = 0x4201;
+const PTRACE_GETSIGINFO: usize = 0x4202;
+const PTRACE_SETSIGINFO: usize = 0x4203;
+const PTRACE_GETREGSET: usize = 0x4204;
+const PTRACE_SETREGSET: usize = 0x4205;
+const PTRACE_SEIZE: usize = 0x4206;
+
+/// Register set of PTRACE_GETREGSET, the same as in core dumps
+const NT_PRSTATUS: usize = 1;
+
+impl Syscall<'_> {
+    pub fn sys_ptrace(
+        &mut self,
+        request: usize,
+        pid: usize,
+        addr: usize,
+        data: usize,
+    ) -> SysResult {
+        info!(
+            "ptrace: request: {:#x}, pid: {}, addr: {:#x}, data: {:#x}",
+            request, pid, addr, data
+        );
+        let pid = if request == PTRACE_TRACEME {
+            pid
+        } else {
+            self.global_pid(pid)?
+        };
+        match request {
+            PTRACE_TRACEME => {
+                let parent = self.process().parent.0.get();
+                if self.thread.ptrace_attach(parent, PtraceOptions::empty()) {
+                    Ok(0)
+                } else {
+                    Err(SysError::EPERM)
+                }
+            }
+            PTRACE_ATTACH | PTRACE_SEIZE => {
+                let options = if request == PTRACE_SEIZE {
+                    PtraceOptions::from_bits(data).ok_or(SysError::EINVAL)?
+                } else {
+                    PtraceOptions::empty()
+                };
+                let tracer = self.process().pid.get();
+                let thread = THREADS.read().get(&pid).cloned().ok_or(SysError::ESRCH)?;
+                if Arc::ptr_eq(&thread.proc, &self.thread.proc) {
+                    return Err(SysError::EPERM);
+                }
+                if !thread.ptrace_attach(tracer, options) {
+                    return Err(SysError::EPERM);
+                }
+                if request == PTRACE_ATTACH {
+                    send_signal(
+                        thread.proc.clone(),
+                        pid as isize,
+                        Siginfo {
+                            signo: Signal::SIGSTOP as i32,
+                            errno: 0,
+                            code: SI_USER,
+                            field: Default::default(),
+                        },
+                    );
+                }
+                Ok(0)
+            }
+            _ => {
+                let thread = self.tracee(pid)?;
+                self.ptrace_tracee(request, &thread, addr, data)
+            }
+        }
+    }
+
+    /// Requests on the stopped tracee `thread`
+    fn ptrace_tracee(
+        &mut self,
+        request: usize,
+        thread: &Arc<Thread>,
+        addr: usize,
+        data: usize,
+    ) -> SysResult {
+        match request {
+            PTRACE_PEEKTEXT | PTRACE_PEEKDATA => {
+                let mut word = [0u8; size_of::<usize>()];
+                let ok = thread.vm.lock().read_forced(addr, &mut word);
+                if !ok {
+                    return Err(SysError::EIO);
+                }
+                UserOutPtr::<usize>::from(data).write(usize::from_ne_bytes(word))?;
+                Ok(0)
+            }
+            PTRACE_POKETEXT | PTRACE_POKEDATA => {
+                let ok = thread.vm.lock().write_forced(addr, &data.to_ne_bytes());
+                if ok {
+                    Ok(0)
+                } else {
+                    Err(SysError::EIO)
+                }
+            }
+            PTRACE_PEEKUSER => {
+                let value = match elf_greg_index(addr) {
+                    Some(idx) => with_state(thread, |state| elf_gregs_from_tf(&state.regs)[idx])?,
+                    None if user_area_padding(addr) => 0,
+                    None => return Err(SysError::EIO),
+                };
+                UserOutPtr::<usize>::from(data).write(value)?;
+                Ok(0)
+            }
+            PTRACE_POKEUSER => match elf_greg_index(addr) {
+                Some(idx) => {
+                    with_state(thread, |state| {
+                        let mut regs = elf_gregs_from_tf(&state.regs);
+                        regs[idx] = data;
+                        set_elf_gregs(&mut state.regs, &regs);
+                    })?;
+                    Ok(0)
+                }
+                None if user_area_padding(addr) => Ok(0),
+                None => Err(SysError::EIO),
+            },
+            PTRACE_GETREGS => {
+                let regs = with_state(thread, |state| elf_gregs_from_tf(&state.regs))?;
+                UserOutPtr::<usize>::from(data).write_array(&regs)?;
+                Ok(0)
+            }
+            PTRACE_SETREGS => {
+                let regs = UserInPtr::<[usize; ELF_NGREG]>::from(data).read()?;
+                with_state(thread, |state| set_elf_gregs(&mut state.regs, &regs))?;
+                Ok(0)
+            }
+            PTRACE_GETREGSET => {
+                if addr != NT_PRSTATUS {
+                    return Err(SysError::EINVAL);
+                }
+                let mut iov_ptr = UserInOutPtr::<IoVec>::from(data);
+                let mut iov = iov_ptr.read()?;
+                let regs = with_state(thread, |state| elf_gregs_from_tf(&state.regs))?;
+                let count = ELF_NGREG.min(iov.len / size_of::<usize>());
+                UserOutPtr::<usize>::from(iov.base as usize).write_array(&regs[..count])?;
+                iov.len = count * size_of::<usize>();
+                iov_ptr.write(iov)?;
+                Ok(0)
+            }
+            PTRACE_SETREGSET => {
+                if addr != NT_PRSTATUS {
+                    return Err(SysError::EINVAL);
+                }
+                let iov = UserInPtr::<IoVec>::from(data).read()?;
+                let count = ELF_NGREG.min(iov.len / size_of::<usize>());
+                let new_regs = UserInPtr::<usize>::from(iov.base as usize).read_array(count)?;
+                with_state(thread, |state| {
+                    let mut regs = elf_gregs_from_tf(&state.regs);
+                    regs[..count].copy_from_slice(&new_regs);
+                    set_elf_gregs(&mut state.regs, &regs);
+                })?;
+                Ok(0)
+            }
+            PTRACE_GETSIGINFO => {
+                let siginfo = with_state(thread, |state| state.siginfo)?.ok_or(SysError::EINVAL)?;
+                UserOutPtr::<Siginfo>::from(data).write(siginfo)?;
+                Ok(0)
+            }
+            PTRACE_SETSIGINFO => {
+                let siginfo = UserInPtr::<Siginfo>::from(data).read()?;
+                with_state(thread, |state| match state.siginfo {
+                    Some(_) => {
+                        state.siginfo = Some(siginfo);
+                        Ok(0)
+                    }
+                    None => Err(SysError::EINVAL),
+                })?
+            }
+            PTRACE_SETOPTIONS => {
+                let options = PtraceOptions::from_bits(data).ok_or(SysError::EINVAL)?;
+                with_state(thread, |state| state.options = options)?;
+                Ok(0)
+            }
+            PTRACE_GETEVENTMSG => {
+                let msg = with_state(thread, |state| state.event_msg)?;
+                UserOutPtr::<usize>::from(data).write(msg)?;
+                Ok(0)
+            }
+            PTRACE_CONT | PTRACE_SYSCALL | PTRACE_SINGLESTEP | PTRACE_DETACH => {
+                if data > Signal::RTMAX {
+                    return Err(SysError::EIO);
+                }
+                let mode = match request {
+                    PTRACE_SYSCALL => ResumeMode::Syscall,
+                    PTRACE_SINGLESTEP => ResumeMode::SingleStep,
+                    _ => ResumeMode::Cont,
+                };
+                with_state(thread, |state| {
+                    // the flag is set again from the mode when the tracee resumes
+                    set_single_step(&mut state.regs, mode == ResumeMode::SingleStep)?;
+                    state.detach = request == PTRACE_DETACH;
+                    state.resume(mode, data);
+                    Ok(0)
+                })?
+            }
+            PTRACE_KILL => {
+                send_signal(
+                    thread.proc.clone(),
+                    -1,
+                    Siginfo {
+                        signo: Signal::SIGKILL as i32,
+                        errno: 0,
+                        code: SI_USER,
+                        field: Default::default(),
+                    },
+                );
+                Ok(0)
+            }
+            _ => {
+                warn!("ptrace: unsupported request {:#x}", request);
+                Err(SysError::EIO)
+            }
+        }
+    }
+
+    /// Find the tracee `pid` of current process, which must be stopped
+    fn tracee(&self, pid: usize) -> Result<Arc<Thread>, SysError> {
+        let tracer = self.process().pid.get();
+        let thread = THREADS.read().get(&pid).cloned().ok_or(SysError::ESRCH)?;
+        let stopped = thread.inner.lock().ptrace.as_ref().map_or(false, |state| {
+            state.tracer == tracer && state.stop.is_some()
+        });
+        if stopped {
+            Ok(thread)
+        } else {
+            Err(SysError::ESRCH)
+        }
+    }
+}
+
+/// Run `f` with the tracing state of `thread`
+fn with_state<T>(thread: &Thread, f: impl FnOnce(&mut PtraceState) -> T) -> Result<T, SysError> {
+    let mut inner = thread.inner.lock();
+    let state = inner.ptrace.as_mut().ok_or(SysError::ESRCH)?;
+    Ok(f(state))
+}
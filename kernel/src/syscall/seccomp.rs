@@ -0,0 +1,184 @@
+//! Syscalls for seccomp
+
+use super::*;
+use crate::arch::signal::get_pc;
+use crate::process::ptrace::{ptrace_event_stop, PTRACE_EVENT_SECCOMP};
+use crate::process::seccomp::*;
+use crate::signal::{Siginfo, SiginfoFields, SigsysFields, SYS_SECCOMP};
+use crate::util::bpf::{SockFilter, SockFprog};
+
+const SECCOMP_SET_MODE_STRICT: usize = 0;
+const SECCOMP_SET_MODE_FILTER: usize = 1;
+const SECCOMP_GET_ACTION_AVAIL: usize = 2;
+
+const SECCOMP_FILTER_FLAG_TSYNC: usize = 1;
+const SECCOMP_FILTER_FLAG_LOG: usize = 2;
+const SECCOMP_FILTER_FLAG_SPEC_ALLOW: usize = 4;
+
+/// Largest errno returned by SECCOMP_RET_ERRNO
+const MAX_ERRNO: u32 = 4095;
+
+impl Syscall<'_> {
+    pub fn sys_seccomp(&mut self, op: usize, flags: usize, args: usize) -> SysResult {
+        info!(
+            "seccomp: op: {}, flags: {:#x}, args: {:#x}",
+            op, flags, args
+        );
+        match op {
+            SECCOMP_SET_MODE_STRICT => {
+                if flags != 0 || args != 0 {
+                    return Err(SysError::EINVAL);
+                }
+                self.seccomp_set_strict()
+            }
+            SECCOMP_SET_MODE_FILTER => {
+                let known = SECCOMP_FILTER_FLAG_TSYNC
+                    | SECCOMP_FILTER_FLAG_LOG
+                    | SECCOMP_FILTER_FLAG_SPEC_ALLOW;
+                if flags & !known != 0 {
+                    return Err(SysError::EINVAL);
+                }
+                self.seccomp_add_filter(args, flags & SECCOMP_FILTER_FLAG_TSYNC != 0)
+            }
+            SECCOMP_GET_ACTION_AVAIL => {
+                let action = UserInPtr::<u32>::from(args).read()?;
+                match action {
+                    SECCOMP_RET_KILL_PROCESS
+                    | SECCOMP_RET_KILL_THREAD
+                    | SECCOMP_RET_TRAP
+                    | SECCOMP_RET_ERRNO
+                    | SECCOMP_RET_TRACE
+                    | SECCOMP_RET_LOG
+                    | SECCOMP_RET_ALLOW => Ok(0),
+                    _ => Err(SysError::EOPNOTSUPP),
+                }
+            }
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    /// Enter SECCOMP_MODE_STRICT
+    pub fn seccomp_set_strict(&mut self) -> SysResult {
+        let mut inner = self.thread.inner.lock();
+        if inner.seccomp.mode() == SECCOMP_MODE_FILTER {
+            return Err(SysError::EINVAL);
+        }
+        inner.seccomp.strict = true;
+        Ok(0)
+    }
+
+    /// Attach the filter program at `fprog` to current thread,
+    /// or to all threads of current process with `tsync`
+    pub fn seccomp_add_filter(&mut self, fprog: usize, tsync: bool) -> SysResult {
+        // there are no capabilities to tell CAP_SYS_ADMIN by,
+        // so PR_SET_NO_NEW_PRIVS is required as for unprivileged callers on Linux
+        if !self.thread.inner.lock().no_new_privs {
+            return Err(SysError::EACCES);
+        }
+        let fprog = UserInPtr::<SockFprog>::from(fprog).read()?;
+        let filter =
+            UserInPtr::<SockFilter>::from(fprog.filter as usize).read_array(fprog.len as usize)?;
+        if !check_filter(&filter) {
+            return Err(SysError::EINVAL);
+        }
+        if self.thread.inner.lock().seccomp.strict {
+            return Err(SysError::EINVAL);
+        }
+        let filter = Arc::new(filter);
+        if tsync {
+            let threads = self.process().threads.clone();
+            let table = THREADS.read();
+            for tid in threads {
+                if let Some(thread) = table.get(&tid) {
+                    thread.inner.lock().seccomp.filters.push(filter.clone());
+                }
+            }
+        } else {
+            self.thread.inner.lock().seccomp.filters.push(filter);
+        }
+        Ok(0)
+    }
+
+    /// Check syscall `id` against the seccomp filters of current thread.
+    /// Return the syscall to run, which a tracer may have changed,
+    /// or the result of the syscall if it should not run.
+    pub(super) async fn seccomp_check(
+        &mut self,
+        mut id: usize,
+        mut args: [usize; 6],
+    ) -> Result<(usize, [usize; 6]), isize> {
+        let pc = get_pc(self.context);
+        let enosys = Err(-(SysError::ENOSYS as isize));
+        // whether a tracer has seen the syscall, which is checked again as it may be changed
+        let mut traced = false;
+        loop {
+            let ret = {
+                let inner = self.thread.inner.lock();
+                if inner.seccomp.mode() == SECCOMP_MODE_DISABLED {
+                    return Ok((id, args));
+                }
+                inner.seccomp.check(&SeccompData::new(id, pc, args))
+            };
+            let data = ret & SECCOMP_RET_DATA;
+            match ret & SECCOMP_RET_ACTION_FULL {
+                SECCOMP_RET_ALLOW => return Ok((id, args)),
+                SECCOMP_RET_LOG => {
+                    info!("seccomp: thread {} syscall {} logged", self.thread.tid, id);
+                    return Ok((id, args));
+                }
+                SECCOMP_RET_ERRNO => return Err(-(data.min(MAX_ERRNO) as isize)),
+                SECCOMP_RET_TRAP => {
+                    info!("seccomp: thread {} syscall {} trapped", self.thread.tid, id);
+                    force_signal(self.thread, self.sigsys(id, pc, data));
+                    return enosys;
+                }
+                // as on Linux, the tracer is not asked again for the syscall it let through
+                SECCOMP_RET_TRACE if traced => return Ok((id, args)),
+                SECCOMP_RET_TRACE => {
+                    if !self
+                        .thread
+                        .ptrace_event(PTRACE_EVENT_SECCOMP, data as usize)
+                    {
+                        return enosys;
+                    }
+                    ptrace_event_stop(self.thread, self.context).await;
+                    // the tracer skips the syscall by setting its number to -1
+                    id = self.context.get_syscall_num();
+                    if id == usize::max_value() {
+                        return enosys;
+                    }
+                    args = self.context.get_syscall_args();
+                    traced = true;
+                }
+                // there are no listeners of SECCOMP_RET_USER_NOTIF
+                SECCOMP_RET_USER_NOTIF => return enosys,
+                _ => {
+                    // SECCOMP_RET_KILL_*, threads can not be killed alone,
+                    // so kill the whole process with a core dump
+                    warn!(
+                        "seccomp: thread {} killed by syscall {}",
+                        self.thread.tid, id
+                    );
+                    self.process().dispositions[Signal::SIGSYS as usize] = SignalAction::default();
+                    force_signal(self.thread, self.sigsys(id, pc, data));
+                    return enosys;
+                }
+            }
+        }
+    }
+
+    fn sigsys(&self, id: usize, pc: usize, data: u32) -> Siginfo {
+        let mut field = SiginfoFields::default();
+        field.sigsys = SigsysFields {
+            call_addr: pc,
+            syscall: id as i32,
+            arch: crate::arch::syscall::AUDIT_ARCH,
+        };
+        Siginfo {
+            signo: Signal::SIGSYS as i32,
+            errno: data as i32,
+            code: SYS_SECCOMP,
+            field,
+        }
+    }
+}
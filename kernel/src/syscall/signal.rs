@@ -117,7 +117,8 @@ impl Syscall<'_> {
             };
             match pid {
                 pid if pid > 0 => {
-                    if let Some(process) = process(pid as usize) {
+                    let pid = self.global_pid(pid as usize)?;
+                    if let Some(process) = process(pid) {
                         send_signal(process, -1, info);
                         Ok(0)
                     } else {
@@ -136,13 +137,17 @@ impl Syscall<'_> {
                     // TODO: check permissions
                     // sig is sent to every process for which the calling process
                     // has permission to send signals, except for process 1 (init)
-                    for process in PROCESSES.read().values() {
-                        send_signal(process.clone(), -1, info);
+                    let pid_ns = self.process().ns.pid.clone();
+                    for (&pid, process) in PROCESSES.read().iter() {
+                        if pid_ns.local(pid).is_some() {
+                            send_signal(process.clone(), -1, info);
+                        }
                     }
                     Ok(0)
                 }
                 _ => {
-                    let process_group = process_group((-pid) as Pgid);
+                    let pgid = self.global_pid((-pid) as usize)?;
+                    let process_group = process_group(pgid as Pgid);
                     if process_group.is_empty() {
                         Err(ESRCH)
                     } else {
@@ -162,6 +167,7 @@ impl Syscall<'_> {
     pub fn sys_tkill(&mut self, tid: usize, signum: usize) -> SysResult {
         if let Some(signal) = <Signal as FromPrimitive>::from_usize(signum) {
             info!("tkill: tid: {}, signal: {:?}", tid, signal);
+            let tid = self.global_pid(tid)?;
             if let Some(process) = process_of(tid) {
                 send_signal(
                     process,
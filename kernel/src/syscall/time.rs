@@ -126,6 +126,19 @@ impl TimeVal {
             usec: (usec % USEC_PER_SEC) as usize,
         }
     }
+
+    pub fn to_duration(&self) -> Duration {
+        Duration::new(self.sec as u64, (self.usec * 1000) as u32)
+    }
+}
+
+impl From<Duration> for TimeVal {
+    fn from(duration: Duration) -> Self {
+        TimeVal {
+            sec: duration.as_secs() as usize,
+            usec: duration.subsec_micros() as usize,
+        }
+    }
 }
 
 #[repr(C)]
@@ -167,6 +180,15 @@ impl TimeSpec {
     pub fn is_zero(&self) -> bool {
         self.sec == 0 && self.nsec == 0
     }
+
+    /// Deadline in `timer_now` of this absolute time since epoch
+    pub fn to_deadline(&self) -> Duration {
+        let left = self
+            .to_duration()
+            .checked_sub(TimeSpec::get_epoch().to_duration())
+            .unwrap_or(Duration::from_secs(0));
+        crate::arch::timer::timer_now() + left
+    }
 }
 
 impl Into<Timespec> for TimeSpec {
@@ -0,0 +1,373 @@
+//! Classic BPF, the filter language of seccomp and socket filters
+//!
+//! Ref: [https://www.kernel.org/doc/Documentation/networking/filter.txt]
+
+/// Linux struct sock_filter
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// Linux struct sock_fprog
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SockFprog {
+    pub len: u16,
+    pub filter: *const SockFilter,
+}
+
+pub const BPF_MAXINSNS: usize = 4096;
+pub const BPF_MEMWORDS: usize = 16;
+
+// instruction classes
+pub const BPF_LD: u16 = 0x00;
+pub const BPF_LDX: u16 = 0x01;
+pub const BPF_ST: u16 = 0x02;
+pub const BPF_STX: u16 = 0x03;
+pub const BPF_ALU: u16 = 0x04;
+pub const BPF_JMP: u16 = 0x05;
+pub const BPF_RET: u16 = 0x06;
+pub const BPF_MISC: u16 = 0x07;
+
+// ld/ldx fields
+pub const BPF_W: u16 = 0x00;
+pub const BPF_H: u16 = 0x08;
+pub const BPF_B: u16 = 0x10;
+pub const BPF_IMM: u16 = 0x00;
+pub const BPF_ABS: u16 = 0x20;
+pub const BPF_IND: u16 = 0x40;
+pub const BPF_MEM: u16 = 0x60;
+pub const BPF_LEN: u16 = 0x80;
+pub const BPF_MSH: u16 = 0xa0;
+
+// alu/jmp fields
+pub const BPF_ADD: u16 = 0x00;
+pub const BPF_SUB: u16 = 0x10;
+pub const BPF_MUL: u16 = 0x20;
+pub const BPF_DIV: u16 = 0x30;
+pub const BPF_OR: u16 = 0x40;
+pub const BPF_AND: u16 = 0x50;
+pub const BPF_LSH: u16 = 0x60;
+pub const BPF_RSH: u16 = 0x70;
+pub const BPF_NEG: u16 = 0x80;
+pub const BPF_MOD: u16 = 0x90;
+pub const BPF_XOR: u16 = 0xa0;
+pub const BPF_JA: u16 = 0x00;
+pub const BPF_JEQ: u16 = 0x10;
+pub const BPF_JGT: u16 = 0x20;
+pub const BPF_JGE: u16 = 0x30;
+pub const BPF_JSET: u16 = 0x40;
+pub const BPF_K: u16 = 0x00;
+pub const BPF_X: u16 = 0x08;
+
+// ret fields
+pub const BPF_A: u16 = 0x10;
+
+// misc fields
+pub const BPF_TAX: u16 = 0x00;
+pub const BPF_TXA: u16 = 0x80;
+
+pub fn class(code: u16) -> u16 {
+    code & 0x07
+}
+
+pub fn size(code: u16) -> u16 {
+    code & 0x18
+}
+
+pub fn mode(code: u16) -> u16 {
+    code & 0xe0
+}
+
+pub fn op(code: u16) -> u16 {
+    code & 0xf0
+}
+
+pub fn src(code: u16) -> u16 {
+    code & 0x08
+}
+
+/// Bytes loaded by a BPF_LD instruction
+fn load_size(code: u16) -> Option<u32> {
+    match size(code) {
+        BPF_W => Some(4),
+        BPF_H => Some(2),
+        BPF_B => Some(1),
+        _ => None,
+    }
+}
+
+/// Check a program like `bpf_check_classic` of Linux:
+/// known opcodes only, in-range jumps and memory slots,
+/// no division by a zero constant, and a return at the end.
+pub fn check(prog: &[SockFilter]) -> bool {
+    if prog.is_empty() || prog.len() > BPF_MAXINSNS {
+        return false;
+    }
+    for (pc, ins) in prog.iter().enumerate() {
+        let code = ins.code;
+        let k = ins.k as usize;
+        // instructions after this one
+        let rest = prog.len() - pc - 1;
+        let valid = match class(code) {
+            BPF_LD => {
+                load_size(code).is_some()
+                    && match mode(code) {
+                        BPF_IMM | BPF_ABS | BPF_IND | BPF_LEN => true,
+                        BPF_MEM => k < BPF_MEMWORDS,
+                        _ => false,
+                    }
+            }
+            BPF_LDX => match mode(code) {
+                BPF_IMM | BPF_LEN => size(code) == BPF_W,
+                BPF_MEM => size(code) == BPF_W && k < BPF_MEMWORDS,
+                BPF_MSH => size(code) == BPF_B,
+                _ => false,
+            },
+            BPF_ST | BPF_STX => code & 0xf8 == 0 && k < BPF_MEMWORDS,
+            BPF_ALU => match op(code) {
+                BPF_DIV | BPF_MOD => src(code) == BPF_X || k != 0,
+                BPF_NEG => src(code) == BPF_K,
+                BPF_ADD | BPF_SUB | BPF_MUL | BPF_OR | BPF_AND | BPF_LSH | BPF_RSH | BPF_XOR => {
+                    true
+                }
+                _ => false,
+            },
+            BPF_JMP => match op(code) {
+                BPF_JA => src(code) == BPF_K && k < rest,
+                BPF_JEQ | BPF_JGT | BPF_JGE | BPF_JSET => {
+                    (ins.jt as usize) < rest && (ins.jf as usize) < rest
+                }
+                _ => false,
+            },
+            BPF_RET => code == BPF_RET | BPF_K || code == BPF_RET | BPF_A,
+            BPF_MISC => code == BPF_MISC | BPF_TAX || code == BPF_MISC | BPF_TXA,
+            _ => false,
+        };
+        if !valid {
+            return false;
+        }
+    }
+    class(prog[prog.len() - 1].code) == BPF_RET
+}
+
+/// Run a program which passed `check`.
+///
+/// `len` is the size of the input for BPF_LEN.
+/// `load(offset, size)` reads 1, 2 or 4 bytes of the input,
+/// the program returns 0 if it fails.
+pub fn run(prog: &[SockFilter], len: u32, load: impl Fn(u32, u32) -> Option<u32>) -> u32 {
+    let mut a: u32 = 0;
+    let mut x: u32 = 0;
+    let mut mem = [0u32; BPF_MEMWORDS];
+    let mut pc = 0;
+    while pc < prog.len() {
+        let ins = prog[pc];
+        let code = ins.code;
+        let k = ins.k;
+        pc += 1;
+        match class(code) {
+            BPF_LD => {
+                a = match mode(code) {
+                    BPF_IMM => k,
+                    BPF_ABS => match load(k, load_size(code).unwrap()) {
+                        Some(value) => value,
+                        None => return 0,
+                    },
+                    BPF_IND => match load(x.wrapping_add(k), load_size(code).unwrap()) {
+                        Some(value) => value,
+                        None => return 0,
+                    },
+                    BPF_MEM => mem[k as usize],
+                    BPF_LEN => len,
+                    _ => unreachable!(),
+                }
+            }
+            BPF_LDX => {
+                x = match mode(code) {
+                    BPF_IMM => k,
+                    BPF_MEM => mem[k as usize],
+                    BPF_LEN => len,
+                    // IP header length
+                    BPF_MSH => match load(k, 1) {
+                        Some(value) => (value & 0xf) << 2,
+                        None => return 0,
+                    },
+                    _ => unreachable!(),
+                }
+            }
+            BPF_ST => mem[k as usize] = a,
+            BPF_STX => mem[k as usize] = x,
+            BPF_ALU => {
+                let operand = if src(code) == BPF_X { x } else { k };
+                a = match op(code) {
+                    BPF_ADD => a.wrapping_add(operand),
+                    BPF_SUB => a.wrapping_sub(operand),
+                    BPF_MUL => a.wrapping_mul(operand),
+                    BPF_DIV if operand == 0 => return 0,
+                    BPF_DIV => a / operand,
+                    BPF_MOD if operand == 0 => return 0,
+                    BPF_MOD => a % operand,
+                    BPF_OR => a | operand,
+                    BPF_AND => a & operand,
+                    BPF_LSH => a.checked_shl(operand).unwrap_or(0),
+                    BPF_RSH => a.checked_shr(operand).unwrap_or(0),
+                    BPF_NEG => a.wrapping_neg(),
+                    BPF_XOR => a ^ operand,
+                    _ => unreachable!(),
+                }
+            }
+            BPF_JMP => {
+                let operand = if src(code) == BPF_X { x } else { k };
+                let taken = match op(code) {
+                    BPF_JA => {
+                        pc += k as usize;
+                        continue;
+                    }
+                    BPF_JEQ => a == operand,
+                    BPF_JGT => a > operand,
+                    BPF_JGE => a >= operand,
+                    BPF_JSET => a & operand != 0,
+                    _ => unreachable!(),
+                };
+                pc += if taken { ins.jt } else { ins.jf } as usize;
+            }
+            BPF_RET => return if code & BPF_A != 0 { a } else { k },
+            BPF_MISC => {
+                if code & BPF_TXA != 0 {
+                    a = x;
+                } else {
+                    x = a;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    // checked programs always end with a return
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stmt(code: u16, k: u32) -> SockFilter {
+        SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+
+    /// Run `prog` on `data` in network byte order
+    fn run_on(prog: &[SockFilter], data: &[u8]) -> u32 {
+        assert!(check(prog));
+        run(prog, data.len() as u32, |offset, size| {
+            let start = offset as usize;
+            let bytes = data.get(start..start.checked_add(size as usize)?)?;
+            Some(bytes.iter().fold(0, |value, &b| value << 8 | b as u32))
+        })
+    }
+
+    #[test]
+    fn check_rejects_invalid_programs() {
+        let ret = stmt(BPF_RET | BPF_K, 0);
+        assert!(!check(&[]));
+        assert!(check(&[ret]));
+        // no return at the end
+        assert!(!check(&[stmt(BPF_LD | BPF_W | BPF_IMM, 1)]));
+        // jumps out of the program
+        assert!(!check(&[jump(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 0), ret]));
+        assert!(!check(&[stmt(BPF_JMP | BPF_JA, 1), ret]));
+        assert!(check(&[jump(BPF_JMP | BPF_JEQ | BPF_K, 0, 0, 0), ret]));
+        // division by a zero constant
+        assert!(!check(&[stmt(BPF_ALU | BPF_DIV | BPF_K, 0), ret]));
+        assert!(check(&[stmt(BPF_ALU | BPF_DIV | BPF_X, 0), ret]));
+        // memory slots
+        assert!(!check(&[stmt(BPF_ST, BPF_MEMWORDS as u32), ret]));
+        assert!(!check(&[stmt(BPF_LD | BPF_W | BPF_MEM, 16), ret]));
+        // unknown opcodes
+        assert!(!check(&[stmt(BPF_ALU | 0xb0, 0), ret]));
+        assert!(!check(&[stmt(BPF_RET | BPF_X, 0)]));
+        assert!(!check(&vec![ret; BPF_MAXINSNS + 1]));
+    }
+
+    #[test]
+    fn run_loads_and_jumps() {
+        // accept IPv4 over ethernet with its length, drop others
+        let prog = [
+            stmt(BPF_LD | BPF_H | BPF_ABS, 12),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, 0x0800, 0, 2),
+            stmt(BPF_LD | BPF_W | BPF_LEN, 0),
+            stmt(BPF_RET | BPF_A, 0),
+            stmt(BPF_RET | BPF_K, 0),
+        ];
+        let mut frame = [0u8; 20];
+        frame[12] = 0x08;
+        assert_eq!(run_on(&prog, &frame), 20);
+        frame[12] = 0x86;
+        frame[13] = 0xdd;
+        assert_eq!(run_on(&prog, &frame), 0);
+        // loading out of the input fails
+        assert_eq!(run_on(&prog, &frame[..13]), 0);
+    }
+
+    #[test]
+    fn run_alu_and_memory() {
+        let prog = [
+            stmt(BPF_LD | BPF_W | BPF_IMM, 6),
+            stmt(BPF_ST, 3),
+            stmt(BPF_LDX | BPF_W | BPF_IMM, 4),
+            stmt(BPF_ALU | BPF_MUL | BPF_X, 0),
+            stmt(BPF_ALU | BPF_SUB | BPF_K, 1),
+            stmt(BPF_ALU | BPF_MOD | BPF_K, 7),
+            stmt(BPF_LDX | BPF_W | BPF_MEM, 3),
+            stmt(BPF_ALU | BPF_LSH | BPF_X, 0),
+            stmt(BPF_JMP | BPF_JA, 1),
+            stmt(BPF_RET | BPF_K, 1),
+            stmt(BPF_RET | BPF_A, 0),
+        ];
+        // ((6 * 4 - 1) % 7) << 6
+        assert_eq!(run_on(&prog, &[]), 128);
+        let prog = [
+            stmt(BPF_LD | BPF_W | BPF_IMM, 5),
+            stmt(BPF_ALU | BPF_NEG, 0),
+            stmt(BPF_MISC | BPF_TAX, 0),
+            stmt(BPF_LD | BPF_W | BPF_IMM, 0),
+            stmt(BPF_MISC | BPF_TXA, 0),
+            stmt(BPF_RET | BPF_A, 0),
+        ];
+        assert_eq!(run_on(&prog, &[]), 5u32.wrapping_neg());
+        // division by zero in X returns 0
+        let prog = [
+            stmt(BPF_LD | BPF_W | BPF_IMM, 5),
+            stmt(BPF_ALU | BPF_DIV | BPF_X, 0),
+            stmt(BPF_RET | BPF_K, 1),
+        ];
+        assert_eq!(run_on(&prog, &[]), 0);
+    }
+
+    #[test]
+    fn run_indirect_loads() {
+        // load the byte after an IPv4 header of variable length
+        let prog = [
+            stmt(BPF_LDX | BPF_B | BPF_MSH, 0),
+            stmt(BPF_LD | BPF_B | BPF_IND, 0),
+            stmt(BPF_RET | BPF_A, 0),
+        ];
+        let mut packet = [0u8; 28];
+        packet[0] = 0x46;
+        packet[24] = 0xab;
+        assert_eq!(run_on(&prog, &packet), 0xab);
+        assert_eq!(run_on(&prog, &packet[..24]), 0);
+    }
+}